@@ -0,0 +1,100 @@
+//! One log line per HTTP request: method, path, status, latency, body sizes
+//! and request id. Bodies themselves (audio included) are never logged, and
+//! neither are query strings.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::body::HttpBody as _;
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Deserialize;
+
+/// Tags the log lines of one request; TTS clips are named after it too.
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Set on every request by [`layer`], and returned as `x-request-id`.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestId(pub u64);
+
+impl RequestId {
+    /// A fresh id, for work that doesn't come in over HTTP.
+    pub fn next() -> Self {
+        Self(NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// The `[access_log]` config section.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLog {
+    pub enabled: bool,
+    /// Fraction of successful requests that are logged. Errors (4xx, 5xx)
+    /// are always logged.
+    pub sample_rate: f64,
+    /// Paths never logged when successful, e.g. health checks.
+    pub skip_paths: Vec<String>,
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            sample_rate: 1.0,
+            skip_paths: vec!["/api/health".to_string()],
+        }
+    }
+}
+
+impl AccessLog {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(0.0..=1.0).contains(&self.sample_rate) {
+            anyhow::bail!("access_log.sample_rate must be between 0 and 1");
+        }
+        Ok(())
+    }
+
+    fn wants(&self, path: &str, status: u16) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        if status >= 400 {
+            return true;
+        }
+        !self.skip_paths.iter().any(|p| p == path) && rand::random::<f64>() < self.sample_rate
+    }
+}
+
+/// Middleware assigning the request id and writing the access log line.
+pub async fn layer(State(config): State<Arc<AccessLog>>, mut request: Request, next: Next) -> Response {
+    let RequestId(id) = RequestId::next();
+    request.extensions_mut().insert(RequestId(id));
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let request_bytes = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .map_or_else(|| "-".to_string(), |v| format!("{v}B"));
+
+    let start = Instant::now();
+    let mut response = next.run(request).await;
+    let status = response.status().as_u16();
+    response.headers_mut().insert("x-request-id", HeaderValue::from(id));
+
+    if config.wants(&path, status) {
+        let response_bytes = response
+            .body()
+            .size_hint()
+            .exact()
+            .map_or_else(|| "stream".to_string(), |n| format!("{n}B"));
+        println!(
+            "access[{id}]: {method} {path} {status} {:.1?} in={request_bytes} out={response_bytes}",
+            start.elapsed()
+        );
+    }
+    response
+}
@@ -0,0 +1,448 @@
+//! Voice characteristics of recordings: drafting a Parler description from a
+//! reference sample, comparing how alike two speakers sound (and two clips
+//! overall), and checking that generated speech has a believable pace.
+
+use std::ops::RangeInclusive;
+
+use serde::{Deserialize, Serialize};
+
+use crate::audio::Pcm;
+
+/// Pitch tracking runs on a decimated copy of the input; 16 kHz keeps the
+/// autocorrelation cheap while covering the whole speaking range.
+const ANALYSIS_RATE: u32 = 16_000;
+const FRAME_SECS: f64 = 0.04;
+const HOP_SECS: f64 = 0.01;
+const MIN_F0_HZ: f64 = 60.0;
+const MAX_F0_HZ: f64 = 400.0;
+/// Minimum normalized autocorrelation for a frame to count as voiced.
+const VOICING_THRESHOLD: f32 = 0.5;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VoiceAnalysis {
+    pub duration_secs: f64,
+    /// Time spent above the speech energy threshold.
+    pub speech_secs: f64,
+    pub pitch_median_hz: Option<f64>,
+    pub pitch_mean_hz: Option<f64>,
+    /// Spread of the pitch contour around the median, in semitones.
+    pub pitch_std_semitones: Option<f64>,
+    /// Syllable nuclei per second of speech.
+    pub syllables_per_sec: Option<f64>,
+    pub estimated_gender: Option<&'static str>,
+    /// Loud speech frames vs. the quietest frames, in dB.
+    pub snr_db: f64,
+}
+
+pub fn analyze(pcm: &Pcm) -> VoiceAnalysis {
+    let (samples, rate) = decimate(pcm);
+    let frame_len = (FRAME_SECS * rate as f64) as usize;
+    let hop = ((HOP_SECS * rate as f64) as usize).max(1);
+
+    let frames: Vec<&[f32]> = (0..samples.len().saturating_sub(frame_len))
+        .step_by(hop)
+        .map(|start| &samples[start..start + frame_len])
+        .collect();
+    let energies: Vec<f32> = frames.iter().map(|f| rms(f)).collect();
+
+    let noise = percentile(&energies, 0.1).unwrap_or(0.0).max(1e-5);
+    let peak = percentile(&energies, 0.95).unwrap_or(0.0).max(noise);
+    let snr_db = (20.0 * (peak / noise).log10()).clamp(0.0, 80.0) as f64;
+    let speech_threshold = noise + (peak - noise) * 0.2;
+
+    let pitches: Vec<Option<f64>> = frames
+        .iter()
+        .zip(&energies)
+        .map(|(frame, &e)| if e > speech_threshold { detect_pitch(frame, rate) } else { None })
+        .collect();
+
+    let speech_frames = energies.iter().filter(|&&e| e > speech_threshold).count();
+    let speech_secs = speech_frames as f64 * hop as f64 / rate as f64;
+
+    let mut voiced: Vec<f64> = pitches.iter().flatten().copied().collect();
+    voiced.sort_by(|a, b| a.total_cmp(b));
+    let (pitch_median_hz, pitch_mean_hz, pitch_std_semitones) = if voiced.len() >= 10 {
+        let median = voiced[voiced.len() / 2];
+        let mean = voiced.iter().sum::<f64>() / voiced.len() as f64;
+        let semitones: Vec<f64> = voiced.iter().map(|f| 12.0 * (f / median).log2()).collect();
+        let st_mean = semitones.iter().sum::<f64>() / semitones.len() as f64;
+        let st_var = semitones.iter().map(|s| (s - st_mean).powi(2)).sum::<f64>()
+            / semitones.len() as f64;
+        (Some(median), Some(mean), Some(st_var.sqrt()))
+    } else {
+        (None, None, None)
+    };
+
+    let syllables_per_sec = if speech_secs >= 0.5 {
+        Some(count_syllables(&energies, &pitches, speech_threshold) as f64 / speech_secs)
+    } else {
+        None
+    };
+
+    let estimated_gender = pitch_median_hz.and_then(|f0| {
+        if f0 < 160.0 {
+            Some("male")
+        } else if f0 > 180.0 {
+            Some("female")
+        } else {
+            None
+        }
+    });
+
+    VoiceAnalysis {
+        duration_secs: pcm.duration_secs(),
+        speech_secs,
+        pitch_median_hz,
+        pitch_mean_hz,
+        pitch_std_semitones,
+        syllables_per_sec,
+        estimated_gender,
+        snr_db,
+    }
+}
+
+/// Turns an analysis into a description in the style of the Parler training data.
+pub fn draft_description(analysis: &VoiceAnalysis) -> String {
+    let speaker = match analysis.estimated_gender {
+        Some(gender) => format!("A {gender} speaker"),
+        None => "A speaker".to_string(),
+    };
+
+    let (low, high) = match analysis.estimated_gender {
+        Some("male") => (105.0, 145.0),
+        Some("female") => (180.0, 240.0),
+        _ => (130.0, 220.0),
+    };
+    let pitch = match analysis.pitch_median_hz {
+        Some(f0) if f0 < low => "a low-pitched voice",
+        Some(f0) if f0 > high => "a high-pitched voice",
+        _ => "a moderate pitch",
+    };
+
+    let delivery = match analysis.pitch_std_semitones {
+        Some(st) if st < 1.5 => "a monotone",
+        Some(st) if st < 3.0 => "a slightly expressive",
+        Some(_) => "an expressive and animated",
+        None => "a",
+    };
+
+    let pace = match analysis.syllables_per_sec {
+        Some(rate) if rate < 3.3 => "at a slow pace",
+        Some(rate) if rate > 5.3 => "at a fast pace",
+        _ => "with a moderate speed",
+    };
+
+    let recording = if analysis.snr_db >= 35.0 {
+        "The recording is very clear, with the speaker in a quiet room."
+    } else if analysis.snr_db >= 20.0 {
+        "The recording is fairly clean, with slight background noise."
+    } else {
+        "The recording is noisy, with noticeable background sound."
+    };
+
+    format!("{speaker} with {pitch} delivers {delivery} speech {pace}. {recording}")
+}
+
+/// Box-filters and decimates to roughly `ANALYSIS_RATE`.
+fn decimate(pcm: &Pcm) -> (Vec<f32>, u32) {
+    let factor = (pcm.sample_rate / ANALYSIS_RATE).max(1) as usize;
+    if factor == 1 {
+        return (pcm.samples.clone(), pcm.sample_rate);
+    }
+    let samples = pcm
+        .samples
+        .chunks(factor)
+        .map(|c| c.iter().sum::<f32>() / c.len() as f32)
+        .collect();
+    (samples, pcm.sample_rate / factor as u32)
+}
+
+fn rms(frame: &[f32]) -> f32 {
+    (frame.iter().map(|s| s * s).sum::<f32>() / frame.len().max(1) as f32).sqrt()
+}
+
+fn percentile(values: &[f32], q: f64) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let idx = ((sorted.len() - 1) as f64 * q).round() as usize;
+    Some(sorted[idx])
+}
+
+/// Normalized autocorrelation pitch estimate for one frame.
+fn detect_pitch(frame: &[f32], rate: u32) -> Option<f64> {
+    let mean = frame.iter().sum::<f32>() / frame.len() as f32;
+    let x: Vec<f32> = frame.iter().map(|s| s - mean).collect();
+    let min_lag = (rate as f64 / MAX_F0_HZ) as usize;
+    let max_lag = ((rate as f64 / MIN_F0_HZ) as usize).min(x.len() / 2);
+    if min_lag == 0 || min_lag >= max_lag {
+        return None;
+    }
+
+    let correlations: Vec<f32> = (min_lag..=max_lag)
+        .map(|lag| {
+            let (a, b) = (&x[..x.len() - lag], &x[lag..]);
+            let dot: f32 = a.iter().zip(b).map(|(p, q)| p * q).sum();
+            let norm = (a.iter().map(|v| v * v).sum::<f32>() * b.iter().map(|v| v * v).sum::<f32>())
+                .sqrt();
+            if norm > 0.0 {
+                dot / norm
+            } else {
+                0.0
+            }
+        })
+        .collect();
+
+    let best = correlations.iter().copied().fold(f32::MIN, f32::max);
+    if best < VOICING_THRESHOLD {
+        return None;
+    }
+    // Take the shortest lag close to the best peak to avoid octave-down errors.
+    let idx = correlations.iter().position(|&c| c >= best * 0.9)?;
+    Some(rate as f64 / (min_lag + idx) as f64)
+}
+
+/// Counts voiced local maxima of the smoothed energy envelope, at least
+/// 100 ms apart, as syllable nuclei.
+fn count_syllables(energies: &[f32], pitches: &[Option<f64>], threshold: f32) -> usize {
+    const RADIUS: usize = 5;
+    let smoothed: Vec<f32> = (0..energies.len())
+        .map(|i| {
+            let window = &energies[i.saturating_sub(2)..(i + 3).min(energies.len())];
+            window.iter().sum::<f32>() / window.len() as f32
+        })
+        .collect();
+
+    let mut count = 0;
+    let mut last_peak: Option<usize> = None;
+    for i in 0..smoothed.len() {
+        let window = &smoothed[i.saturating_sub(RADIUS)..(i + RADIUS + 1).min(smoothed.len())];
+        let is_peak = smoothed[i] > threshold && window.iter().all(|&v| v <= smoothed[i]);
+        let voiced = pitches[i.saturating_sub(2)..(i + 3).min(pitches.len())]
+            .iter()
+            .any(Option::is_some);
+        if is_peak && voiced && last_peak.is_none_or(|p| i - p >= 2 * RADIUS) {
+            count += 1;
+            last_peak = Some(i);
+        }
+    }
+    count
+}
+
+const MEL_BANDS: usize = 40;
+const CEPSTRAL_COEFFS: usize = 20;
+const FFT_SIZE: usize = 512;
+
+/// Fixed-size voice fingerprint: mean and spread of the MFCCs over speech
+/// frames. A lightweight stand-in for a neural speaker-verification model;
+/// good at separating clearly different voices, not at verifying identity.
+pub fn speaker_embedding(pcm: &Pcm) -> Vec<f32> {
+    let cepstra: Vec<[f32; CEPSTRAL_COEFFS]> = speech_log_mels(pcm).iter().map(|log_mel| dct(log_mel)).collect();
+    let mut embedding = vec![0.0f32; 2 * (CEPSTRAL_COEFFS - 1)];
+    if cepstra.is_empty() {
+        return embedding;
+    }
+    let n = cepstra.len() as f32;
+    // c0 only tracks loudness, so it is left out.
+    for k in 1..CEPSTRAL_COEFFS {
+        let mean = cepstra.iter().map(|c| c[k]).sum::<f32>() / n;
+        let var = cepstra.iter().map(|c| (c[k] - mean).powi(2)).sum::<f32>() / n;
+        embedding[k - 1] = mean;
+        embedding[CEPSTRAL_COEFFS - 1 + k - 1] = var.sqrt();
+    }
+    embedding
+}
+
+/// Natural-log mel band energies of each speech frame.
+fn speech_log_mels(pcm: &Pcm) -> Vec<Vec<f32>> {
+    let (samples, rate) = decimate(pcm);
+    let frame_len = (0.025 * rate as f64) as usize;
+    let hop = ((HOP_SECS * rate as f64) as usize).max(1);
+    let window: Vec<f32> = (0..frame_len)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / frame_len as f32).cos())
+        .collect();
+    let filters = mel_filterbank(rate);
+    let fft = rustfft::FftPlanner::<f32>::new().plan_fft_forward(FFT_SIZE);
+
+    let frames: Vec<&[f32]> = (0..samples.len().saturating_sub(frame_len))
+        .step_by(hop)
+        .map(|start| &samples[start..start + frame_len])
+        .collect();
+    let energies: Vec<f32> = frames.iter().map(|f| rms(f)).collect();
+    let noise = percentile(&energies, 0.1).unwrap_or(0.0);
+    let peak = percentile(&energies, 0.95).unwrap_or(0.0);
+    let speech_threshold = noise + (peak - noise) * 0.2;
+
+    let mut log_mels = Vec::new();
+    let mut buffer = vec![rustfft::num_complex::Complex32::default(); FFT_SIZE];
+    for (frame, &energy) in frames.iter().zip(&energies) {
+        if energy <= speech_threshold {
+            continue;
+        }
+        buffer.iter_mut().for_each(|c| *c = Default::default());
+        for (i, (s, w)) in frame.iter().zip(&window).enumerate().take(FFT_SIZE) {
+            buffer[i].re = s * w;
+        }
+        fft.process(&mut buffer);
+        let power: Vec<f32> = buffer[..FFT_SIZE / 2 + 1].iter().map(|c| c.norm_sqr()).collect();
+        let log_mel: Vec<f32> = filters
+            .iter()
+            .map(|f| f.iter().zip(&power).map(|(w, p)| w * p).sum::<f32>().max(1e-10).ln())
+            .collect();
+        log_mels.push(log_mel);
+    }
+    log_mels
+}
+
+/// Cosine similarity of two embeddings mapped to `[0, 1]`.
+pub fn similarity(a: &[f32], b: &[f32]) -> f64 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = a.iter().map(|x| x * x).sum::<f32>().sqrt() * b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return 0.0;
+    }
+    ((dot / norm) as f64 + 1.0) / 2.0
+}
+
+/// Level and length of one clip, as compared.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClipLevels {
+    pub duration_secs: f64,
+    pub rms_dbfs: f64,
+    pub peak_dbfs: f64,
+}
+
+/// How clip `b` differs from clip `a`, for regression tests of a model or
+/// config change: render the same prompts before and after, then compare.
+/// Deltas are `b` minus `a`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Comparison {
+    pub a: ClipLevels,
+    pub b: ClipLevels,
+    pub duration_delta_secs: f64,
+    pub loudness_delta_db: f64,
+    /// Root-mean-square difference of the two average mel spectra over
+    /// speech, after evening out their levels, in dB; 0 for the same
+    /// timbre. None when either clip has no speech.
+    pub spectral_distance_db: Option<f64>,
+    /// `similarity` of the speaker embeddings.
+    pub speaker_similarity: f64,
+}
+
+pub fn compare(a: &Pcm, b: &Pcm) -> Comparison {
+    let (levels_a, levels_b) = (levels(a), levels(b));
+    let (spectrum_a, spectrum_b) = (average_spectrum(a), average_spectrum(b));
+    let spectral_distance_db = spectrum_a.zip(spectrum_b).map(|(sa, sb)| {
+        let diffs: Vec<f64> = sa.iter().zip(&sb).map(|(x, y)| y - x).collect();
+        let offset = diffs.iter().sum::<f64>() / diffs.len() as f64;
+        (diffs.iter().map(|d| (d - offset).powi(2)).sum::<f64>() / diffs.len() as f64).sqrt()
+    });
+    Comparison {
+        duration_delta_secs: levels_b.duration_secs - levels_a.duration_secs,
+        loudness_delta_db: levels_b.rms_dbfs - levels_a.rms_dbfs,
+        spectral_distance_db,
+        speaker_similarity: similarity(&speaker_embedding(a), &speaker_embedding(b)),
+        a: levels_a,
+        b: levels_b,
+    }
+}
+
+pub fn levels(pcm: &Pcm) -> ClipLevels {
+    let level = |value: f32| 20.0 * (value.max(1e-6) as f64).log10();
+    let peak = pcm.samples.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+    ClipLevels {
+        duration_secs: pcm.duration_secs(),
+        rms_dbfs: level(rms(&pcm.samples)),
+        peak_dbfs: level(peak),
+    }
+}
+
+/// Mean mel band energies over speech frames, in dB.
+fn average_spectrum(pcm: &Pcm) -> Option<Vec<f64>> {
+    let log_mels = speech_log_mels(pcm);
+    if log_mels.is_empty() {
+        return None;
+    }
+    let n = log_mels.len() as f64;
+    let to_db = 10.0 / std::f64::consts::LN_10;
+    Some(
+        (0..MEL_BANDS)
+            .map(|band| log_mels.iter().map(|m| m[band] as f64).sum::<f64>() / n * to_db)
+            .collect(),
+    )
+}
+
+/// Triangular filters evenly spaced on the mel scale up to Nyquist.
+fn mel_filterbank(rate: u32) -> Vec<Vec<f32>> {
+    let to_mel = |hz: f32| 2595.0 * (1.0 + hz / 700.0).log10();
+    let to_hz = |mel: f32| 700.0 * (10f32.powf(mel / 2595.0) - 1.0);
+    let bins = FFT_SIZE / 2 + 1;
+    let max_mel = to_mel(rate as f32 / 2.0);
+    let edges: Vec<f32> = (0..MEL_BANDS + 2)
+        .map(|i| to_hz(max_mel * i as f32 / (MEL_BANDS + 1) as f32) * FFT_SIZE as f32 / rate as f32)
+        .collect();
+    (0..MEL_BANDS)
+        .map(|m| {
+            let (lo, mid, hi) = (edges[m], edges[m + 1], edges[m + 2]);
+            (0..bins)
+                .map(|b| {
+                    let b = b as f32;
+                    if b <= lo || b >= hi {
+                        0.0
+                    } else if b <= mid {
+                        (b - lo) / (mid - lo)
+                    } else {
+                        (hi - b) / (hi - mid)
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// DCT-II of the log-mel energies, truncated to `CEPSTRAL_COEFFS`.
+fn dct(log_mel: &[f32]) -> [f32; CEPSTRAL_COEFFS] {
+    let n = log_mel.len() as f32;
+    let mut out = [0.0; CEPSTRAL_COEFFS];
+    for (k, value) in out.iter_mut().enumerate() {
+        *value = log_mel
+            .iter()
+            .enumerate()
+            .map(|(i, &x)| x * (std::f32::consts::PI * k as f32 * (i as f32 + 0.5) / n).cos())
+            .sum();
+    }
+    out
+}
+
+/// Pace of ordinary read speech. Parler output far outside it usually means
+/// the model mumbled through the prompt or stopped before the end of it.
+pub const PLAUSIBLE_WPM: RangeInclusive<f64> = 90.0..=220.0;
+
+/// Pace Parler voices usually read at, for estimating lengths up front.
+pub const TYPICAL_WPM: f64 = 150.0;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SpeechRate {
+    pub words_per_minute: f64,
+    /// Outside `PLAUSIBLE_WPM`.
+    pub suspicious: bool,
+}
+
+/// Words per minute of `text` spoken over `duration_secs`; `None` when
+/// either is empty.
+pub fn speech_rate(text: &str, duration_secs: f64) -> Option<SpeechRate> {
+    let words = text
+        .split_whitespace()
+        .filter(|w| w.chars().any(char::is_alphanumeric))
+        .count();
+    if words == 0 || duration_secs <= 0.0 {
+        return None;
+    }
+    let words_per_minute = words as f64 * 60.0 / duration_secs;
+    Some(SpeechRate {
+        words_per_minute,
+        suspicious: !PLAUSIBLE_WPM.contains(&words_per_minute),
+    })
+}
@@ -0,0 +1,367 @@
+//! Decoding of uploaded WAV audio, the WAV framing of streamed output, and
+//! the other encodings clips are served in.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+/// Mono PCM samples in `[-1, 1]`.
+#[derive(Debug, Clone)]
+pub struct Pcm {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+}
+
+impl Pcm {
+    pub fn duration_secs(&self) -> f64 {
+        self.samples.len() as f64 / self.sample_rate as f64
+    }
+}
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// Parses a RIFF/WAVE file (integer PCM or 32-bit float) and downmixes it to mono.
+pub fn read_wav(bytes: &[u8]) -> Result<Pcm> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        bail!("not a RIFF/WAVE file");
+    }
+
+    let mut format = None;
+    let mut data = None;
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let id = &bytes[pos..pos + 4];
+        let len = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into()?) as usize;
+        let body_end = (pos + 8).saturating_add(len).min(bytes.len());
+        let body = &bytes[pos + 8..body_end];
+        match id {
+            b"fmt " => {
+                if body.len() < 16 {
+                    bail!("truncated fmt chunk");
+                }
+                let mut tag = u16::from_le_bytes([body[0], body[1]]);
+                if tag == WAVE_FORMAT_EXTENSIBLE && body.len() >= 26 {
+                    // The sub-format GUID starts with the real format tag.
+                    tag = u16::from_le_bytes([body[24], body[25]]);
+                }
+                let channels = u16::from_le_bytes([body[2], body[3]]);
+                let sample_rate = u32::from_le_bytes(body[4..8].try_into()?);
+                let bits = u16::from_le_bytes([body[14], body[15]]);
+                format = Some((tag, channels, sample_rate, bits));
+            }
+            b"data" => data = Some(body),
+            _ => {}
+        }
+        // Chunks are padded to an even length.
+        pos = pos + 8 + len + (len & 1);
+    }
+
+    let (tag, channels, sample_rate, bits) = format.context("missing fmt chunk")?;
+    let data = data.context("missing data chunk")?;
+    if channels == 0 || sample_rate == 0 {
+        bail!("invalid channel count or sample rate");
+    }
+
+    let interleaved: Vec<f32> = match (tag, bits) {
+        (WAVE_FORMAT_PCM, 8) => data.iter().map(|&b| (b as f32 - 128.0) / 128.0).collect(),
+        (WAVE_FORMAT_PCM, 16) => data
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0)
+            .collect(),
+        (WAVE_FORMAT_PCM, 24) => data
+            .chunks_exact(3)
+            .map(|b| i32::from_le_bytes([0, b[0], b[1], b[2]]) as f32 / 2_147_483_648.0)
+            .collect(),
+        (WAVE_FORMAT_PCM, 32) => data
+            .chunks_exact(4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.0)
+            .collect(),
+        (WAVE_FORMAT_IEEE_FLOAT, 32) => data
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+        _ => bail!("unsupported WAV encoding (format {tag}, {bits} bits)"),
+    };
+
+    let channels = channels as usize;
+    let samples = interleaved
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+    Ok(Pcm {
+        samples,
+        sample_rate,
+    })
+}
+
+/// Size field of a streamed WAV whose length isn't known yet; browsers and
+/// ffmpeg read it as "until the end of the stream".
+const UNKNOWN_LENGTH: u32 = 0xFFFF_FFFF;
+
+/// Sample rate of G.711 µ-law telephony audio.
+const MULAW_RATE: u32 = 8000;
+
+/// What `/api/tts` sends: a WAV file or headerless telephony audio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Wav,
+    /// 16-bit little-endian PCM, mono.
+    Pcm16le,
+    /// 8 kHz G.711 µ-law, as Asterisk and most SIP stacks take it.
+    Mulaw8k,
+}
+
+impl OutputFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "" | "wav" => Some(Self::Wav),
+            "pcm16le" => Some(Self::Pcm16le),
+            "mulaw8k" => Some(Self::Mulaw8k),
+            _ => None,
+        }
+    }
+
+    /// The only sample rate the format allows, if it is fixed.
+    pub fn fixed_rate(self) -> Option<u32> {
+        match self {
+            Self::Mulaw8k => Some(MULAW_RATE),
+            Self::Wav | Self::Pcm16le => None,
+        }
+    }
+
+    pub fn content_type(self, sample_rate: u32) -> String {
+        match self {
+            Self::Wav => "audio/wav".to_string(),
+            Self::Pcm16le => format!("audio/pcm;rate={sample_rate};channels=1"),
+            Self::Mulaw8k => "audio/basic".to_string(),
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Wav => "wav",
+            Self::Pcm16le => "pcm",
+            Self::Mulaw8k => "ulaw",
+        }
+    }
+
+    /// What a stream starts with, before any samples.
+    pub fn stream_header(self, sample_rate: u32) -> Vec<u8> {
+        match self {
+            Self::Wav => wav_header(sample_rate, None, WavFormat::Pcm16),
+            Self::Pcm16le | Self::Mulaw8k => Vec::new(),
+        }
+    }
+
+    /// Samples as the format's data, without any header.
+    pub fn encode_samples(self, samples: &[f32]) -> Vec<u8> {
+        match self {
+            Self::Wav | Self::Pcm16le => pcm16le(samples),
+            Self::Mulaw8k => samples.iter().map(|&s| mulaw(s)).collect(),
+        }
+    }
+
+    /// A whole clip, header included.
+    pub fn encode_clip(self, samples: &[f32], sample_rate: u32) -> Vec<u8> {
+        let data = self.encode_samples(samples);
+        match self {
+            Self::Wav => [wav_header(sample_rate, Some(data.len()), WavFormat::Pcm16), data].concat(),
+            Self::Pcm16le | Self::Mulaw8k => data,
+        }
+    }
+}
+
+/// Sample encoding of the WAV files generated clips are stored as.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WavFormat {
+    /// 16-bit integer PCM, which every player takes.
+    #[default]
+    Pcm16,
+    Pcm24,
+    /// 32-bit float: the decoder output exactly, at twice the size.
+    Float32,
+}
+
+impl WavFormat {
+    fn tag(self) -> u16 {
+        match self {
+            Self::Pcm16 | Self::Pcm24 => WAVE_FORMAT_PCM,
+            Self::Float32 => WAVE_FORMAT_IEEE_FLOAT,
+        }
+    }
+
+    fn bytes_per_sample(self) -> u16 {
+        match self {
+            Self::Pcm16 => 2,
+            Self::Pcm24 => 3,
+            Self::Float32 => 4,
+        }
+    }
+}
+
+/// A mono WAV file of `samples` in `format`.
+pub fn write_wav(samples: &[f32], sample_rate: u32, format: WavFormat) -> Vec<u8> {
+    let data: Vec<u8> = match format {
+        WavFormat::Pcm16 => pcm16le(samples),
+        WavFormat::Pcm24 => samples
+            .iter()
+            .flat_map(|&s| {
+                let [b0, b1, b2, _] = ((s.clamp(-1.0, 1.0) * 8_388_607.0) as i32).to_le_bytes();
+                [b0, b1, b2]
+            })
+            .collect(),
+        WavFormat::Float32 => samples.iter().flat_map(|s| s.to_le_bytes()).collect(),
+    };
+    [wav_header(sample_rate, Some(data.len()), format), data].concat()
+}
+
+/// Header of a mono WAV with `data_bytes` of samples, or of unknown length
+/// for a stream.
+fn wav_header(sample_rate: u32, data_bytes: Option<usize>, format: WavFormat) -> Vec<u8> {
+    let data_len = data_bytes.map_or(UNKNOWN_LENGTH, |n| n as u32);
+    let riff_len = data_bytes.map_or(UNKNOWN_LENGTH, |n| n as u32 + 36);
+    let bytes = format.bytes_per_sample();
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&riff_len.to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&format.tag().to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * bytes as u32).to_le_bytes());
+    header.extend_from_slice(&bytes.to_le_bytes());
+    header.extend_from_slice(&(8 * bytes).to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    header
+}
+
+/// Samples as 16-bit little-endian PCM, converted like the WAV writer does.
+fn pcm16le(samples: &[f32]) -> Vec<u8> {
+    samples.iter().flat_map(|&s| to_i16(s).to_le_bytes()).collect()
+}
+
+fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * 32767.0) as i16
+}
+
+/// G.711 µ-law encoding of one sample.
+fn mulaw(sample: f32) -> u8 {
+    const BIAS: i32 = 0x84;
+    const CLIP: i32 = 32635;
+    let linear = to_i16(sample) as i32;
+    let sign = if linear < 0 { 0x80 } else { 0 };
+    let magnitude = linear.abs().min(CLIP) + BIAS;
+    let exponent = 31 - ((magnitude >> 7) as u32).leading_zeros().min(31);
+    let mantissa = (magnitude >> (exponent + 3)) & 0x0F;
+    !(sign | (exponent << 4) as i32 | mantissa) as u8
+}
+
+/// Opus runs at 48 kHz; clips are resampled to it.
+#[cfg(feature = "opus")]
+pub const OPUS_RATE: u32 = 48000;
+#[cfg(feature = "opus")]
+const OPUS_FRAME_SAMPLES: usize = OPUS_RATE as usize / 50;
+/// Identifies the one logical stream in each OGG file.
+#[cfg(feature = "opus")]
+const OGG_STREAM_SERIAL: u32 = 1;
+
+/// Mono 48 kHz samples as an OGG/Opus file.
+#[cfg(feature = "opus")]
+pub fn encode_ogg_opus(samples: &[f32]) -> Result<Vec<u8>> {
+    use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+
+    let mut encoder = opus::Encoder::new(OPUS_RATE, opus::Channels::Mono, opus::Application::Voip)?;
+    let pre_skip = encoder.get_lookahead()? as u16;
+    let mut writer = PacketWriter::new(Vec::new());
+
+    // RFC 7845 identification and comment headers, one page each.
+    let mut head = b"OpusHead".to_vec();
+    head.push(1);
+    head.push(1);
+    head.extend_from_slice(&pre_skip.to_le_bytes());
+    head.extend_from_slice(&OPUS_RATE.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes());
+    head.push(0);
+    writer.write_packet(head, OGG_STREAM_SERIAL, PacketWriteEndInfo::EndPage, 0)?;
+    let vendor = b"ttser";
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor);
+    tags.extend_from_slice(&0u32.to_le_bytes());
+    writer.write_packet(tags, OGG_STREAM_SERIAL, PacketWriteEndInfo::EndPage, 0)?;
+
+    let pcm: Vec<i16> = samples.iter().map(|&s| to_i16(s)).collect();
+    let frames = pcm.len().div_ceil(OPUS_FRAME_SAMPLES).max(1);
+    let mut packet = vec![0u8; 4000];
+    for k in 0..frames {
+        let mut frame = pcm[(k * OPUS_FRAME_SAMPLES).min(pcm.len())..((k + 1) * OPUS_FRAME_SAMPLES).min(pcm.len())].to_vec();
+        frame.resize(OPUS_FRAME_SAMPLES, 0);
+        let len = encoder.encode(&frame, &mut packet)?;
+        let last = k + 1 == frames;
+        // The last granule position trims the padding off the end.
+        let samples_so_far = if last { pcm.len() } else { (k + 1) * OPUS_FRAME_SAMPLES };
+        let end = if last { PacketWriteEndInfo::EndStream } else { PacketWriteEndInfo::NormalPacket };
+        writer.write_packet(packet[..len].to_vec(), OGG_STREAM_SERIAL, end, pre_skip as u64 + samples_so_far as u64)?;
+    }
+    Ok(writer.into_inner())
+}
+
+/// Zero crossings of the sinc kept on each side by [`resample`].
+const RESAMPLE_ZEROS: f64 = 16.0;
+
+/// Windowed-sinc resampling. Going down, the cutoff follows the new Nyquist
+/// frequency, so telephony rates don't alias.
+pub fn resample(samples: &[f32], from: u32, to: u32) -> Vec<f32> {
+    if from == to || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let ratio = to as f64 / from as f64;
+    // A little below Nyquist, for the window's transition band.
+    let cutoff = ratio.min(1.0) * 0.95;
+    let half_width = RESAMPLE_ZEROS / cutoff;
+    let len = (samples.len() as f64 * ratio).round() as usize;
+    (0..len)
+        .map(|n| {
+            let t = n as f64 / ratio;
+            let first = (t - half_width).ceil().max(0.0) as usize;
+            let last = ((t + half_width).floor() as usize).min(samples.len() - 1);
+            let mut sum = 0.0;
+            for (k, &sample) in samples.iter().enumerate().take(last + 1).skip(first) {
+                let offset = t - k as f64;
+                let x = std::f64::consts::PI * offset * cutoff;
+                let sinc = if x.abs() < 1e-9 { 1.0 } else { x.sin() / x };
+                let window = 0.5 + 0.5 * (std::f64::consts::PI * offset / half_width).cos();
+                sum += sample as f64 * cutoff * sinc * window;
+            }
+            sum as f32
+        })
+        .collect()
+}
+
+/// Mono MP3 at a bitrate suited to speech, for podcast players and other
+/// clients that don't take WAV.
+pub fn encode_mp3(samples: &[f32], sample_rate: u32) -> Result<Vec<u8>> {
+    use mp3lame_encoder::{Bitrate, Builder, FlushNoGap, MonoPcm, Quality};
+
+    let mut builder = Builder::new().context("LAME failed to initialise")?;
+    builder.set_num_channels(1).map_err(lame)?;
+    builder.set_sample_rate(sample_rate).map_err(lame)?;
+    builder.set_brate(Bitrate::Kbps64).map_err(lame)?;
+    builder.set_quality(Quality::Good).map_err(lame)?;
+    let mut encoder = builder.build().map_err(lame)?;
+
+    let pcm: Vec<i16> = samples.iter().map(|&s| to_i16(s)).collect();
+    let mut mp3 = Vec::with_capacity(mp3lame_encoder::max_required_buffer_size(pcm.len()) + 7200);
+    encoder.encode_to_vec(MonoPcm(&pcm), &mut mp3).map_err(lame)?;
+    encoder.flush_to_vec::<FlushNoGap>(&mut mp3).map_err(lame)?;
+    Ok(mp3)
+}
+
+/// LAME's errors don't implement `std::error::Error`.
+fn lame(e: impl std::fmt::Display) -> anyhow::Error {
+    anyhow::anyhow!("MP3 encoding failed: {e}")
+}
@@ -0,0 +1,927 @@
+//! Audiobook jobs: long texts, web articles fetched and extracted
+//! server-side, or uploaded PDFs, rendered in the background chunk by chunk.
+//! Progress (per page, for PDFs) is read from `/api/jobs/{id}`; the finished
+//! book is stored in the namespace's history like any other clip, along with
+//! an M4B with chapter markers when one is asked for.
+//!
+//! Jobs live in memory and are forgotten a day after they finish (or on
+//! restart); their audio stays in the history.
+
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::error::SynthesisError;
+use crate::generation::{FinishReason, Stopping, TokenControls};
+use crate::m4b::{self, Chapter};
+use crate::namespace::Namespace;
+use crate::quality::QualityRetry;
+use crate::sampler::SamplerKind;
+use crate::{audio, chunking, extract, fetch, AppState, CreateWavArgs, PostProcess, CHUNK_GAP_SECS};
+
+/// Finished jobs are dropped from the registry after this long.
+const JOB_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Lines longer than this are never chapter headings.
+const MAX_HEADING_CHARS: usize = 80;
+/// Words that start a chapter heading when followed by its number
+/// ("Chapter 3", "Part IV", "Chapter One: ...").
+const NUMBERED_HEADINGS: &[&str] = &["chapter", "part", "book"];
+/// Words that start a heading on their own.
+const NAMED_HEADINGS: &[&str] = &["prologue", "epilogue", "introduction", "afterword"];
+const NUMBER_WORDS: &[&str] = &[
+    "one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten", "eleven", "twelve", "thirteen",
+    "fourteen", "fifteen", "sixteen", "seventeen", "eighteen", "nineteen", "twenty",
+];
+
+/// Where a book's text comes from.
+pub enum Source {
+    Text(String),
+    /// A web page whose article text is extracted.
+    Url(String),
+    /// A PDF document, read page by page; `pages` (1-based) limits it to a
+    /// range.
+    Pdf {
+        bytes: Vec<u8>,
+        pages: Option<RangeInclusive<usize>>,
+    },
+}
+
+/// A validated `/api/audiobooks` request.
+pub struct Request {
+    pub source: Source,
+    pub title: Option<String>,
+    pub description: String,
+    pub voice: Option<String>,
+    pub voice_version: Option<u32>,
+    pub temperature: Option<f64>,
+    pub seed: Option<u64>,
+    pub top_p: Option<f64>,
+    pub format: BookFormat,
+}
+
+/// What the finished book is served as. The history always keeps the WAV.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BookFormat {
+    Wav,
+    /// AAC in MP4 with chapter markers and metadata, for audiobook players.
+    M4b,
+}
+
+impl BookFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "wav" => Some(Self::Wav),
+            "m4b" => Some(Self::M4b),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Wav => "audio/wav",
+            Self::M4b => "audio/mp4",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    /// Downloading and extracting the article.
+    Fetching,
+    /// Reading the text out of the PDF.
+    Extracting,
+    Rendering,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Progress {
+    /// Chunks rendered so far, out of `total`.
+    pub done: usize,
+    pub total: usize,
+    /// The same per page of a PDF, pages without text left out.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pages: Vec<PageProgress>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PageProgress {
+    /// 1-based, as in the document.
+    pub page: usize,
+    pub done: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl From<SynthesisError> for JobError {
+    fn from(e: SynthesisError) -> Self {
+        Self {
+            code: e.code(),
+            message: e.message(),
+        }
+    }
+}
+
+/// What `/api/jobs/{id}` reports.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub id: String,
+    pub state: JobState,
+    pub title: Option<String>,
+    /// The article's address, for jobs made from a URL.
+    pub url: Option<String>,
+    pub format: BookFormat,
+    pub progress: Progress,
+    /// History id of the finished book.
+    pub clip_id: Option<String>,
+    pub duration_secs: Option<f64>,
+    /// Where each chapter starts in the finished book: at Markdown
+    /// headings and lines such as "Chapter 3" in text, at the top-level
+    /// bookmarks of a PDF.
+    pub chapters: Vec<Chapter>,
+    /// Chunks rendered again since the book was last assembled.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub edited_chunks: Vec<usize>,
+    pub error: Option<JobError>,
+    pub created_at: u64,
+    pub finished_at: Option<u64>,
+}
+
+/// What `/api/jobs/{id}/chunks` lists.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChunkInfo {
+    pub index: usize,
+    /// The PDF page it is on.
+    pub page: Option<usize>,
+    pub text: String,
+    pub seed: Option<u64>,
+    /// Rendered again since the book was last assembled.
+    pub edited: bool,
+}
+
+/// A `/api/jobs/{id}/chunks/{n}/rerender` body; what isn't given stays as
+/// it was.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChunkEdit {
+    pub text: Option<String>,
+    pub seed: Option<u64>,
+}
+
+/// What rendering a chunk again did.
+#[derive(Debug, Clone, Serialize)]
+pub struct Rerender {
+    #[serde(flatten)]
+    pub chunk: ChunkInfo,
+    /// False when the chunk's audio was already rendered from the same text
+    /// and seed, and was kept.
+    pub rendered: bool,
+}
+
+struct Job {
+    namespace: String,
+    /// The `Idempotency-Key` it was submitted with.
+    key: Option<String>,
+    status: Mutex<JobStatus>,
+    /// Set once the book is rendered.
+    book: Mutex<Option<Book>>,
+    /// Held while a chunk is rendered again or the book assembled, so
+    /// edits don't interleave.
+    editing: tokio::sync::Mutex<()>,
+}
+
+impl Job {
+    fn update(&self, change: impl FnOnce(&mut JobStatus)) {
+        change(&mut self.status.lock().unwrap());
+    }
+
+    /// The rendered book; `409` until the job is done.
+    fn book(&self) -> Result<Book, SynthesisError> {
+        let book = self.book.lock().unwrap().clone();
+        book.ok_or(SynthesisError::Request(StatusCode::CONFLICT))
+    }
+}
+
+/// What a finished job keeps to render chunks again and re-assemble.
+#[derive(Clone)]
+struct Book {
+    clip_id: String,
+    title: String,
+    format: BookFormat,
+    description: String,
+    voice: Option<String>,
+    voice_version: Option<u32>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    retry_degenerate: bool,
+    stopping: Stopping,
+    /// The chapter each section starts, if any.
+    chapters: Vec<Option<String>>,
+    chunks: Vec<BookChunk>,
+}
+
+#[derive(Clone)]
+struct BookChunk {
+    section: usize,
+    page: Option<usize>,
+    text: String,
+    seed: Option<u64>,
+    /// Hash of what its audio was rendered from.
+    key: u64,
+    steps: usize,
+    finish: FinishReason,
+    quality_retry: Option<QualityRetry>,
+}
+
+impl Book {
+    fn args(&self, request_id: u64, prompt: &str, seed: Option<u64>) -> Arc<CreateWavArgs> {
+        Arc::new(CreateWavArgs {
+            request_id,
+            description: self.description.clone(),
+            prompt: prompt.to_string(),
+            temperature: self.temperature,
+            seed,
+            top_p: self.top_p,
+            post_process: PostProcess::default(),
+            tokens: TokenControls::default(),
+            sampler: SamplerKind::Stock,
+            stopping: self.stopping.clone(),
+            retry_degenerate: self.retry_degenerate,
+            // Chunks are joined into the book anyway.
+            wav_format: crate::audio::WavFormat::Pcm16,
+            bleep: None,
+            progress: None,
+        })
+    }
+
+    fn key(&self, text: &str, seed: Option<u64>) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (text, &self.description, self.temperature.map(f64::to_bits), self.top_p.map(f64::to_bits), seed)
+            .hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn info(&self, n: usize, edited: &[usize]) -> ChunkInfo {
+        let chunk = &self.chunks[n];
+        ChunkInfo {
+            index: n,
+            page: chunk.page,
+            text: chunk.text.clone(),
+            seed: chunk.seed,
+            edited: edited.contains(&n),
+        }
+    }
+
+    /// The text read, sections apart by blank lines.
+    fn text(&self) -> String {
+        let mut text = String::new();
+        for (k, chunk) in self.chunks.iter().enumerate() {
+            if k > 0 {
+                let same = self.chunks[k - 1].section == chunk.section;
+                text.push_str(if same { " " } else { "\n\n" });
+            }
+            text.push_str(&chunk.text);
+        }
+        text
+    }
+}
+
+/// The jobs started since startup, by id.
+pub struct Jobs {
+    jobs: Mutex<HashMap<String, Arc<Job>>>,
+    http: reqwest::Client,
+}
+
+impl Jobs {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            jobs: Mutex::default(),
+            http: fetch::public_client(FETCH_TIMEOUT)?,
+        })
+    }
+
+    /// Registers a job for `request` and renders it in the background.
+    /// Returns its status as queued.
+    ///
+    /// A job submitted before with the same `key` in the namespace, and not
+    /// yet forgotten, is not started again: its current status comes back
+    /// instead, flagged as a replay.
+    pub fn start(
+        &self,
+        state: &AppState,
+        namespace: Arc<Namespace>,
+        request_id: u64,
+        request: Request,
+        key: Option<String>,
+    ) -> (JobStatus, bool) {
+        let now = crate::unix_now();
+        let status = JobStatus {
+            id: format!("job_{now}_{request_id}"),
+            state: JobState::Queued,
+            title: request.title.clone(),
+            url: match &request.source {
+                Source::Url(url) => Some(url.clone()),
+                Source::Text(_) | Source::Pdf { .. } => None,
+            },
+            format: request.format,
+            progress: Progress::default(),
+            clip_id: None,
+            duration_secs: None,
+            chapters: Vec::new(),
+            edited_chunks: Vec::new(),
+            error: None,
+            created_at: now,
+            finished_at: None,
+        };
+        let job = Arc::new(Job {
+            namespace: namespace.name.clone(),
+            key: key.clone(),
+            status: Mutex::new(status.clone()),
+            book: Mutex::new(None),
+            editing: tokio::sync::Mutex::new(()),
+        });
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            jobs.retain(|_, job| {
+                let finished = job.status.lock().unwrap().finished_at;
+                finished.is_none_or(|at| at + JOB_RETENTION.as_secs() > now)
+            });
+            let original = jobs
+                .values()
+                .find(|job| key.is_some() && job.key == key && job.namespace == namespace.name);
+            if let Some(original) = original {
+                let status = original.status.lock().unwrap().clone();
+                println!("audiobook[{}]: replayed for idempotency key", status.id);
+                return (status, true);
+            }
+            jobs.insert(status.id.clone(), job.clone());
+        }
+        println!("audiobook[{}]: queued in namespace {}", status.id, namespace.name);
+
+        let state = state.clone();
+        tokio::spawn(async move {
+            let outcome = render(&state, &job, &namespace, request_id, request).await;
+            let id = job.status.lock().unwrap().id.clone();
+            match &outcome {
+                Ok(()) => println!("audiobook[{id}]: done"),
+                Err(e) => println!("audiobook[{id}]: failed: {} ({})", e.message, e.code),
+            }
+            job.update(|status| {
+                status.finished_at = Some(crate::unix_now());
+                if let Err(e) = outcome {
+                    status.state = JobState::Failed;
+                    status.error = Some(e);
+                }
+            });
+        });
+        (status, false)
+    }
+
+    /// The job's status, when it belongs to `namespace`.
+    pub fn status(&self, namespace: &str, id: &str) -> Option<JobStatus> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(id).filter(|job| job.namespace == namespace)?;
+        let status = job.status.lock().unwrap().clone();
+        Some(status)
+    }
+
+    /// The chunks of a finished book.
+    pub fn chunks(&self, namespace: &str, id: &str) -> Result<Vec<ChunkInfo>, SynthesisError> {
+        let job = self.find(namespace, id)?;
+        let book = job.book()?;
+        let edited = job.status.lock().unwrap().edited_chunks.clone();
+        Ok((0..book.chunks.len()).map(|n| book.info(n, &edited)).collect())
+    }
+
+    /// The WAV of chunk `n` of a finished book.
+    pub fn chunk_audio(&self, namespace: &Namespace, id: &str, n: usize) -> Result<Vec<u8>, SynthesisError> {
+        let job = self.find(&namespace.name, id)?;
+        let book = job.book()?;
+        read_chunk(namespace, &book, n)
+    }
+
+    /// Renders chunk `n` of a finished book again, with the edit's text and
+    /// seed when given. Nothing is rendered when a seed is set and neither
+    /// changed, since the audio would come out the same. The book itself
+    /// keeps the old audio until it is assembled again.
+    pub async fn rerender(
+        &self,
+        state: &AppState,
+        namespace: &Namespace,
+        request_id: u64,
+        id: &str,
+        n: usize,
+        edit: ChunkEdit,
+    ) -> Result<Rerender, SynthesisError> {
+        let job = self.find(&namespace.name, id)?;
+        let _editing = job.editing.try_lock().map_err(|_| SynthesisError::Request(StatusCode::CONFLICT))?;
+        let mut book = job.book()?;
+        let chunk = book.chunks.get(n).ok_or(SynthesisError::Request(StatusCode::NOT_FOUND))?;
+        let text = edit.text.map(|t| t.trim().to_string()).unwrap_or_else(|| chunk.text.clone());
+        if text.is_empty() {
+            return Err(SynthesisError::Request(StatusCode::BAD_REQUEST));
+        }
+        let seed = edit.seed.or(chunk.seed);
+        let key = book.key(&text, seed);
+        let rendered = seed.is_none() || key != chunk.key;
+        if rendered {
+            let pool = state.pool().await.map_err(SynthesisError::ModelLoad)?;
+            let clip = crate::create_wav_file(&pool, &book.args(request_id, &text, seed), usize::MAX, None).await?;
+            namespace.history.save_chunk(&book.clip_id, n, &clip.wav).map_err(SynthesisError::Io)?;
+            namespace.usage.record(text.chars().count(), clip.duration_secs);
+            let chunk = &mut book.chunks[n];
+            chunk.text = text;
+            chunk.seed = seed;
+            chunk.key = key;
+            chunk.steps = clip.steps;
+            chunk.finish = clip.finish;
+            chunk.quality_retry = clip.quality_retry;
+            println!("audiobook[{id}]: chunk {n} rendered again");
+        }
+        let edited = {
+            let mut status = job.status.lock().unwrap();
+            if rendered && !status.edited_chunks.contains(&n) {
+                status.edited_chunks.push(n);
+                status.edited_chunks.sort_unstable();
+            }
+            status.edited_chunks.clone()
+        };
+        let chunk = book.info(n, &edited);
+        *job.book.lock().unwrap() = Some(book);
+        Ok(Rerender { chunk, rendered })
+    }
+
+    /// Joins a finished book's chunks again, replacing its audio (and M4B)
+    /// in the history with one that has the re-rendered chunks.
+    pub async fn assemble(&self, state: &AppState, namespace: &Namespace, id: &str) -> Result<JobStatus, SynthesisError> {
+        let job = self.find(&namespace.name, id)?;
+        let _editing = job.editing.try_lock().map_err(|_| SynthesisError::Request(StatusCode::CONFLICT))?;
+        let book = job.book()?;
+        let created_at = job.status.lock().unwrap().created_at;
+        let (chapters, duration_secs) = assemble(state, namespace, &book, created_at).await?;
+        println!("audiobook[{id}]: assembled again");
+        job.update(|status| {
+            status.chapters = chapters;
+            status.duration_secs = Some(duration_secs);
+            status.edited_chunks.clear();
+        });
+        let status = job.status.lock().unwrap().clone();
+        Ok(status)
+    }
+
+    fn find(&self, namespace: &str, id: &str) -> Result<Arc<Job>, SynthesisError> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(id).filter(|job| job.namespace == namespace);
+        job.cloned().ok_or(SynthesisError::Request(StatusCode::NOT_FOUND))
+    }
+
+    async fn fetch_article(&self, url: &str) -> anyhow::Result<extract::Article> {
+        // The client checks host names and redirects, not address literals.
+        fetch::public_url(url)?;
+        let page = fetch::read(&self.http, url, fetch::MAX_PAGE_BYTES).await?;
+        Ok(extract::extract(&String::from_utf8_lossy(&page)))
+    }
+}
+
+/// A run of the book's text that chunks don't cross.
+struct Section {
+    /// The PDF page it is on.
+    page: Option<usize>,
+    /// The title of the chapter it starts, if it starts one.
+    chapter: Option<String>,
+    text: String,
+}
+
+async fn render(
+    state: &AppState,
+    job: &Job,
+    namespace: &Namespace,
+    request_id: u64,
+    request: Request,
+) -> Result<(), JobError> {
+    // Sections are read in order; chunks don't cross them, so a PDF's
+    // progress can be told per page and chapters start on a chunk.
+    let sections = match request.source {
+        Source::Text(text) => text_sections(&text),
+        Source::Url(url) => {
+            job.update(|status| status.state = JobState::Fetching);
+            let article = state.jobs.fetch_article(&url).await.map_err(|e| JobError {
+                code: "fetch_failed",
+                message: format!("reading {url} failed: {e:#}"),
+            })?;
+            job.update(|status| {
+                status.title = status.title.take().or(article.title);
+            });
+            vec![Section {
+                page: None,
+                chapter: None,
+                text: article.text,
+            }]
+        }
+        Source::Pdf { bytes, pages } => {
+            job.update(|status| status.state = JobState::Extracting);
+            pdf_sections(bytes, pages).await?
+        }
+    };
+    // A chapter starting on a section without text starts with the next.
+    let mut kept: Vec<Section> = Vec::new();
+    let mut pending = None;
+    for mut section in sections {
+        if !section.text.chars().any(char::is_alphanumeric) {
+            pending = section.chapter.or(pending);
+            continue;
+        }
+        section.chapter = section.chapter.or(pending.take());
+        kept.push(section);
+    }
+    let sections = kept;
+    if sections.is_empty() {
+        return Err(JobError {
+            code: "no_text",
+            message: "there is no text to read".to_string(),
+        });
+    }
+
+    let (id, title, created_at) = {
+        let status = job.status.lock().unwrap();
+        (status.id.clone(), status.title.clone(), status.created_at)
+    };
+    // The job status only gets the error's fixed message.
+    let failed = |e: SynthesisError| {
+        println!("audiobook[{id}]: {e} ({})", e.code());
+        JobError::from(e)
+    };
+    let mut book = Book {
+        clip_id: format!("generated_audio_{created_at}_{request_id}"),
+        title: title.unwrap_or_else(|| "Audiobook".to_string()),
+        format: request.format,
+        description: request.description,
+        voice: request.voice,
+        voice_version: request.voice_version,
+        temperature: request.temperature,
+        top_p: request.top_p,
+        retry_degenerate: state.config.retry_degenerate,
+        stopping: state.config.max_steps.stopping(),
+        chapters: sections.iter().map(|s| s.chapter.clone()).collect(),
+        chunks: Vec::new(),
+    };
+    for (k, section) in sections.iter().enumerate() {
+        for text in chunking::split(&section.text, state.config.chunk_chars) {
+            book.chunks.push(BookChunk {
+                section: k,
+                page: section.page,
+                key: book.key(&text, request.seed),
+                text,
+                seed: request.seed,
+                steps: 0,
+                finish: FinishReason::Eos,
+                quality_retry: None,
+            });
+        }
+    }
+    let pages = sections
+        .iter()
+        .enumerate()
+        .filter_map(|(k, s)| {
+            s.page.map(|page| PageProgress {
+                page,
+                done: 0,
+                total: book.chunks.iter().filter(|c| c.section == k).count(),
+            })
+        })
+        .collect();
+    job.update(|status| {
+        status.state = JobState::Rendering;
+        status.progress = Progress {
+            done: 0,
+            total: book.chunks.len(),
+            pages,
+        };
+    });
+    let pool = state.pool().await.map_err(SynthesisError::ModelLoad).map_err(failed)?;
+
+    // Every worker takes chunks, each kept in the history as it is done so
+    // it can be rendered again on its own later.
+    let work: Vec<_> = book
+        .chunks
+        .iter()
+        .map(|chunk| (chunk.section, book.args(request_id, &chunk.text, chunk.seed)))
+        .collect();
+    let (clip_id, id) = (&book.clip_id, &id);
+    let renders = work.into_iter().enumerate().map(|(k, (section, args))| {
+        let pool = pool.clone();
+        async move {
+            let clip = crate::create_wav_file(&pool, &args, usize::MAX, None).await?;
+            namespace.history.save_chunk(clip_id, k, &clip.wav).map_err(|e| {
+                println!("audiobook[{id}]: saving chunk {k} failed: {e:#}");
+                SynthesisError::Io(e)
+            })?;
+            job.update(|status| {
+                status.progress.done += 1;
+                // Pages are listed in section order, so a section's page
+                // entry is at the same index.
+                if let Some(page) = status.progress.pages.get_mut(section) {
+                    page.done += 1;
+                }
+            });
+            Ok::<_, SynthesisError>((k, clip.steps, clip.finish, clip.quality_retry))
+        }
+    });
+    let rendered = futures::stream::iter(renders)
+        .buffer_unordered(pool.size())
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(failed)?;
+    for (k, steps, finish, quality_retry) in rendered {
+        let chunk = &mut book.chunks[k];
+        chunk.steps = steps;
+        chunk.finish = finish;
+        chunk.quality_retry = quality_retry;
+    }
+
+    let (chapters, duration_secs) = assemble(state, namespace, &book, created_at).await.map_err(failed)?;
+    namespace.usage.record(book.text().chars().count(), duration_secs);
+    job.update(|status| {
+        status.state = JobState::Done;
+        status.clip_id = Some(book.clip_id.clone());
+        status.duration_secs = Some(duration_secs);
+        status.chapters = chapters;
+    });
+    *job.book.lock().unwrap() = Some(book);
+    Ok(())
+}
+
+/// Joins the book's chunk WAVs and stores the result in the history under
+/// its clip id, with an M4B when the book is wanted as one. Returns the
+/// chapters and the book's length.
+async fn assemble(
+    state: &AppState,
+    namespace: &Namespace,
+    book: &Book,
+    created_at: u64,
+) -> Result<(Vec<Chapter>, f64), SynthesisError> {
+    let mut samples = Vec::new();
+    let mut sample_rate = 0;
+    let mut chapters = Vec::new();
+    for (k, chunk) in book.chunks.iter().enumerate() {
+        let pcm = audio::read_wav(&read_chunk(namespace, book, k)?).map_err(SynthesisError::Decode)?;
+        sample_rate = pcm.sample_rate;
+        if k > 0 {
+            samples.extend(std::iter::repeat_n(0f32, (CHUNK_GAP_SECS * sample_rate as f64) as usize));
+        }
+        let starts_section = k == 0 || book.chunks[k - 1].section != chunk.section;
+        if let Some(title) = book.chapters[chunk.section].as_ref().filter(|_| starts_section) {
+            chapters.push(Chapter {
+                title: title.clone(),
+                start_secs: samples.len() as f64 / sample_rate as f64,
+            });
+        }
+        samples.extend(pcm.samples);
+    }
+    // Whatever comes before the first heading is read under the book's title.
+    if chapters.first().is_some_and(|c| c.start_secs > 0.0) {
+        chapters.insert(
+            0,
+            Chapter {
+                title: book.title.clone(),
+                start_secs: 0.0,
+            },
+        );
+    }
+    let wav = audio::OutputFormat::Wav.encode_clip(&samples, sample_rate);
+    let duration_secs = samples.len() as f64 / sample_rate as f64;
+
+    let text = book.text();
+    let policy = state.config.log_prompts;
+    let record = crate::history::ClipRecord {
+        id: book.clip_id.clone(),
+        created_at,
+        prompt: policy.sanitize(&text),
+        description: policy.sanitize(&book.description),
+        temperature: book.temperature,
+        // One seed for the book, unless chunks were rendered with others.
+        seed: book.chunks[0].seed.filter(|seed| book.chunks.iter().all(|c| c.seed == Some(*seed))),
+        top_p: book.top_p,
+        sample_rate,
+        duration_secs,
+        voice: book.voice.clone(),
+        voice_version: book.voice_version,
+        expires_at: namespace
+            .retention_for(book.voice.as_deref())
+            .map(|ttl| created_at + ttl.as_secs()),
+        speech_rate: crate::analysis::speech_rate(&text, duration_secs),
+        quality_retry: book.chunks.iter().find_map(|c| c.quality_retry.clone()),
+        steps: Some(book.chunks.iter().map(|c| c.steps).sum()),
+        finish_reason: Some(if book.chunks.iter().any(|c| c.finish == FinishReason::MaxSteps) {
+            FinishReason::MaxSteps
+        } else {
+            FinishReason::Eos
+        }),
+        memory: None,
+        timings: None,
+        over_budget: Vec::new(),
+        downgraded_max_steps: None,
+        features: Vec::new(),
+        settings: None,
+        self_check: None,
+    };
+    namespace.history.save(&record, &wav).map_err(|e| {
+        println!("audiobook[{}]: saving to history failed: {e:#}", book.clip_id);
+        SynthesisError::Io(e)
+    })?;
+    if let Some(tracker) = &state.experiments {
+        tracker.log(
+            &record,
+            &[
+                ("namespace", namespace.name.clone()),
+                ("kind", "audiobook".to_string()),
+                ("chunks", book.chunks.len().to_string()),
+            ],
+        );
+    }
+    if book.format == BookFormat::M4b {
+        let metadata = m4b::Metadata {
+            title: book.title.clone(),
+            artist: book.voice.clone(),
+        };
+        let book_chapters = chapters.clone();
+        let m4b = tokio::task::spawn_blocking(move || m4b::encode(&samples, sample_rate, &book_chapters, &metadata))
+            .await
+            .map_err(|e| SynthesisError::Encode(e.into()))?
+            .map_err(SynthesisError::Encode)?;
+        namespace.history.save_attachment(&book.clip_id, "m4b", &m4b).map_err(|e| {
+            println!("audiobook[{}]: saving the M4B failed: {e:#}", book.clip_id);
+            SynthesisError::Io(e)
+        })?;
+    }
+    Ok((chapters, duration_secs))
+}
+
+/// Chunk `n`'s WAV from the history.
+fn read_chunk(namespace: &Namespace, book: &Book, n: usize) -> Result<Vec<u8>, SynthesisError> {
+    namespace
+        .history
+        .chunk(&book.clip_id, n)
+        .map_err(SynthesisError::Io)?
+        .ok_or(SynthesisError::Request(StatusCode::NOT_FOUND))
+}
+
+/// Plain text split into sections at its chapter headings. A Markdown
+/// heading's `#`s are not read out.
+fn text_sections(text: &str) -> Vec<Section> {
+    let mut sections = vec![Section {
+        page: None,
+        chapter: None,
+        text: String::new(),
+    }];
+    for line in text.lines() {
+        if let Some(title) = heading(line) {
+            sections.push(Section {
+                page: None,
+                chapter: Some(title.to_string()),
+                text: String::new(),
+            });
+        }
+        let section = sections.last_mut().expect("there is always a section");
+        section.text.push_str(line.trim_start_matches('#').trim_start());
+        section.text.push('\n');
+    }
+    sections
+}
+
+/// The chapter title `line` starts, if it is a heading.
+fn heading(line: &str) -> Option<&str> {
+    let line = line.trim();
+    if let Some(rest) = line.strip_prefix('#') {
+        let title = rest.trim_start_matches('#');
+        return title.starts_with(' ').then(|| title.trim()).filter(|t| !t.is_empty());
+    }
+    if line.chars().count() > MAX_HEADING_CHARS {
+        return None;
+    }
+    let mut words = line
+        .split_whitespace()
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase());
+    let first = words.next()?;
+    if NAMED_HEADINGS.contains(&first.as_str()) {
+        return Some(line);
+    }
+    let number = words.next()?;
+    let numeral = number.chars().all(|c| c.is_ascii_digit())
+        || number.chars().all(|c| "ivxlc".contains(c))
+        || NUMBER_WORDS.contains(&number.as_str());
+    (NUMBERED_HEADINGS.contains(&first.as_str()) && !number.is_empty() && numeral).then_some(line)
+}
+
+/// A PDF's pages as sections, with chapters where its top-level bookmarks
+/// point. The first page inherits the chapter it is in when the range
+/// starts midway through one.
+async fn pdf_sections(bytes: Vec<u8>, range: Option<RangeInclusive<usize>>) -> Result<Vec<Section>, JobError> {
+    let outline = {
+        let bytes = bytes.clone();
+        // Documents without bookmarks (or unreadable ones) have no chapters.
+        tokio::task::spawn_blocking(move || outline(&bytes)).await.unwrap_or_default()
+    };
+    let pages = pdf_pages(bytes, range).await?;
+    let first = pages.first().map_or(0, |(page, _)| *page);
+    Ok(pages
+        .into_iter()
+        .map(|(page, text)| {
+            let chapter = if page == first {
+                outline.iter().rev().find(|(start, _)| *start <= page)
+            } else {
+                outline.iter().rev().find(|(start, _)| *start == page)
+            };
+            Section {
+                page: Some(page),
+                chapter: chapter.map(|(_, title)| title.clone()),
+                text,
+            }
+        })
+        .collect())
+}
+
+/// The top-level bookmarks of a PDF as (page, title), in page order.
+fn outline(bytes: &[u8]) -> Vec<(usize, String)> {
+    let Ok(toc) = pdf_extract::Document::load_mem(bytes).and_then(|document| document.get_toc()) else {
+        return Vec::new();
+    };
+    let Some(top) = toc.toc.iter().map(|entry| entry.level).min() else {
+        return Vec::new();
+    };
+    let mut entries: Vec<_> = toc
+        .toc
+        .into_iter()
+        .filter(|entry| entry.level == top)
+        .map(|entry| (entry.page, entry.title.trim().to_string()))
+        .filter(|(_, title)| !title.is_empty())
+        .collect();
+    // Several bookmarks on one page make one chapter, after the last.
+    entries.sort_by_key(|(page, _)| *page);
+    entries
+}
+
+/// The text of each page of a PDF within `range` (all by default), with
+/// its page number.
+async fn pdf_pages(bytes: Vec<u8>, range: Option<RangeInclusive<usize>>) -> Result<Vec<(usize, String)>, JobError> {
+    let unreadable = |message: String| JobError {
+        code: "pdf_unreadable",
+        message,
+    };
+    // pdf-extract panics on some malformed documents.
+    let pages = tokio::task::spawn_blocking(move || pdf_extract::extract_text_from_mem_by_pages(&bytes))
+        .await
+        .map_err(|_| unreadable("the PDF could not be read".to_string()))?
+        .map_err(|e| unreadable(format!("the PDF could not be read: {e}")))?;
+    let count = pages.len();
+    let range = range.unwrap_or(1..=count.max(1));
+    if *range.start() > count {
+        return Err(JobError {
+            code: "invalid_pages",
+            message: format!("the PDF has {count} pages"),
+        });
+    }
+    Ok(pages
+        .into_iter()
+        .enumerate()
+        .map(|(k, text)| (k + 1, text))
+        .filter(|(page, _)| range.contains(page))
+        .map(|(page, text)| (page, unwrap_lines(&text)))
+        .collect())
+}
+
+/// A page's lines run together, with words hyphenated across a line break
+/// joined again.
+fn unwrap_lines(page: &str) -> String {
+    let mut text = String::new();
+    for line in page.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let continues = line.chars().next().is_some_and(char::is_lowercase);
+        if continues && text.ends_with('-') {
+            text.pop();
+        } else if !text.is_empty() {
+            text.push(' ');
+        }
+        text.push_str(&line.split_whitespace().collect::<Vec<_>>().join(" "));
+    }
+    text
+}
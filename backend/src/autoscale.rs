@@ -0,0 +1,81 @@
+//! One number for an autoscaler to track, at `/api/autoscale`: 1 when
+//! every generation worker is busy and nothing waits, more the longer the
+//! queue is expected to take. A Kubernetes HPA (through an external metrics
+//! adapter) or KEDA's metrics API scaler targeting 1 then adds replicas in
+//! proportion to the backlog and removes them as workers go idle.
+
+use serde::{Deserialize, Serialize};
+
+use crate::pool::PoolLoad;
+
+/// The `[autoscale]` config section.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Autoscale {
+    /// Estimated queue wait that adds 1 to the signal, in seconds.
+    pub target_queue_wait_secs: f64,
+}
+
+impl Default for Autoscale {
+    fn default() -> Self {
+        Self {
+            target_queue_wait_secs: 10.0,
+        }
+    }
+}
+
+impl Autoscale {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.target_queue_wait_secs.is_finite() || self.target_queue_wait_secs <= 0.0 {
+            anyhow::bail!("autoscale.target_queue_wait_secs must be positive");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Signal {
+    /// `utilization` plus the estimated queue wait in units of
+    /// `target_queue_wait_secs`.
+    pub value: f64,
+    /// Share of generation workers running a job.
+    pub utilization: f64,
+    /// Jobs (prompt chunks) waiting for a worker.
+    pub queue_depth: usize,
+    /// How long the queue takes to drain at the recent job time; absent
+    /// until a job has finished.
+    pub queue_wait_secs: Option<f64>,
+    pub busy_workers: usize,
+    /// 0 until the model has loaded.
+    pub workers: usize,
+    /// Recent run time of one job, in seconds.
+    pub job_secs: Option<f64>,
+    /// Recent seconds of generation per second of audio.
+    pub real_time_factor: Option<f64>,
+}
+
+/// The signal for the serving pool's `load`, if the model is loaded.
+pub fn signal(config: &Autoscale, load: Option<PoolLoad>, real_time_factor: Option<f64>) -> Signal {
+    let load = load.unwrap_or(PoolLoad {
+        workers: 0,
+        busy: 0,
+        queued: 0,
+        job_secs: None,
+    });
+    let workers = load.workers.max(1) as f64;
+    let utilization = load.busy as f64 / workers;
+    let queue_wait_secs = load.job_secs.map(|secs| load.queued as f64 * secs / workers);
+    // Before any job has finished, each waiting one counts as a worker's
+    // worth of load.
+    let backlog = queue_wait_secs.map_or(load.queued as f64 / workers, |wait| wait / config.target_queue_wait_secs);
+    Signal {
+        value: utilization + backlog,
+        utilization,
+        queue_depth: load.queued,
+        queue_wait_secs,
+        busy_workers: load.busy,
+        workers: load.workers,
+        job_secs: load.job_secs,
+        real_time_factor,
+    }
+}
@@ -0,0 +1,203 @@
+//! Bleeping configured words out of a clip. Parler-TTS gives no alignment,
+//! so a word's place in the audio is estimated from its place in the chunk
+//! it was generated from: the chunk's speech (leading and trailing silence
+//! trimmed) is spread over its text by character, with pauses weighted at
+//! punctuation, and the estimate is padded on both sides.
+
+use std::ops::Range;
+
+use serde::Deserialize;
+
+/// Frame for finding where a chunk's speech starts and ends.
+const FRAME_SECS: f64 = 0.01;
+
+/// Frames this far below the loudest one count as silence.
+const SILENCE_DB: f64 = 35.0;
+
+/// Fade at the edges of a bleep, so it doesn't click.
+const FADE_SECS: f64 = 0.005;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BleepMode {
+    /// A sine tone over the word.
+    Tone,
+    Silence,
+}
+
+/// The `[bleep]` config section; requests turn it on with `bleep=true`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Bleep {
+    /// Case-insensitive words or phrases; a trailing `*` matches any ending
+    /// (`darn*` also takes "darned").
+    pub words: Vec<String>,
+    pub mode: BleepMode,
+    pub tone_hz: f64,
+    pub tone_dbfs: f64,
+    /// Added before and after each estimated word, to cover the estimate's
+    /// error.
+    pub padding_ms: f64,
+}
+
+impl Default for Bleep {
+    fn default() -> Self {
+        Self {
+            words: Vec::new(),
+            mode: BleepMode::Tone,
+            tone_hz: 1000.0,
+            tone_dbfs: -12.0,
+            padding_ms: 80.0,
+        }
+    }
+}
+
+impl Bleep {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.words.iter().any(|w| tokens(w).is_empty()) {
+            anyhow::bail!("bleep.words must not contain empty entries");
+        }
+        if !(20.0..=8000.0).contains(&self.tone_hz) {
+            anyhow::bail!("bleep.tone_hz must be between 20 and 8000");
+        }
+        if !(-60.0..=0.0).contains(&self.tone_dbfs) {
+            anyhow::bail!("bleep.tone_dbfs must be between -60 and 0");
+        }
+        if !(0.0..=1000.0).contains(&self.padding_ms) {
+            anyhow::bail!("bleep.padding_ms must be between 0 and 1000");
+        }
+        Ok(())
+    }
+
+    /// Bleeps the configured words of `text` in `samples`, the audio
+    /// generated from it. Returns how many were found.
+    pub fn apply(&self, text: &str, samples: &mut [f32], sample_rate: u32) -> usize {
+        let ranges = self.ranges(text, samples, sample_rate);
+        let fade = ((FADE_SECS * sample_rate as f64) as usize).max(1);
+        let amplitude = 10f64.powf(self.tone_dbfs / 20.0) as f32;
+        for range in &ranges {
+            let len = range.len();
+            for (n, sample) in samples[range.clone()].iter_mut().enumerate() {
+                // 0 at the edges of the range, 1 inside the fades.
+                let edge = (n.min(len - 1 - n) as f32 / fade as f32).min(1.0);
+                let replacement = match self.mode {
+                    BleepMode::Tone => {
+                        let t = (range.start + n) as f64 / sample_rate as f64;
+                        amplitude * (std::f64::consts::TAU * self.tone_hz * t).sin() as f32
+                    }
+                    BleepMode::Silence => 0.0,
+                };
+                *sample = *sample * (1.0 - edge) + replacement * edge;
+            }
+        }
+        ranges.len()
+    }
+
+    /// The estimated sample ranges of the configured words, padded and
+    /// merged where they overlap.
+    fn ranges(&self, text: &str, samples: &[f32], sample_rate: u32) -> Vec<Range<usize>> {
+        let words = tokens(text);
+        let patterns: Vec<Vec<String>> = self
+            .words
+            .iter()
+            .map(|w| tokens(w).into_iter().map(|(_, t)| t).collect())
+            .collect();
+        let mut found = Vec::new();
+        for start in 0..words.len() {
+            for pattern in &patterns {
+                let end = start + pattern.len();
+                if end <= words.len() && pattern.iter().zip(&words[start..end]).all(|(p, (_, w))| matches(p, w)) {
+                    found.push(words[start].0.start..words[end - 1].0.end);
+                }
+            }
+        }
+        if found.is_empty() {
+            return Vec::new();
+        }
+
+        // The share of the speech each character takes.
+        let weight = |c: char| match c {
+            '.' | '!' | '?' | ';' | ':' => 3.0,
+            ',' => 2.0,
+            c if c.is_whitespace() => 0.5,
+            c if c.is_alphanumeric() => 1.0,
+            _ => 0.25,
+        };
+        let mut offsets = Vec::with_capacity(text.len() + 1);
+        let mut total = 0f64;
+        for (at, c) in text.char_indices() {
+            offsets.push((at, total));
+            total += weight(c);
+        }
+        offsets.push((text.len(), total));
+        let position = |byte: usize| {
+            let n = offsets.partition_point(|(at, _)| *at < byte);
+            offsets.get(n).map_or(total, |(_, w)| *w)
+        };
+
+        let speech = speech_span(samples, sample_rate);
+        let padding = (self.padding_ms / 1000.0 * sample_rate as f64) as usize;
+        let to_sample = |w: f64| speech.start + (w / total.max(f64::EPSILON) * speech.len() as f64) as usize;
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        found.sort_by_key(|r| r.start);
+        for word in found {
+            let start = to_sample(position(word.start)).saturating_sub(padding);
+            let end = (to_sample(position(word.end)) + padding).min(samples.len());
+            match ranges.last_mut() {
+                Some(last) if start <= last.end => last.end = last.end.max(end),
+                _ if start < end => ranges.push(start..end),
+                _ => {}
+            }
+        }
+        ranges
+    }
+}
+
+/// Lowercased words of `text` with their byte ranges. Apostrophes inside a
+/// word belong to it.
+fn tokens(text: &str) -> Vec<(Range<usize>, String)> {
+    let mut tokens = Vec::new();
+    let mut current: Option<usize> = None;
+    let mut chars = text.char_indices().peekable();
+    while let Some((at, c)) = chars.next() {
+        let inner_apostrophe = matches!(c, '\'' | '’')
+            && current.is_some()
+            && chars.peek().is_some_and(|(_, next)| next.is_alphanumeric());
+        if c.is_alphanumeric() || inner_apostrophe || (c == '*' && current.is_some()) {
+            current.get_or_insert(at);
+            continue;
+        }
+        if let Some(start) = current.take() {
+            tokens.push((start..at, text[start..at].to_lowercase()));
+        }
+    }
+    if let Some(start) = current {
+        tokens.push((start..text.len(), text[start..].to_lowercase()));
+    }
+    tokens
+}
+
+fn matches(pattern: &str, word: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => word.starts_with(prefix),
+        None => pattern == word,
+    }
+}
+
+/// Where the speech in `samples` starts and ends.
+fn speech_span(samples: &[f32], sample_rate: u32) -> Range<usize> {
+    let frame = ((FRAME_SECS * sample_rate as f64) as usize).max(1);
+    let levels: Vec<f32> = samples
+        .chunks(frame)
+        .map(|f| (f.iter().map(|s| s * s).sum::<f32>() / f.len() as f32).sqrt())
+        .collect();
+    let loudest = levels.iter().copied().fold(0f32, f32::max);
+    let threshold = loudest * 10f64.powf(-SILENCE_DB / 20.0) as f32;
+    match (
+        levels.iter().position(|l| *l > threshold),
+        levels.iter().rposition(|l| *l > threshold),
+    ) {
+        (Some(first), Some(last)) if loudest > 0.0 => first * frame..((last + 1) * frame).min(samples.len()),
+        _ => 0..samples.len(),
+    }
+}
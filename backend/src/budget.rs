@@ -0,0 +1,140 @@
+//! Per-stage time budgets for `/api/tts`: requests whose tokenize, generate,
+//! decode or encode stage runs long are flagged, and generation can be
+//! capped ahead of time so interactive latency stays bounded.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Tokenize,
+    Generate,
+    Decode,
+    Encode,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [Stage::Tokenize, Stage::Generate, Stage::Decode, Stage::Encode];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Stage::Tokenize => "tokenize",
+            Stage::Generate => "generate",
+            Stage::Decode => "decode",
+            Stage::Encode => "encode",
+        }
+    }
+}
+
+/// Seconds spent in each stage. For a chunked prompt the first three are
+/// those of the slowest chunk, since budgets apply chunk by chunk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct StageTimings {
+    pub tokenize_secs: f64,
+    pub generate_secs: f64,
+    pub decode_secs: f64,
+    pub encode_secs: f64,
+}
+
+impl StageTimings {
+    pub fn get(&self, stage: Stage) -> f64 {
+        match stage {
+            Stage::Tokenize => self.tokenize_secs,
+            Stage::Generate => self.generate_secs,
+            Stage::Decode => self.decode_secs,
+            Stage::Encode => self.encode_secs,
+        }
+    }
+
+    pub fn record(&mut self, stage: Stage, elapsed: Duration) {
+        let slot = match stage {
+            Stage::Tokenize => &mut self.tokenize_secs,
+            Stage::Generate => &mut self.generate_secs,
+            Stage::Decode => &mut self.decode_secs,
+            Stage::Encode => &mut self.encode_secs,
+        };
+        *slot += elapsed.as_secs_f64();
+    }
+
+    /// Adds up two attempts at the same chunk (a quality retry).
+    pub fn add(&self, other: &StageTimings) -> StageTimings {
+        StageTimings {
+            tokenize_secs: self.tokenize_secs + other.tokenize_secs,
+            generate_secs: self.generate_secs + other.generate_secs,
+            decode_secs: self.decode_secs + other.decode_secs,
+            encode_secs: self.encode_secs + other.encode_secs,
+        }
+    }
+
+    /// The per-stage maximum of two chunks.
+    pub fn max(&self, other: &StageTimings) -> StageTimings {
+        StageTimings {
+            tokenize_secs: self.tokenize_secs.max(other.tokenize_secs),
+            generate_secs: self.generate_secs.max(other.generate_secs),
+            decode_secs: self.decode_secs.max(other.decode_secs),
+            encode_secs: self.encode_secs.max(other.encode_secs),
+        }
+    }
+
+    /// A `Server-Timing` header value, in milliseconds.
+    pub fn server_timing(&self) -> String {
+        Stage::ALL
+            .iter()
+            .map(|&stage| format!("{};dur={:.1}", stage.as_str(), self.get(stage) * 1000.0))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// The `[budgets]` config section, in seconds. Unset stages are unlimited.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Budgets {
+    pub tokenize_secs: Option<f64>,
+    pub generate_secs: Option<f64>,
+    pub decode_secs: Option<f64>,
+    pub encode_secs: Option<f64>,
+    /// Lower `max_steps` so that, at the recent generation speed, a chunk
+    /// finishes within `generate_secs`.
+    pub downgrade: bool,
+}
+
+impl Budgets {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let limits = [self.tokenize_secs, self.generate_secs, self.decode_secs, self.encode_secs];
+        if limits.iter().flatten().any(|secs| !secs.is_finite() || *secs <= 0.0) {
+            anyhow::bail!("budgets must be positive numbers of seconds");
+        }
+        if self.downgrade && self.generate_secs.is_none() {
+            anyhow::bail!("budgets.downgrade needs budgets.generate_secs");
+        }
+        Ok(())
+    }
+
+    fn limit(&self, stage: Stage) -> Option<f64> {
+        match stage {
+            Stage::Tokenize => self.tokenize_secs,
+            Stage::Generate => self.generate_secs,
+            Stage::Decode => self.decode_secs,
+            Stage::Encode => self.encode_secs,
+        }
+    }
+
+    /// The stages in `timings` that ran past their budget.
+    pub fn exceeded(&self, timings: &StageTimings) -> Vec<Stage> {
+        Stage::ALL
+            .into_iter()
+            .filter(|&stage| self.limit(stage).is_some_and(|limit| timings.get(stage) > limit))
+            .collect()
+    }
+
+    /// The `max_steps` that fits the generate budget at `secs_per_step`,
+    /// when downgrading is on and the speed is known.
+    pub fn max_steps(&self, secs_per_step: Option<f64>) -> Option<usize> {
+        let budget = self.generate_secs.filter(|_| self.downgrade)?;
+        let secs_per_step = secs_per_step.filter(|s| *s > 0.0)?;
+        Some(((budget / secs_per_step) as usize).max(1))
+    }
+}
@@ -0,0 +1,309 @@
+//! Switching the model at runtime behind a health gate. A candidate model
+//! is loaded next to the serving one and both render the same canary
+//! prompt with the same seed; the candidate is promoted only when its clip
+//! is free of defects, ends on its own, and its length and loudness stay
+//! close to the serving model's. Otherwise it is dropped and the serving
+//! model carries on.
+//!
+//! Requests keep going to the serving model while the candidate loads and
+//! checks, and those running when it is promoted finish on the model they
+//! started on, so a switch fails no request.
+
+use std::sync::{Arc, Mutex, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::SynthesisError;
+use crate::generation::FinishReason;
+use crate::pool::EnginePool;
+use crate::quality::{self, Defect};
+use crate::{audio, AppState, CreateWavArgs, PostProcess};
+
+/// The `[canary]` config section.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Canary {
+    pub prompt: String,
+    pub description: String,
+    pub seed: u64,
+    /// Largest factor by which the candidate's clip may be longer or
+    /// shorter than the serving model's.
+    pub max_duration_ratio: f64,
+    /// Largest change in RMS level, in dB.
+    pub max_loudness_change_db: f64,
+}
+
+impl Default for Canary {
+    fn default() -> Self {
+        Self {
+            prompt: "The quick brown fox jumps over the lazy dog, then takes a well earned nap.".to_string(),
+            description: "A female speaker delivers her words in a clear, moderately paced voice, with very clear audio."
+                .to_string(),
+            seed: 0,
+            max_duration_ratio: 1.5,
+            max_loudness_change_db: 6.0,
+        }
+    }
+}
+
+impl Canary {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.prompt.trim().is_empty() || self.description.trim().is_empty() {
+            anyhow::bail!("canary.prompt and canary.description must not be empty");
+        }
+        if !self.max_duration_ratio.is_finite() || self.max_duration_ratio < 1.0 {
+            anyhow::bail!("canary.max_duration_ratio must be at least 1");
+        }
+        if !self.max_loudness_change_db.is_finite() || self.max_loudness_change_db <= 0.0 {
+            anyhow::bail!("canary.max_loudness_change_db must be positive");
+        }
+        Ok(())
+    }
+}
+
+/// What the canary clip of one model measured.
+#[derive(Debug, Clone, Serialize)]
+pub struct CanaryStats {
+    pub version: String,
+    pub duration_secs: f64,
+    pub rms_dbfs: f64,
+    pub peak_dbfs: f64,
+    pub steps: usize,
+    pub finish_reason: FinishReason,
+    pub defect: Option<Defect>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CanaryReport {
+    pub repo: String,
+    pub revision: String,
+    /// Unix time, seconds.
+    pub checked_at: u64,
+    pub serving: CanaryStats,
+    pub candidate: CanaryStats,
+    /// Why the candidate was rejected; empty when it was promoted.
+    pub problems: Vec<String>,
+    pub promoted: bool,
+}
+
+/// A candidate being loaded or checked.
+#[derive(Debug, Clone, Serialize)]
+pub struct Standby {
+    pub repo: String,
+    pub revision: String,
+    /// Unix time, seconds.
+    pub started_at: u64,
+    pub phase: StandbyPhase,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StandbyPhase {
+    /// Downloading and loading the weights.
+    Loading,
+    /// Rendering the canary on both models.
+    Checking,
+}
+
+/// A switch that ended without a report: the candidate didn't download or
+/// load, or the canary couldn't be rendered.
+#[derive(Debug, Clone, Serialize)]
+pub struct SwitchFailure {
+    pub repo: String,
+    pub revision: String,
+    /// Unix time, seconds.
+    pub failed_at: u64,
+    pub error: String,
+}
+
+/// The model promoted at runtime, if any, and the last canary run.
+#[derive(Default)]
+pub struct ModelSwitch {
+    promoted: RwLock<Option<Arc<EnginePool>>>,
+    /// Held while a candidate is loaded and checked, so one runs at a time.
+    busy: Arc<tokio::sync::Mutex<()>>,
+    standby: Mutex<Option<Standby>>,
+    last: Mutex<Option<CanaryReport>>,
+    last_failure: Mutex<Option<SwitchFailure>>,
+}
+
+impl ModelSwitch {
+    /// The promoted model, which is served instead of the startup one.
+    pub fn promoted(&self) -> Option<Arc<EnginePool>> {
+        self.promoted.read().unwrap().clone()
+    }
+
+    pub fn last_report(&self) -> Option<CanaryReport> {
+        self.last.lock().unwrap().clone()
+    }
+
+    /// The candidate being warmed up, while a switch is running.
+    pub fn standby(&self) -> Option<Standby> {
+        self.standby.lock().unwrap().clone()
+    }
+
+    pub fn last_failure(&self) -> Option<SwitchFailure> {
+        self.last_failure.lock().unwrap().clone()
+    }
+
+    /// Loads `repo` at `revision`, checks it against the serving model and
+    /// promotes it if it passes. `None` while another switch is running.
+    pub async fn switch(&self, state: &AppState, repo: &str, revision: &str) -> Option<Result<CanaryReport, SynthesisError>> {
+        let _busy = self.busy.try_lock().ok()?;
+        self.begin(repo, revision);
+        Some(self.run(state, repo, revision).await)
+    }
+
+    /// Starts the same switch as a task and returns at once, for loads that
+    /// take longer than a client waits; the outcome shows in the model
+    /// status. `None` while another switch is running.
+    pub fn switch_in_background(self: &Arc<Self>, state: &AppState, repo: String, revision: String) -> Option<Standby> {
+        let busy = self.busy.clone().try_lock_owned().ok()?;
+        let standby = self.begin(&repo, &revision);
+        let (switch, state) = (self.clone(), state.clone());
+        tokio::spawn(async move {
+            let _busy = busy;
+            let _ = switch.run(&state, &repo, &revision).await;
+        });
+        Some(standby)
+    }
+
+    fn begin(&self, repo: &str, revision: &str) -> Standby {
+        let standby = Standby {
+            repo: repo.to_string(),
+            revision: revision.to_string(),
+            started_at: crate::unix_now(),
+            phase: StandbyPhase::Loading,
+        };
+        *self.standby.lock().unwrap() = Some(standby.clone());
+        standby
+    }
+
+    /// Runs the switch [`begin`](Self::begin) announced, clearing the
+    /// standby at the end and recording a failure to produce a report.
+    async fn run(&self, state: &AppState, repo: &str, revision: &str) -> Result<CanaryReport, SynthesisError> {
+        let result = self.check(state, repo, revision).await;
+        *self.standby.lock().unwrap() = None;
+        if let Err(e) = &result {
+            println!("model: switching to {repo}@{revision} failed: {e}");
+            *self.last_failure.lock().unwrap() = Some(SwitchFailure {
+                repo: repo.to_string(),
+                revision: revision.to_string(),
+                failed_at: crate::unix_now(),
+                error: e.to_string(),
+            });
+        }
+        result
+    }
+
+    async fn check(&self, state: &AppState, repo: &str, revision: &str) -> Result<CanaryReport, SynthesisError> {
+        let serving = state.pool().await.map_err(SynthesisError::ModelLoad)?;
+        println!("model: loading candidate {repo}@{revision}");
+        let files = crate::hub::fetch_repo_files(&state.config, repo, revision)
+            .await
+            .map_err(SynthesisError::ModelLoad)?;
+        let config = state.config.clone();
+        let engine = tokio::task::spawn_blocking(move || crate::tts_model::load(&config, &files))
+            .await
+            .map_err(|e| SynthesisError::ModelLoad(e.into()))?
+            .map_err(SynthesisError::ModelLoad)?;
+        let candidate = Arc::new(EnginePool::new(engine, state.config.workers));
+
+        if let Some(standby) = self.standby.lock().unwrap().as_mut() {
+            standby.phase = StandbyPhase::Checking;
+        }
+        let canary = &state.config.canary;
+        let serving_stats = measure(state, &serving, canary).await?;
+        let candidate_stats = measure(state, &candidate, canary).await?;
+        let problems = compare(canary, &serving_stats, &candidate_stats);
+        let promoted = problems.is_empty();
+        let report = CanaryReport {
+            repo: repo.to_string(),
+            revision: revision.to_string(),
+            checked_at: crate::unix_now(),
+            serving: serving_stats,
+            candidate: candidate_stats,
+            problems,
+            promoted,
+        };
+        if promoted {
+            println!(
+                "model: promoted {} (was {})",
+                report.candidate.version, report.serving.version
+            );
+            *self.promoted.write().unwrap() = Some(candidate.clone());
+            // Cached phrases belong to the old model.
+            let state = state.clone();
+            tokio::spawn(async move { crate::render_quick_phrases(&state, &candidate).await });
+        } else {
+            println!(
+                "model: kept {}, the canary of {} failed: {}",
+                report.serving.version,
+                report.candidate.version,
+                report.problems.join("; ")
+            );
+        }
+        *self.last.lock().unwrap() = Some(report.clone());
+        Ok(report)
+    }
+}
+
+/// Renders the canary on `pool` without post-processing, so loudness
+/// differences aren't normalized away.
+async fn measure(state: &AppState, pool: &Arc<EnginePool>, canary: &Canary) -> Result<CanaryStats, SynthesisError> {
+    let args = Arc::new(CreateWavArgs {
+        request_id: 0,
+        description: canary.description.clone(),
+        prompt: canary.prompt.clone(),
+        temperature: None,
+        seed: Some(canary.seed),
+        top_p: None,
+        post_process: PostProcess {
+            raw: true,
+            ..PostProcess::default()
+        },
+        tokens: Default::default(),
+        sampler: crate::sampler::SamplerKind::Stock,
+        stopping: state.config.max_steps.stopping(),
+        retry_degenerate: false,
+        wav_format: audio::WavFormat::Pcm16,
+        bleep: None,
+        progress: None,
+    });
+    let clip = crate::create_wav_file(pool, &args, state.config.chunk_chars, None).await?;
+    let pcm = audio::read_wav(&clip.wav).map_err(SynthesisError::Decode)?;
+    let level = |value: f32| 20.0 * (value.max(1e-6) as f64).log10();
+    let rms = (pcm.samples.iter().map(|s| s * s).sum::<f32>() / pcm.samples.len().max(1) as f32).sqrt();
+    let peak = pcm.samples.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+    Ok(CanaryStats {
+        version: pool.engine().version().to_string(),
+        duration_secs: clip.duration_secs,
+        rms_dbfs: level(rms),
+        peak_dbfs: level(peak),
+        steps: clip.steps,
+        finish_reason: clip.finish,
+        defect: quality::assess(&pcm.samples, pcm.sample_rate, &canary.prompt),
+    })
+}
+
+fn compare(canary: &Canary, serving: &CanaryStats, candidate: &CanaryStats) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let Some(defect) = candidate.defect {
+        problems.push(format!("the clip is degenerate ({})", defect.as_str()));
+    }
+    if candidate.finish_reason == FinishReason::MaxSteps {
+        problems.push(format!("it did not end within {} steps", candidate.steps));
+    }
+    let ratio = candidate.duration_secs / serving.duration_secs.max(1e-3);
+    if ratio > canary.max_duration_ratio || ratio < 1.0 / canary.max_duration_ratio {
+        problems.push(format!(
+            "it lasts {:.2} s against {:.2} s",
+            candidate.duration_secs, serving.duration_secs
+        ));
+    }
+    let change = candidate.rms_dbfs - serving.rms_dbfs;
+    if change.abs() > canary.max_loudness_change_db {
+        problems.push(format!("its level is {change:+.1} dB off"));
+    }
+    problems
+}
@@ -0,0 +1,71 @@
+//! Failure injection for testing clients: slow generations, random 500s and
+//! audio responses that break off partway. Only honoured by debug builds.
+
+use std::time::Duration;
+
+use axum::body::Body;
+use serde::{de::Error as _, Deserialize, Deserializer};
+
+/// The `[chaos]` config section. Rates are probabilities per request.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Chaos {
+    /// Answer `/api/tts` with a 500 before doing any work.
+    pub error_rate: f64,
+    /// Hold a generation back by `delay_secs` before it starts.
+    pub slow_rate: f64,
+    #[serde(rename = "delay_secs", deserialize_with = "de_delay")]
+    pub delay: Duration,
+    /// Send only the first half of an audio body, then drop the connection.
+    pub truncate_rate: f64,
+}
+
+impl Chaos {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !cfg!(debug_assertions) {
+            anyhow::bail!("the [chaos] section is only available in debug builds");
+        }
+        let rates = [self.error_rate, self.slow_rate, self.truncate_rate];
+        if rates.iter().any(|rate| !(0.0..=1.0).contains(rate)) {
+            anyhow::bail!("chaos rates must be between 0 and 1");
+        }
+        Ok(())
+    }
+
+    pub fn fail(&self) -> bool {
+        roll(self.error_rate)
+    }
+
+    pub async fn delay(&self, tag: &str) {
+        if roll(self.slow_rate) {
+            println!("{tag}: chaos: delaying by {:?}", self.delay);
+            tokio::time::sleep(self.delay).await;
+        }
+    }
+
+    /// `data` as a response body, possibly cut off halfway with an error.
+    pub fn body(&self, tag: &str, data: Vec<u8>) -> Body {
+        if !roll(self.truncate_rate) {
+            return Body::from(data);
+        }
+        println!("{tag}: chaos: truncating the response at {} of {} bytes", data.len() / 2, data.len());
+        let mut data = data;
+        data.truncate(data.len() / 2);
+        let chunks: [std::io::Result<Vec<u8>>; 2] = [
+            Ok(data),
+            Err(std::io::Error::other("chaos: truncated stream")),
+        ];
+        Body::from_stream(futures::stream::iter(chunks))
+    }
+}
+
+/// Seconds, as a float; `nan`, `inf` and negative ones are refused.
+fn de_delay<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let secs = f64::deserialize(deserializer)?;
+    Duration::try_from_secs_f64(secs)
+        .map_err(|_| D::Error::custom(format!("chaos.delay_secs must be a non-negative number of seconds, not {secs}")))
+}
+
+fn roll(rate: f64) -> bool {
+    rate > 0.0 && rand::random::<f64>() < rate
+}
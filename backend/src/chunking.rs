@@ -0,0 +1,70 @@
+//! Splitting long prompts into pieces the model can read in one go.
+//!
+//! Parler-TTS drifts and mumbles on long inputs, and a single generation is
+//! bounded by `max_steps`, so long texts are cut at sentence ends and each
+//! piece is generated on its own.
+
+/// Splits `text` into chunks of at most `max_chars` characters, breaking
+/// after sentence punctuation where possible, then after commas, then at
+/// whitespace. Text no longer than `max_chars` comes back as one chunk.
+pub fn split(text: &str, max_chars: usize) -> Vec<String> {
+    let text = text.trim();
+    if text.chars().count() <= max_chars {
+        return vec![text.to_string()];
+    }
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    for sentence in pieces(text, &['.', '!', '?', ';', '\n']) {
+        if sentence.chars().count() > max_chars {
+            flush(&mut chunks, &mut current);
+            for clause in pieces(sentence, &[',', ':']) {
+                pack(&mut chunks, &mut current, clause, max_chars);
+            }
+            flush(&mut chunks, &mut current);
+        } else {
+            pack(&mut chunks, &mut current, sentence, max_chars);
+        }
+    }
+    flush(&mut chunks, &mut current);
+    chunks
+}
+
+/// `text` cut after each of `ends` (kept with the piece before it).
+fn pieces<'a>(text: &'a str, ends: &[char]) -> Vec<&'a str> {
+    text.split_inclusive(ends)
+        .map(str::trim)
+        .filter(|piece| !piece.is_empty())
+        .collect()
+}
+
+/// Appends `piece` to `current`, starting a new chunk when it would not fit
+/// and breaking at whitespace when `piece` alone is too long.
+fn pack(chunks: &mut Vec<String>, current: &mut String, piece: &str, max_chars: usize) {
+    if current.chars().count() + 1 + piece.chars().count() <= max_chars {
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(piece);
+        return;
+    }
+    flush(chunks, current);
+    if piece.chars().count() <= max_chars {
+        current.push_str(piece);
+        return;
+    }
+    for word in piece.split_whitespace() {
+        if !current.is_empty() && current.chars().count() + 1 + word.chars().count() > max_chars {
+            flush(chunks, current);
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(word);
+    }
+}
+
+fn flush(chunks: &mut Vec<String>, current: &mut String) {
+    if !current.is_empty() {
+        chunks.push(std::mem::take(current));
+    }
+}
@@ -0,0 +1,69 @@
+//! Identical requests served by one generation. When a request arrives
+//! while an identical one (same text, voice and parameters) is still being
+//! generated, typically from a double-clicked Generate button, it waits for
+//! that generation and answers with its clip instead of running the model
+//! again.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use futures::future::{BoxFuture, FutureExt, Shared};
+
+use crate::error::SynthesisError;
+
+type Outcome<T> = Result<Arc<T>, Arc<SynthesisError>>;
+
+pub struct Coalescer<T> {
+    running: Mutex<HashMap<String, Shared<BoxFuture<'static, Outcome<T>>>>>,
+}
+
+impl<T> Default for Coalescer<T> {
+    fn default() -> Self {
+        Self {
+            running: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> Coalescer<T> {
+    /// Runs `work` under `key`, or waits for the run of `key` already going.
+    /// The flag tells whether this call joined another one.
+    ///
+    /// The work runs as its own task, so it finishes (and is stored) even
+    /// when the request that started it goes away.
+    pub async fn run<F>(self: &Arc<Self>, key: String, work: F) -> (Result<T, SynthesisError>, bool)
+    where
+        F: Future<Output = Result<T, SynthesisError>> + Send + 'static,
+    {
+        let (shared, joined) = {
+            let mut running = self.running.lock().unwrap();
+            match running.get(&key) {
+                Some(shared) => (shared.clone(), true),
+                None => {
+                    let coalescer = self.clone();
+                    let done = key.clone();
+                    let task = tokio::spawn(async move {
+                        let outcome = work.await.map(Arc::new).map_err(Arc::new);
+                        coalescer.running.lock().unwrap().remove(&done);
+                        outcome
+                    });
+                    let shared = async move {
+                        task.await.unwrap_or_else(|e| {
+                            Err(Arc::new(SynthesisError::Generate(anyhow::anyhow!("generation task failed: {e}"))))
+                        })
+                    }
+                    .boxed()
+                    .shared();
+                    running.insert(key, shared.clone());
+                    (shared, false)
+                }
+            }
+        };
+        let outcome = match shared.await {
+            Ok(value) => Ok(Arc::unwrap_or_clone(value)),
+            Err(e) => Err(Arc::try_unwrap(e).unwrap_or_else(|e| e.duplicate())),
+        };
+        (outcome, joined)
+    }
+}
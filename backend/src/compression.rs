@@ -0,0 +1,106 @@
+//! Gzip and Brotli for the JSON endpoints. Applied per route group, so a
+//! deployment behind a compressing proxy can turn it off where the proxy
+//! already does the work. Audio, archives and other bodies that are
+//! compressed already are always sent as they are.
+
+use axum::http::{header, Response};
+use axum::Router;
+use serde::Deserialize;
+use tower_http::compression::predicate::{Predicate, SizeAbove};
+use tower_http::compression::{CompressionLayer, CompressionLevel};
+
+use crate::AppState;
+
+/// Content types never compressed, matched by prefix.
+const INCOMPRESSIBLE: &[&str] = &["audio/", "video/", "image/", "application/zip", "application/gzip", "text/event-stream"];
+
+/// Endpoints that are compressed together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteGroup {
+    /// `/api/history*`.
+    History,
+    /// `/api/voices*`.
+    Voices,
+    /// `/api/audiobooks` and `/api/jobs/*`.
+    Jobs,
+    /// `/api/estimate` and `/api/chunks`.
+    Text,
+    /// `/api/usage`.
+    Usage,
+    /// `/api/stats` and `/api/info`.
+    Stats,
+    /// `/api/admin/*`, `/api/autoscale` and `/metrics`.
+    Admin,
+}
+
+impl RouteGroup {
+    fn all() -> Vec<Self> {
+        vec![Self::History, Self::Voices, Self::Jobs, Self::Text, Self::Usage, Self::Stats, Self::Admin]
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Fastest,
+    Default,
+    Best,
+}
+
+/// The `[compression]` config section.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Compression {
+    /// The groups compressed; empty turns compression off.
+    pub groups: Vec<RouteGroup>,
+    /// Smaller bodies are sent as they are.
+    pub min_bytes: u16,
+    pub level: Level,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            groups: RouteGroup::all(),
+            min_bytes: 1024,
+            level: Level::Default,
+        }
+    }
+}
+
+impl Compression {
+    /// `routes` with compression when `group` is configured for it.
+    pub fn wrap(&self, group: RouteGroup, routes: Router<AppState>) -> Router<AppState> {
+        if !self.groups.contains(&group) {
+            return routes;
+        }
+        let level = match self.level {
+            Level::Fastest => CompressionLevel::Fastest,
+            Level::Default => CompressionLevel::Default,
+            Level::Best => CompressionLevel::Best,
+        };
+        routes.layer(
+            CompressionLayer::new()
+                .quality(level)
+                .compress_when(Compressible(SizeAbove::new(self.min_bytes))),
+        )
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Compressible(SizeAbove);
+
+impl Predicate for Compressible {
+    fn should_compress<B>(&self, response: &Response<B>) -> bool
+    where
+        B: axum::body::HttpBody,
+    {
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        self.0.should_compress(response) && !INCOMPRESSIBLE.iter().any(|t| content_type.starts_with(t))
+    }
+}
@@ -0,0 +1,617 @@
+//! Server configuration, read from a TOML file and overridable from the
+//! command line.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::access_log::AccessLog;
+use crate::audio::WavFormat;
+use crate::autoscale::Autoscale;
+use crate::budget::Budgets;
+use crate::bleep::Bleep;
+use crate::canary::Canary;
+use crate::chaos::Chaos;
+use crate::compression::Compression;
+use crate::experiments::Experiments;
+use crate::features::Features;
+use crate::generation::StepLimit;
+use crate::locale_voices;
+use crate::models::Models;
+use crate::numbers::Spelling;
+use crate::openai::OpenAi;
+use crate::podcast::Podcast;
+use crate::privacy::PromptLogging;
+use crate::reporting::ErrorReporting;
+use crate::rtc::WebRtc;
+use crate::schedule::{self, Schedule};
+use crate::self_check::SelfCheck;
+use crate::slo::Slo;
+use crate::telegram::{self, Telegram};
+use crate::tts_jobs::Queue;
+use crate::verify::Verify;
+use crate::versioning::ApiVersioning;
+
+/// Config file used when neither `--config` nor `TTSER_CONFIG` is given.
+const DEFAULT_CONFIG_FILE: &str = "ttser.toml";
+
+/// `default_description` when the config file doesn't set one.
+const DEFAULT_DESCRIPTION: &str =
+    "A female speaker delivers her words in a clear, moderately paced voice, with very clear audio.";
+
+/// Environment variable that overrides `encryption_key`.
+const ENCRYPTION_KEY_ENV: &str = "TTSER_ENCRYPTION_KEY";
+
+#[derive(Parser, Debug)]
+#[command(about = "Parler-TTS HTTP server")]
+pub struct Args {
+    /// Path to a TOML config file (defaults to ./ttser.toml when present).
+    #[arg(long, env = "TTSER_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, overriding `bind` from the config file.
+    #[arg(long)]
+    pub bind: Option<String>,
+
+    /// Separate address for the admin routes, overriding `admin_bind`.
+    #[arg(long)]
+    pub admin_bind: Option<String>,
+
+    /// Run on the CPU even when an accelerator is available.
+    #[arg(long)]
+    pub cpu: bool,
+
+    /// Device to load the model on: `cpu`, `cuda:<idx>`, `metal` or
+    /// `auto`, overriding `device`.
+    #[arg(long, env = "TTSER_DEVICE")]
+    pub device: Option<DeviceChoice>,
+
+    /// Checkpoint served by default, overriding `[models] repo`.
+    #[arg(long)]
+    pub model: Option<String>,
+
+    /// Run the decoder on quantized weights, for CPUs: `q8_0` when no
+    /// type is given, overriding `quantized`.
+    #[arg(long, num_args = 0..=1, default_missing_value = "q8_0")]
+    pub quantized: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Send load to a running server and report latency percentiles and
+    /// error rates.
+    Loadtest(LoadtestArgs),
+    /// Render the `[verify]` prompts and check them against the golden
+    /// set, to catch drift after an upgrade.
+    Verify(VerifyArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct VerifyArgs {
+    /// Record the renders as the new golden set instead of checking them.
+    #[arg(long)]
+    pub update: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct LoadtestArgs {
+    /// Server root URL. Defaults to the configured `bind` address.
+    #[arg(long)]
+    pub url: Option<String>,
+
+    /// Requests kept in flight at once.
+    #[arg(long, default_value_t = 4)]
+    pub concurrency: usize,
+
+    /// How long to keep sending requests (e.g. 90s, 5m).
+    #[arg(long, default_value = "1m", value_parser = parse_duration_arg)]
+    pub duration: Duration,
+
+    /// API key for a namespace, sent as a bearer token.
+    #[arg(long, env = "TTSER_API_KEY")]
+    pub api_key: Option<String>,
+
+    /// Voice preset to request instead of a built-in description.
+    #[arg(long)]
+    pub voice: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    /// Several listeners, each with its own route sets. When set, `bind`
+    /// is ignored.
+    pub listeners: Vec<ListenerConfig>,
+    /// Serves `/metrics`, `/api/admin/*` and debugging here instead of on
+    /// `bind`, which then only has the public routes.
+    pub admin_bind: Option<String>,
+    /// Same as `device = "cpu"`.
+    pub cpu: bool,
+    /// Where the model runs. `auto` takes the first CUDA or Metal device
+    /// the build supports, else the CPU.
+    pub device: DeviceChoice,
+    /// Hugging Face hub cache directory (the `hub` folder, e.g.
+    /// `~/.cache/huggingface/hub`). Falls back to `HF_HOME` and then the
+    /// hf-hub default.
+    pub cache_dir: Option<PathBuf>,
+    /// Weight dtype the model runs in: `f32`, `f16` or `bf16`.
+    pub dtype: String,
+    /// Where weights converted to `dtype` are kept between restarts. Unset
+    /// means converting on every start.
+    pub warm_cache_dir: Option<PathBuf>,
+    /// Quantize the decoder's projections and heads to this GGUF type
+    /// (`q8_0`, `q6k`, `q5k`, `q4k` or `q4_0`), for CPU-only servers. The
+    /// quantized file is kept in `warm_cache_dir`.
+    pub quantized: Option<String>,
+    /// How prompts and descriptions are recorded in logs and stored request
+    /// records: `full`, `hashed` or `off`.
+    pub log_prompts: PromptLogging,
+    /// Where generated clips and their history records are written; each
+    /// namespace gets a subdirectory. Keep it outside `public/`, which is
+    /// served without authentication.
+    pub audio_dir: PathBuf,
+    /// 64 hex digits (32 bytes). When set, clips and history records are
+    /// encrypted at rest. Prefer the `TTSER_ENCRYPTION_KEY` environment
+    /// variable over putting the key in the file.
+    pub encryption_key: Option<String>,
+    /// How long generated clips are kept (e.g. `1h`, `30d`). Unset keeps
+    /// them until deleted.
+    #[serde(deserialize_with = "de_retention")]
+    pub retention: Option<Duration>,
+    /// Used by requests that give neither a description nor a voice.
+    /// Empty makes one of them required.
+    pub default_description: String,
+    /// Named voices requests can use instead of a description.
+    pub voices: BTreeMap<String, VoicePreset>,
+    /// Voice presets by language tag (`de`, `pt-BR`), for requests with
+    /// neither a voice nor a description. Picked by the request's `locale`,
+    /// otherwise its `Accept-Language`; before `default_description`.
+    pub locale_voices: BTreeMap<String, String>,
+    /// Prompts rendered in every voice preset at startup and served from
+    /// memory, e.g. greetings, error messages and digits.
+    pub quick_phrases: Vec<String>,
+    /// Render quick phrases that are missing or were invalidated (by a
+    /// model upgrade or an edited voice) right after startup. Otherwise
+    /// each is rendered by the first request for it.
+    pub rerender_quick_phrases: bool,
+    /// Tenants, each reached through its own API keys.
+    pub namespaces: BTreeMap<String, NamespaceConfig>,
+    /// Serve requests without an API key from the `default` namespace even
+    /// when namespaces are configured. Always on when there are none.
+    pub allow_anonymous: bool,
+    /// Sample encoding of the WAV files clips are stored as: `pcm16`,
+    /// `pcm24` or `float32`.
+    pub wav_format: WavFormat,
+    /// Regenerate once with a new seed when output is near-silent, heavily
+    /// clipped or far too short/long for its text.
+    pub retry_degenerate: bool,
+    /// Let a request identical to one still generating wait for that one's
+    /// clip instead of generating its own.
+    pub coalesce_requests: bool,
+    /// Generations that may run at once. Long prompts are split into
+    /// chunks that spread over idle workers, so more workers also means
+    /// faster long renders when the device has headroom.
+    pub workers: usize,
+    /// Prompts and descriptions whose token ids are kept for reuse, least
+    /// recently used out first; 0 tokenizes every time.
+    pub tokenizer_cache: usize,
+    /// Prompts longer than this many characters are split at sentence ends
+    /// and generated chunk by chunk, then joined in order.
+    pub chunk_chars: usize,
+    /// Splits the decoder across several GPUs, for cards too small to hold
+    /// the whole model. Unset runs everything on one device.
+    pub device_map: Option<DeviceMap>,
+    /// Failure injection for testing clients (debug builds only).
+    pub chaos: Option<Chaos>,
+    /// The checkpoint served by default, and those requests may choose.
+    pub models: Models,
+    /// The check a model switched to at runtime must pass to be served.
+    pub canary: Canary,
+    /// How `/api/autoscale` weighs the queue.
+    pub autoscale: Autoscale,
+    /// What requests in interactive mode aim for.
+    pub slo: Slo,
+    /// OpenAI voice names for `/v1/audio/speech`.
+    pub openai: OpenAi,
+    /// Transcribing clips to catch skipped or garbled words.
+    pub self_check: SelfCheck,
+    /// The `/api/tts/jobs` queue.
+    pub queue: Queue,
+    /// Decoder steps of requests that don't set `max_steps`.
+    pub max_steps: StepLimit,
+    /// Words bleeped out of clips requested with `bleep`.
+    pub bleep: Bleep,
+    /// Letter and symbol pronunciations for requests with `spell`.
+    pub spelling: Spelling,
+    /// Per-stage time limits for `/api/tts`.
+    pub budgets: Budgets,
+    /// Golden renders `ttser-backend verify` checks against.
+    pub verify: Verify,
+    /// Versioned `/api/v1` routes and deprecation of unversioned ones.
+    pub api: ApiVersioning,
+    pub access_log: AccessLog,
+    /// Gzip and Brotli for JSON responses, by route group.
+    pub compression: Compression,
+    pub error_reporting: ErrorReporting,
+    /// Logs every generation's parameters and quality-gate results to an
+    /// MLflow tracking server or a webhook.
+    pub experiments: Experiments,
+    /// Experimental behaviors requests may opt into.
+    pub features: Features,
+    /// Live audio to WebRTC peers (builds with the `webrtc` feature).
+    pub webrtc: Option<WebRtc>,
+    /// Voice-note replies to Telegram messages (builds with the `telegram`
+    /// feature).
+    pub telegram: Option<Telegram>,
+    /// Podcast feed of articles from RSS feeds, read aloud.
+    pub podcast: Option<Podcast>,
+    /// Synthesis jobs run on cron expressions.
+    pub schedules: Vec<Schedule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamespaceConfig {
+    pub api_keys: Vec<String>,
+    /// This namespace's own presets; the top-level `voices` are not shared.
+    #[serde(default)]
+    pub voices: BTreeMap<String, VoicePreset>,
+    /// Overrides the server-wide `retention`.
+    #[serde(default, deserialize_with = "de_retention")]
+    pub retention: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VoicePreset {
+    pub description: String,
+    /// Sampling settings for requests in this voice that don't give their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    /// Overrides the server-wide `retention` for clips in this voice.
+    #[serde(
+        default,
+        deserialize_with = "de_retention",
+        serialize_with = "ser_retention",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub retention: Option<Duration>,
+}
+
+impl VoicePreset {
+    /// Whether it sets any sampling settings. Quick phrases are only kept
+    /// for presets that don't, being rendered with the defaults.
+    pub fn has_sampling(&self) -> bool {
+        self.seed.is_some() || self.temperature.is_some() || self.top_p.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// `host:port` (IPv6 as `[::]:8039`) or `unix:<path>`.
+    pub bind: String,
+    #[serde(default = "RouteSet::all")]
+    pub routes: Vec<RouteSet>,
+}
+
+/// Groups of endpoints a listener can serve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteSet {
+    /// Generation, history and usage endpoints, and the frontend.
+    Public,
+    /// `/metrics`, model cache management and debugging.
+    Admin,
+}
+
+impl RouteSet {
+    pub fn all() -> Vec<Self> {
+        vec![Self::Public, Self::Admin]
+    }
+}
+
+/// The `device` setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum DeviceChoice {
+    Auto,
+    Cpu,
+    /// A CUDA ordinal, as `nvidia-smi` numbers the cards.
+    Cuda(usize),
+    Metal(usize),
+}
+
+impl std::str::FromStr for DeviceChoice {
+    type Err = String;
+
+    /// `auto`, `cpu`, `cuda`, `cuda:<idx>`, `metal` or `metal:<idx>`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let s = s.trim().to_ascii_lowercase();
+        let (kind, ordinal) = match s.split_once(':') {
+            Some((kind, ordinal)) => {
+                let ordinal = ordinal.parse().map_err(|_| format!("bad device ordinal in {s:?}"))?;
+                (kind, ordinal)
+            }
+            None => (s.as_str(), 0),
+        };
+        match kind {
+            "auto" if !s.contains(':') => Ok(Self::Auto),
+            "cpu" if !s.contains(':') => Ok(Self::Cpu),
+            "cuda" => Ok(Self::Cuda(ordinal)),
+            "metal" => Ok(Self::Metal(ordinal)),
+            _ => Err(format!("unknown device {s:?} (cpu, cuda:<idx>, metal or auto)")),
+        }
+    }
+}
+
+impl TryFrom<String> for DeviceChoice {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+impl std::fmt::Display for DeviceChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Auto => f.write_str("auto"),
+            Self::Cpu => f.write_str("cpu"),
+            Self::Cuda(ordinal) => write!(f, "cuda:{ordinal}"),
+            Self::Metal(ordinal) => write!(f, "metal:{ordinal}"),
+        }
+    }
+}
+
+/// Placement of the decoder layers on CUDA devices.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceMap {
+    /// CUDA ordinals, in layer order. The first also holds the text
+    /// encoder, the embeddings and heads, and the audio codec.
+    pub devices: Vec<usize>,
+    /// Decoder layers on each device. Defaults to an even split.
+    #[serde(default)]
+    pub layers: Vec<usize>,
+}
+
+impl DeviceMap {
+    /// `(ordinal, layer count)` for each device, covering `num_layers`.
+    pub fn split(&self, num_layers: usize) -> anyhow::Result<Vec<(usize, usize)>> {
+        let n = self.devices.len();
+        if n == 0 {
+            anyhow::bail!("device_map.devices is empty");
+        }
+        let layers = if self.layers.is_empty() {
+            (0..n).map(|i| num_layers / n + usize::from(i < num_layers % n)).collect()
+        } else if self.layers.len() != n {
+            anyhow::bail!("device_map has {} layer counts for {n} devices", self.layers.len());
+        } else {
+            self.layers.clone()
+        };
+        let total: usize = layers.iter().sum();
+        if total != num_layers {
+            anyhow::bail!("device_map places {total} decoder layers, the model has {num_layers}");
+        }
+        Ok(self.devices.iter().copied().zip(layers).collect())
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:8039".to_string(),
+            listeners: Vec::new(),
+            admin_bind: None,
+            cpu: false,
+            device: DeviceChoice::Auto,
+            cache_dir: None,
+            dtype: "f32".to_string(),
+            warm_cache_dir: None,
+            quantized: None,
+            log_prompts: PromptLogging::Full,
+            audio_dir: PathBuf::from("./audio"),
+            encryption_key: None,
+            retention: None,
+            default_description: DEFAULT_DESCRIPTION.to_string(),
+            voices: BTreeMap::new(),
+            locale_voices: BTreeMap::new(),
+            quick_phrases: Vec::new(),
+            rerender_quick_phrases: true,
+            namespaces: BTreeMap::new(),
+            allow_anonymous: false,
+            wav_format: WavFormat::default(),
+            retry_degenerate: true,
+            coalesce_requests: true,
+            workers: 1,
+            tokenizer_cache: 1024,
+            chunk_chars: 300,
+            device_map: None,
+            chaos: None,
+            canary: Canary::default(),
+            autoscale: Autoscale::default(),
+            slo: Slo::default(),
+            openai: OpenAi::default(),
+            self_check: SelfCheck::default(),
+            queue: Queue::default(),
+            models: Models::default(),
+            max_steps: StepLimit::default(),
+            bleep: Bleep::default(),
+            spelling: Spelling::default(),
+            budgets: Budgets::default(),
+            verify: Verify::default(),
+            api: ApiVersioning::default(),
+            access_log: AccessLog::default(),
+            compression: Compression::default(),
+            error_reporting: ErrorReporting::default(),
+            experiments: Experiments::default(),
+            features: Features::default(),
+            webrtc: None,
+            telegram: None,
+            podcast: None,
+            schedules: Vec::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Loads the config file named by `args` (if any) and applies CLI overrides.
+    pub fn load(args: &Args) -> anyhow::Result<Self> {
+        let path = match &args.config {
+            Some(path) => Some(path.clone()),
+            None => Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|p| p.exists()),
+        };
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        if let Some(bind) = &args.bind {
+            config.bind = bind.clone();
+            config.listeners.clear();
+        }
+        if let Some(admin_bind) = &args.admin_bind {
+            config.admin_bind = Some(admin_bind.clone());
+            config.listeners.clear();
+        }
+        config.cpu |= args.cpu;
+        if let Some(device) = args.device {
+            config.device = device;
+        }
+        if config.cpu {
+            if !matches!(config.device, DeviceChoice::Auto | DeviceChoice::Cpu) {
+                anyhow::bail!("cpu is set, but device is {}", config.device);
+            }
+            config.device = DeviceChoice::Cpu;
+        }
+        if config.device_map.is_some() && config.device != DeviceChoice::Auto {
+            anyhow::bail!("device_map places the model itself, leave device unset");
+        }
+        if let Some(model) = &args.model {
+            config.models.repo = model.clone();
+        }
+        if let Some(quantized) = &args.quantized {
+            config.quantized = Some(quantized.clone());
+        }
+        if let Ok(key) = std::env::var(ENCRYPTION_KEY_ENV) {
+            config.encryption_key = Some(key);
+        }
+        if let Some(chaos) = &config.chaos {
+            chaos.validate()?;
+        }
+        config.models.validate()?;
+        config.canary.validate()?;
+        config.autoscale.validate()?;
+        config.slo.validate(&config.models)?;
+        config.openai.validate()?;
+        config.self_check.validate()?;
+        config.queue.validate()?;
+        config.max_steps.validate()?;
+        config.bleep.validate()?;
+        config.spelling.validate()?;
+        locale_voices::validate(&config.locale_voices)?;
+        config.api.validate()?;
+        config.budgets.validate()?;
+        config.verify.validate()?;
+        config.experiments.validate()?;
+        config.access_log.validate()?;
+        if let Some(webrtc) = &config.webrtc {
+            webrtc.validate()?;
+        }
+        if let Some(bot) = &mut config.telegram {
+            if let Ok(token) = std::env::var(telegram::TOKEN_ENV) {
+                bot.token = token;
+            }
+            bot.validate()?;
+        }
+        if let Some(podcast) = &config.podcast {
+            podcast.validate()?;
+        }
+        schedule::validate(&config.schedules)?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading config file {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("parsing config file {}", path.display()))
+    }
+
+    /// The configured listeners, or `bind` (and `admin_bind`).
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+        match &self.admin_bind {
+            None => vec![ListenerConfig {
+                bind: self.bind.clone(),
+                routes: RouteSet::all(),
+            }],
+            Some(admin_bind) => vec![
+                ListenerConfig {
+                    bind: self.bind.clone(),
+                    routes: vec![RouteSet::Public],
+                },
+                ListenerConfig {
+                    bind: admin_bind.clone(),
+                    routes: vec![RouteSet::Admin],
+                },
+            ],
+        }
+    }
+
+    pub fn hf_cache(&self) -> hf_hub::Cache {
+        match &self.cache_dir {
+            Some(dir) => hf_hub::Cache::new(dir.clone()),
+            None => hf_hub::Cache::from_env(),
+        }
+    }
+}
+
+/// Parses durations such as `90s`, `15m`, `1h`, `30d` or `2w`.
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = text.split_at(split);
+    let amount: u64 = amount.parse().ok()?;
+    let unit_secs = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    amount.checked_mul(unit_secs).map(Duration::from_secs)
+}
+
+fn parse_duration_arg(text: &str) -> Result<Duration, String> {
+    parse_duration(text).ok_or_else(|| format!("invalid duration {text:?}, expected e.g. 90s or 5m"))
+}
+
+/// In seconds, the way `de_retention` reads it back.
+fn ser_retention<S: Serializer>(retention: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    match retention {
+        Some(ttl) => serializer.serialize_str(&format!("{}s", ttl.as_secs())),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn de_retention<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    parse_duration(&text)
+        .map(Some)
+        .ok_or_else(|| D::Error::custom(format!("invalid duration {text:?}, expected e.g. 15m, 1h or 30d")))
+}
@@ -0,0 +1,58 @@
+//! At-rest encryption for files the server writes (generated audio and
+//! history records), using XChaCha20-Poly1305 with a random nonce per file.
+//!
+//! Sealed layout: `MAGIC || nonce (24 bytes) || ciphertext+tag`.
+
+use anyhow::{bail, Context};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+const MAGIC: &[u8; 6] = b"TTSE1\0";
+const NONCE_LEN: usize = 24;
+
+/// Suffix added to the name of every sealed file.
+pub const SEALED_EXTENSION: &str = "enc";
+
+#[derive(Clone)]
+pub struct Cipher(XChaCha20Poly1305);
+
+impl Cipher {
+    /// Builds a cipher from a 32-byte key written as 64 hex digits.
+    pub fn from_hex_key(key: &str) -> anyhow::Result<Self> {
+        let key = key.trim();
+        if key.len() != 64 || !key.is_ascii() {
+            bail!("encryption key must be 64 hex digits");
+        }
+        let bytes = (0..32)
+            .map(|i| u8::from_str_radix(&key[2 * i..2 * i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .context("encryption key must be 64 hex digits")?;
+        Ok(Self(XChaCha20Poly1305::new_from_slice(&bytes)?))
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| anyhow::anyhow!("encryption failed"))?;
+        let mut out = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len());
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let Some(body) = sealed.strip_prefix(MAGIC.as_slice()) else {
+            bail!("not an encrypted ttser file");
+        };
+        if body.len() < NONCE_LEN {
+            bail!("truncated encrypted file");
+        }
+        let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+        self.0
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("decryption failed (wrong key or corrupted file)"))
+    }
+}
@@ -0,0 +1,478 @@
+//! The resident Parler-TTS model, loaded once and shared by every request.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Error as E};
+use candle::quantized::{gguf_file, GgmlDType, QTensor};
+use candle::{DType, Device, DeviceLocation, IndexOp, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::parler_tts::Config;
+use candle_transformers::quantized_var_builder;
+use tokenizers::Tokenizer;
+
+use crate::budget::{Stage, StageTimings};
+use crate::config::{DeviceChoice, ServerConfig};
+use crate::error::SynthesisError;
+use crate::generation;
+use crate::hub::ModelFiles;
+use crate::model::Model;
+use crate::token_cache::{CacheStats, TokenCache};
+use crate::tts_model::{Capabilities, Phase, Sampling, Synthesis, TtsModel, Unsupported, UnsupportedChar};
+
+/// Frames a streamed generation decodes at a time: about 0.4 s of audio
+/// at the DAC's 86 frames a second.
+const STREAM_FRAMES: usize = 32;
+
+/// Frames decoded again on either side of a streamed window and dropped,
+/// so the window's edges come out as they would in one pass.
+const STREAM_CONTEXT: usize = 8;
+
+/// Encoded descriptions kept, so successive prompts in a voice (a chunked
+/// prompt, a `/api/tts/ws` session) skip the text encoder.
+const ENCODED_DESCRIPTIONS: usize = 16;
+
+pub struct ParlerEngine {
+    /// Cloned per request: the weights are shared, the KV caches are not.
+    model: Model,
+    tokenizer: Tokenizer,
+    /// Ids of recent prompts and descriptions.
+    tokens: TokenCache,
+    /// Recent descriptions and their encoder output, most recent last.
+    encoded: Mutex<VecDeque<(String, Tensor)>>,
+    config: Config,
+    device: Device,
+    /// CUDA ordinals the model occupies; empty on other devices.
+    gpus: Vec<usize>,
+    /// `cpu`, `cuda:1`, `metal:0`, or each card of a device map.
+    device_name: String,
+    /// Hub revision and dtype of the weights, and their quantization.
+    version: String,
+}
+
+impl TtsModel for ParlerEngine {
+    fn load(server_config: &ServerConfig, files: &ModelFiles) -> anyhow::Result<Self> {
+        let start = std::time::Instant::now();
+        let tokenizer = Tokenizer::from_file(&files.tokenizer).map_err(E::msg)?;
+        let config: Config = serde_json::from_reader(std::fs::File::open(&files.config)?)?;
+        let dtype: DType = server_config
+            .dtype
+            .parse()
+            .with_context(|| format!("unsupported dtype {:?}", server_config.dtype))?;
+        let quantized = server_config.quantized.as_deref().map(quantization).transpose()?;
+
+        let weights = match &server_config.warm_cache_dir {
+            Some(dir) => warm_weights(dir, files, dtype)?,
+            None => files.weights.clone(),
+        };
+        let load = |device: &Device| unsafe { VarBuilder::from_mmaped_safetensors(&weights, dtype, device) };
+        let (model, device, gpus) = match &server_config.device_map {
+            None => {
+                let device = pick_device(server_config.device)?;
+                let gpus = match device.location() {
+                    DeviceLocation::Cuda { gpu_id } => vec![gpu_id],
+                    _ => Vec::new(),
+                };
+                let model = match quantized {
+                    None => Model::new(&config, load(&device)?)?,
+                    Some((name, kind)) => {
+                        let dir = server_config.warm_cache_dir.as_deref();
+                        let decoder = quantize_decoder(dir, files, kind, name, &device)?;
+                        Model::quantized(&config, load(&device)?, decoder)?
+                    }
+                };
+                (model, device, gpus)
+            }
+            Some(_) if quantized.is_some() => anyhow::bail!("device_map can't be combined with quantized"),
+            Some(map) => {
+                let mut shards = Vec::new();
+                let mut gpus = Vec::new();
+                for (ordinal, layers) in map.split(config.decoder.num_hidden_layers)? {
+                    gpus.push(ordinal);
+                    let device = Device::new_cuda(ordinal).with_context(|| format!("opening cuda:{ordinal}"))?;
+                    println!("placing {layers} decoder layers on cuda:{ordinal}");
+                    shards.push((load(&device)?, layers));
+                }
+                let device = shards[0].0.device().clone();
+                (Model::sharded(&config, &shards)?, device, gpus)
+            }
+        };
+        let precision = match quantized {
+            Some((name, _)) => format!("{}-{name}", dtype.as_str()),
+            None => dtype.as_str().to_string(),
+        };
+        let device_name = match device.location() {
+            _ if gpus.len() > 1 => gpus.iter().map(|g| format!("cuda:{g}")).collect::<Vec<_>>().join(","),
+            DeviceLocation::Cpu => "cpu".to_string(),
+            DeviceLocation::Cuda { gpu_id } => format!("cuda:{gpu_id}"),
+            DeviceLocation::Metal { gpu_id } => format!("metal:{gpu_id}"),
+        };
+        println!("loaded the model ({precision}) on {device_name} in {:?}", start.elapsed());
+
+        Ok(Self {
+            model,
+            tokenizer,
+            tokens: TokenCache::new(server_config.tokenizer_cache),
+            encoded: Mutex::default(),
+            config,
+            device,
+            gpus,
+            device_name,
+            version: format!("{}-{precision}", files.revision()),
+        })
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            name: "parler-tts",
+            sample_rate: self.config.audio_encoder.sampling_rate,
+            audio_vocab_size: Some(self.config.decoder.vocab_size),
+            voice_descriptions: true,
+            incremental_stream: true,
+        }
+    }
+
+    fn gpus(&self) -> &[usize] {
+        &self.gpus
+    }
+
+    fn device(&self) -> &str {
+        &self.device_name
+    }
+
+    fn version(&self) -> &str {
+        &self.version
+    }
+
+    fn synthesize(&self, prompt: &str, description: &str, sampling: &Sampling) -> Result<Synthesis, SynthesisError> {
+        self.run(prompt, description, sampling, None)
+    }
+
+    /// Decodes every [`STREAM_FRAMES`] frames as they complete, in
+    /// overlapping windows; the clip is the pieces joined.
+    fn stream(
+        &self,
+        prompt: &str,
+        description: &str,
+        sampling: &Sampling,
+        on_pcm: &mut dyn FnMut(&Tensor),
+    ) -> Result<Synthesis, SynthesisError> {
+        self.run(prompt, description, sampling, Some(on_pcm))
+    }
+
+    /// Characters that come out as the unknown token or that the
+    /// normalizer drops.
+    fn unsupported(&self, text: &str) -> anyhow::Result<Vec<UnsupportedChar>> {
+        let encoding = self.tokenizer.encode_char_offsets(text, false).map_err(E::msg)?;
+        let unknown = self.tokenizer.token_to_id("<unk>");
+        let chars: Vec<char> = text.chars().collect();
+        let mut reasons = vec![Some(Unsupported::Dropped); chars.len()];
+        for (id, &(start, end)) in encoding.get_ids().iter().zip(encoding.get_offsets()) {
+            let reason = (Some(*id) == unknown).then_some(Unsupported::Unknown);
+            for slot in &mut reasons[start.min(chars.len())..end.min(chars.len())] {
+                *slot = reason;
+            }
+        }
+        Ok(chars
+            .into_iter()
+            .zip(reasons)
+            .enumerate()
+            .filter(|(_, (character, _))| !character.is_whitespace())
+            .filter_map(|(position, (character, reason))| Some(UnsupportedChar { position, character, reason: reason? }))
+            .collect())
+    }
+
+    fn token_cache_stats(&self) -> Option<CacheStats> {
+        Some(self.tokens.stats())
+    }
+}
+
+impl ParlerEngine {
+    /// Generates `prompt`, decoding it in one pass at the end, or window by
+    /// window into `on_pcm` while generating when given.
+    fn run(
+        &self,
+        prompt: &str,
+        description: &str,
+        sampling: &Sampling,
+        mut on_pcm: Option<&mut dyn FnMut(&Tensor)>,
+    ) -> Result<Synthesis, SynthesisError> {
+        let mut timings = StageTimings::default();
+        let progress = sampling.progress.as_deref();
+        let phase = |phase| {
+            if let Some(hook) = progress {
+                hook.phase(phase);
+            }
+        };
+        phase(Phase::Tokenizing);
+        let start = Instant::now();
+        let description_tokens = self.tokenize(description).map_err(SynthesisError::Tokenize)?;
+        let prompt_tokens = self.tokenize(prompt).map_err(SynthesisError::Tokenize)?;
+        timings.record(Stage::Tokenize, start.elapsed());
+        let prompt_len = prompt_tokens.dim(1).map_err(|e| SynthesisError::Tokenize(e.into()))?;
+        let stopping = sampling.stopping.for_prompt(prompt_len);
+        let mut sampler = sampling.sampler.build(
+            sampling.seed,
+            sampling.temperature,
+            sampling.top_p,
+            stopping.max_steps,
+        );
+
+        // Frames whose audio has gone out, the pieces, and the time spent
+        // decoding them.
+        let streaming = on_pcm.is_some();
+        let mut streamed = 0;
+        let mut pieces = Vec::new();
+        let mut decode_time = Duration::ZERO;
+        let (started, mut calls) = (Instant::now(), 0u32);
+        let mut stream_frames = |codes: &[Vec<u32>], last: bool| -> candle::Result<()> {
+            let Some(on_pcm) = on_pcm.as_mut() else {
+                return Ok(());
+            };
+            calls += 1;
+            let complete = codes.iter().map(Vec::len).min().unwrap_or(0);
+            // Short of the first full window, what there is goes out once
+            // waiting for the rest would miss the first audio deadline.
+            let due = streamed == 0
+                && sampling.first_audio_by.is_some_and(|by| {
+                    let steps_left = (STREAM_FRAMES + STREAM_CONTEXT).saturating_sub(complete) as u32;
+                    Instant::now() + started.elapsed() / calls * (steps_left + 1) >= by
+                });
+            // The newest frames wait for their right-hand context.
+            let until = if last || due { complete } else { complete.saturating_sub(STREAM_CONTEXT) };
+            if until <= streamed || (!last && !due && until - streamed < STREAM_FRAMES) {
+                return Ok(());
+            }
+            let start = Instant::now();
+            let from = streamed.saturating_sub(STREAM_CONTEXT);
+            let window: Vec<Vec<u32>> = codes.iter().map(|c| c[from..complete].to_vec()).collect();
+            let pcm = self.decode(&Tensor::new(window, &Device::Cpu)?)?;
+            let hop = pcm.dim(0)? / (complete - from);
+            let piece = pcm.narrow(0, (streamed - from) * hop, (until - streamed) * hop)?;
+            decode_time += start.elapsed();
+            on_pcm(&piece);
+            pieces.push(piece);
+            streamed = until;
+            Ok(())
+        };
+
+        phase(Phase::Generating);
+        let mut steps = 0;
+        let start = Instant::now();
+        let mut model = self.model.clone();
+        let encoded = self
+            .encode(&mut model, description, &description_tokens)
+            .map_err(|e| SynthesisError::Generate(e.into()))?;
+        let generated = generation::generate(
+            &mut model,
+            self.config.decoder.num_codebooks,
+            &prompt_tokens,
+            &encoded,
+            sampler.as_mut(),
+            &sampling.tokens,
+            &stopping,
+            &mut |codes| {
+                steps += 1;
+                if let Some(hook) = progress {
+                    hook.step(steps, stopping.max_steps);
+                }
+                stream_frames(codes, false)
+            },
+        )
+        .map_err(|e| SynthesisError::Generate(e.into()))?;
+        let generate_time = start.elapsed();
+        phase(Phase::Decoding);
+        let start = Instant::now();
+        let pcm = if streaming {
+            let rest = generated.codes.to_vec2::<u32>().and_then(|codes| stream_frames(&codes, true));
+            rest.and_then(|()| match pieces.is_empty() {
+                true => Tensor::zeros(0, DType::F32, &Device::Cpu),
+                false => Tensor::cat(&pieces, 0),
+            })
+        } else {
+            self.decode(&generated.codes)
+        };
+        let pcm = pcm.map_err(|e| SynthesisError::Decode(e.into()))?;
+        let tail = start.elapsed();
+        // Streamed windows were decoded between generation steps.
+        if streaming {
+            timings.record(Stage::Generate, (generate_time + tail).saturating_sub(decode_time));
+            timings.record(Stage::Decode, decode_time);
+        } else {
+            timings.record(Stage::Generate, generate_time);
+            timings.record(Stage::Decode, tail);
+        }
+        Ok(Synthesis {
+            pcm,
+            codes: generated.codes,
+            steps: generated.steps,
+            max_steps: stopping.max_steps,
+            finish: generated.finish,
+            timings,
+        })
+    }
+
+    /// The encoder output for `description`, from the recent ones when it
+    /// is among them.
+    fn encode(&self, model: &mut Model, description: &str, tokens: &Tensor) -> candle::Result<Tensor> {
+        let cached = {
+            let mut encoded = self.encoded.lock().unwrap();
+            let hit = encoded.iter().position(|(d, _)| d == description);
+            hit.and_then(|n| encoded.remove(n))
+        };
+        let tensor = match cached {
+            Some((_, tensor)) => tensor,
+            None => generation::encode_description(model, tokens)?,
+        };
+        let mut encoded = self.encoded.lock().unwrap();
+        // Another request may have encoded it meanwhile.
+        encoded.retain(|(d, _)| d != description);
+        if encoded.len() >= ENCODED_DESCRIPTIONS {
+            encoded.pop_front();
+        }
+        encoded.push_back((description.to_string(), tensor.clone()));
+        Ok(tensor)
+    }
+
+    /// Mono F32 PCM of `codes`, `(num_codebooks, frames)`.
+    fn decode(&self, codes: &Tensor) -> candle::Result<Tensor> {
+        let codes = codes.to_dtype(DType::I64)?.unsqueeze(0)?;
+        let pcm = self.model.audio_encoder.decode_codes(&codes.to_device(&self.device)?)?;
+        pcm.i((0, 0))?.to_dtype(DType::F32)
+    }
+
+    fn tokenize(&self, text: &str) -> anyhow::Result<Tensor> {
+        let ids = self.tokens.get_or_insert(text, || {
+            anyhow::Ok(self.tokenizer.encode(text, true).map_err(E::msg)?.get_ids().to_vec())
+        })?;
+        Ok(Tensor::new(&*ids, &self.device)?.unsqueeze(0)?)
+    }
+}
+
+/// The device `choice` names. `auto` is the first CUDA or Metal device
+/// when the build supports one, otherwise the CPU.
+fn pick_device(choice: DeviceChoice) -> anyhow::Result<Device> {
+    match choice {
+        DeviceChoice::Cpu => Ok(Device::Cpu),
+        DeviceChoice::Cuda(ordinal) if !candle::utils::cuda_is_available() => {
+            anyhow::bail!("device cuda:{ordinal} needs a build with the cuda feature")
+        }
+        DeviceChoice::Cuda(ordinal) => Device::new_cuda(ordinal).with_context(|| format!("opening cuda:{ordinal}")),
+        DeviceChoice::Metal(ordinal) if !candle::utils::metal_is_available() => {
+            anyhow::bail!("device metal:{ordinal} needs a build with the metal feature")
+        }
+        DeviceChoice::Metal(ordinal) => Device::new_metal(ordinal).with_context(|| format!("opening metal:{ordinal}")),
+        DeviceChoice::Auto if candle::utils::cuda_is_available() => Ok(Device::new_cuda(0)?),
+        DeviceChoice::Auto if candle::utils::metal_is_available() => Ok(Device::new_metal(0)?),
+        DeviceChoice::Auto => {
+            println!("no CUDA or Metal support in this build, running on the CPU");
+            Ok(Device::Cpu)
+        }
+    }
+}
+
+/// Weights to load for `dtype`, converting and persisting them under `dir`
+/// the first time so later starts can mmap them directly.
+fn warm_weights(dir: &Path, files: &ModelFiles, dtype: DType) -> anyhow::Result<Vec<PathBuf>> {
+    let weights = &files.weights;
+    let source = unsafe { candle::safetensors::MmapedSafetensors::multi(weights)? };
+    let tensors = source.tensors();
+    if tensors
+        .iter()
+        .all(|(_, view)| DType::try_from(view.dtype()).is_ok_and(|d| d == dtype))
+    {
+        return Ok(weights.to_vec());
+    }
+
+    let path = dir.join(format!("{}-{}.safetensors", files.revision(), dtype.as_str()));
+    if path.is_file() {
+        println!("using warm weights from {}", path.display());
+        return Ok(vec![path]);
+    }
+
+    let start = std::time::Instant::now();
+    let mut converted = HashMap::with_capacity(tensors.len());
+    for (name, _) in &tensors {
+        let tensor = source.load(name, &Device::Cpu)?.to_dtype(dtype)?;
+        converted.insert(name.clone(), tensor);
+    }
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("creating warm cache {}", dir.display()))?;
+    let partial = path.with_extension("partial");
+    candle::safetensors::save(&converted, &partial)?;
+    std::fs::rename(&partial, &path)?;
+    println!("wrote warm weights to {} in {:?}", path.display(), start.elapsed());
+    Ok(vec![path])
+}
+
+/// The `quantized` setting as a GGUF type: `q8_0` (int8, the closest to
+/// full precision), `q6k`, `q5k`, `q4k` or `q4_0`.
+fn quantization(name: &str) -> anyhow::Result<(&str, GgmlDType)> {
+    let kind = match name {
+        "q8_0" => GgmlDType::Q8_0,
+        "q6k" => GgmlDType::Q6K,
+        "q5k" => GgmlDType::Q5K,
+        "q4k" => GgmlDType::Q4K,
+        "q4_0" => GgmlDType::Q4_0,
+        _ => anyhow::bail!("unsupported quantization {name:?} (q8_0, q6k, q5k, q4k or q4_0)"),
+    };
+    Ok((name, kind))
+}
+
+/// Whether the weight `name` is one of the decoder's attention or
+/// feed-forward projections, or a head: the matrices generation spends
+/// its time multiplying by.
+fn is_projection(name: &str) -> bool {
+    name.starts_with("decoder.")
+        && (name.ends_with("_proj.weight")
+            || name.ends_with(".fc1.weight")
+            || name.ends_with(".fc2.weight")
+            || name.starts_with("decoder.lm_heads."))
+}
+
+/// The decoder projections and heads quantized to `kind`: from the GGUF
+/// file under `dir` when an earlier start wrote one (or one was put there),
+/// otherwise quantized from the weights now and, with a `dir`, kept there.
+fn quantize_decoder(
+    dir: Option<&Path>,
+    files: &ModelFiles,
+    kind: GgmlDType,
+    name: &str,
+    device: &Device,
+) -> anyhow::Result<quantized_var_builder::VarBuilder> {
+    let path = dir.map(|dir| dir.join(format!("{}-{name}.gguf", files.revision())));
+    if let Some(path) = path.as_ref().filter(|path| path.is_file()) {
+        println!("using quantized weights from {}", path.display());
+        return Ok(quantized_var_builder::VarBuilder::from_gguf(path, device)?);
+    }
+
+    let start = std::time::Instant::now();
+    let source = unsafe { candle::safetensors::MmapedSafetensors::multi(&files.weights)? };
+    let mut quantized = Vec::new();
+    for (tensor_name, view) in source.tensors() {
+        // Rows must divide into whole blocks; the rest load in full.
+        let shape = view.shape();
+        if !is_projection(&tensor_name) || shape.len() != 2 || shape[1] % kind.block_size() != 0 {
+            continue;
+        }
+        let tensor = source.load(&tensor_name, &Device::Cpu)?.to_dtype(DType::F32)?;
+        quantized.push((tensor_name, QTensor::quantize(&tensor, kind)?));
+    }
+    let tensors: Vec<_> = quantized.iter().map(|(n, t)| (n.as_str(), t)).collect();
+    let mut gguf = std::io::Cursor::new(Vec::new());
+    gguf_file::write(&mut gguf, &[], &tensors)?;
+    println!("quantized {} decoder tensors to {name} in {:?}", tensors.len(), start.elapsed());
+
+    let Some(path) = path else {
+        return Ok(quantized_var_builder::VarBuilder::from_gguf_buffer(gguf.get_ref(), device)?);
+    };
+    std::fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))
+        .with_context(|| format!("creating warm cache {}", path.display()))?;
+    let partial = path.with_extension("partial");
+    std::fs::write(&partial, gguf.get_ref())?;
+    std::fs::rename(&partial, &path)?;
+    println!("wrote quantized weights to {}", path.display());
+    Ok(quantized_var_builder::VarBuilder::from_gguf(&path, device)?)
+}
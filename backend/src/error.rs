@@ -0,0 +1,130 @@
+//! What can go wrong while serving a TTS request, by stage, and how each
+//! failure is reported to the client.
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+
+use crate::json_body::FieldError;
+use crate::pool::InferencePanic;
+use crate::tts_model::UnsupportedChar;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SynthesisError {
+    /// The request itself was refused (bad form fields, unknown voice).
+    #[error("request rejected")]
+    Request(StatusCode),
+    /// A JSON body with missing or invalid fields.
+    #[error("{} fields are missing or invalid", .0.len())]
+    InvalidFields(Vec<FieldError>),
+    #[error("model unavailable: {0:#}")]
+    ModelLoad(anyhow::Error),
+    #[error("tokenizing failed: {0:#}")]
+    Tokenize(anyhow::Error),
+    /// The prompt has characters the model can't read, and the request
+    /// asked for it to be refused then.
+    #[error("the text has {} characters the model can't read", .0.len())]
+    UnsupportedCharacters(Vec<UnsupportedChar>),
+    #[error("generation failed: {0:#}")]
+    Generate(anyhow::Error),
+    #[error("decoding audio failed: {0:#}")]
+    Decode(anyhow::Error),
+    #[error("encoding audio failed: {0:#}")]
+    Encode(anyhow::Error),
+    #[error("storing the clip failed: {0:#}")]
+    Io(anyhow::Error),
+}
+
+impl SynthesisError {
+    /// Machine-readable name, sent to clients as `error.code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Request(_) => "invalid_request",
+            Self::InvalidFields(_) => "invalid_fields",
+            Self::ModelLoad(_) => "model_unavailable",
+            Self::Tokenize(_) => "tokenize_failed",
+            Self::UnsupportedCharacters(_) => "unsupported_characters",
+            Self::Generate(_) => "generate_failed",
+            Self::Decode(_) => "decode_failed",
+            Self::Encode(_) => "encode_failed",
+            Self::Io(_) => "storage_failed",
+        }
+    }
+
+    /// What clients are told, as `error.message`. Failures inside the
+    /// server get a fixed sentence per code; their error chain, which can
+    /// name paths and hub details, only goes to the server log.
+    pub fn message(&self) -> String {
+        match self {
+            Self::Request(status) => status.canonical_reason().unwrap_or("rejected").to_string(),
+            Self::InvalidFields(_) | Self::UnsupportedCharacters(_) => self.to_string(),
+            Self::ModelLoad(_) => "the model is unavailable".to_string(),
+            Self::Tokenize(_) => "the text could not be tokenized".to_string(),
+            Self::Generate(_) => "generation failed".to_string(),
+            Self::Decode(_) => "decoding audio failed".to_string(),
+            Self::Encode(_) => "encoding audio failed".to_string(),
+            Self::Io(_) => "storing the clip failed".to_string(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Request(status) => *status,
+            Self::ModelLoad(_) => StatusCode::SERVICE_UNAVAILABLE,
+            // The tokenizer only trips over the text it was given.
+            Self::InvalidFields(_) | Self::Tokenize(_) | Self::UnsupportedCharacters(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Generate(_) | Self::Decode(_) | Self::Encode(_) | Self::Io(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// The same failure again, for a request that shared the generation.
+    pub fn duplicate(&self) -> Self {
+        let again = |e: &anyhow::Error| anyhow::anyhow!("{e:#}");
+        match self {
+            Self::Request(status) => Self::Request(*status),
+            Self::InvalidFields(errors) => Self::InvalidFields(errors.clone()),
+            Self::ModelLoad(e) => Self::ModelLoad(again(e)),
+            Self::Tokenize(e) => Self::Tokenize(again(e)),
+            Self::UnsupportedCharacters(chars) => Self::UnsupportedCharacters(chars.clone()),
+            Self::Generate(e) => Self::Generate(again(e)),
+            Self::Decode(e) => Self::Decode(again(e)),
+            Self::Encode(e) => Self::Encode(again(e)),
+            Self::Io(e) => Self::Io(again(e)),
+        }
+    }
+
+    /// Whether the model panicked rather than returning an error.
+    pub fn is_panic(&self) -> bool {
+        matches!(self, Self::Generate(e) if e.is::<InferencePanic>())
+    }
+}
+
+impl From<StatusCode> for SynthesisError {
+    fn from(status: StatusCode) -> Self {
+        Self::Request(status)
+    }
+}
+
+/// `{"error": {"code": ..., "message": ...}}` with the stage's status, and
+/// the offending `characters` for `unsupported_characters` and `fields`
+/// for `invalid_fields`. The full error is logged for failures the client
+/// only sees the `message` of.
+impl IntoResponse for SynthesisError {
+    fn into_response(self) -> Response {
+        let message = self.message();
+        if !matches!(self, Self::Request(_) | Self::InvalidFields(_) | Self::UnsupportedCharacters(_)) {
+            println!("tts: {self} ({})", self.code());
+        }
+        let mut body = json!({ "error": { "code": self.code(), "message": message } });
+        if let Self::UnsupportedCharacters(chars) = &self {
+            body["error"]["characters"] = json!(chars);
+        }
+        if let Self::InvalidFields(errors) = &self {
+            body["error"]["fields"] = json!(errors);
+        }
+        (self.status(), Json(body)).into_response()
+    }
+}
@@ -0,0 +1,257 @@
+//! Optional experiment tracking: every generation's parameters, timings and
+//! quality-gate results sent to an MLflow tracking server (or anything that
+//! speaks its REST API, e.g. W&B's MLflow import) as one run each, or posted
+//! to a webhook as JSON. Prompts and descriptions are sent as the
+//! `log_prompts` setting records them.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::history::ClipRecord;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// MLflow caps param values at this many characters.
+const MAX_PARAM_CHARS: usize = 500;
+
+/// How runs are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// `runs/create`, `runs/log-batch` and `runs/update` of the MLflow REST
+    /// API under `url`.
+    Mlflow,
+    /// One JSON object POSTed to `url` per generation.
+    Webhook,
+}
+
+/// The `[experiments]` config section. Tracking is off without a URL.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Experiments {
+    /// The tracking server, e.g. `http://mlflow:5000`, or the webhook.
+    pub url: Option<String>,
+    pub protocol: Protocol,
+    /// MLflow experiment the runs are created in.
+    pub experiment_id: String,
+    /// Sent as a bearer token.
+    pub token: Option<String>,
+    /// Fraction of generations logged.
+    pub sample_rate: f64,
+    /// Added to every run, e.g. `team = "voices"`.
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Default for Experiments {
+    fn default() -> Self {
+        Self {
+            url: None,
+            protocol: Protocol::Mlflow,
+            experiment_id: "0".to_string(),
+            token: None,
+            sample_rate: 1.0,
+            tags: BTreeMap::new(),
+        }
+    }
+}
+
+impl Experiments {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(url) = &self.url {
+            reqwest::Url::parse(url).map_err(|e| anyhow::anyhow!("invalid experiments.url: {e}"))?;
+        }
+        if !(0.0..=1.0).contains(&self.sample_rate) {
+            anyhow::bail!("experiments.sample_rate must be between 0 and 1");
+        }
+        Ok(())
+    }
+}
+
+/// One generation, as logged.
+#[derive(Debug, Clone, Serialize)]
+struct Run {
+    name: String,
+    /// Unix time, milliseconds.
+    created_at: u64,
+    params: BTreeMap<String, String>,
+    metrics: BTreeMap<String, f64>,
+    tags: BTreeMap<String, String>,
+}
+
+pub struct Tracker {
+    config: Experiments,
+    http: reqwest::Client,
+}
+
+impl Tracker {
+    /// `None` when tracking is off.
+    pub fn new(config: &Experiments) -> anyhow::Result<Option<Self>> {
+        let Some(url) = &config.url else {
+            return Ok(None);
+        };
+        println!("logging generations to {url} ({:?})", config.protocol);
+        Ok(Some(Self {
+            config: config.clone(),
+            http: reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?,
+        }))
+    }
+
+    /// Logs the generation that produced `record` in the background, with
+    /// `context` (e.g. namespace and sampler) added to its params. Failures
+    /// are only printed.
+    pub fn log(self: &std::sync::Arc<Self>, record: &ClipRecord, context: &[(&str, String)]) {
+        if rand::random::<f64>() >= self.config.sample_rate {
+            return;
+        }
+        let run = self.run(record, context);
+        let tracker = self.clone();
+        tokio::spawn(async move {
+            let id = run.name.clone();
+            if let Err(e) = tracker.send(run).await {
+                println!("experiments: logging {id} failed: {e:#}");
+            }
+        });
+    }
+
+    fn run(&self, record: &ClipRecord, context: &[(&str, String)]) -> Run {
+        let mut params = BTreeMap::new();
+        let mut param = |key: &str, value: String| {
+            params.insert(key.to_string(), value.chars().take(MAX_PARAM_CHARS).collect());
+        };
+        param("prompt", record.prompt.clone());
+        param("description", record.description.clone());
+        let optional = [
+            ("voice", record.voice.clone()),
+            ("voice_version", record.voice_version.map(|v| v.to_string())),
+            ("temperature", record.temperature.map(|t| t.to_string())),
+            ("top_p", record.top_p.map(|p| p.to_string())),
+            ("seed", record.seed.map(|s| s.to_string())),
+            ("downgraded_max_steps", record.downgraded_max_steps.map(|s| s.to_string())),
+        ];
+        for (key, value) in optional {
+            if let Some(value) = value {
+                param(key, value);
+            }
+        }
+        for (key, value) in context {
+            param(key, value.clone());
+        }
+
+        let mut metrics = BTreeMap::from([
+            ("duration_secs".to_string(), record.duration_secs),
+            ("quality_retry".to_string(), record.quality_retry.is_some() as u8 as f64),
+        ]);
+        if let Some(steps) = record.steps {
+            metrics.insert("steps".to_string(), steps as f64);
+        }
+        if let Some(finish) = record.finish_reason {
+            let max_steps = finish == crate::generation::FinishReason::MaxSteps;
+            metrics.insert("hit_max_steps".to_string(), max_steps as u8 as f64);
+        }
+        if let Some(rate) = &record.speech_rate {
+            metrics.insert("words_per_minute".to_string(), rate.words_per_minute);
+        }
+        if let Some(timings) = &record.timings {
+            for stage in crate::budget::Stage::ALL {
+                metrics.insert(format!("{}_secs", stage.as_str()), timings.get(stage));
+            }
+        }
+
+        let mut tags = self.config.tags.clone();
+        tags.insert("source".to_string(), "ttser".to_string());
+        if let Some(finish) = record.finish_reason {
+            tags.insert("finish_reason".to_string(), finish.as_str().to_string());
+        }
+        if let Some(retry) = &record.quality_retry {
+            tags.insert("quality_defect".to_string(), retry.defect.as_str().to_string());
+            if let Some(defect) = retry.still_defective {
+                tags.insert("still_defective".to_string(), defect.as_str().to_string());
+            }
+        }
+        if let Some(rate) = &record.speech_rate {
+            tags.insert("speech_rate_suspicious".to_string(), rate.suspicious.to_string());
+        }
+        if !record.features.is_empty() {
+            tags.insert("features".to_string(), crate::features::header_value(&record.features));
+        }
+        if !record.over_budget.is_empty() {
+            let stages: Vec<_> = record.over_budget.iter().map(|s| s.as_str()).collect();
+            tags.insert("over_budget".to_string(), stages.join(","));
+        }
+
+        Run {
+            name: record.id.clone(),
+            created_at: record.created_at * 1000,
+            params,
+            metrics,
+            tags,
+        }
+    }
+
+    async fn send(&self, run: Run) -> anyhow::Result<()> {
+        let url = self.config.url.as_deref().unwrap_or_default().trim_end_matches('/');
+        match self.config.protocol {
+            Protocol::Webhook => {
+                self.post(url, &run).await?;
+            }
+            Protocol::Mlflow => {
+                let api = format!("{url}/api/2.0/mlflow/runs");
+                let created = self
+                    .post(
+                        &format!("{api}/create"),
+                        &json!({
+                            "experiment_id": self.config.experiment_id,
+                            "run_name": run.name,
+                            "start_time": run.created_at,
+                        }),
+                    )
+                    .await?;
+                let run_id = created["run"]["info"]["run_id"]
+                    .as_str()
+                    .ok_or_else(|| anyhow::anyhow!("the tracking server returned no run id"))?;
+                let timestamp = crate::unix_now() * 1000;
+                let pairs = |map: &BTreeMap<String, String>| {
+                    map.iter().map(|(key, value)| json!({ "key": key, "value": value })).collect::<Vec<_>>()
+                };
+                let metrics: Vec<_> = run
+                    .metrics
+                    .iter()
+                    .map(|(key, value)| json!({ "key": key, "value": value, "timestamp": timestamp, "step": 0 }))
+                    .collect();
+                self.post(
+                    &format!("{api}/log-batch"),
+                    &json!({
+                        "run_id": run_id,
+                        "params": pairs(&run.params),
+                        "metrics": metrics,
+                        "tags": pairs(&run.tags),
+                    }),
+                )
+                .await?;
+                self.post(
+                    &format!("{api}/update"),
+                    &json!({ "run_id": run_id, "status": "FINISHED", "end_time": timestamp }),
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn post(&self, url: &str, body: &impl Serialize) -> anyhow::Result<serde_json::Value> {
+        let mut request = self
+            .http
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(body)?);
+        if let Some(token) = &self.config.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await?.error_for_status()?;
+        let text = response.text().await?;
+        Ok(serde_json::from_str(&text).unwrap_or(serde_json::Value::Null))
+    }
+}
@@ -0,0 +1,161 @@
+//! ZIP archives of history: `clips/<id>.wav` for each clip plus
+//! `manifest.json` and `manifest.csv` describing how they were made.
+
+use std::io::Write;
+
+use serde::{Deserialize, Serialize};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::history::{ClipRecord, History};
+
+pub const MANIFEST_JSON: &str = "manifest.json";
+const MANIFEST_CSV: &str = "manifest.csv";
+
+/// One manifest row: a record and the archive path of its audio.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub file: String,
+    #[serde(flatten)]
+    pub record: ClipRecord,
+}
+
+/// Which clips to export; every given criterion must match.
+#[derive(Debug, Default, Deserialize)]
+pub struct Selection {
+    /// Comma-separated clip ids.
+    pub ids: Option<String>,
+    pub voice: Option<String>,
+    /// Unix time bounds on `created_at`, inclusive.
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+}
+
+impl Selection {
+    pub fn matches(&self, record: &ClipRecord) -> bool {
+        self.ids
+            .as_deref()
+            .is_none_or(|ids| ids.split(',').any(|id| id.trim() == record.id))
+            && self
+                .voice
+                .as_deref()
+                .is_none_or(|voice| record.voice.as_deref() == Some(voice))
+            && self.since.is_none_or(|t| record.created_at >= t)
+            && self.until.is_none_or(|t| record.created_at <= t)
+    }
+}
+
+/// Writes `records` and their audio as a ZIP to `out`, which only needs to
+/// be `Write`, so the archive can be streamed as it is produced. Clips whose
+/// audio has gone missing are left out of the manifest.
+pub fn write_archive<W: Write>(history: &History, records: Vec<ClipRecord>, out: W) -> anyhow::Result<()> {
+    // WAV barely compresses; storing keeps the export cheap.
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(true);
+    let mut zip = ZipWriter::new_stream(out);
+
+    let mut manifest = Vec::with_capacity(records.len());
+    for record in records {
+        let Some(wav) = history.audio(&record.id)? else {
+            continue;
+        };
+        let file = format!("clips/{}.wav", record.id);
+        zip.start_file(file.as_str(), options)?;
+        zip.write_all(&wav)?;
+        manifest.push(ManifestEntry { file, record });
+    }
+
+    zip.start_file(MANIFEST_JSON, options)?;
+    zip.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+    zip.start_file(MANIFEST_CSV, options)?;
+    zip.write_all(manifest_csv(&manifest).as_bytes())?;
+    zip.finish()?;
+    Ok(())
+}
+
+fn manifest_csv(manifest: &[ManifestEntry]) -> String {
+    let mut csv = String::from(
+        "id,file,created_at,voice,prompt,description,temperature,seed,top_p,sample_rate,duration_secs,expires_at\n",
+    );
+    for ManifestEntry { file, record } in manifest {
+        let row = [
+            record.id.clone(),
+            file.clone(),
+            record.created_at.to_string(),
+            record.voice.clone().unwrap_or_default(),
+            record.prompt.clone(),
+            record.description.clone(),
+            optional(record.temperature),
+            optional(record.seed),
+            optional(record.top_p),
+            record.sample_rate.to_string(),
+            format!("{:.3}", record.duration_secs),
+            optional(record.expires_at),
+        ];
+        let row: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// Quotes a field when it contains a delimiter, quote or line break.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// `Write` adapter that hands the output to an async consumer in chunks;
+/// used from a blocking task to feed a streamed response body.
+pub struct ChunkSender {
+    tx: tokio::sync::mpsc::Sender<std::io::Result<Vec<u8>>>,
+    buffer: Vec<u8>,
+}
+
+const CHUNK_BYTES: usize = 256 * 1024;
+
+impl ChunkSender {
+    pub fn new(tx: tokio::sync::mpsc::Sender<std::io::Result<Vec<u8>>>) -> Self {
+        Self {
+            tx,
+            buffer: Vec::with_capacity(CHUNK_BYTES),
+        }
+    }
+
+    /// Passes an error to the consumer so it can abort the stream.
+    pub fn fail(self, error: std::io::Error) {
+        let _ = self.tx.blocking_send(Err(error));
+    }
+
+    fn send_buffer(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_BYTES));
+        self.tx
+            .blocking_send(Ok(chunk))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "client went away"))
+    }
+}
+
+impl Write for ChunkSender {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK_BYTES {
+            self.send_buffer()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send_buffer()
+    }
+}
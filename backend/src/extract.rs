@@ -0,0 +1,136 @@
+//! Readability-style extraction of an article's text from a web page: the
+//! element holding the most paragraph text is taken as the article, and
+//! navigation, sidebars, comments and the like are left out.
+
+use std::collections::HashMap;
+
+use scraper::{ElementRef, Html, Selector};
+
+/// Paragraphs shorter than this are bylines, captions or buttons.
+const MIN_PARAGRAPH_CHARS: usize = 25;
+
+/// Elements that never hold article text.
+const SKIPPED_TAGS: &[&str] = &[
+    "nav", "header", "footer", "aside", "form", "script", "style", "noscript", "figcaption", "button",
+];
+
+/// Class or id words (or their starts) marking page furniture.
+const SKIPPED_NAMES: &[&str] = &[
+    "comment", "sidebar", "footer", "nav", "menu", "share", "social", "related", "promo", "advert",
+    "cookie", "newsletter", "subscribe",
+];
+
+/// Elements whose text is read as one block.
+const BLOCK_TAGS: &[&str] = &["p", "h1", "h2", "h3", "h4", "h5", "h6", "li", "blockquote", "pre"];
+
+#[derive(Debug, Clone)]
+pub struct Article {
+    pub title: Option<String>,
+    /// Paragraphs separated by blank lines.
+    pub text: String,
+}
+
+/// Extracts the article from a whole page or an HTML fragment (such as an
+/// RSS item's description). Text without any markup comes back as it is.
+pub fn extract(html: &str) -> Article {
+    let document = Html::parse_document(html);
+    let title = meta(&document, "meta[property='og:title']")
+        .or_else(|| first_text(&document, "title"))
+        .or_else(|| first_text(&document, "h1"));
+
+    // Each paragraph votes for its parent, weighted by its length and
+    // commas, the way Readability scores candidates.
+    let mut scores: HashMap<_, f64> = HashMap::new();
+    for paragraph in document.select(&selector("p")) {
+        if skipped(&paragraph) {
+            continue;
+        }
+        let text = clean(paragraph.text());
+        if text.chars().count() < MIN_PARAGRAPH_CHARS {
+            continue;
+        }
+        let Some(parent) = paragraph.parent().and_then(ElementRef::wrap) else {
+            continue;
+        };
+        let score = 1.0 + text.matches(',').count() as f64 + (text.len() as f64 / 100.0).min(3.0);
+        *scores.entry(parent.id()).or_default() += score;
+    }
+    let best = scores
+        .into_iter()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .and_then(|(id, _)| document.tree.get(id))
+        .and_then(ElementRef::wrap);
+
+    let text = match best {
+        Some(root) => blocks(root),
+        None => visible_text(document.root_element()),
+    };
+    Article { title, text }
+}
+
+/// The readable blocks under `root`, in document order.
+fn blocks(root: ElementRef) -> String {
+    let mut paragraphs = Vec::new();
+    for block in root.select(&selector(&BLOCK_TAGS.join(", "))) {
+        // A list item's paragraphs are read with the item.
+        let nested = block
+            .ancestors()
+            .take_while(|node| node.id() != root.id())
+            .filter_map(ElementRef::wrap)
+            .any(|e| BLOCK_TAGS.contains(&e.value().name()));
+        if nested || skipped(&block) {
+            continue;
+        }
+        let text = clean(block.text());
+        if !text.is_empty() {
+            paragraphs.push(text);
+        }
+    }
+    paragraphs.join("\n\n")
+}
+
+/// All text under `root` but scripts and styles, for pages (or plain text)
+/// without paragraphs.
+fn visible_text(root: ElementRef) -> String {
+    let parts = root.descendants().filter_map(|node| {
+        let text = node.value().as_text()?;
+        let parent = node.parent().and_then(ElementRef::wrap)?;
+        (!matches!(parent.value().name(), "script" | "style" | "noscript")).then_some(&**text)
+    });
+    clean(parts)
+}
+
+fn skipped(element: &ElementRef) -> bool {
+    std::iter::once(*element)
+        .chain(element.ancestors().filter_map(ElementRef::wrap))
+        .any(|e| {
+            let value = e.value();
+            if SKIPPED_TAGS.contains(&value.name()) {
+                return true;
+            }
+            let names = format!("{} {}", value.attr("class").unwrap_or(""), value.id().unwrap_or(""))
+                .to_ascii_lowercase();
+            names
+                .split(|c: char| !c.is_ascii_alphanumeric())
+                .any(|word| SKIPPED_NAMES.iter().any(|n| word.starts_with(n)))
+        })
+}
+
+fn selector(css: &str) -> Selector {
+    Selector::parse(css).expect("selectors are constant")
+}
+
+fn meta(document: &Html, css: &str) -> Option<String> {
+    let element = document.select(&selector(css)).next()?;
+    Some(clean(std::iter::once(element.value().attr("content")?))).filter(|t| !t.is_empty())
+}
+
+fn first_text(document: &Html, css: &str) -> Option<String> {
+    let element = document.select(&selector(css)).next()?;
+    Some(clean(element.text())).filter(|t| !t.is_empty())
+}
+
+/// Joins text nodes with runs of whitespace collapsed.
+fn clean<'a>(parts: impl Iterator<Item = &'a str>) -> String {
+    parts.collect::<String>().split_whitespace().collect::<Vec<_>>().join(" ")
+}
@@ -0,0 +1,77 @@
+//! Experimental behaviors a request opts into with the `X-Parler-Features`
+//! header or a `features` form field, so they can be rolled out and compared
+//! per request instead of by editing the config. Both take a comma- or
+//! space-separated list of names; the clip's record keeps the ones in
+//! effect.
+
+use serde::{Deserialize, Serialize};
+
+pub const HEADER: &str = "x-parler-features";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// Mirostat sampling for requests that don't choose a `sampler`.
+    MirostatSampler,
+    /// Stream the audio as chunks are decoded, for requests that don't set
+    /// `stream`.
+    StreamingDecode,
+    /// Normalize to a fixed peak level instead of integrated loudness.
+    PeakNormalizer,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 3] = [Feature::MirostatSampler, Feature::StreamingDecode, Feature::PeakNormalizer];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::MirostatSampler => "mirostat_sampler",
+            Self::StreamingDecode => "streaming_decode",
+            Self::PeakNormalizer => "peak_normalizer",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|f| f.as_str() == name)
+    }
+
+    /// Whether the feature changes the audio, not just how it is delivered.
+    pub fn changes_output(self) -> bool {
+        !matches!(self, Self::StreamingDecode)
+    }
+}
+
+/// The `[features]` config section.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    /// Features requests may turn on; asking for any other is a 400.
+    pub allowed: Vec<Feature>,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            allowed: Feature::ALL.to_vec(),
+        }
+    }
+}
+
+impl Features {
+    /// Adds the features listed in `value` to `enabled`, keeping it sorted
+    /// and without repeats. `None` when a name is unknown or not allowed.
+    pub fn parse_into(&self, value: &str, enabled: &mut Vec<Feature>) -> Option<()> {
+        for name in value.split(|c: char| c == ',' || c.is_whitespace()).filter(|n| !n.is_empty()) {
+            let feature = Feature::parse(&name.to_ascii_lowercase()).filter(|f| self.allowed.contains(f))?;
+            if let Err(at) = enabled.binary_search(&feature) {
+                enabled.insert(at, feature);
+            }
+        }
+        Some(())
+    }
+}
+
+/// `features` as a header value.
+pub fn header_value(features: &[Feature]) -> String {
+    features.iter().map(|f| f.as_str()).collect::<Vec<_>>().join(",")
+}
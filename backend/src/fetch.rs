@@ -0,0 +1,143 @@
+//! Fetching pages over HTTP: bodies are read up to a byte cap as they
+//! arrive, whatever `Content-Length` says, and URLs from requests go
+//! through a client that only connects to public addresses, so a tenant
+//! can't have the server read its own admin listener, the cloud metadata
+//! service or anything else on the private network.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::bail;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect;
+use reqwest::Url;
+
+/// Pages larger than this are not read.
+pub const MAX_PAGE_BYTES: usize = 5 * 1024 * 1024;
+
+/// Redirects followed before giving up.
+const MAX_REDIRECTS: usize = 5;
+
+/// The body at `url`, failing once it passes `max_bytes`.
+pub async fn read(http: &reqwest::Client, url: &str, max_bytes: usize) -> anyhow::Result<Vec<u8>> {
+    let mut response = http.get(url).send().await?.error_for_status()?;
+    if response.content_length().is_some_and(|len| len > max_bytes as u64) {
+        bail!("larger than {max_bytes} bytes");
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > max_bytes {
+            bail!("larger than {max_bytes} bytes");
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// A client for URLs given in requests. Host names only resolve to public
+/// addresses and redirects are checked hop by hop. It connects directly,
+/// as behind a proxy the addresses it reaches aren't its own to check.
+pub fn public_client(timeout: Duration) -> anyhow::Result<reqwest::Client> {
+    let redirects = redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error(format!("more than {MAX_REDIRECTS} redirects"))
+        } else if let Err(e) = public_url(attempt.url().as_str()) {
+            attempt.error(e.to_string())
+        } else {
+            attempt.follow()
+        }
+    });
+    Ok(reqwest::Client::builder()
+        .timeout(timeout)
+        .no_proxy()
+        .dns_resolver(Arc::new(PublicResolver))
+        .redirect(redirects)
+        .build()?)
+}
+
+/// `url` when it is `http` or `https` and doesn't name a private address
+/// outright; host names are checked as they resolve.
+pub fn public_url(url: &str) -> anyhow::Result<Url> {
+    let parsed = Url::parse(url)?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        bail!("only http and https URLs are fetched");
+    }
+    let Some(host) = parsed.host_str() else {
+        bail!("the URL has no host");
+    };
+    // IPv6 addresses come in brackets.
+    let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() else {
+        return Ok(parsed);
+    };
+    if !is_public(ip) {
+        bail!("{ip} is not a public address");
+    }
+    Ok(parsed)
+}
+
+/// Resolves like the system does, leaving out addresses that aren't public.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let found: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            let public: Vec<SocketAddr> = found.into_iter().filter(|addr| is_public(addr.ip())).collect();
+            if public.is_empty() {
+                return Err(format!("{host} doesn't resolve to a public address").into());
+            }
+            let addrs: Addrs = Box::new(public.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/// Whether `ip` is reachable on the internet rather than this host, its
+/// network or a reserved range.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Carrier-grade NAT, 100.64.0.0/10.
+        || (a == 100 && (64..128).contains(&b))
+        // Protocol assignments, 192.0.0.0/24.
+        || ip.octets()[..3] == [192, 0, 0]
+        // Benchmarking, 198.18.0.0/15.
+        || (a == 198 && (18..20).contains(&b))
+        // Reserved, 240.0.0.0/4.
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local, fc00::/7.
+        || (first & 0xfe00) == 0xfc00
+        // Link-local, fe80::/10.
+        || (first & 0xffc0) == 0xfe80
+        // Documentation, 2001:db8::/32.
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8)
+        // IPv4-compatible, and NAT64 of any IPv4 address.
+        || ip.segments()[..6] == [0; 6]
+        || ip.segments()[..6] == [0x64, 0xff9b, 0, 0, 0, 0])
+}
@@ -0,0 +1,249 @@
+//! The fragment bank behind `/api/tts/template`: the numbers, weekdays and
+//! months announcements are mostly filled in with ("platform nine", "at
+//! four fifteen", "on Monday"), rendered once per voice, so values made only
+//! of them need no model at all. A fragment is rendered the first time a
+//! template needs it, or all of a voice's at once on a rebuild, and kept on
+//! disk named after the model version, voice description and text, like the
+//! quick phrases.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+
+use axum::http::StatusCode;
+use serde::Serialize;
+
+use crate::audio::{self, Pcm};
+use crate::error::SynthesisError;
+use crate::generation::FinishReason;
+use crate::history::{ClipRecord, History};
+use crate::namespace::Namespace;
+use crate::{numbers, phrases, schedule, templates, AppState};
+
+/// A fragment and the group it belongs to.
+#[derive(Debug, Clone)]
+pub struct Fragment {
+    pub category: &'static str,
+    pub text: String,
+}
+
+/// The digits, the numbers up to fifty-nine (for clock times), the
+/// weekdays and the months, in English.
+static BANK: LazyLock<Vec<Fragment>> = LazyLock::new(|| {
+    let locale = numbers::Locale::english();
+    let numbers = (0..60).map(|n| Fragment {
+        category: if n < 10 { "digits" } else { "numbers" },
+        text: locale.cardinal(n),
+    });
+    let weekdays = schedule::WEEKDAYS.iter().map(|day| Fragment {
+        category: "weekdays",
+        text: day.to_string(),
+    });
+    let months = locale.months().into_iter().map(|month| Fragment {
+        category: "months",
+        text: month.to_string(),
+    });
+    numbers.chain(weekdays).chain(months).collect()
+});
+
+/// The fragments `text` is made of, when it is made of nothing else:
+/// "four fifteen" and "9" (as "nine") are, "platform nine" is not.
+pub fn split(text: &str) -> Option<Vec<String>> {
+    let words = numbers::verbalize(text, numbers::Locale::english());
+    let fragments: Option<Vec<String>> = words
+        .split_whitespace()
+        .map(|word| {
+            let word = word.trim_matches(|c: char| !c.is_alphanumeric());
+            BANK.iter().find(|f| f.text.eq_ignore_ascii_case(word)).map(|f| f.text.clone())
+        })
+        .collect();
+    fragments.filter(|fragments| !fragments.is_empty())
+}
+
+/// What `/api/tts/template/fragments` reports for a voice.
+#[derive(Debug, Serialize)]
+pub struct BankReport {
+    pub voice: String,
+    pub model_version: String,
+    /// Whether a rebuild is still rendering.
+    pub rebuilding: bool,
+    pub rendered: usize,
+    pub total: usize,
+    pub fragments: Vec<FragmentStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FragmentStatus {
+    pub category: &'static str,
+    pub text: String,
+    pub rendered: bool,
+    pub duration_secs: Option<f64>,
+}
+
+/// The rendered fragments, by [`phrases::fingerprint`].
+pub struct FragmentBank {
+    store: History,
+    /// Fragments read or rendered since startup.
+    clips: RwLock<HashMap<String, Arc<Pcm>>>,
+    /// Descriptions of the voices being rebuilt.
+    rebuilding: Mutex<HashSet<String>>,
+}
+
+impl FragmentBank {
+    pub fn new(store: History) -> Self {
+        Self {
+            store,
+            clips: RwLock::default(),
+            rebuilding: Mutex::default(),
+        }
+    }
+
+    /// The fragment rendered as `id`, read from disk the first time.
+    pub fn get(&self, id: &str) -> anyhow::Result<Option<Arc<Pcm>>> {
+        if let Some(pcm) = self.clips.read().unwrap().get(id) {
+            return Ok(Some(pcm.clone()));
+        }
+        let Some(wav) = self.store.audio(id)? else {
+            return Ok(None);
+        };
+        let pcm = Arc::new(audio::read_wav(&wav)?);
+        self.clips.write().unwrap().insert(id.to_string(), pcm.clone());
+        Ok(Some(pcm))
+    }
+
+    /// Keeps `text` in the voice `description`, rendered as `wav`, under `id`.
+    pub fn insert(&self, id: &str, description: &str, text: &str, wav: &[u8], pcm: Arc<Pcm>) -> anyhow::Result<()> {
+        let record = ClipRecord {
+            id: id.to_string(),
+            created_at: crate::unix_now(),
+            prompt: text.to_string(),
+            description: description.to_string(),
+            temperature: None,
+            seed: None,
+            top_p: None,
+            sample_rate: pcm.sample_rate,
+            duration_secs: pcm.samples.len() as f64 / pcm.sample_rate as f64,
+            voice: None,
+            voice_version: None,
+            expires_at: None,
+            speech_rate: None,
+            quality_retry: None,
+            steps: None,
+            finish_reason: Some(FinishReason::Eos),
+            memory: None,
+            timings: None,
+            over_budget: Vec::new(),
+            downgraded_max_steps: None,
+            features: Vec::new(),
+            settings: None,
+            self_check: None,
+        };
+        self.store.save(&record, wav)?;
+        self.clips.write().unwrap().insert(id.to_string(), pcm);
+        Ok(())
+    }
+
+    fn report(&self, voice: &str, version: &str, description: &str) -> anyhow::Result<BankReport> {
+        let mut fragments = Vec::with_capacity(BANK.len());
+        for fragment in BANK.iter() {
+            let record = self.store.record(&phrases::fingerprint(version, description, &fragment.text))?;
+            fragments.push(FragmentStatus {
+                category: fragment.category,
+                text: fragment.text.clone(),
+                rendered: record.is_some(),
+                duration_secs: record.map(|record| record.duration_secs),
+            });
+        }
+        Ok(BankReport {
+            voice: voice.to_string(),
+            model_version: version.to_string(),
+            rebuilding: self.rebuilding.lock().unwrap().contains(description),
+            rendered: fragments.iter().filter(|f| f.rendered).count(),
+            total: fragments.len(),
+            fragments,
+        })
+    }
+
+    /// Deletes every fragment in the voice `description`, of any model
+    /// version. Returns how many there were.
+    fn remove_voice(&self, description: &str) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        for record in self.store.records()? {
+            if record.description == description && self.store.remove(&record.id)? {
+                self.clips.write().unwrap().remove(&record.id);
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// The description and loaded model version `voice` is rendered with:
+/// `404` for voices the namespace doesn't have.
+async fn voice(state: &AppState, namespace: &Namespace, voice: &str) -> Result<(String, String), SynthesisError> {
+    let current = namespace.voices.get(voice).ok_or(StatusCode::NOT_FOUND)?;
+    let pool = state.pool().await.map_err(SynthesisError::ModelLoad)?;
+    Ok((current.preset.description.clone(), pool.engine().version().to_string()))
+}
+
+/// The bank's fragments in `voice`, and which of them are rendered.
+pub async fn report(state: &AppState, namespace: &Namespace, name: &str) -> Result<BankReport, SynthesisError> {
+    let (description, version) = voice(state, namespace, name).await?;
+    let (bank, name) = (state.fragments.clone(), name.to_string());
+    tokio::task::spawn_blocking(move || bank.report(&name, &version, &description))
+        .await
+        .map_err(|e| SynthesisError::Io(e.into()))?
+        .map_err(SynthesisError::Io)
+}
+
+/// Deletes `voice`'s fragments and renders them all again in the
+/// background, for a voice whose renderings came out badly. `409` while a
+/// rebuild of the voice is running.
+pub async fn rebuild(state: &AppState, namespace: Arc<Namespace>, name: &str) -> Result<BankReport, SynthesisError> {
+    let (description, version) = voice(state, &namespace, name).await?;
+    let bank = state.fragments.clone();
+    if !bank.rebuilding.lock().unwrap().insert(description.clone()) {
+        return Err(StatusCode::CONFLICT.into());
+    }
+    let (store, text) = (bank.clone(), description.clone());
+    let removed = tokio::task::spawn_blocking(move || store.remove_voice(&text)).await;
+    let removed = match removed.map_err(anyhow::Error::from).and_then(|removed| removed) {
+        Ok(removed) => removed,
+        Err(e) => {
+            bank.rebuilding.lock().unwrap().remove(&description);
+            return Err(SynthesisError::Io(e));
+        }
+    };
+    println!("fragments: rebuilding {:?} in namespace {}, {removed} removed", name, namespace.name);
+    let report = match report(state, &namespace, name).await {
+        Ok(report) => report,
+        Err(e) => {
+            bank.rebuilding.lock().unwrap().remove(&description);
+            return Err(e);
+        }
+    };
+
+    let (state, name) = (state.clone(), name.to_string());
+    tokio::spawn(async move {
+        let mut rendered = 0;
+        for fragment in BANK.iter() {
+            let id = phrases::fingerprint(&version, &description, &fragment.text);
+            let part = templates::render_part(&state, namespace.clone(), &name, &fragment.text).await;
+            let (wav, pcm) = match part {
+                Ok(part) => part,
+                Err(e) => {
+                    println!("fragments: rendering {:?} failed: {e} ({})", fragment.text, e.code());
+                    continue;
+                }
+            };
+            let (bank, description, text) = (bank.clone(), description.clone(), fragment.text.clone());
+            match tokio::task::spawn_blocking(move || bank.insert(&id, &description, &text, &wav, pcm)).await {
+                Ok(Ok(())) => rendered += 1,
+                Ok(Err(e)) => println!("fragments: storing {:?} failed: {e:#}", fragment.text),
+                Err(e) => println!("fragments: storing {:?} panicked: {e}", fragment.text),
+            }
+        }
+        bank.rebuilding.lock().unwrap().remove(&description);
+        println!("fragments: rebuilt {rendered} of {} for {name:?}", BANK.len());
+    });
+    Ok(report)
+}
@@ -0,0 +1,287 @@
+//! The decoding loop, following `parler_tts::Model::generate` but with a
+//! pluggable sampler and hooks for steering which audio tokens get sampled.
+
+use candle::{DType, Device, IndexOp, Result, Tensor};
+use serde::{Deserialize, Serialize};
+
+use crate::model::Model;
+use crate::sampler::Sampler;
+
+/// Token-level overrides, for research and debugging of the generation
+/// process. Token ids are audio codebook entries (`0..decoder.vocab_size`).
+#[derive(Debug, Clone, Default)]
+pub struct TokenControls {
+    /// Never sampled, in any codebook.
+    pub banned: Vec<u32>,
+    /// Emitted as the first tokens of every codebook instead of sampling.
+    pub forced: Vec<u32>,
+}
+
+impl TokenControls {
+    pub fn is_empty(&self) -> bool {
+        self.banned.is_empty() && self.forced.is_empty()
+    }
+
+    /// The first id outside `0..vocab_size`, if any.
+    pub fn out_of_range(&self, vocab_size: usize) -> Option<u32> {
+        self.banned
+            .iter()
+            .chain(&self.forced)
+            .copied()
+            .find(|&id| id as usize >= vocab_size)
+    }
+
+    /// `logits` with the banned ids, and `also` if given, made unsampleable.
+    fn mask_banned(&self, logits: &Tensor, also: Option<u32>) -> Result<Tensor> {
+        if self.banned.is_empty() && also.is_none() {
+            return Ok(logits.clone());
+        }
+        let mut values = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        for &id in self.banned.iter().chain(&also) {
+            if let Some(v) = values.get_mut(id as usize) {
+                *v = f32::NEG_INFINITY;
+            }
+        }
+        Tensor::new(values, logits.device())
+    }
+}
+
+/// When decoding may end.
+#[derive(Debug, Clone)]
+pub struct Stopping {
+    /// With `per_token`, only the ceiling.
+    pub max_steps: usize,
+    /// Scales `max_steps` to each run's prompt length.
+    pub per_token: Option<PerToken>,
+    /// The end-of-audio token can't be sampled before this many steps.
+    pub min_steps: usize,
+    /// With `false`, end-of-audio is never sampled and every run goes to
+    /// `max_steps`.
+    pub stop_on_eos: bool,
+}
+
+impl Default for Stopping {
+    fn default() -> Self {
+        Self {
+            max_steps: 512,
+            per_token: None,
+            min_steps: 0,
+            stop_on_eos: true,
+        }
+    }
+}
+
+impl Stopping {
+    /// The rule for a run whose prompt is `prompt_tokens` tokens long.
+    pub fn for_prompt(&self, prompt_tokens: usize) -> Stopping {
+        let Some(per_token) = self.per_token else {
+            return self.clone();
+        };
+        let steps = (prompt_tokens as f64 / per_token.tokens_per_step).ceil() as usize;
+        let floor = per_token.floor.max(self.min_steps).min(self.max_steps);
+        Stopping {
+            max_steps: steps.clamp(floor, self.max_steps),
+            per_token: None,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PerToken {
+    pub tokens_per_step: f64,
+    pub floor: usize,
+}
+
+/// The `[max_steps]` config section: how many decoder steps a request that
+/// doesn't set `max_steps` may take. By default that is in proportion to
+/// the prompt's token count, so a short prompt that fails to end stops
+/// early and a long chunk isn't cut off at a fixed length.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StepLimit {
+    /// With `false`, every run gets `ceiling` steps.
+    pub scale_with_prompt: bool,
+    /// Prompt tokens per decoder step; the default allows about 33 steps
+    /// (0.4 s of audio) per token, which is half again a typical speaking
+    /// rate.
+    pub tokens_per_step: f64,
+    pub floor: usize,
+    pub ceiling: usize,
+}
+
+impl Default for StepLimit {
+    fn default() -> Self {
+        Self {
+            scale_with_prompt: true,
+            tokens_per_step: 0.03,
+            floor: 128,
+            ceiling: crate::MAX_STEPS_LIMIT,
+        }
+    }
+}
+
+impl StepLimit {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.tokens_per_step.is_finite() || self.tokens_per_step <= 0.0 {
+            anyhow::bail!("max_steps.tokens_per_step must be a positive number");
+        }
+        if self.floor == 0 || self.floor > self.ceiling || self.ceiling > crate::MAX_STEPS_LIMIT {
+            anyhow::bail!(
+                "max_steps needs 0 < floor <= ceiling <= {}",
+                crate::MAX_STEPS_LIMIT
+            );
+        }
+        Ok(())
+    }
+
+    /// The stopping rule of requests that don't set `max_steps`.
+    pub fn stopping(&self) -> Stopping {
+        Stopping {
+            max_steps: self.ceiling,
+            per_token: self.scale_with_prompt.then_some(PerToken {
+                tokens_per_step: self.tokens_per_step,
+                floor: self.floor,
+            }),
+            ..Stopping::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// Every codebook emitted end-of-audio.
+    Eos,
+    /// Cut off at `max_steps`; the audio is likely truncated.
+    MaxSteps,
+}
+
+impl FinishReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Eos => "eos",
+            Self::MaxSteps => "max_steps",
+        }
+    }
+}
+
+pub struct Generated {
+    /// Audio codes, `(num_codebooks, frames)`, on the CPU.
+    pub codes: Tensor,
+    pub steps: usize,
+    pub finish: FinishReason,
+}
+
+/// The description run through the text encoder, projected for the
+/// decoder: what conditions the voice. It depends on the description
+/// alone, so it can be kept for the next prompt in the same voice.
+pub fn encode_description(model: &mut Model, description_tokens: &Tensor) -> Result<Tensor> {
+    model.text_encoder.clear_kv_cache();
+    let encoded = model.text_encoder.forward(description_tokens)?;
+    match model.enc_to_dec_proj.as_ref() {
+        None => Ok(encoded),
+        Some(proj) => encoded.apply(proj),
+    }
+}
+
+/// Runs the decoder until end-of-audio or `stopping.max_steps`, passing
+/// the codes so far (one list per codebook, the later ones shorter by the
+/// delay pattern) to `on_step` after each step. `encoded` is the
+/// description from [`encode_description`].
+#[allow(clippy::too_many_arguments)]
+pub fn generate(
+    model: &mut Model,
+    num_codebooks: usize,
+    prompt_tokens: &Tensor,
+    encoded: &Tensor,
+    sampler: &mut dyn Sampler,
+    controls: &TokenControls,
+    stopping: &Stopping,
+    on_step: &mut dyn FnMut(&[Vec<u32>]) -> Result<()>,
+) -> Result<Generated> {
+    model.decoder.clear_kv_cache();
+    let prompt_hidden_states = prompt_tokens.apply(&model.embed_prompts)?;
+    let (start_token, pad_token) = (model.decoder_start_token_id, model.pad_token_id);
+    let mut audio_tokens = vec![start_token; num_codebooks];
+    let mut all_audio_tokens = vec![vec![]; num_codebooks];
+    let prompt_len = prompt_hidden_states.dim(1)?;
+    let mut finish = FinishReason::MaxSteps;
+    let mut steps = 0;
+    for step in 0..stopping.max_steps {
+        steps = step + 1;
+        let hold_eos = (!stopping.stop_on_eos || step < stopping.min_steps).then_some(pad_token);
+        let input_ids = Tensor::from_slice(
+            audio_tokens.as_slice(),
+            (1, num_codebooks, 1),
+            prompt_tokens.device(),
+        )?;
+        let (prompt_hidden_states, pos) = if step == 0 {
+            (Some(&prompt_hidden_states), 0)
+        } else {
+            (None, step + prompt_len)
+        };
+        let causal_mask = if pos == 0 {
+            causal_mask(prompt_len + 1, prompt_len + 1, input_ids.device())?
+        } else {
+            causal_mask(1, pos + 1, input_ids.device())?
+        };
+        let logits = model.decoder.forward(
+            &input_ids,
+            prompt_hidden_states,
+            Some(&causal_mask),
+            encoded,
+            None,
+            pos,
+        )?;
+        // Codebook k starts k steps late (the delay pattern).
+        for (codebook, logit) in logits.iter().enumerate() {
+            if codebook > step {
+                break;
+            }
+            if audio_tokens[codebook] == pad_token {
+                continue;
+            }
+            audio_tokens[codebook] = match controls.forced.get(step - codebook) {
+                Some(&token) => token,
+                None => {
+                    let logit = logit.i((0, logit.dim(1)? - 1))?;
+                    sampler.sample(&controls.mask_banned(&logit, hold_eos)?, codebook, step)?
+                }
+            };
+        }
+        if audio_tokens.iter().all(|v| v == &pad_token) {
+            finish = FinishReason::Eos;
+            break;
+        }
+        for (codebook, &token) in audio_tokens.iter().enumerate() {
+            if token != start_token && token != pad_token {
+                all_audio_tokens[codebook].push(token)
+            }
+        }
+        on_step(&all_audio_tokens)?;
+    }
+
+    let min_len = all_audio_tokens.iter().map(|v| v.len()).min().unwrap_or(0);
+    all_audio_tokens.iter_mut().for_each(|v| v.resize(min_len, 0));
+    Ok(Generated {
+        codes: Tensor::new(all_audio_tokens, &Device::Cpu)?,
+        steps,
+        finish,
+    })
+}
+
+fn causal_mask(q_len: usize, kv_len: usize, device: &Device) -> Result<Tensor> {
+    let mask: Vec<_> = (0..q_len)
+        .flat_map(|i| {
+            (0..kv_len).map(move |j| {
+                if i + kv_len < j + q_len {
+                    f32::NEG_INFINITY
+                } else {
+                    0.
+                }
+            })
+        })
+        .collect();
+    Tensor::from_slice(&mask, (q_len, kv_len), device)
+}
@@ -0,0 +1,247 @@
+//! Record of generated clips: each clip is `<id>.wav` plus a `<id>.json`
+//! record of how it was made, both in the audio directory. With encryption
+//! configured both files are sealed and get a `.enc` suffix. Long clips
+//! may also keep the WAV of each chunk under `<id>.chunks/`.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::analysis::SpeechRate;
+use crate::budget::{Stage, StageTimings};
+use crate::crypto::{Cipher, SEALED_EXTENSION};
+use crate::generation::FinishReason;
+use crate::memory::MemoryPeak;
+use crate::features::Feature;
+use crate::quality::QualityRetry;
+use crate::self_check::Report as SelfCheckReport;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClipRecord {
+    pub id: String,
+    /// Unix time, seconds.
+    pub created_at: u64,
+    /// Prompt and description as allowed by the `log_prompts` setting.
+    pub prompt: String,
+    pub description: String,
+    pub temperature: Option<f64>,
+    pub seed: Option<u64>,
+    pub top_p: Option<f64>,
+    pub sample_rate: u32,
+    pub duration_secs: f64,
+    /// Voice preset the clip was generated with, if any.
+    #[serde(default)]
+    pub voice: Option<String>,
+    /// Version of the preset `voice` it was generated with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voice_version: Option<u32>,
+    /// Unix time after which the cleanup task deletes the clip.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub speech_rate: Option<SpeechRate>,
+    /// Present when the first attempt was degenerate and this is the retry.
+    #[serde(default)]
+    pub quality_retry: Option<QualityRetry>,
+    /// Decoder steps taken, and whether it stopped on its own.
+    #[serde(default)]
+    pub steps: Option<usize>,
+    #[serde(default)]
+    pub finish_reason: Option<FinishReason>,
+    /// Peak host and GPU memory while it was generated.
+    #[serde(default)]
+    pub memory: Option<MemoryPeak>,
+    /// Time spent per stage, and the stages that ran past their budget.
+    #[serde(default)]
+    pub timings: Option<StageTimings>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub over_budget: Vec<Stage>,
+    /// The lowered `max_steps`, when it was capped to fit the budget.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downgraded_max_steps: Option<usize>,
+    /// Experimental features the request turned on.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<Feature>,
+    /// The request's other form fields, for `/api/history/<id>/replay`.
+    /// Only kept when prompts are logged in full, since a replay needs the
+    /// prompt as it was.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings: Option<BTreeMap<String, String>>,
+    /// The clip's transcript and how far it is from the prompt, when it
+    /// was checked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub self_check: Option<SelfCheckReport>,
+}
+
+/// Other renderings kept next to a clip, by extension, and removed with it.
+const ATTACHMENTS: &[&str] = &["m4b"];
+
+pub struct History {
+    dir: PathBuf,
+    cipher: Option<Cipher>,
+}
+
+impl History {
+    pub fn new(dir: PathBuf, cipher: Option<Cipher>) -> Self {
+        Self { dir, cipher }
+    }
+
+    /// Stores a clip's audio and its record.
+    pub fn save(&self, record: &ClipRecord, wav: &[u8]) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        self.write(&format!("{}.wav", record.id), wav)?;
+        self.write(&format!("{}.json", record.id), &serde_json::to_vec_pretty(record)?)
+    }
+
+    /// All readable records, newest first.
+    pub fn records(&self) -> anyhow::Result<Vec<ClipRecord>> {
+        let suffix = match &self.cipher {
+            Some(_) => format!(".json.{SEALED_EXTENSION}"),
+            None => ".json".to_string(),
+        };
+        let mut records = Vec::new();
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(records),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            let Some(id) = name.strip_suffix(&suffix) else {
+                continue;
+            };
+            match self.record(id) {
+                Ok(Some(record)) => records.push(record),
+                Ok(None) => {}
+                Err(e) => println!("history: skipping unreadable record {id}: {e:#}"),
+            }
+        }
+        records.sort_by(|a, b| b.created_at.cmp(&a.created_at).then_with(|| b.id.cmp(&a.id)));
+        Ok(records)
+    }
+
+    pub fn record(&self, id: &str) -> anyhow::Result<Option<ClipRecord>> {
+        if !valid_id(id) {
+            return Ok(None);
+        }
+        match self.read(&format!("{id}.json"))? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Deletes a clip and its record. Returns whether anything was removed.
+    pub fn remove(&self, id: &str) -> anyhow::Result<bool> {
+        if !valid_id(id) {
+            return Ok(false);
+        }
+        let mut removed = false;
+        let attachments = ATTACHMENTS.iter().map(|extension| format!("{id}.{extension}"));
+        for name in [format!("{id}.wav"), format!("{id}.json")].into_iter().chain(attachments) {
+            match std::fs::remove_file(self.path(&name)) {
+                Ok(()) => removed = true,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        match std::fs::remove_dir_all(self.dir.join(chunk_dir(id))) {
+            Ok(()) => removed = true,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(removed)
+    }
+
+    /// Deletes every clip whose `expires_at` is before `now`; returns how many.
+    pub fn remove_expired(&self, now: u64) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        for record in self.records()? {
+            if record.expires_at.is_some_and(|t| t <= now) && self.remove(&record.id)? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// A clip's WAV bytes, or `None` if there is no such clip.
+    pub fn audio(&self, id: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        if !valid_id(id) {
+            return Ok(None);
+        }
+        self.read(&format!("{id}.wav"))
+    }
+
+    /// Stores another rendering of clip `id`, such as an M4B of an
+    /// audiobook. `extension` must be one of `ATTACHMENTS`.
+    pub fn save_attachment(&self, id: &str, extension: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        if !valid_id(id) || !ATTACHMENTS.contains(&extension) {
+            anyhow::bail!("no {extension:?} attachment for clip {id:?}");
+        }
+        self.write(&format!("{id}.{extension}"), bytes)
+    }
+
+    pub fn attachment(&self, id: &str, extension: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        if !valid_id(id) || !ATTACHMENTS.contains(&extension) {
+            return Ok(None);
+        }
+        self.read(&format!("{id}.{extension}"))
+    }
+
+    /// Stores the WAV of chunk `n` of clip `id`, for rendering parts of a
+    /// long clip again later.
+    pub fn save_chunk(&self, id: &str, n: usize, wav: &[u8]) -> anyhow::Result<()> {
+        if !valid_id(id) {
+            anyhow::bail!("invalid clip id {id:?}");
+        }
+        std::fs::create_dir_all(self.dir.join(chunk_dir(id)))?;
+        self.write(&format!("{}/{n}.wav", chunk_dir(id)), wav)
+    }
+
+    pub fn chunk(&self, id: &str, n: usize) -> anyhow::Result<Option<Vec<u8>>> {
+        if !valid_id(id) {
+            return Ok(None);
+        }
+        self.read(&format!("{}/{n}.wav", chunk_dir(id)))
+    }
+
+    fn read(&self, name: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let bytes = match std::fs::read(self.path(name)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match &self.cipher {
+            Some(cipher) => cipher.open(&bytes).map(Some),
+            None => Ok(Some(bytes)),
+        }
+    }
+
+    fn write(&self, name: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        let bytes = match &self.cipher {
+            Some(cipher) => cipher.seal(bytes)?,
+            None => bytes.to_vec(),
+        };
+        std::fs::write(self.path(name), bytes)?;
+        Ok(())
+    }
+
+    /// On-disk path of `name`, sealed or not.
+    fn path(&self, name: &str) -> PathBuf {
+        match &self.cipher {
+            Some(_) => self.dir.join(format!("{name}.{SEALED_EXTENSION}")),
+            None => self.dir.join(name),
+        }
+    }
+}
+
+/// Directory of a clip's chunk WAVs, next to the clip.
+fn chunk_dir(id: &str) -> String {
+    format!("{id}.chunks")
+}
+
+/// Clip ids are generated by the server; anything else is rejected before it
+/// reaches the filesystem.
+pub fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
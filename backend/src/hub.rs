@@ -0,0 +1,211 @@
+//! Model file retrieval from the Hugging Face hub, using the async API so
+//! downloads run on the tokio runtime and weight shards come down in parallel.
+//! Checkpoints are sharded (a safetensors index naming the shards) or a
+//! single `model.safetensors`, as mini checkpoints ship; local directories
+//! laid out like a hub repo load the same way.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use hf_hub::api::tokio::{ApiBuilder, ApiRepo, Progress};
+use hf_hub::{CacheRepo, Repo, RepoType};
+use sha2::{Digest, Sha256};
+
+use crate::config::ServerConfig;
+
+pub const MODEL_REPO: &str = "parler-tts/parler-tts-large-v1";
+pub const MODEL_REVISION: &str = "main";
+
+/// Parallel range requests per file download.
+const CHUNKS_IN_FLIGHT: usize = 8;
+
+const SAFETENSORS_INDEX: &str = "model.safetensors.index.json";
+const SINGLE_SAFETENSORS: &str = "model.safetensors";
+
+/// Local paths of everything needed to build the model.
+#[derive(Debug, Clone)]
+pub struct ModelFiles {
+    pub config: PathBuf,
+    pub tokenizer: PathBuf,
+    pub weights: Vec<PathBuf>,
+}
+
+/// Resolves the default model's files (`[models] repo`) from the cache,
+/// downloading whatever is missing.
+pub async fn fetch_model_files(server_config: &ServerConfig) -> anyhow::Result<ModelFiles> {
+    let models = &server_config.models;
+    fetch_repo_files(server_config, &models.repo, &models.revision).await
+}
+
+/// The same for another Parler-TTS checkpoint, `repo` at `revision`. A
+/// `repo` that is an existing directory is read from there (`revision` is
+/// then ignored).
+pub async fn fetch_repo_files(server_config: &ServerConfig, repo: &str, revision: &str) -> anyhow::Result<ModelFiles> {
+    if Path::new(repo).is_dir() {
+        return local_files(Path::new(repo));
+    }
+    let start = std::time::Instant::now();
+    let cache = server_config.hf_cache();
+    let api = ApiBuilder::from_cache(cache.clone())
+        .with_max_files(CHUNKS_IN_FLIGHT)
+        .build()?;
+    let hub_repo = Repo::with_revision(repo.to_string(), RepoType::Model, revision.to_string());
+    let repo = api.repo(hub_repo.clone());
+    let cached = cache.repo(hub_repo);
+
+    let (config, tokenizer, shards) = tokio::try_join!(
+        async { repo.get("config.json").await.context("fetching config.json") },
+        async { repo.get("tokenizer.json").await.context("fetching tokenizer.json") },
+        weight_files(&repo, &cached),
+    )?;
+
+    let progress = DownloadProgress::default();
+    let weights = futures::future::try_join_all(shards.iter().map(|shard| {
+        let (repo, progress) = (&repo, progress.clone());
+        let cached = cached.get(shard);
+        async move {
+            match cached {
+                Some(path) => Ok(path),
+                None => repo
+                    .download_with_progress(shard, progress)
+                    .await
+                    .with_context(|| format!("downloading {shard}")),
+            }
+        }
+    }))
+    .await?;
+
+    println!("retrieved the files in {:?}", start.elapsed());
+    Ok(ModelFiles {
+        config,
+        tokenizer,
+        weights,
+    })
+}
+
+/// The weight files of a hub repo: the shards its index lists, or the
+/// single file. The cache answers first, so a cached model starts offline.
+async fn weight_files(repo: &ApiRepo, cached: &CacheRepo) -> anyhow::Result<Vec<String>> {
+    if let Some(index) = cached.get(SAFETENSORS_INDEX) {
+        return safetensors_shards(&std::fs::read(index)?);
+    }
+    if cached.get(SINGLE_SAFETENSORS).is_some() {
+        return Ok(vec![SINGLE_SAFETENSORS.to_string()]);
+    }
+    let info = repo.info().await.context("listing the repo's files")?;
+    let has = |name: &str| info.siblings.iter().any(|s| s.rfilename == name);
+    if has(SAFETENSORS_INDEX) {
+        let index = repo.get(SAFETENSORS_INDEX).await.context("fetching the safetensors index")?;
+        safetensors_shards(&std::fs::read(index)?)
+    } else if has(SINGLE_SAFETENSORS) {
+        Ok(vec![SINGLE_SAFETENSORS.to_string()])
+    } else {
+        bail!("the repo has neither {SAFETENSORS_INDEX} nor {SINGLE_SAFETENSORS}")
+    }
+}
+
+/// A checkpoint in a local directory.
+fn local_files(dir: &Path) -> anyhow::Result<ModelFiles> {
+    let file = |name: &str| {
+        let path = dir.join(name);
+        anyhow::ensure!(path.is_file(), "{} has no {name}", dir.display());
+        Ok(path)
+    };
+    let shards = match file(SAFETENSORS_INDEX) {
+        Ok(index) => safetensors_shards(&std::fs::read(index)?)?,
+        Err(_) => vec![SINGLE_SAFETENSORS.to_string()],
+    };
+    println!("loading the checkpoint in {}", dir.display());
+    Ok(ModelFiles {
+        config: file("config.json")?,
+        tokenizer: file("tokenizer.json")?,
+        weights: shards.iter().map(|shard| file(shard)).collect::<anyhow::Result<_>>()?,
+    })
+}
+
+impl ModelFiles {
+    /// The hub commit the weights come from: snapshot folders are named
+    /// after it, so this changes when the model is upgraded. A local
+    /// checkpoint can change in place, so its revision is derived from the
+    /// weights' sizes and modification times instead.
+    pub fn revision(&self) -> String {
+        let dir = self.weights.first().and_then(|p| p.parent());
+        let in_snapshots = dir
+            .and_then(|d| d.parent())
+            .and_then(|d| d.file_name())
+            .is_some_and(|name| name == "snapshots");
+        if let (true, Some(name)) = (in_snapshots, dir.and_then(|d| d.file_name())) {
+            return name.to_string_lossy().into_owned();
+        }
+        let mut hasher = Sha256::new();
+        for path in &self.weights {
+            if let Ok(meta) = std::fs::metadata(path) {
+                let modified = meta.modified().ok().and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok());
+                hasher.update(format!("{}:{}:{:?}\n", path.display(), meta.len(), modified));
+            }
+        }
+        let digest: String = hasher.finalize().iter().take(6).map(|b| format!("{b:02x}")).collect();
+        format!("local-{digest}")
+    }
+}
+
+/// Shard file names listed in a safetensors index's `weight_map`.
+fn safetensors_shards(index: &[u8]) -> anyhow::Result<Vec<String>> {
+    let json: serde_json::Value = serde_json::from_slice(index).context("parsing safetensors index")?;
+    let weight_map = match json.get("weight_map") {
+        None => bail!("no weight map in safetensors index"),
+        Some(serde_json::Value::Object(map)) => map,
+        Some(_) => bail!("weight map in safetensors index is not a map"),
+    };
+    let mut shards: Vec<String> = weight_map
+        .values()
+        .filter_map(|v| v.as_str().map(str::to_string))
+        .collect();
+    shards.sort();
+    shards.dedup();
+    Ok(shards)
+}
+
+/// Aggregated progress over all shards being downloaded, logged every 5%.
+#[derive(Clone, Default)]
+struct DownloadProgress {
+    totals: Arc<Totals>,
+}
+
+#[derive(Default)]
+struct Totals {
+    size: AtomicU64,
+    done: AtomicU64,
+    last_reported: AtomicU64,
+}
+
+impl Progress for DownloadProgress {
+    async fn init(&mut self, size: usize, filename: &str) {
+        self.totals.size.fetch_add(size as u64, Ordering::Relaxed);
+        println!("downloading {filename} ({:.1} MB)", size as f64 / 1e6);
+    }
+
+    async fn update(&mut self, size: usize) {
+        let done = self.totals.done.fetch_add(size as u64, Ordering::Relaxed) + size as u64;
+        let total = self.totals.size.load(Ordering::Relaxed).max(1);
+        let percent = done * 100 / total;
+        let last = self.totals.last_reported.load(Ordering::Relaxed);
+        if percent >= last + 5
+            && self
+                .totals
+                .last_reported
+                .compare_exchange(last, percent, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            println!(
+                "model download {percent}% ({:.1}/{:.1} MB)",
+                done as f64 / 1e6,
+                total as f64 / 1e6
+            );
+        }
+    }
+
+    async fn finish(&mut self) {}
+}
@@ -0,0 +1,163 @@
+//! Registers clips made elsewhere (the CLI, another instance's export) in a
+//! namespace's history. Input is a manifest plus audio files, either as
+//! separate uploads or inside a ZIP laid out like an export.
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use serde::{Deserialize, Serialize};
+
+use crate::export::MANIFEST_JSON;
+use crate::history::{valid_id, ClipRecord};
+use crate::namespace::Namespace;
+use crate::privacy::PromptLogging;
+
+/// Uploaded audio by file name (or archive path).
+pub type AudioFiles = HashMap<String, Vec<u8>>;
+
+/// A manifest row. Everything but `file` is optional, so hand-written
+/// manifests stay short; rows of an export's `manifest.json` fit as is.
+#[derive(Debug, Deserialize)]
+pub struct ImportEntry {
+    /// Name of the uploaded file, or its path inside the archive.
+    pub file: String,
+    pub id: Option<String>,
+    #[serde(default)]
+    pub prompt: String,
+    #[serde(default)]
+    pub description: String,
+    pub voice: Option<String>,
+    pub voice_version: Option<u32>,
+    pub temperature: Option<f64>,
+    pub seed: Option<u64>,
+    pub top_p: Option<f64>,
+    pub created_at: Option<u64>,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub imported: Vec<String>,
+    pub skipped: Vec<Skipped>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Skipped {
+    pub file: String,
+    pub reason: String,
+}
+
+/// Manifest and audio files pulled out of an export-style ZIP, which may
+/// inflate to at most `max_bytes` in all. The sizes the archive declares
+/// aren't trusted.
+pub fn unpack_archive(bytes: &[u8], max_bytes: usize) -> anyhow::Result<(Vec<ImportEntry>, AudioFiles)> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes))?;
+    let mut manifest = None;
+    let mut files = HashMap::new();
+    let mut remaining = max_bytes as u64;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if file.is_dir() {
+            continue;
+        }
+        let name = file.name().to_string();
+        let mut data = Vec::new();
+        let read = (&mut file).take(remaining + 1).read_to_end(&mut data)? as u64;
+        if read > remaining {
+            anyhow::bail!("archive inflates to more than {max_bytes} bytes");
+        }
+        remaining -= read;
+        if name == MANIFEST_JSON {
+            manifest = Some(serde_json::from_slice(&data)?);
+        } else {
+            files.insert(name, data);
+        }
+    }
+    let manifest = manifest.ok_or_else(|| anyhow::anyhow!("archive has no {MANIFEST_JSON}"))?;
+    Ok((manifest, files))
+}
+
+/// Where imported clips go and how their records are filled in.
+pub struct ImportContext<'a> {
+    pub namespace: &'a Namespace,
+    pub policy: PromptLogging,
+    pub now: u64,
+}
+
+impl ImportContext<'_> {
+    pub fn import(&self, manifest: Vec<ImportEntry>, mut files: AudioFiles) -> ImportReport {
+        let mut report = ImportReport::default();
+        for (n, entry) in manifest.into_iter().enumerate() {
+            let file = entry.file.clone();
+            match self.import_one(n, entry, &mut files) {
+                Ok(id) => report.imported.push(id),
+                Err(e) => report.skipped.push(Skipped {
+                    file,
+                    reason: format!("{e:#}"),
+                }),
+            }
+        }
+        report
+    }
+
+    fn import_one(
+        &self,
+        n: usize,
+        entry: ImportEntry,
+        files: &mut AudioFiles,
+    ) -> anyhow::Result<String> {
+        let wav = files
+            .remove(&entry.file)
+            .ok_or_else(|| anyhow::anyhow!("no uploaded file named {:?}", entry.file))?;
+        let pcm = crate::audio::read_wav(&wav)?;
+
+        let history = &self.namespace.history;
+        // Keep the original id when it is free, so migrated links keep working.
+        let id = match entry.id {
+            Some(id) if valid_id(&id) && history.record(&id)?.is_none() => id,
+            _ => {
+                let mut k = n;
+                loop {
+                    let id = format!("imported_{}_{k}", self.now);
+                    if history.record(&id)?.is_none() {
+                        break id;
+                    }
+                    k += 1;
+                }
+            }
+        };
+        // Entries without an expiry get the one they'd have had if made here.
+        let expires_at = entry.expires_at.or_else(|| {
+            self.namespace
+                .retention_for(entry.voice.as_deref())
+                .map(|ttl| self.now + ttl.as_secs())
+        });
+        let record = ClipRecord {
+            id: id.clone(),
+            created_at: entry.created_at.unwrap_or(self.now),
+            prompt: self.policy.sanitize(&entry.prompt),
+            description: self.policy.sanitize(&entry.description),
+            temperature: entry.temperature,
+            seed: entry.seed,
+            top_p: entry.top_p,
+            sample_rate: pcm.sample_rate,
+            duration_secs: pcm.duration_secs(),
+            voice: entry.voice,
+            voice_version: entry.voice_version,
+            expires_at,
+            speech_rate: crate::analysis::speech_rate(&entry.prompt, pcm.duration_secs()),
+            quality_retry: None,
+            steps: None,
+            finish_reason: None,
+            memory: None,
+            timings: None,
+            over_budget: Vec::new(),
+            downgraded_max_steps: None,
+            features: Vec::new(),
+            settings: None,
+            self_check: None,
+        };
+        history.save(&record, &wav)?;
+        Ok(id)
+    }
+}
@@ -0,0 +1,160 @@
+//! What this server is and can do, reported once at startup (a JSON log
+//! line for log collectors and a block for people) and served at
+//! `/api/info` so clients can adapt, e.g. offer Opus only when it is built.
+
+use serde::Serialize;
+
+use crate::config::{RouteSet, ServerConfig};
+use crate::features::Feature;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerInfo {
+    pub version: &'static str,
+    /// Cargo features the server was built with.
+    pub build_features: Vec<&'static str>,
+    pub acceleration: Acceleration,
+    pub model: ModelInfo,
+    pub limits: Limits,
+    /// Experimental features requests may turn on.
+    pub request_features: Vec<Feature>,
+    /// `format`s of `/api/tts` and `/api/transcode`.
+    pub formats: Formats,
+    pub listeners: Vec<ListenerInfo>,
+}
+
+/// What the tensor library was built with.
+#[derive(Debug, Clone, Serialize)]
+pub struct Acceleration {
+    pub mkl: bool,
+    pub accelerate: bool,
+    pub cuda: bool,
+    pub metal: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelInfo {
+    pub repo: String,
+    pub revision: String,
+    /// What else the `model` request field may name.
+    pub allowed: Vec<String>,
+    pub dtype: String,
+    /// As configured: `auto`, `cpu`, `cuda:<idx>`, `metal:<idx>` or
+    /// `device_map`. `/api/health` has the device it chose.
+    pub device: String,
+    pub workers: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Limits {
+    pub chunk_chars: usize,
+    pub max_steps: usize,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    pub max_tts_bytes: usize,
+    pub max_upload_bytes: usize,
+    pub max_document_bytes: usize,
+    pub max_import_bytes: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Formats {
+    pub tts: Vec<&'static str>,
+    pub transcode: Vec<&'static str>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ListenerInfo {
+    pub address: String,
+    pub routes: Vec<RouteSet>,
+}
+
+impl ServerInfo {
+    pub fn new(config: &ServerConfig, limits: Limits, listeners: Vec<ListenerInfo>) -> Self {
+        let acceleration = Acceleration {
+            mkl: candle::utils::has_mkl(),
+            accelerate: candle::utils::has_accelerate(),
+            cuda: candle::utils::cuda_is_available(),
+            metal: candle::utils::metal_is_available(),
+        };
+        let device = match &config.device_map {
+            Some(_) => "device_map".to_string(),
+            None => config.device.to_string(),
+        };
+        let mut transcode = vec!["wav", "pcm16le", "mulaw8k", "m4a"];
+        if cfg!(feature = "opus") {
+            transcode.push("opus");
+        }
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            build_features: [
+                ("cuda", cfg!(feature = "cuda")),
+                ("metal", cfg!(feature = "metal")),
+                ("mkl", cfg!(feature = "mkl")),
+                ("accelerate", cfg!(feature = "accelerate")),
+                ("opus", cfg!(feature = "opus")),
+                ("webrtc", cfg!(feature = "webrtc")),
+                ("telegram", cfg!(feature = "telegram")),
+            ]
+            .into_iter()
+            .filter_map(|(name, on)| on.then_some(name))
+            .collect(),
+            acceleration,
+            model: ModelInfo {
+                repo: config.models.repo.clone(),
+                revision: config.models.revision.clone(),
+                allowed: config.models.allowed.clone(),
+                dtype: config.dtype.clone(),
+                device,
+                workers: config.workers,
+            },
+            limits,
+            request_features: config.features.allowed.clone(),
+            formats: Formats {
+                tts: vec!["wav", "pcm16le", "mulaw8k"],
+                transcode,
+            },
+            listeners,
+        }
+    }
+
+    /// The report for people reading the console.
+    pub fn banner(&self) -> String {
+        let list = |items: &[&str]| if items.is_empty() { "none".to_string() } else { items.join(", ") };
+        let acceleration: Vec<&str> = [
+            ("mkl", self.acceleration.mkl),
+            ("accelerate", self.acceleration.accelerate),
+            ("cuda", self.acceleration.cuda),
+            ("metal", self.acceleration.metal),
+        ]
+        .into_iter()
+        .filter_map(|(name, on)| on.then_some(name))
+        .collect();
+        let requests: Vec<&str> = self.request_features.iter().map(|f| f.as_str()).collect();
+        let (model, limits) = (&self.model, &self.limits);
+        let mut lines = vec![
+            format!("ttser {}", self.version),
+            format!("  build features:   {}", list(&self.build_features)),
+            format!("  acceleration:     {}", list(&acceleration)),
+            format!(
+                "  model:            {}@{} ({}, {}, {} workers)",
+                model.repo, model.revision, model.dtype, model.device, model.workers
+            ),
+            format!(
+                "  limits:           {} chars per chunk, {} steps, {}-{} Hz output",
+                limits.chunk_chars, limits.max_steps, limits.min_sample_rate, limits.max_sample_rate
+            ),
+            format!(
+                "  body limits:      tts {} MiB, uploads {} MiB, documents {} MiB, imports {} MiB",
+                limits.max_tts_bytes >> 20,
+                limits.max_upload_bytes >> 20,
+                limits.max_document_bytes >> 20,
+                limits.max_import_bytes >> 20
+            ),
+            format!("  request features: {}", list(&requests)),
+        ];
+        for listener in &self.listeners {
+            lines.push(format!("  listening on:     {} ({:?})", listener.address, listener.routes));
+        }
+        lines.join("\n")
+    }
+}
@@ -0,0 +1,164 @@
+//! `GET /api/tts/ws`: a conversational session over one WebSocket. The
+//! client sends one text segment at a time and gets each one's audio back
+//! in binary messages, without an HTTP request per turn; the voice stays
+//! the same from segment to segment, so its encoded description is reused
+//! and only the text is new work.
+//!
+//! Client messages are JSON, `{"text": "...", "settings": {...}}`: `text`
+//! is spoken as one `/api/tts` request with the session's settings (the
+//! query string's fields), and `settings`, when given, changes them from
+//! that segment on. Each segment is answered with a `start` event, its
+//! audio (one message, or the pieces of the stream with `stream` set),
+//! then `end`; segments are spoken in the order they were sent.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use axum::extract::ws::{Message, WebSocket};
+use axum::http::HeaderMap;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::access_log::RequestId;
+use crate::namespace::Namespace;
+use crate::AppState;
+
+/// `/api/tts` fields a session can't set: each segment brings its text,
+/// and its audio comes over the socket.
+pub const NOT_SETTINGS: &[&str] = &["text", "webrtc_session"];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Segment {
+    text: String,
+    #[serde(default)]
+    settings: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event {
+    /// Sent before the segment's audio.
+    Start { index: usize, clip_id: Option<String> },
+    /// The segment's audio is complete.
+    End { index: usize, bytes: usize },
+    /// The segment wasn't spoken, or not to the end; the session goes on.
+    Error { index: Option<usize>, code: String, message: String },
+}
+
+/// Serves the session until the client closes it or goes away. `settings`
+/// are the query string's `/api/tts` fields.
+pub async fn run(
+    socket: WebSocket,
+    state: AppState,
+    namespace: Arc<Namespace>,
+    headers: HeaderMap,
+    settings: Vec<(String, String)>,
+) {
+    let mut settings: BTreeMap<String, String> = settings.into_iter().collect();
+    let (mut sender, mut receiver) = socket.split();
+    let mut spoken = 0;
+    while let Some(Ok(message)) = receiver.next().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let segment = match parse(text.as_str()) {
+            Ok(segment) => segment,
+            Err(message) => {
+                let event = Event::Error {
+                    index: None,
+                    code: "invalid_message".to_string(),
+                    message,
+                };
+                if send_event(&mut sender, &event).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+        settings.extend(segment.settings);
+        let index = spoken;
+        spoken += 1;
+        if speak(&mut sender, &state, &namespace, &headers, &settings, index, segment.text).await.is_err() {
+            // The client went away.
+            break;
+        }
+    }
+    println!("session: namespace {} closed after {spoken} segments", namespace.name);
+}
+
+fn parse(text: &str) -> Result<Segment, String> {
+    let segment: Segment = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    if let Some(name) = segment.settings.keys().find(|name| NOT_SETTINGS.contains(&name.as_str())) {
+        return Err(format!("{name} can't be set in settings"));
+    }
+    Ok(segment)
+}
+
+/// Renders one segment and sends its events and audio; an error only when
+/// sending failed.
+async fn speak(
+    sender: &mut futures::stream::SplitSink<WebSocket, Message>,
+    state: &AppState,
+    namespace: &Arc<Namespace>,
+    headers: &HeaderMap,
+    settings: &BTreeMap<String, String>,
+    index: usize,
+    text: String,
+) -> Result<(), axum::Error> {
+    let RequestId(request_id) = RequestId::next();
+    println!("tts[{request_id}]: session segment {} of namespace {}", index + 1, namespace.name);
+    let mut fields: Vec<(String, String)> = settings.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+    fields.push(("text".to_string(), text));
+    let response = match crate::synthesize(state.clone(), request_id, namespace.clone(), headers, fields).await {
+        Ok(response) => response,
+        Err(e) => {
+            println!("tts[{request_id}]: session segment failed: {e}");
+            let event = Event::Error {
+                index: Some(index),
+                code: e.code().to_string(),
+                message: e.message(),
+            };
+            return send_event(sender, &event).await;
+        }
+    };
+    let clip_id = response
+        .headers()
+        .get("x-clip-id")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    send_event(sender, &Event::Start { index, clip_id }).await?;
+    // A streamed response comes in pieces as it is generated, which go out
+    // as they come.
+    let mut body = response.into_body().into_data_stream();
+    let mut bytes = 0;
+    while let Some(piece) = body.next().await {
+        match piece {
+            Ok(piece) if piece.is_empty() => {}
+            Ok(piece) => {
+                bytes += piece.len();
+                sender.send(Message::Binary(piece)).await?;
+            }
+            Err(e) => {
+                println!("tts[{request_id}]: session segment failed: {e}");
+                let event = Event::Error {
+                    index: Some(index),
+                    code: "stream_failed".to_string(),
+                    message: "the audio stream failed".to_string(),
+                };
+                return send_event(sender, &event).await;
+            }
+        }
+    }
+    send_event(sender, &Event::End { index, bytes }).await
+}
+
+async fn send_event(
+    sender: &mut futures::stream::SplitSink<WebSocket, Message>,
+    event: &Event,
+) -> Result<(), axum::Error> {
+    let json = serde_json::to_string(event).unwrap_or_default();
+    sender.send(Message::Text(json.into())).await
+}
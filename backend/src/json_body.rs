@@ -0,0 +1,90 @@
+//! `application/json` bodies for `POST /api/tts`, for programmatic clients
+//! that would rather not build multipart forms:
+//! `{"text": "...", "description": "...", "seed": 7}`. Each member becomes
+//! the form field of its name. The common fields are checked here, and all
+//! that are wrong are reported together, each with what is wrong with it.
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// A missing or invalid member of a JSON body.
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    /// None when the body as a whole is unreadable.
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: &str, message: impl Into<String>) -> Self {
+        Self {
+            field: Some(field.to_string()),
+            message: message.into(),
+        }
+    }
+}
+
+/// The form fields of a JSON body, or everything wrong with it.
+/// `max_steps_limit` bounds `max_steps` as for forms.
+pub fn fields(body: &[u8], max_steps_limit: usize) -> Result<Vec<(String, String)>, Vec<FieldError>> {
+    let members: Map<String, Value> = serde_json::from_slice(body).map_err(|e| {
+        vec![FieldError {
+            field: None,
+            message: format!("the body must be a JSON object: {e}"),
+        }]
+    })?;
+    let mut errors = Vec::new();
+    match members.get("text") {
+        None | Some(Value::Null) => errors.push(FieldError::new("text", "is required")),
+        Some(Value::String(text)) if text.trim().is_empty() => errors.push(FieldError::new("text", "must not be empty")),
+        _ => {}
+    }
+    let mut fields = Vec::new();
+    for (name, value) in &members {
+        let field = match value {
+            Value::Null => continue,
+            Value::String(s) => s.clone(),
+            Value::Number(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Array(_) | Value::Object(_) => {
+                errors.push(FieldError::new(name, "must be a string, number or boolean"));
+                continue;
+            }
+        };
+        if let Err(message) = check(name, value, max_steps_limit) {
+            errors.push(FieldError::new(name, message));
+            continue;
+        }
+        fields.push((name.clone(), field));
+    }
+    if errors.is_empty() {
+        Ok(fields)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Checks the type and range of the fields that have one.
+fn check(name: &str, value: &Value, max_steps_limit: usize) -> Result<(), String> {
+    let number = || value.as_f64().filter(|n| n.is_finite());
+    match name {
+        "text" | "description" | "voice" if !value.is_string() => Err("must be a string".to_string()),
+        "temperature" => match number() {
+            Some(t) if t >= 0.0 => Ok(()),
+            _ => Err("must be a number no lower than 0".to_string()),
+        },
+        "top_p" => match number() {
+            Some(p) if p > 0.0 && p <= 1.0 => Ok(()),
+            _ => Err("must be a number above 0 and at most 1".to_string()),
+        },
+        "seed" => match value.as_u64() {
+            Some(_) => Ok(()),
+            None => Err("must be a non-negative integer".to_string()),
+        },
+        "max_steps" => match value.as_u64() {
+            Some(n) if (1..=max_steps_limit as u64).contains(&n) => Ok(()),
+            _ => Err(format!("must be an integer from 1 to {max_steps_limit}")),
+        },
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,57 @@
+//! Where the server accepts connections: a TCP address, or a Unix domain
+//! socket for deployments behind a reverse proxy on the same host.
+
+use std::path::PathBuf;
+
+use axum::Router;
+
+/// Prefix of `bind` values that name a Unix socket path.
+pub const UNIX_PREFIX: &str = "unix:";
+
+pub enum Listener {
+    Tcp(tokio::net::TcpListener),
+    Unix(tokio::net::UnixListener),
+}
+
+impl Listener {
+    /// Binds `addr`: `host:port`, or `unix:<path>`. A socket file left
+    /// behind by an earlier run is replaced.
+    pub async fn bind(addr: &str) -> anyhow::Result<Self> {
+        match unix_path(addr) {
+            Some(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                if std::fs::metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+                    std::fs::remove_file(&path)?;
+                }
+                Ok(Self::Unix(tokio::net::UnixListener::bind(path)?))
+            }
+            None => Ok(Self::Tcp(tokio::net::TcpListener::bind(addr).await?)),
+        }
+    }
+
+    /// Printable address, in the form `bind` takes.
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Tcp(listener) => listener
+                .local_addr()
+                .map_or_else(|_| "tcp".to_string(), |addr| format!("http://{addr}")),
+            Self::Unix(listener) => listener
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(|p| format!("{UNIX_PREFIX}{}", p.display())))
+                .unwrap_or_else(|| format!("{UNIX_PREFIX}(unnamed)")),
+        }
+    }
+
+    pub async fn serve(self, app: Router) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(listener) => axum::serve(listener, app).await,
+            Self::Unix(listener) => axum::serve(listener, app).await,
+        }
+    }
+}
+
+/// The socket path of a `unix:<path>` bind address.
+pub fn unix_path(addr: &str) -> Option<PathBuf> {
+    addr.strip_prefix(UNIX_PREFIX).map(PathBuf::from)
+}
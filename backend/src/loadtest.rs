@@ -0,0 +1,137 @@
+//! `ttser-backend loadtest`: drives a running server with representative
+//! requests and reports latency percentiles and error rates.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::config::LoadtestArgs;
+
+/// A mix of lengths and the things TTS front ends trip over.
+const PROMPTS: &[&str] = &[
+    "Hello, and welcome.",
+    "The quick brown fox jumps over the lazy dog.",
+    "Your order number is 4 8 1 5, and it will arrive on Tuesday the 12th.",
+    "Did you remember to lock the door before you left this morning?",
+    "In the beginning the universe was created. This has made a lot of people very angry and been widely regarded as a bad move.",
+    "Turn left in two hundred meters, then keep right at the fork and follow the signs for the city centre.",
+];
+
+const DESCRIPTION: &str =
+    "A female speaker delivers a slightly expressive and animated speech with a moderate speed and pitch. The recording is of very high quality, with the speaker's voice sounding clear and very close up.";
+
+/// Longest a single request may take before it counts as failed.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(600);
+
+enum Outcome {
+    Ok,
+    Status(u16),
+    Error(String),
+}
+
+struct Sample {
+    latency: Duration,
+    outcome: Outcome,
+}
+
+/// Runs the test against `url` (the server root) and prints a summary.
+pub async fn run(url: &str, args: &LoadtestArgs) -> anyhow::Result<()> {
+    let client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+    let endpoint = format!("{}/api/tts", url.trim_end_matches('/'));
+    println!(
+        "loadtest: {} workers against {endpoint} for {:?}",
+        args.concurrency, args.duration
+    );
+
+    let start = Instant::now();
+    let deadline = start + args.duration;
+    let workers: Vec<_> = (0..args.concurrency.max(1))
+        .map(|worker| {
+            let client = client.clone();
+            let endpoint = endpoint.clone();
+            let api_key = args.api_key.clone();
+            let voice = args.voice.clone();
+            tokio::spawn(async move {
+                let mut samples = Vec::new();
+                let mut n = worker;
+                while Instant::now() < deadline {
+                    let prompt = PROMPTS[n % PROMPTS.len()];
+                    n += 1;
+                    let mut form = reqwest::multipart::Form::new()
+                        .text("text", prompt)
+                        .text("seed", n.to_string());
+                    form = match &voice {
+                        Some(voice) => form.text("voice", voice.clone()),
+                        None => form.text("description", DESCRIPTION),
+                    };
+                    let mut request = client.post(&endpoint).multipart(form);
+                    if let Some(key) = &api_key {
+                        request = request.bearer_auth(key);
+                    }
+                    let sent = Instant::now();
+                    let outcome = match request.send().await {
+                        Ok(response) if response.status().is_success() => match response.bytes().await {
+                            Ok(_) => Outcome::Ok,
+                            Err(e) => Outcome::Error(format!("reading body: {e}")),
+                        },
+                        Ok(response) => Outcome::Status(response.status().as_u16()),
+                        Err(e) => Outcome::Error(e.to_string()),
+                    };
+                    samples.push(Sample {
+                        latency: sent.elapsed(),
+                        outcome,
+                    });
+                }
+                samples
+            })
+        })
+        .collect();
+
+    let mut samples = Vec::new();
+    for worker in workers {
+        samples.extend(worker.await?);
+    }
+    report(&samples, start.elapsed());
+    Ok(())
+}
+
+fn report(samples: &[Sample], elapsed: Duration) {
+    let total = samples.len();
+    let mut latencies: Vec<Duration> = samples
+        .iter()
+        .filter(|s| matches!(s.outcome, Outcome::Ok))
+        .map(|s| s.latency)
+        .collect();
+    latencies.sort();
+    let mut failures: BTreeMap<String, usize> = BTreeMap::new();
+    for sample in samples {
+        match &sample.outcome {
+            Outcome::Ok => {}
+            Outcome::Status(code) => *failures.entry(format!("HTTP {code}")).or_default() += 1,
+            Outcome::Error(e) => *failures.entry(e.clone()).or_default() += 1,
+        }
+    }
+
+    let failed = total - latencies.len();
+    println!(
+        "requests: {total} in {:.1?} ({:.2}/s), {} ok, {failed} failed ({:.1}%)",
+        elapsed,
+        total as f64 / elapsed.as_secs_f64(),
+        latencies.len(),
+        if total == 0 { 0.0 } else { 100.0 * failed as f64 / total as f64 }
+    );
+    if let (Some(min), Some(max)) = (latencies.first(), latencies.last()) {
+        let pct = |p: f64| latencies[((latencies.len() - 1) as f64 * p).round() as usize];
+        println!(
+            "latency: min {:.2?}  p50 {:.2?}  p90 {:.2?}  p95 {:.2?}  p99 {:.2?}  max {:.2?}",
+            min,
+            pct(0.50),
+            pct(0.90),
+            pct(0.95),
+            pct(0.99),
+            max
+        );
+    }
+    for (reason, count) in failures {
+        println!("  {count} x {reason}");
+    }
+}
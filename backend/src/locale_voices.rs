@@ -0,0 +1,80 @@
+//! Voice presets by language, for requests that give neither a voice nor a
+//! description: an internationalized frontend sends its `locale` (or just
+//! the browser's `Accept-Language`) and gets the voice configured for it.
+
+use std::collections::BTreeMap;
+
+use axum::http::{header, HeaderMap};
+
+/// Checks the `locale_voices` config: keys are language tags such as `de`
+/// or `pt-BR`, values preset names.
+pub fn validate(voices: &BTreeMap<String, String>) -> anyhow::Result<()> {
+    for (tag, voice) in voices {
+        let valid = !tag.is_empty() && tag.split(['-', '_']).all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()));
+        if !valid {
+            anyhow::bail!("locale_voices: {tag:?} is not a language tag");
+        }
+        if voice.trim().is_empty() {
+            anyhow::bail!("locale_voices.{tag} must name a voice preset");
+        }
+    }
+    Ok(())
+}
+
+/// The language tags of an `Accept-Language` header, most preferred first.
+/// Ranges with `q=0` and `*` are left out.
+pub fn accepted(header: &str) -> Vec<String> {
+    let mut tags: Vec<(String, f64)> = header
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim();
+            let q = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f64>().ok())?;
+            (!tag.is_empty() && tag != "*" && q > 0.0).then(|| (tag.to_string(), q))
+        })
+        .collect();
+    // Stable, so equal weights keep the header's order.
+    tags.sort_by(|a, b| b.1.total_cmp(&a.1));
+    tags.into_iter().map(|(tag, _)| tag).collect()
+}
+
+/// The voice configured for the first of `tags` that has one `available`:
+/// the tag itself (`pt-BR`), then its language (`pt`).
+pub fn voice_for(voices: &BTreeMap<String, String>, tags: &[String], available: impl Fn(&str) -> bool) -> Option<String> {
+    let normalize = |tag: &str| tag.trim().replace('_', "-").to_ascii_lowercase();
+    let find = |wanted: &str| {
+        voices
+            .iter()
+            .find(|(tag, voice)| normalize(tag) == wanted && available(voice))
+            .map(|(_, voice)| voice.clone())
+    };
+    tags.iter().find_map(|tag| {
+        let tag = normalize(tag);
+        let language = tag.split('-').next().unwrap_or(&tag).to_string();
+        find(&tag).or_else(|| find(&language))
+    })
+}
+
+/// The voice for a request without one: by its explicit `locale` when it
+/// has one, otherwise by its `Accept-Language`.
+pub fn for_request(
+    voices: &BTreeMap<String, String>,
+    locale: Option<&str>,
+    headers: &HeaderMap,
+    available: impl Fn(&str) -> bool,
+) -> Option<String> {
+    if voices.is_empty() {
+        return None;
+    }
+    let tags = match locale {
+        Some(tag) => vec![tag.to_string()],
+        None => headers
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|v| v.to_str().ok())
+            .map(accepted)
+            .unwrap_or_default(),
+    };
+    voice_for(voices, &tags, available)
+}
@@ -0,0 +1,104 @@
+//! Integrated loudness after ITU-R BS.1770 (the measure EBU R 128 builds
+//! on), for normalizing clips to a LUFS target: K-weighting, mean square
+//! over 400 ms blocks overlapping by 75%, then the absolute gate at
+//! -70 LUFS and the relative gate 10 LU below the absolutely gated mean.
+
+use std::f64::consts::PI;
+
+/// Clips quieter than this RMS are left as they are, so normalizing
+/// doesn't amplify noise into speech-level hiss.
+const SILENCE_RMS: f32 = 2e-3;
+
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = 10.0;
+
+/// Integrated loudness of mono `samples`, in LUFS; None for clips shorter
+/// than one block or quieter than the absolute gate throughout.
+pub fn integrated(samples: &[f32], sample_rate: u32) -> Option<f64> {
+    let mut shelf = Biquad::high_shelf(sample_rate as f64);
+    let mut high_pass = Biquad::high_pass(sample_rate as f64);
+    // Mean squares of consecutive 100 ms windows; a block is four of them.
+    let window = (sample_rate / 10).max(1) as usize;
+    let mut windows = Vec::with_capacity(samples.len() / window + 1);
+    let (mut sum, mut count) = (0.0f64, 0);
+    for &x in samples {
+        let y = high_pass.apply(shelf.apply(x as f64));
+        sum += y * y;
+        count += 1;
+        if count == window {
+            windows.push(sum / window as f64);
+            (sum, count) = (0.0, 0);
+        }
+    }
+    let blocks: Vec<f64> = windows
+        .windows(4)
+        .map(|w| w.iter().sum::<f64>() / 4.0)
+        .filter(|&power| lufs(power) > ABSOLUTE_GATE_LUFS)
+        .collect();
+    if blocks.is_empty() {
+        return None;
+    }
+    let gate = lufs(blocks.iter().sum::<f64>() / blocks.len() as f64) - RELATIVE_GATE_LU;
+    let gated: Vec<f64> = blocks.into_iter().filter(|&power| lufs(power) > gate).collect();
+    (!gated.is_empty()).then(|| lufs(gated.iter().sum::<f64>() / gated.len() as f64))
+}
+
+/// The gain that brings `samples` to `target_lufs`; 1 for near-silent clips
+/// and ones too short to measure.
+pub fn normalize_gain(samples: &[f32], sample_rate: u32, target_lufs: f64) -> f64 {
+    let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len().max(1) as f32).sqrt();
+    if rms < SILENCE_RMS {
+        return 1.0;
+    }
+    integrated(samples, sample_rate).map_or(1.0, |loudness| 10f64.powf((target_lufs - loudness) / 20.0))
+}
+
+fn lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.max(1e-12).log10()
+}
+
+/// One stage of the K-weighting filter, with the coefficients of
+/// BS.1770 derived for the sample rate at hand.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    /// The +4 dB shelf above about 1.7 kHz modelling the head.
+    fn high_shelf(sample_rate: f64) -> Self {
+        let (gain_db, q, center_hz) = (3.999_843_853_973_347, 0.707_175_236_955_419_6, 1_681.974_450_955_533);
+        let k = (PI * center_hz / sample_rate).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.499_666_774_154_541_6);
+        let a0 = 1.0 + k / q + k * k;
+        Self {
+            b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    /// The high-pass at about 38 Hz (the RLB weighting).
+    fn high_pass(sample_rate: f64) -> Self {
+        let (q, center_hz) = (0.500_327_037_323_877_3, 38.135_470_876_024_44);
+        let k = (PI * center_hz / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        Self {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn apply(&mut self, x0: f64) -> f64 {
+        let y0 = self.b[0] * x0 + self.b[1] * self.x[0] + self.b[2] * self.x[1] - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+        self.x = [x0, self.x[0]];
+        self.y = [y0, self.y[0]];
+        y0
+    }
+}
@@ -5,25 +5,140 @@ extern crate intel_mkl_src;
 extern crate accelerate_src;
 
 use axum::{
-    extract::Multipart,
-    http::{header, StatusCode},
-    response::{IntoResponse, Response},
+    extract::{DefaultBodyLimit, Extension, FromRequest, Multipart, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::Response,
     routing::{get, post},
-    Router,
+    Json, Router,
 };
-use serde_json;
-use candle::{DType, Device, Error, IndexOp, Tensor};
-use candle_nn::VarBuilder;
-use candle_transformers::models::parler_tts::{Config, Model};
+use candle::Tensor;
+use base64::Engine;
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::sync::Arc;
-use tokenizers::Tokenizer;
+use futures::{StreamExt, TryStreamExt};
+use tokio::sync::OnceCell;
 use tower_http::{
+    catch_panic::CatchPanicLayer,
     cors::CorsLayer,
     services::ServeDir
 };
-use std::path::Path;
 use tracing_subscriber::fmt::init as tracing_init;
-use anyhow::Error as E;
+
+mod access_log;
+mod analysis;
+mod audio;
+mod audiobook;
+mod autoscale;
+mod bleep;
+mod budget;
+mod canary;
+mod chaos;
+mod chunking;
+mod coalesce;
+mod compression;
+mod config;
+mod crypto;
+mod engine;
+mod error;
+mod experiments;
+mod extract;
+mod features;
+mod fetch;
+mod fragments;
+mod generation;
+mod export;
+mod history;
+mod hub;
+mod import;
+mod info;
+mod interactive;
+mod json_body;
+mod listener;
+mod loadtest;
+mod locale_voices;
+mod loudness;
+mod m4b;
+mod memory;
+mod metrics;
+mod model;
+mod model_cache;
+mod models;
+mod namespace;
+mod numbers;
+mod openai;
+mod phrases;
+mod podcast;
+mod pool;
+mod privacy;
+mod relay;
+mod quality;
+mod reporting;
+mod rtc;
+mod sampler;
+mod schedule;
+mod self_check;
+mod slo;
+mod systemd;
+mod telegram;
+mod templates;
+mod token_cache;
+mod transcode;
+mod tts_jobs;
+mod tts_model;
+mod verify;
+mod versioning;
+mod voices;
+
+use access_log::RequestId;
+use compression::RouteGroup;
+use config::{Args, Command, RouteSet, ServerConfig};
+use crypto::Cipher;
+use budget::{Stage, StageTimings};
+use tts_model::{Sampling, Synthesis, TtsModel};
+use error::SynthesisError;
+use generation::{FinishReason, Stopping, TokenControls};
+use namespace::{Namespaces, Tenant};
+use pool::EnginePool;
+use privacy::PromptLogging;
+use quality::{Defect, QualityRetry};
+use sampler::SamplerKind;
+
+/// Upper bound for an `/api/tts` request (axum's default, reported in
+/// `/api/info`).
+const MAX_TTS_BYTES: usize = 2 * 1024 * 1024;
+
+/// Upper bound for uploaded reference recordings.
+const MAX_UPLOAD_BYTES: usize = 32 * 1024 * 1024;
+
+/// Longest `Idempotency-Key` accepted on job submission.
+const MAX_IDEMPOTENCY_KEY_BYTES: usize = 255;
+
+/// Upper bound for an `/api/audiobooks` request (a PDF, mostly).
+const MAX_DOCUMENT_BYTES: usize = 64 * 1024 * 1024;
+
+/// Upper bound for a history import (manifest plus all its audio).
+const MAX_IMPORT_BYTES: usize = 1024 * 1024 * 1024;
+
+/// Unsupported characters listed in `x-unsupported-characters`.
+const MAX_REPORTED_CHARS: usize = 32;
+
+/// Upper bound for the `max_steps` request field (about 45 s of audio).
+const MAX_STEPS_LIMIT: usize = 4096;
+
+/// Range of the `sample_rate` form field of /api/tts.
+const MIN_OUTPUT_RATE: u32 = 8000;
+const MAX_OUTPUT_RATE: u32 = 48000;
+
+/// How often expired clips are deleted.
+const CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+/// Subdirectory of `audio_dir` holding quick phrase renderings.
+const QUICK_PHRASE_DIR: &str = ".quick_phrases";
+/// Subdirectory of `audio_dir` holding the template fragment bank.
+const FRAGMENT_DIR: &str = ".fragments";
 
 
 async fn debug_endpoint() -> &'static str {
@@ -32,21 +147,344 @@ async fn debug_endpoint() -> &'static str {
 }
 
 
+#[derive(Clone)]
+pub struct AppState {
+    config: Arc<ServerConfig>,
+    /// Loaded once, on startup, so the first request doesn't pay for the
+    /// download and weight loading.
+    pool: Arc<OnceCell<Arc<EnginePool>>>,
+    namespaces: Arc<Namespaces>,
+    metrics: Arc<metrics::Metrics>,
+    phrases: Arc<phrases::PhraseCache>,
+    /// Static parts of `/api/tts/template` announcements.
+    template_parts: Arc<templates::PartCache>,
+    fragments: Arc<fragments::FragmentBank>,
+    #[cfg(feature = "webrtc")]
+    webrtc: Option<Arc<rtc::Sessions>>,
+    podcast: Option<Arc<podcast::Station>>,
+    jobs: Arc<audiobook::Jobs>,
+    tts_jobs: Arc<tts_jobs::TtsJobs>,
+    /// Set once the listeners are bound.
+    info: Arc<std::sync::OnceLock<info::ServerInfo>>,
+    experiments: Option<Arc<experiments::Tracker>>,
+    self_check: Option<Arc<self_check::Checker>>,
+    model_switch: Arc<canary::ModelSwitch>,
+    /// Checkpoints other than the default that requests asked for.
+    models: Arc<models::Registry>,
+    /// `/api/tts` generations in flight, with the id of the clip each makes.
+    inflight: Arc<coalesce::Coalescer<(Rendered, String)>>,
+}
+
+impl AppState {
+    /// The serving model: the one promoted through `/api/admin/model`, or
+    /// else the startup one, loading it on first use.
+    async fn pool(&self) -> anyhow::Result<Arc<EnginePool>> {
+        if let Some(pool) = self.model_switch.promoted() {
+            return Ok(pool);
+        }
+        self.pool
+            .get_or_try_init(|| async {
+                let models = &self.config.models;
+                let pool = models::load(&self.config, &models.repo, &models.revision).await?;
+                println!("serving with {} generation workers", pool.size());
+                systemd::notify("READY=1\nSTATUS=model loaded");
+                Ok(Arc::new(pool))
+            })
+            .await
+            .cloned()
+    }
+
+    /// The serving model, if it is loaded.
+    fn loaded_pool(&self) -> Option<Arc<EnginePool>> {
+        self.model_switch.promoted().or_else(|| self.pool.get().cloned())
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let bind = "0.0.0.0:8039";
+    let args = Args::parse();
+    let config = ServerConfig::load(&args)?;
+
+    if let Some(Command::Loadtest(loadtest)) = &args.command {
+        let public = config
+            .listeners()
+            .into_iter()
+            .find(|l| l.routes.contains(&RouteSet::Public))
+            .map(|l| l.bind);
+        let url = match (&loadtest.url, public) {
+            (Some(url), _) => url.clone(),
+            (None, Some(bind)) if listener::unix_path(&bind).is_none() => {
+                format!("http://{}", bind.replace("0.0.0.0", "127.0.0.1").replace("[::]", "[::1]"))
+            }
+            (None, _) => anyhow::bail!("no public TCP listener is configured; pass --url"),
+        };
+        return loadtest::run(&url, loadtest).await;
+    }
+    if let Some(Command::Verify(verify)) = &args.command {
+        return verify::run(&config, verify).await;
+    }
 
     tracing_init();
+    let _reporting = reporting::init(&config.error_reporting)?;
+
+    let cipher = config.encryption_key.as_deref().map(Cipher::from_hex_key).transpose()?;
+    if cipher.is_some() {
+        println!("encrypting generated audio and history at rest");
+    }
+    if let Some(chaos) = &config.chaos {
+        println!("WARNING: failure injection is on: {chaos:?}");
+    }
+    let state = AppState {
+        namespaces: Arc::new(Namespaces::from_config(&config, cipher.clone())?),
+        phrases: Arc::new(phrases::PhraseCache::new(history::History::new(
+            config.audio_dir.join(QUICK_PHRASE_DIR),
+            cipher.clone(),
+        ))),
+        fragments: Arc::new(fragments::FragmentBank::new(history::History::new(
+            config.audio_dir.join(FRAGMENT_DIR),
+            cipher,
+        ))),
+        #[cfg(feature = "webrtc")]
+        webrtc: config.webrtc.as_ref().map(rtc::Sessions::new).transpose()?.map(Arc::new),
+        podcast: config
+            .podcast
+            .as_ref()
+            .map(|podcast| podcast::Station::new(podcast, &config.audio_dir))
+            .transpose()?
+            .map(Arc::new),
+        experiments: experiments::Tracker::new(&config.experiments)?.map(Arc::new),
+        self_check: self_check::Checker::new(&config.self_check)?.map(Arc::new),
+        model_switch: Arc::new(canary::ModelSwitch::default()),
+        models: Arc::default(),
+        inflight: Arc::new(coalesce::Coalescer::default()),
+        tts_jobs: Arc::new(tts_jobs::TtsJobs::new(&config.queue)),
+        template_parts: Arc::default(),
+        config: Arc::new(config),
+        pool: Arc::new(OnceCell::new()),
+        metrics: Arc::new(metrics::Metrics::default()),
+        jobs: Arc::new(audiobook::Jobs::new()?),
+        info: Arc::new(std::sync::OnceLock::new()),
+    };
+
+    let cleanup = state.namespaces.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            for namespace in cleanup.all() {
+                let history = namespace.history.clone();
+                let now = unix_now();
+                let name = &namespace.name;
+                match tokio::task::spawn_blocking(move || history.remove_expired(now)).await {
+                    Ok(Ok(0)) => {}
+                    Ok(Ok(removed)) => println!("history[{name}]: removed {removed} expired clips"),
+                    Ok(Err(e)) => println!("history[{name}]: cleanup failed: {e:#}"),
+                    Err(e) => println!("history[{name}]: cleanup task panicked: {e}"),
+                }
+            }
+        }
+    });
+
+    let prefetch = state.clone();
+    systemd::notify("STATUS=loading the model");
+    tokio::spawn(async move {
+        match prefetch.pool().await {
+            Ok(pool) => render_quick_phrases(&prefetch, &pool).await,
+            Err(e) => println!("model load failed, retrying on first request: {e:#}"),
+        }
+    });
 
-let api_routes = Router::new()
-    .route("/tts", post(generate_tts))
-    .route("/health", get(health_check))
-    .route("/debug", get(debug_endpoint));
+    #[cfg(feature = "telegram")]
+    if let Some(bot) = &state.config.telegram {
+        telegram::start(&state, bot)?;
+    }
+    if let Some(station) = &state.podcast {
+        podcast::start(&state, station.clone())?;
+    }
+    schedule::start(&state, &state.config.schedules)?;
+
+    let mut listeners = Vec::new();
+    let activated = systemd::activated_listeners()?;
+    let configured = state.config.listeners();
+    if activated.is_empty() {
+        for listener_config in configured {
+            let listener = listener::Listener::bind(&listener_config.bind).await?;
+            listeners.push((listener, listener_config.routes));
+        }
+    } else {
+        // Passed sockets take the configured route sets in order; any
+        // beyond those only serve the public routes.
+        if activated.len() > configured.len() {
+            println!(
+                "systemd: {} sockets passed for {} listeners, the rest serve public routes only",
+                activated.len(),
+                configured.len()
+            );
+        }
+        for (k, listener) in activated.into_iter().enumerate() {
+            let routes = configured.get(k).map_or_else(|| vec![RouteSet::Public], |c| c.routes.clone());
+            listeners.push((listener, routes));
+        }
+    }
+
+    let limits = info::Limits {
+        chunk_chars: state.config.chunk_chars,
+        max_steps: MAX_STEPS_LIMIT,
+        min_sample_rate: MIN_OUTPUT_RATE,
+        max_sample_rate: MAX_OUTPUT_RATE,
+        max_tts_bytes: MAX_TTS_BYTES,
+        max_upload_bytes: MAX_UPLOAD_BYTES,
+        max_document_bytes: MAX_DOCUMENT_BYTES,
+        max_import_bytes: MAX_IMPORT_BYTES,
+    };
+    let addresses = listeners
+        .iter()
+        .map(|(listener, routes)| info::ListenerInfo {
+            address: listener.describe(),
+            routes: routes.clone(),
+        })
+        .collect();
+    let report = state.info.get_or_init(|| info::ServerInfo::new(&state.config, limits, addresses));
+    println!("{}", report.banner());
+    println!("startup: {}", serde_json::to_string(report)?);
+
+    let mut servers = Vec::new();
+    for (listener, routes) in listeners {
+        servers.push(listener.serve(router(state.clone(), &routes)));
+    }
+    println!("Serving static files from: ./public/");
+    futures::future::try_join_all(servers).await?;
+
+    Ok(())
+}
 
 
-    let app = Router::new()
-        .nest("/api", api_routes)
-        .fallback_service(ServeDir::new("public").not_found_service(
+/// The routes in `routes`, plus `/api/health` on every listener. Public
+/// listeners also serve the frontend, admin ones `/metrics`.
+fn router(state: AppState, routes: &[RouteSet]) -> Router {
+    let access_log = Arc::new(state.config.access_log.clone());
+    let compression = &state.config.compression;
+    let versioning = Arc::new(state.config.api.clone());
+    let mut api = Router::new().route("/health", get(health_check));
+    if routes.contains(&RouteSet::Public) {
+        api = api
+            .route("/tts", post(generate_tts).layer(DefaultBodyLimit::max(MAX_TTS_BYTES)))
+            .route("/tts/template", post(template_tts).layer(DefaultBodyLimit::max(MAX_TTS_BYTES)))
+            .route("/tts/template/fragments", get(fragment_bank))
+            .route("/tts/template/fragments/rebuild", post(rebuild_fragment_bank))
+            .route("/tts/relay", get(relay_tts))
+            .route("/tts/ws", get(tts_session))
+            .route(
+                "/history/{id}/replay",
+                post(replay_clip).layer(DefaultBodyLimit::max(MAX_TTS_BYTES)),
+            )
+            .route(
+                "/describe",
+                post(describe_voice).layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES)),
+            )
+            .route(
+                "/similarity",
+                post(speaker_similarity).layer(DefaultBodyLimit::max(2 * MAX_UPLOAD_BYTES)),
+            )
+            .route("/transcode", post(transcode_clip))
+            .route("/audio/concat", post(concat_clips))
+            .merge(compression.wrap(
+                RouteGroup::History,
+                Router::new()
+                    .route("/history", get(list_history))
+                    .route("/history/export", get(export_history))
+                    .route("/history/compare", get(compare_clips))
+                    .route(
+                        "/history/import",
+                        post(import_history).layer(DefaultBodyLimit::max(MAX_IMPORT_BYTES)),
+                    )
+                    .route("/history/{id}/audio", get(history_audio)),
+            ))
+            .merge(compression.wrap(RouteGroup::Usage, Router::new().route("/usage", get(usage_report))))
+            .merge(compression.wrap(
+                RouteGroup::Stats,
+                Router::new()
+                    .route("/stats", get(server_stats))
+                    .route("/info", get(server_info)),
+            ))
+            .merge(compression.wrap(
+                RouteGroup::Voices,
+                Router::new()
+                    .route("/voices", get(list_voices))
+                    .route("/voices/{name}", axum::routing::put(update_voice))
+                    .route("/voices/{name}/versions", get(voice_versions))
+                    .route("/voices/{name}/rollback", post(rollback_voice)),
+            ))
+            .merge(compression.wrap(
+                RouteGroup::Text,
+                Router::new()
+                    .route(
+                        "/estimate",
+                        post(estimate_text).layer(DefaultBodyLimit::max(MAX_DOCUMENT_BYTES)),
+                    )
+                    .route(
+                        "/chunks",
+                        post(split_text).layer(DefaultBodyLimit::max(MAX_DOCUMENT_BYTES)),
+                    ),
+            ))
+            .merge(compression.wrap(
+                RouteGroup::Jobs,
+                Router::new()
+                    .route(
+                        "/audiobooks",
+                        post(create_audiobook).layer(DefaultBodyLimit::max(MAX_DOCUMENT_BYTES)),
+                    )
+                    .route("/jobs/{id}", get(job_status))
+                    .route("/jobs/{id}/audio", get(job_audio))
+                    .route("/jobs/{id}/chunks", get(job_chunks))
+                    .route("/jobs/{id}/chunks/{n}/audio", get(job_chunk_audio))
+                    .route("/jobs/{id}/chunks/{n}/rerender", post(rerender_job_chunk))
+                    .route("/jobs/{id}/assemble", post(assemble_job))
+                    .route(
+                        "/tts/jobs",
+                        post(create_tts_job).layer(DefaultBodyLimit::max(MAX_TTS_BYTES)),
+                    )
+                    .route("/tts/jobs/{id}", get(tts_job_status))
+                    .route("/tts/jobs/{id}/audio", get(tts_job_audio))
+                    .route("/tts/jobs/{id}/events", get(tts_job_events)),
+            ));
+        #[cfg(feature = "webrtc")]
+        {
+            api = api
+                .route("/webrtc/offer", post(webrtc_offer))
+                .route("/webrtc/{session}", axum::routing::delete(webrtc_hangup));
+        }
+    }
+    let mut app = Router::new();
+    if routes.contains(&RouteSet::Admin) {
+        let admin = Router::new()
+            .route("/model", get(model_status).post(switch_model))
+            .route("/model/cache", get(model_cache_report).delete(purge_model_cache))
+            .route("/debug", get(debug_endpoint));
+        api = api
+            .nest("/admin", compression.wrap(RouteGroup::Admin, admin))
+            .merge(compression.wrap(RouteGroup::Admin, Router::new().route("/autoscale", get(autoscale_signal))));
+        app = app.merge(compression.wrap(RouteGroup::Admin, Router::new().route("/metrics", get(metrics_report))));
+    }
+
+    if routes.contains(&RouteSet::Public) {
+        // Where OpenAI's SDKs look, given this server as their base URL.
+        app = app.route("/v1/audio/speech", post(openai_speech));
+    }
+    if routes.contains(&RouteSet::Public) && state.podcast.is_some() {
+        // Outside /api, since podcast apps can't send API keys.
+        app = app
+            .route("/podcast/feed.xml", get(podcast_feed))
+            .route("/podcast/episodes/{file}", get(podcast_episode));
+    }
+
+    // Unversioned paths are an alias of the current version.
+    let mut app = app
+        .nest("/api", api.clone())
+        .nest(&format!("/api/v{}", versioning::CURRENT), api)
+        .with_state(state);
+    if routes.contains(&RouteSet::Public) {
+        app = app.fallback_service(ServeDir::new("public").not_found_service(
             tower::service_fn(|_| async {
                 let body = std::fs::read_to_string("public/index.html")
                     .unwrap_or_else(|_| "404 Not Found".to_string());
@@ -57,211 +495,2985 @@ let api_routes = Router::new()
                         .unwrap()
                 )
             })
-        ))
-        .layer(CorsLayer::permissive());
+        ));
+    }
+    app.layer(middleware::from_fn_with_state(versioning, versioning::layer))
+        .layer(middleware::from_fn_with_state(access_log, access_log::layer))
+        .layer(CorsLayer::permissive())
+        // Turns a panic in a handler into a 500 for that request.
+        .layer(CatchPanicLayer::new())
+}
 
-    let listener = tokio::net::TcpListener::bind(bind).await?;
-    println!("Server running on http://{}", bind);
-    println!("Serving static files from: ./public/");
-    
-    axum::serve(listener, app).await?;
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    /// Where the serving model runs; absent until it has loaded.
+    device: Option<String>,
+}
 
-    Ok(())
+async fn health_check(State(state): State<AppState>) -> Json<Health> {
+    Json(Health {
+        status: "ok",
+        device: state.loaded_pool().map(|pool| pool.engine().device().to_string()),
+    })
+}
+
+/// Form fields of `/api/tts` the history keeps no settings for: the text
+/// and voice are in the record itself, the rest shapes the response only or
+/// went into the recorded prompt already.
+const NOT_REPLAYED: &[&str] = &[
+    "text",
+    "description",
+    "voice",
+    "features",
+    "locale",
+    "spell",
+    "stream",
+    "format",
+    "sample_rate",
+    "webrtc_session",
+];
+
+async fn generate_tts(
+    State(state): State<AppState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Tenant(namespace): Tenant,
+    headers: HeaderMap,
+    request: axum::extract::Request,
+) -> Result<Response, SynthesisError> {
+    let fields = tts_fields(&state, request_id, &headers, request).await?;
+    synthesize(state, request_id, namespace, &headers, fields).await
+}
+
+/// Queues an `/api/tts` request to render in the background. Answers
+/// `202` with the job's status and its `Location`.
+async fn create_tts_job(
+    State(state): State<AppState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Tenant(namespace): Tenant,
+    headers: HeaderMap,
+    request: axum::extract::Request,
+) -> Result<Response, SynthesisError> {
+    let fields = tts_fields(&state, request_id, &headers, request).await?;
+    synthesize_or_queue(state, request_id, namespace, &headers, fields, true).await
+}
+
+async fn tts_job_status(
+    State(state): State<AppState>,
+    Tenant(namespace): Tenant,
+    Path(id): Path<String>,
+) -> Result<Json<tts_jobs::JobStatus>, StatusCode> {
+    state.tts_jobs.status(&namespace.name, &id).map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+/// A TTS job's progress, as server-sent events until it finishes.
+async fn tts_job_events(
+    State(state): State<AppState>,
+    Tenant(namespace): Tenant,
+    Path(id): Path<String>,
+) -> Result<Response, StatusCode> {
+    let events = state.tts_jobs.events(&namespace.name, &id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .header("x-job-id", id)
+        .body(axum::body::Body::from_stream(events))
+        .unwrap())
+}
+
+/// A finished TTS job's audio, in the format it was submitted with.
+async fn tts_job_audio(
+    State(state): State<AppState>,
+    Tenant(namespace): Tenant,
+    Path(id): Path<String>,
+) -> Result<Response, SynthesisError> {
+    let jobs = state.tts_jobs.clone();
+    let lookup = id.clone();
+    let (audio, content_type) = tokio::task::spawn_blocking(move || jobs.audio(&namespace, &lookup))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)??;
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header("x-job-id", id)
+        .body(axum::body::Body::from(audio))
+        .unwrap())
+}
+
+/// The fields of an `/api/tts` body, a JSON object or a multipart form.
+async fn tts_fields(
+    state: &AppState,
+    request_id: u64,
+    headers: &HeaderMap,
+    request: axum::extract::Request,
+) -> Result<Vec<(String, String)>, SynthesisError> {
+    let json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("application/json"));
+    if json {
+        let body = axum::body::to_bytes(request.into_body(), MAX_TTS_BYTES)
+            .await
+            .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?;
+        json_body::fields(&body, MAX_STEPS_LIMIT).map_err(|errors| {
+            println!("tts[{request_id}]: {} invalid fields in the JSON body", errors.len());
+            SynthesisError::InvalidFields(errors)
+        })
+    } else {
+        let mut multipart = Multipart::from_request(request, state).await.map_err(|_| StatusCode::BAD_REQUEST)?;
+        let mut fields = Vec::new();
+        while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
+            let name = field.name().unwrap_or("").to_string();
+            let data = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+            fields.push((name, data));
+        }
+        Ok(fields)
+    }
+}
+
+/// OpenAI's `POST /v1/audio/speech`, rendered as an `/api/tts` request
+/// and converted to the `response_format` asked for.
+async fn openai_speech(
+    State(state): State<AppState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Tenant(namespace): Tenant,
+    headers: HeaderMap,
+    Json(request): Json<openai::SpeechRequest>,
+) -> Result<Response, SynthesisError> {
+    let format = openai::ResponseFormat::parse(request.response_format.as_deref()).ok_or_else(|| {
+        println!("tts[{request_id}]: openai: unsupported response_format {:?}", request.response_format);
+        StatusCode::BAD_REQUEST
+    })?;
+    let default_description = &state.config.default_description;
+    let fields = openai::tts_fields(&state.config.openai, &namespace, default_description, &request, format)
+        .map_err(|e| {
+            println!("tts[{request_id}]: openai: {e}");
+            StatusCode::BAD_REQUEST
+        })?;
+    println!("tts[{request_id}]: openai: model {:?}, voice {:?}", request.model, request.voice);
+    let response = synthesize(state, request_id, namespace, &headers, fields).await?;
+    if !format.converts() {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    let wav = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| SynthesisError::Encode(e.into()))?;
+    let (audio, sample_rate) = tokio::task::spawn_blocking(move || format.convert(&wav))
+        .await
+        .map_err(|e| SynthesisError::Encode(e.into()))?
+        .map_err(SynthesisError::Encode)?;
+    parts.headers.insert(header::CONTENT_TYPE, format.content_type(sample_rate).parse().unwrap());
+    parts.headers.remove(header::CONTENT_DISPOSITION);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Ok(Response::from_parts(parts, axum::body::Body::from(audio)))
+}
+
+/// Upgrades to the text-in, speech-out socket of [`relay`]. The query
+/// string takes the `/api/tts` fields every sentence is rendered with.
+async fn relay_tts(
+    State(state): State<AppState>,
+    Tenant(namespace): Tenant,
+    headers: HeaderMap,
+    Query(settings): Query<Vec<(String, String)>>,
+    upgrade: axum::extract::WebSocketUpgrade,
+) -> Result<Response, StatusCode> {
+    if let Some((name, _)) = settings.iter().find(|(name, _)| relay::NOT_SETTINGS.contains(&name.as_str())) {
+        println!("relay: {name} can't be set in the query string");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(upgrade.on_upgrade(move |socket| relay::run(socket, state, namespace, headers, settings)))
+}
+
+/// Upgrades to the one-segment-at-a-time socket of [`interactive`]. The
+/// query string takes the `/api/tts` fields the session starts with.
+async fn tts_session(
+    State(state): State<AppState>,
+    Tenant(namespace): Tenant,
+    headers: HeaderMap,
+    Query(settings): Query<Vec<(String, String)>>,
+    upgrade: axum::extract::WebSocketUpgrade,
+) -> Result<Response, StatusCode> {
+    if let Some((name, _)) = settings.iter().find(|(name, _)| interactive::NOT_SETTINGS.contains(&name.as_str())) {
+        println!("session: {name} can't be set in the query string");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(upgrade.on_upgrade(move |socket| interactive::run(socket, state, namespace, headers, settings)))
 }
 
+/// Renders a clip from the history again with the settings it was made
+/// with, each form field sent replacing the recorded one: `seed=7` for
+/// another take, `description=...` for a different delivery.
+async fn replay_clip(
+    State(state): State<AppState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Tenant(namespace): Tenant,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    multipart: Result<Multipart, axum::extract::multipart::MultipartRejection>,
+) -> Result<Response, SynthesisError> {
+    let history = namespace.history.clone();
+    let lookup = id.clone();
+    let record = tokio::task::spawn_blocking(move || history.record(&lookup))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| {
+            println!("history: reading {id} failed: {e:#}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    // Without settings the clip predates replays, or its text was recorded
+    // sanitized.
+    let Some(settings) = record.settings else {
+        return Err(StatusCode::CONFLICT.into());
+    };
+    let mut overrides = BTreeMap::new();
+    // No body replays the clip as it was.
+    if let Ok(mut multipart) = multipart {
+        while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
+            let name = field.name().unwrap_or("").to_string();
+            let data = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+            overrides.insert(name, data);
+        }
+    }
+    let mut fields = settings;
+    fields.insert("text".to_string(), record.prompt);
+    // A new voice or description replaces both: the recorded description
+    // is the old voice's.
+    if !overrides.contains_key("voice") && !overrides.contains_key("description") {
+        fields.insert("description".to_string(), record.description);
+        if let Some(voice) = record.voice {
+            fields.insert("voice".to_string(), voice);
+        }
+    }
+    if !record.features.is_empty() {
+        fields.insert("features".to_string(), features::header_value(&record.features));
+    }
+    fields.extend(overrides);
+    println!("tts[{request_id}]: replaying {id}");
+    synthesize(state, request_id, namespace, &headers, fields.into_iter().collect()).await
+}
 
-async fn health_check() -> &'static str {
-    "OK"
+/// Renders an `/api/tts` request from its form fields.
+async fn synthesize(
+    state: AppState,
+    request_id: u64,
+    namespace: Arc<namespace::Namespace>,
+    headers: &HeaderMap,
+    fields: Vec<(String, String)>,
+) -> Result<Response, SynthesisError> {
+    synthesize_or_queue(state, request_id, namespace, headers, fields, false).await
 }
 
-async fn generate_tts(mut multipart: Multipart) -> Result<Response, StatusCode> {
+/// [`synthesize`], or with `queued` the same checks and then the job put on
+/// the `/api/tts/jobs` queue instead of rendered.
+async fn synthesize_or_queue(
+    state: AppState,
+    request_id: u64,
+    namespace: Arc<namespace::Namespace>,
+    headers: &HeaderMap,
+    fields: Vec<(String, String)>,
+    queued: bool,
+) -> Result<Response, SynthesisError> {
+    let received = std::time::Instant::now();
+    let settings: BTreeMap<String, String> = fields
+        .iter()
+        .filter(|(name, _)| !NOT_REPLAYED.contains(&name.as_str()))
+        .cloned()
+        .collect();
     let mut text = String::new();
     let mut description = String::new();
+    let mut voice: Option<String> = None;
     let mut temperature: Option<f64> = None;
     let mut seed: Option<u64> = None;
     let mut top_p: Option<f64> = None;
+    let mut tokens = TokenControls::default();
+    let mut stopping = state.config.max_steps.stopping();
+    let mut sampler_name: Option<String> = None;
+    let mut sampler_params: Vec<(String, f64)> = Vec::new();
+    let mut post_process = PostProcess::default();
+    // Anything beyond text and voice rules out the quick phrase cache.
+    let mut tuned = false;
+    let mut stream: Option<bool> = None;
+    // `stream=incremental`: decode as the codes come, not chunk by chunk.
+    let mut incremental = false;
+    let mut features = Vec::new();
+    if let Some(value) = headers.get(features::HEADER) {
+        let value = value.to_str().map_err(|_| StatusCode::BAD_REQUEST)?;
+        state.config.features.parse_into(value, &mut features).ok_or(StatusCode::BAD_REQUEST)?;
+    }
+    let mut format = audio::OutputFormat::Wav;
+    let mut output_rate: Option<u32> = None;
+    let mut webrtc_session: Option<String> = None;
+    let mut locale: Option<numbers::Locale> = None;
+    let mut locale_tag: Option<String> = None;
+    let mut bleep = false;
+    let mut reject_unsupported = false;
+    let mut spell = false;
+    let mut interactive = false;
+    let mut model: Option<String> = None;
+    let mut self_check: Option<bool> = None;
 
-    // Extract form data
-    while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
-        let name = field.name().unwrap_or("").to_string();
-        let data = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
-
+    for (name, data) in fields {
+        if !matches!(
+            name.as_str(),
+            "text"
+                | "description"
+                | "voice"
+                | "stream"
+                | "format"
+                | "sample_rate"
+                | "webrtc_session"
+                | "features"
+                | "locale"
+                | "reject_unsupported"
+                | "spell"
+                | "interactive"
+                | "self_check"
+        ) {
+            tuned = true;
+        }
         match name.as_str() {
             "text" => text = data,
             "description" => description = data,
+            "voice" => voice = Some(data).filter(|v| !v.is_empty()),
+            "stream" if data.trim() == "incremental" => {
+                stream = Some(true);
+                incremental = true;
+            }
+            "stream" => stream = Some(parse_flag(&data).ok_or(StatusCode::BAD_REQUEST)?),
+            "features" => state
+                .config
+                .features
+                .parse_into(&data, &mut features)
+                .ok_or(StatusCode::BAD_REQUEST)?,
+            "webrtc_session" => webrtc_session = Some(data.trim().to_string()).filter(|s| !s.is_empty()),
+            "locale" if !data.trim().is_empty() => {
+                // Languages the numbers can't be read in are still good
+                // for picking a voice.
+                let tag = data.trim().to_string();
+                locale = numbers::Locale::parse(&tag);
+                let has_voice = || {
+                    locale_voices::voice_for(&state.config.locale_voices, std::slice::from_ref(&tag), |_| true).is_some()
+                };
+                if locale.is_none() && !has_voice() {
+                    return Err(StatusCode::BAD_REQUEST.into());
+                }
+                locale_tag = Some(tag);
+            }
+            "format" => format = audio::OutputFormat::parse(&data).ok_or(StatusCode::BAD_REQUEST)?,
+            "sample_rate" => {
+                output_rate = Some(
+                    data.trim()
+                        .parse()
+                        .ok()
+                        .filter(|rate| (MIN_OUTPUT_RATE..=MAX_OUTPUT_RATE).contains(rate))
+                        .ok_or(StatusCode::BAD_REQUEST)?,
+                )
+            }
             "temperature" => temperature = data.parse().ok(),
             "seed" => seed = data.parse().ok(),
             "top_p" => top_p = data.parse().ok(),
+            "banned_tokens" => tokens.banned = parse_token_ids(&data).ok_or(StatusCode::BAD_REQUEST)?,
+            "forced_tokens" => tokens.forced = parse_token_ids(&data).ok_or(StatusCode::BAD_REQUEST)?,
+            "max_steps" => {
+                stopping.per_token = None;
+                stopping.max_steps = data
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|n| (1..=MAX_STEPS_LIMIT).contains(n))
+                    .ok_or(StatusCode::BAD_REQUEST)?
+            }
+            "min_steps" => stopping.min_steps = data.trim().parse().map_err(|_| StatusCode::BAD_REQUEST)?,
+            "interactive" => interactive = parse_flag(&data).ok_or(StatusCode::BAD_REQUEST)?,
+            "model" => model = Some(data.trim().to_string()).filter(|m| !m.is_empty()),
+            "self_check" => self_check = Some(parse_flag(&data).ok_or(StatusCode::BAD_REQUEST)?),
+            "stop_on_eos" => stopping.stop_on_eos = parse_flag(&data).ok_or(StatusCode::BAD_REQUEST)?,
+            "sampler" => sampler_name = Some(data.trim().to_ascii_lowercase()),
+            "typical_mass" | "mirostat_tau" | "mirostat_eta" | "temperature_end" => {
+                let value = data.trim().parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+                sampler_params.push((name, value));
+            }
+            "raw" => post_process.raw = parse_flag(&data).ok_or(StatusCode::BAD_REQUEST)?,
+            "normalize" => post_process.normalize = parse_flag(&data).ok_or(StatusCode::BAD_REQUEST)?,
+            "compress" => post_process.compress = parse_flag(&data).ok_or(StatusCode::BAD_REQUEST)?,
+            "bleep" => bleep = parse_flag(&data).ok_or(StatusCode::BAD_REQUEST)?,
+            "spell" => spell = parse_flag(&data).ok_or(StatusCode::BAD_REQUEST)?,
+            "reject_unsupported" => reject_unsupported = parse_flag(&data).ok_or(StatusCode::BAD_REQUEST)?,
+            "loudness_target" => {
+                post_process.loudness_target = data
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|lufs| (-70.0..=0.0).contains(lufs))
+                    .ok_or(StatusCode::BAD_REQUEST)?
+            }
             _ => {}
         }
     }
 
-    if text.is_empty() || description.is_empty() {
-        return Err(StatusCode::BAD_REQUEST);
+    // Unsupported characters are reported at their place in the text as
+    // sent, not as spelled out.
+    let written = (spell || locale.is_some()).then(|| text.clone());
+    if spell {
+        text = numbers::spell(&text, locale.unwrap_or(numbers::Locale::english()), &state.config.spelling);
+    } else if let Some(locale) = locale {
+        text = numbers::verbalize(&text, locale);
     }
 
-    // Generate unique filename
-    let timestamp = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    let filename = format!("generated_audio_{}.wav", timestamp);
-    let filepath = format!("./public/audio/{}", filename);
+    let locale_voice = if voice.is_none() && description.is_empty() {
+        locale_voices::for_request(&state.config.locale_voices, locale_tag.as_deref(), headers, |name| {
+            namespace.voices.get(name).is_some()
+        })
+    } else {
+        None
+    };
+    if let Some(name) = &locale_voice {
+        println!("tts[{request_id}]: using voice {name:?} for the request's language");
+        voice = Some(name.clone());
+    }
+    let mut voice_version = None;
+    if let Some(name) = &voice {
+        let current = namespace.voices.get(name).ok_or(StatusCode::BAD_REQUEST)?;
+        if description.is_empty() {
+            description = current.preset.description.clone();
+        }
+        temperature = temperature.or(current.preset.temperature);
+        seed = seed.or(current.preset.seed);
+        top_p = top_p.or(current.preset.top_p);
+        voice_version = Some(current.version);
+    }
+    if description.is_empty() {
+        description = state.config.default_description.clone();
+    }
+    if text.is_empty() || description.is_empty() || stopping.min_steps > stopping.max_steps {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    let self_check = match (self_check, &state.self_check) {
+        (Some(true), None) => {
+            println!("tts[{request_id}]: self_check needs a [self_check] url");
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+        (asked, checker) => asked.unwrap_or_else(|| checker.as_ref().is_some_and(|c| c.always())),
+    };
+    // Interactive requests may have a smaller checkpoint of their own.
+    let model = model.or_else(|| state.config.slo.model.clone().filter(|_| interactive));
+    let model = match model.as_deref().map(|name| state.config.models.resolve(name)).transpose() {
+        Ok(checkpoint) => checkpoint.flatten().map(|(repo, revision)| (repo.to_string(), revision.to_string())),
+        Err(e) => {
+            println!("tts[{request_id}]: {e}");
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+    };
+    if let (Some(fixed), Some(rate)) = (format.fixed_rate(), output_rate) {
+        if fixed != rate {
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+    }
+    let output_rate = format.fixed_rate().or(output_rate);
+    // Features only fill in what the request leaves unset.
+    let default_sampler = if features.contains(&features::Feature::MirostatSampler) { "mirostat" } else { "stock" };
+    let sampler_name = sampler_name.unwrap_or_else(|| default_sampler.to_string());
+    let sampler = parse_sampler(&sampler_name, &sampler_params).ok_or(StatusCode::BAD_REQUEST)?;
+    // Interactive mode streams unless told otherwise, and bounds every
+    // chunk so none holds up the ones after it for long.
+    let slo = interactive.then(|| state.config.slo.target(received));
+    if interactive {
+        if stream.is_none() {
+            stream = Some(true);
+            incremental = true;
+        }
+        stopping.max_steps = stopping.max_steps.min(state.config.slo.max_steps).max(stopping.min_steps);
+    }
+    let chunk_chars = match interactive {
+        true => state.config.chunk_chars.min(state.config.slo.chunk_chars),
+        false => state.config.chunk_chars,
+    };
+    // Server-sent events carry the same stream, base64 in `audio` events.
+    let sse = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/event-stream"));
+    let stream = sse || stream.unwrap_or(features.contains(&features::Feature::StreamingDecode));
+    post_process.peak_normalize = features.contains(&features::Feature::PeakNormalizer);
+    tuned |= features.iter().any(|f| f.changes_output());
+
+    // Quick phrases are rendered with the preset's own description only.
+    let repeatable = !tuned
+        && voice
+            .as_ref()
+            .and_then(|name| namespace.voices.get(name))
+            .is_some_and(|current| current.preset.description == description && !current.preset.has_sampling());
+    let is_phrase = repeatable && state.config.quick_phrases.iter().any(|p| p.trim() == text.trim());
+    // A cached phrase goes out as a plain response, which an event stream
+    // client can't read.
+    let quick_phrase = state.phrases.get(&description, &text).filter(|_| is_phrase && !sse);
 
-    // Ensure audio directory exists
-    std::fs::create_dir_all("./public/audio").map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Quick phrases render at full length, as they are kept for good.
+    let mut downgraded_max_steps = None;
+    let cap = state.config.budgets.max_steps(state.metrics.secs_per_step());
+    if let Some(cap) = cap.filter(|_| !is_phrase) {
+        let cap = cap.max(stopping.min_steps);
+        if cap < stopping.max_steps {
+            println!(
+                "tts[{request_id}]: lowering max_steps from {} to {cap} to fit the generate budget",
+                stopping.max_steps
+            );
+            stopping.max_steps = cap;
+            downgraded_max_steps = Some(cap);
+            state.metrics.record_downgrade();
+        }
+    }
+
+    // Generate unique clip id
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap();
+    let clip_id = format!("generated_audio_{}_{}", now.as_secs(), request_id);
 
-    // Create WAV file
-    let create_wav_args = CreateWavArgs {
+    let create_wav_args = Arc::new(CreateWavArgs {
+        request_id,
         description,
         prompt: text,
-        out_file: filepath.clone(),
         temperature,
         seed,
         top_p,
+        post_process,
+        tokens,
+        sampler,
+        stopping,
+        retry_degenerate: state.config.retry_degenerate,
+        wav_format: state.config.wav_format,
+        bleep: Some(state.config.bleep.clone()).filter(|b| bleep && !b.words.is_empty()),
+        progress: None,
+    });
+    println!("{}", create_wav_args.log_line(state.config.log_prompts));
+    // Bleeping finds the words in a whole chunk, after it is sent.
+    if incremental && create_wav_args.bleep.is_some() {
+        println!("tts[{request_id}]: bleep can't be applied to an incremental stream");
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    if let Some(chaos) = &state.config.chaos {
+        if chaos.fail() {
+            println!("tts[{request_id}]: chaos: failing the request");
+            return Err(SynthesisError::Generate(anyhow::anyhow!("chaos: injected failure")));
+        }
+        chaos.delay(&format!("tts[{request_id}]")).await;
+    }
+
+    let pool = match &quick_phrase {
+        Some(_) => None,
+        None => {
+            let pool = match &model {
+                Some((repo, revision)) => state.models.pool(&state.config, repo, revision).await,
+                None => state.pool().await,
+            };
+            let pool = pool.map_err(|e| {
+                println!("model unavailable: {e:#}");
+                SynthesisError::ModelLoad(e)
+            })?;
+            match pool.engine().capabilities().audio_vocab_size {
+                Some(vocab_size) => {
+                    if let Some(id) = create_wav_args.tokens.out_of_range(vocab_size) {
+                        println!("tts[{request_id}]: token id {id} is outside the audio vocabulary");
+                        return Err(StatusCode::BAD_REQUEST.into());
+                    }
+                }
+                None if !create_wav_args.tokens.is_empty() => {
+                    println!("tts[{request_id}]: the model takes no banned or forced tokens");
+                    return Err(StatusCode::BAD_REQUEST.into());
+                }
+                None => {}
+            }
+            Some(pool)
+        }
+    };
+    let unsupported = match &pool {
+        Some(pool) => pool
+            .engine()
+            .unsupported(written.as_deref().unwrap_or(&create_wav_args.prompt))
+            .map_err(SynthesisError::Tokenize)?,
+        None => Vec::new(),
+    };
+    if !unsupported.is_empty() {
+        println!("tts[{request_id}]: {} unsupported characters in the text", unsupported.len());
+        if reject_unsupported {
+            return Err(SynthesisError::UnsupportedCharacters(unsupported));
+        }
+    }
+    let job = TtsJob {
+        state: state.clone(),
+        namespace,
+        args: create_wav_args,
+        pool,
+        quick_phrase,
+        is_phrase,
+        voice,
+        voice_version,
+        clip_id: clip_id.clone(),
+        created_at: now.as_secs(),
+        downgraded_max_steps,
+        features: features.clone(),
+        settings,
+        chunk_chars,
+        slo,
+        self_check,
     };
-    println!("{:?}",create_wav_args);
+    if queued {
+        if stream || webrtc_session.is_some() {
+            println!("tts[{request_id}]: streamed and WebRTC requests can't be queued");
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+        let chunks = chunking::split(&job.args.prompt, chunk_chars).len();
+        let status = state.tts_jobs.submit(job, format, output_rate, chunks)?;
+        return Ok(Response::builder()
+            .status(StatusCode::ACCEPTED)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::LOCATION, format!("/api/tts/jobs/{}", status.id))
+            .body(axum::body::Body::from(serde_json::to_vec(&status).unwrap()))
+            .unwrap());
+    }
+    if let Some(session) = webrtc_session {
+        // Short sentences a preset says are worth keeping encoded, like
+        // quick phrases, for the namespace whose history has the clip.
+        let sentence = job
+            .pool
+            .as_ref()
+            .filter(|_| repeatable && job.args.prompt.chars().count() <= state.config.slo.chunk_chars)
+            .map(|pool| {
+                let fingerprint = phrases::fingerprint(pool.engine().version(), &job.args.description, &job.args.prompt);
+                format!("{}\0{fingerprint}", job.namespace.name)
+            });
+        return speak(job, &session, incremental, sentence);
+    }
+    let filename = format!("{clip_id}.{}", format.extension());
 
-    if let Err(_) = create_wav_file(create_wav_args) {
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    // Cached phrases are complete already and go out as a plain response.
+    if let (true, Some(pool)) = (stream, &job.pool) {
+        let native_rate = pool.engine().sample_rate();
+        let (sender, body) = futures::channel::mpsc::unbounded();
+        let sink = AudioSink {
+            sender,
+            format,
+            from_rate: native_rate,
+            to_rate: output_rate.unwrap_or(native_rate),
+            incremental,
+            sse,
+            slo: job.slo,
+            first_audio: Arc::default(),
+        };
+        sink.emit(format.stream_header(sink.to_rate));
+        let streamed_id = clip_id.clone();
+        let metrics = state.metrics.clone();
+        tokio::spawn(async move {
+            let result = job.run(Some(&sink)).await;
+            let outcome = sink.slo.map(|slo| slo.report(&metrics, request_id, sink.first_audio.get().copied()));
+            match result {
+                Err(e) if sink.sse => sink.event("error", &serde_json::json!({ "code": e.code() })),
+                // Breaks the connection off, so clients don't take the audio
+                // so far for the whole clip.
+                Err(e) => {
+                    let _ = sink.sender.unbounded_send(Err(std::io::Error::other(e.code())));
+                }
+                Ok(_) if sink.sse => {
+                    let mut done = serde_json::json!({ "clip_id": streamed_id });
+                    if let Some(outcome) = outcome {
+                        done["time_to_first_audio_secs"] = outcome.time_to_first_audio_secs.into();
+                        done["slo_missed"] = outcome.slo_missed.into();
+                    }
+                    sink.event("done", &done);
+                }
+                Ok(_) => {}
+            }
+        });
+        let mut response = Response::builder().status(200).header("x-clip-id", &clip_id);
+        response = if sse {
+            response
+                .header(header::CONTENT_TYPE, "text/event-stream")
+                .header(header::CACHE_CONTROL, "no-cache")
+        } else {
+            response
+                .header(header::CONTENT_TYPE, format.content_type(output_rate.unwrap_or(native_rate)))
+                .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename))
+        };
+        if let Some(max_steps) = downgraded_max_steps {
+            response = response.header("x-downgraded-max-steps", max_steps);
+        }
+        if !unsupported.is_empty() {
+            response = response.header("x-unsupported-characters", unsupported_header(&unsupported));
+        }
+        if let Some(name) = &locale_voice {
+            response = response.header("x-locale-voice", name);
+        }
+        if !features.is_empty() {
+            response = response.header(features::HEADER, features::header_value(&features));
+        }
+        return Ok(response.body(axum::body::Body::from_stream(body)).unwrap());
     }
 
-    // Read the generated file and return it
-    let audio_data = std::fs::read(&filepath).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let from_cache = job.quick_phrase.is_some();
+    let (rendered, clip_id, coalesced) = if state.config.coalesce_requests && !from_cache {
+        let key = job.coalesce_key();
+        let (outcome, joined) = state
+            .inflight
+            .run(key, async move {
+                let clip_id = job.clip_id.clone();
+                Ok((job.run(None).await?, clip_id))
+            })
+            .await;
+        let (rendered, clip_id) = outcome?;
+        if joined {
+            println!("tts[{request_id}]: served by the identical request that generated {clip_id}");
+        }
+        (rendered, clip_id, joined)
+    } else {
+        (job.run(None).await?, clip_id, false)
+    };
+    let filename = format!("{clip_id}.{}", format.extension());
+    // A whole clip's first audio goes out with the rest of it.
+    let outcome = slo.map(|slo| slo.report(&state.metrics, request_id, Some(std::time::Instant::now())));
+    let Rendered {
+        clip,
+        over_budget,
+        speech_rate,
+        self_check,
+    } = rendered;
+    let peak = clip.memory;
+    let sample_rate = output_rate.unwrap_or(clip.sample_rate);
+    let audio_data = if format == audio::OutputFormat::Wav && sample_rate == clip.sample_rate {
+        clip.wav
+    } else {
+        let pcm = audio::read_wav(&clip.wav).map_err(SynthesisError::Encode)?;
+        format.encode_clip(&audio::resample(&pcm.samples, pcm.sample_rate, sample_rate), sample_rate)
+    };
 
-    Ok(Response::builder()
+    let mut response = Response::builder()
         .status(200)
-        .header(header::CONTENT_TYPE, "audio/wav")
+        .header(header::CONTENT_TYPE, format.content_type(sample_rate))
         .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename))
-        .body(axum::body::Body::from(audio_data))
-        .unwrap())
+        .header("x-clip-id", &clip_id)
+        .header("x-finish-reason", clip.finish.as_str())
+        .header("x-peak-host-memory", peak.host_bytes)
+        .header("server-timing", clip.timings.server_timing());
+    if let Some(bytes) = peak.device_bytes {
+        response = response.header("x-peak-device-memory", bytes);
+    }
+    if let Some(retry) = &clip.quality_retry {
+        response = response.header("x-quality-retry", retry.defect.as_str());
+    }
+    if !over_budget.is_empty() {
+        let stages: Vec<_> = over_budget.iter().map(|s| s.as_str()).collect();
+        response = response.header("x-over-budget", stages.join(","));
+    }
+    if let Some(max_steps) = downgraded_max_steps {
+        response = response.header("x-downgraded-max-steps", max_steps);
+    }
+    if from_cache {
+        response = response.header("x-quick-phrase", "true");
+    }
+    if coalesced {
+        response = response.header("x-coalesced", "true");
+    }
+    if !unsupported.is_empty() {
+        response = response.header("x-unsupported-characters", unsupported_header(&unsupported));
+    }
+    if let Some(name) = &locale_voice {
+        response = response.header("x-locale-voice", name);
+    }
+    if !features.is_empty() {
+        response = response.header(features::HEADER, features::header_value(&features));
+    }
+    if let Some(rate) = speech_rate {
+        response = response
+            .header("x-words-per-minute", format!("{:.0}", rate.words_per_minute))
+            .header("x-speech-rate-warning", rate.suspicious.to_string());
+    }
+    if let Some(report) = &self_check {
+        response = response
+            .header("x-word-error-rate", format!("{:.3}", report.word_error_rate))
+            .header("x-self-check-flagged", report.flagged.to_string());
+        if !report.attempts.is_empty() {
+            response = response.header("x-self-check-attempts", report.attempts.len().to_string());
+        }
+    }
+    if let Some(outcome) = outcome {
+        let secs = outcome.time_to_first_audio_secs.unwrap_or_default();
+        response = response
+            .header("x-time-to-first-audio", format!("{secs:.3}"))
+            .header("x-slo-missed", outcome.slo_missed.to_string());
+    }
+    let body = match &state.config.chaos {
+        Some(chaos) => chaos.body(&format!("tts[{request_id}]"), audio_data),
+        None => axum::body::Body::from(audio_data),
+    };
+    Ok(response.body(body).unwrap())
 }
 
-#[derive(Debug)]
-struct CreateWavArgs {
-    description: String,
-    prompt: String,
-    out_file: String,
-    temperature: Option<f64>,
-    seed: Option<u64>,
-    top_p: Option<f64>,
+/// Speaks the clip into WebRTC session `session` as it is generated, and
+/// answers straight away with its id. A `sentence` key has its Opus
+/// packets kept, or sent from the cache when they were kept before.
+#[cfg(feature = "webrtc")]
+fn speak(job: TtsJob, session: &str, incremental: bool, sentence: Option<String>) -> Result<Response, SynthesisError> {
+    let sessions = job.state.webrtc.clone().ok_or(StatusCode::NOT_FOUND)?;
+    let sender = sessions.audio(&job.namespace.name, session).ok_or(StatusCode::NOT_FOUND)?;
+    let packets = sessions.packets(&job.namespace.name, session).ok_or(StatusCode::NOT_FOUND)?;
+    let request_id = job.args.request_id;
+    let sentence = sentence.filter(|_| sessions.caches_sentences());
+    if let Some(cached) = sentence.as_deref().and_then(|key| sessions.cached(key)) {
+        println!("tts[{request_id}]: served from the sentence cache, as clip {}", cached.clip_id);
+        let _ = packets.unbounded_send(cached.packets.clone());
+        // No clip is stored, but the audio still counts.
+        job.namespace.usage.record(job.args.prompt.chars().count(), cached.duration_secs);
+        if let Some(slo) = job.slo {
+            slo.report(&job.state.metrics, request_id, Some(std::time::Instant::now()));
+        }
+        let body = Json(serde_json::json!({ "clip_id": cached.clip_id }));
+        let headers = [("x-clip-id", cached.clip_id.clone()), ("x-sentence-cache", "hit".to_string())];
+        return Ok(axum::response::IntoResponse::into_response((StatusCode::ACCEPTED, headers, body)));
+    }
+    // A sentence to keep is encoded here rather than by the session, so
+    // its packets can be kept as they go out.
+    let (sender, encoding) = match &sentence {
+        Some(_) => {
+            let (pcm, mut received) = futures::channel::mpsc::unbounded::<std::io::Result<Vec<u8>>>();
+            let mut packetizer = rtc::Packetizer::new(session).map_err(|e| SynthesisError::Encode(e.into()))?;
+            let encoding = tokio::spawn(async move {
+                let mut kept = Vec::new();
+                while let Some(Ok(bytes)) = received.next().await {
+                    let encoded = packetizer.push(&bytes);
+                    kept.extend(encoded.iter().cloned());
+                    let _ = packets.unbounded_send(encoded);
+                }
+                let encoded = packetizer.finish();
+                kept.extend(encoded.iter().cloned());
+                let _ = packets.unbounded_send(encoded);
+                kept
+            });
+            (pcm, Some(encoding))
+        }
+        None => (sender, None),
+    };
+    let from_rate = match (&job.quick_phrase, &job.pool) {
+        (Some(phrase), _) => phrase.sample_rate,
+        (None, Some(pool)) => pool.engine().sample_rate(),
+        (None, None) => unreachable!("jobs without a cached clip get a pool"),
+    };
+    let sink = AudioSink {
+        sender,
+        format: audio::OutputFormat::Pcm16le,
+        from_rate,
+        to_rate: rtc::SAMPLE_RATE,
+        incremental,
+        sse: false,
+        slo: job.slo,
+        first_audio: Arc::default(),
+    };
+    let (clip_id, session) = (job.clip_id.clone(), session.to_string());
+    let (metrics, kept_id) = (job.state.metrics.clone(), clip_id.clone());
+    tokio::spawn(async move {
+        let cached = job.quick_phrase.is_some();
+        // Failures are logged, and the session stays open for the next clip.
+        let result = job.run(Some(&sink)).await;
+        if let (true, Ok(rendered)) = (cached, &result) {
+            match audio::read_wav(&rendered.clip.wav) {
+                Ok(pcm) => sink.send(&pcm.samples),
+                Err(e) => println!("webrtc[{session}]: decoding the cached clip failed: {e:#}"),
+            }
+        }
+        if let Some(slo) = sink.slo {
+            slo.report(&metrics, request_id, sink.first_audio.get().copied());
+        }
+        // Closes the encoder's input.
+        drop(sink);
+        if let (Some(encoding), Some(key)) = (encoding, sentence) {
+            match (encoding.await, result) {
+                (Ok(packets), Ok(rendered)) if !packets.is_empty() => {
+                    let duration_secs = rendered.clip.duration_secs;
+                    sessions.cache(key, rtc::CachedSentence { clip_id: kept_id, duration_secs, packets });
+                }
+                _ => {}
+            }
+        }
+    });
+    let body = Json(serde_json::json!({ "clip_id": clip_id }));
+    Ok(axum::response::IntoResponse::into_response((StatusCode::ACCEPTED, [("x-clip-id", clip_id)], body)))
 }
 
-fn create_wav_file(create_wav_args: CreateWavArgs) -> anyhow::Result<()> {
-    let description: String = create_wav_args.description;
-    let prompt: String = create_wav_args.prompt;
-    let out_file: String = create_wav_args.out_file;
-    let temperature: f64 = create_wav_args.temperature.unwrap_or(0.0);
-    let seed: u64 = create_wav_args.seed.unwrap_or(0);
-    let top_p: Option<f64> = create_wav_args.top_p;
-    let max_steps:usize = 512;
+#[cfg(not(feature = "webrtc"))]
+fn speak(_job: TtsJob, _session: &str, _incremental: bool, _sentence: Option<String>) -> Result<Response, SynthesisError> {
+    Err(StatusCode::NOT_IMPLEMENTED.into())
+}
 
-    let start = std::time::Instant::now();
-    let api = hf_hub::api::sync::Api::new()?;
-
-    let repo = api.repo(hf_hub::Repo::with_revision(
-        "parler-tts/parler-tts-large-v1".to_string(),
-        hf_hub::RepoType::Model,
-        "main".to_string(),
-    ));
-    let model_files = hub_load_safetensors(&repo, "model.safetensors.index.json")?;
-    let config = repo.get("config.json")?;
-    let tokenizer = repo.get("tokenizer.json")?;
-    println!("retrieved the files in {:?}", start.elapsed());
-    
-    let start = std::time::Instant::now();
-    let tokenizer = Tokenizer::from_file(tokenizer).unwrap();
-    // let tokenizer = Tokenizer::from_file(tokenizer).map_err(E::msg)?;
-    println!("tokenizer loaded in {:?}", start.elapsed());
-    
-    let start = std::time::Instant::now();
-    let device = candle_examples::device(false)?;
-    println!("device loaded in {:?}", start.elapsed());
-    
-    let start = std::time::Instant::now();
-    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&model_files, DType::F32, &device)? };
-    let config: Config = serde_json::from_reader(std::fs::File::open(config)?)?;
-    println!("config loaded in {:?}", start.elapsed());
+/// Answers a WebRTC offer (`{ "type": "offer", "sdp": ... }`) with a session
+/// that `/api/tts` can speak into through its `webrtc_session` field.
+#[cfg(feature = "webrtc")]
+async fn webrtc_offer(
+    State(state): State<AppState>,
+    Tenant(namespace): Tenant,
+    Json(offer): Json<webrtc::peer_connection::sdp::session_description::RTCSessionDescription>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let sessions = state.webrtc.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    if !sessions.has_room(&namespace.name) {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+    let (session, answer) = sessions.open(&namespace.name, offer).await.map_err(|e| {
+        println!("webrtc: negotiation failed: {e:#}");
+        StatusCode::BAD_REQUEST
+    })?;
+    Ok(Json(serde_json::json!({ "session": session, "answer": answer })))
+}
 
-    let start = std::time::Instant::now();
-    let mut model = Model::new(&config, vb)?;
-    println!("loaded the model in {:?}", start.elapsed());
-
-    // Debug: Print actual input strings and their lengths
-    println!("DEBUG - Input prompt: '{}'", prompt);
-    println!("DEBUG - Input description: '{}'", description);
-
-    let description_token_ids = tokenizer
-        .encode(description, true)
-        .map_err(E::msg)?
-        .get_ids()
-        .to_vec();
-    println!("DEBUG - Description tokens: {} tokens", description_token_ids.len());
-    let description_tokens = Tensor::new(description_token_ids, &device)?.unsqueeze(0)?;
-
-    let prompt_token_ids = tokenizer
-        .encode(prompt, true)
-        .map_err(E::msg)?
-        .get_ids()
-        .to_vec();
-    println!("DEBUG - Prompt tokens: {} tokens", prompt_token_ids.len());
-    let prompt_tokens = Tensor::new(prompt_token_ids, &device)?.unsqueeze(0)?;
-    let lp = candle_transformers::generation::LogitsProcessor::new(
+#[cfg(feature = "webrtc")]
+async fn webrtc_hangup(
+    State(state): State<AppState>,
+    Tenant(namespace): Tenant,
+    Path(session): Path<String>,
+) -> StatusCode {
+    match &state.webrtc {
+        Some(sessions) if sessions.close(&namespace.name, &session).await => StatusCode::NO_CONTENT,
+        _ => StatusCode::NOT_FOUND,
+    }
+}
+
+/// Starts rendering a long text, the article at a URL or a PDF in the
+/// background. Answers `202` with the job's status, or `200` with the
+/// status of the job an `Idempotency-Key` was first sent with.
+async fn create_audiobook(
+    State(state): State<AppState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Tenant(namespace): Tenant,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Response, SynthesisError> {
+    let key = match headers.get("idempotency-key") {
+        Some(value) => {
+            let key = value.to_str().map_err(|_| StatusCode::BAD_REQUEST)?.trim();
+            if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_BYTES {
+                return Err(StatusCode::BAD_REQUEST.into());
+            }
+            Some(key.to_string())
+        }
+        None => None,
+    };
+    let mut text = String::new();
+    let mut url = String::new();
+    let mut pdf: Option<Vec<u8>> = None;
+    let mut pages = None;
+    let mut title = None;
+    let mut description = String::new();
+    let mut voice: Option<String> = None;
+    let (mut temperature, mut seed, mut top_p) = (None, None, None);
+    let mut format = audiobook::BookFormat::Wav;
+    while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
+        let name = field.name().unwrap_or("").to_string();
+        if name == "pdf" {
+            // The file name stands in for a title the form doesn't give.
+            let stem = field.file_name().map(|f| f.strip_suffix(".pdf").unwrap_or(f).to_string());
+            pdf = Some(field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?.to_vec());
+            title = title.or(stem.filter(|s| !s.is_empty()));
+            continue;
+        }
+        let data = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+        match name.as_str() {
+            "text" => text = data,
+            "url" => url = data.trim().to_string(),
+            "pages" => pages = Some(parse_page_range(&data).ok_or(StatusCode::BAD_REQUEST)?),
+            "title" => title = Some(data.trim().to_string()).filter(|t| !t.is_empty()),
+            "description" => description = data,
+            "voice" => voice = Some(data).filter(|v| !v.is_empty()),
+            "temperature" => temperature = Some(data.trim().parse().map_err(|_| StatusCode::BAD_REQUEST)?),
+            "seed" => seed = Some(data.trim().parse().map_err(|_| StatusCode::BAD_REQUEST)?),
+            "top_p" => top_p = Some(data.trim().parse().map_err(|_| StatusCode::BAD_REQUEST)?),
+            "format" => format = audiobook::BookFormat::parse(&data).ok_or(StatusCode::BAD_REQUEST)?,
+            _ => {}
+        }
+    }
+    if voice.is_none() && description.is_empty() {
+        voice = locale_voices::for_request(&state.config.locale_voices, None, &headers, |name| {
+            namespace.voices.get(name).is_some()
+        });
+    }
+    let mut voice_version = None;
+    if let Some(name) = &voice {
+        let current = namespace.voices.get(name).ok_or(StatusCode::BAD_REQUEST)?;
+        if description.is_empty() {
+            description = current.preset.description.clone();
+        }
+        temperature = temperature.or(current.preset.temperature);
+        seed = seed.or(current.preset.seed);
+        top_p = top_p.or(current.preset.top_p);
+        voice_version = Some(current.version);
+    }
+    let source = match (text.trim().is_empty(), url.is_empty(), pdf) {
+        (false, true, None) => audiobook::Source::Text(text),
+        (true, false, None) if fetch::public_url(&url).is_ok() => audiobook::Source::Url(url),
+        (true, true, Some(bytes)) => audiobook::Source::Pdf { bytes, pages },
+        _ => return Err(StatusCode::BAD_REQUEST.into()),
+    };
+    if description.is_empty() {
+        description = state.config.default_description.clone();
+    }
+    if description.is_empty() {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    let request = audiobook::Request {
+        source,
+        title,
+        description,
+        voice,
+        voice_version,
+        temperature,
         seed,
-        Some(temperature),
         top_p,
-    );
-    
-    println!("&prompt_tokens, &description_tokens, max_steps\n{:?}\n",(&prompt_tokens, &description_tokens, max_steps));
-    println!("starting generation...\n");
-    
-    let codes = model.generate(&prompt_tokens, &description_tokens, lp, max_steps)?;
-    println!("generated codes\n{codes}\n");
-
-    let codes = codes.to_dtype(DType::I64)?;
-    codes.save_safetensors("codes", "out.safetensors")?;
-    let codes = codes.unsqueeze(0)?;
-    let pcm = model
-        .audio_encoder
-        .decode_codes(&codes.to_device(&device)?)?;
-    println!("pcm: {pcm}");
-    
-    let pcm = pcm.i((0, 0))?;
-    let pcm = candle_examples::audio::normalize_loudness(&pcm, 24_000, true)?;
-    let pcm = pcm.to_vec1::<f32>()?;
-
-    // Write WAV file using candle_examples method
-    let mut output = std::fs::File::create(&out_file)?;
-    candle_examples::wav::write_pcm_as_wav(&mut output, &pcm, config.audio_encoder.sampling_rate)?;
-
-    println!("Generated audio saved to: {}", out_file);
-    Ok(())
+        format,
+    };
+    let (status, replayed) = state.jobs.start(&state, namespace, request_id, request, key);
+    Ok(Response::builder()
+        .status(if replayed { StatusCode::OK } else { StatusCode::ACCEPTED })
+        .header(header::CONTENT_TYPE, "application/json")
+        .header("idempotent-replayed", replayed.to_string())
+        .header(header::LOCATION, format!("/api/jobs/{}", status.id))
+        .body(axum::body::Body::from(serde_json::to_vec(&status).unwrap()))
+        .unwrap())
+}
+
+#[derive(Deserialize)]
+struct EstimateRequest {
+    text: String,
 }
 
+#[derive(Serialize)]
+struct Estimate {
+    characters: usize,
+    words: usize,
+    /// Requests the text is split into, as `/api/tts` and audiobook jobs
+    /// would split it.
+    chunks: usize,
+    /// How long the audio would play, at an ordinary reading pace.
+    duration_secs: f64,
+}
 
-/// Loads the safetensors files for a model from the hub based on a json index file.
-pub fn hub_load_safetensors(
-    repo: &hf_hub::api::sync::ApiRepo,
-    json_file: &str,
-) -> Result<Vec<std::path::PathBuf>, Error> {
-    let json_file = repo.get(json_file).map_err(candle::Error::wrap)?;
-    let json_file = std::fs::File::open(json_file)?;
-    let json: serde_json::Value =
-        serde_json::from_reader(&json_file).map_err(candle::Error::wrap)?;
-    let weight_map = match json.get("weight_map") {
-        None => candle::bail!("no weight map in {json_file:?}"),
-        Some(serde_json::Value::Object(map)) => map,
-        Some(_) => candle::bail!("weight map in {json_file:?} is not a map"),
+/// What rendering a text would amount to, without rendering it.
+async fn estimate_text(State(state): State<AppState>, Json(request): Json<EstimateRequest>) -> Json<Estimate> {
+    let words = request
+        .text
+        .split_whitespace()
+        .filter(|w| w.chars().any(char::is_alphanumeric))
+        .count();
+    let chunks = match request.text.trim() {
+        "" => 0,
+        text => chunking::split(text, state.config.chunk_chars).len(),
     };
-    let mut safetensors_files = std::collections::HashSet::new();
-    for value in weight_map.values() {
-        if let Some(file) = value.as_str() {
-            safetensors_files.insert(file.to_string());
-        }
+    let gaps = chunks.saturating_sub(1) as f64 * CHUNK_GAP_SECS;
+    Json(Estimate {
+        characters: request.text.chars().count(),
+        words,
+        chunks,
+        duration_secs: words as f64 * 60.0 / analysis::TYPICAL_WPM + gaps,
+    })
+}
+
+#[derive(Serialize)]
+struct Chunks {
+    chunks: Vec<String>,
+    /// Silence put between chunks when they are joined.
+    gap_secs: f64,
+}
+
+/// How a long text is split into requests, so clients can render and edit
+/// it chunk by chunk. Takes the same body as `/api/estimate`.
+async fn split_text(State(state): State<AppState>, Json(request): Json<EstimateRequest>) -> Json<Chunks> {
+    let chunks = match request.text.trim() {
+        "" => Vec::new(),
+        text => chunking::split(text, state.config.chunk_chars),
+    };
+    Json(Chunks {
+        chunks,
+        gap_secs: CHUNK_GAP_SECS,
+    })
+}
+
+async fn job_status(
+    State(state): State<AppState>,
+    Tenant(namespace): Tenant,
+    Path(id): Path<String>,
+) -> Result<Json<audiobook::JobStatus>, StatusCode> {
+    state.jobs.status(&namespace.name, &id).map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Deserialize)]
+struct JobAudioQuery {
+    /// `wav` or `m4b`; the format the job was started with by default.
+    format: Option<String>,
+}
+
+/// The finished book's audio; `409` while the job is still running.
+async fn job_audio(
+    State(state): State<AppState>,
+    Tenant(namespace): Tenant,
+    Path(id): Path<String>,
+    Query(query): Query<JobAudioQuery>,
+) -> Result<Response, StatusCode> {
+    let status = state.jobs.status(&namespace.name, &id).ok_or(StatusCode::NOT_FOUND)?;
+    let format = match &query.format {
+        Some(name) => audiobook::BookFormat::parse(name).ok_or(StatusCode::BAD_REQUEST)?,
+        None => status.format,
+    };
+    let clip_id = status.clip_id.ok_or(StatusCode::CONFLICT)?;
+    if format == audiobook::BookFormat::Wav {
+        return history_audio(State(state), Tenant(namespace), Path(clip_id)).await;
     }
-    let safetensors_files = safetensors_files
-        .iter()
-        .map(|v| repo.get(v).map_err(candle::Error::wrap))
-        .collect::<Result<Vec<_>, candle::Error>>()?;
-    Ok(safetensors_files)
+    let book = namespace
+        .history
+        .attachment(&clip_id, "m4b")
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, format.content_type())
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{clip_id}.m4b\""))
+        .body(axum::body::Body::from(book))
+        .unwrap())
+}
+
+async fn job_chunks(
+    State(state): State<AppState>,
+    Tenant(namespace): Tenant,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<audiobook::ChunkInfo>>, SynthesisError> {
+    state.jobs.chunks(&namespace.name, &id).map(Json)
+}
+
+/// One chunk of a finished book, as last rendered.
+async fn job_chunk_audio(
+    State(state): State<AppState>,
+    Tenant(namespace): Tenant,
+    Path((id, n)): Path<(String, usize)>,
+) -> Result<Response, SynthesisError> {
+    let wav = state.jobs.chunk_audio(&namespace, &id, n)?;
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "audio/wav")
+        .body(axum::body::Body::from(wav))
+        .unwrap())
+}
+
+/// Renders one chunk of a finished book again; `/assemble` then puts it
+/// into the book.
+async fn rerender_job_chunk(
+    State(state): State<AppState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Tenant(namespace): Tenant,
+    Path((id, n)): Path<(String, usize)>,
+    Json(edit): Json<audiobook::ChunkEdit>,
+) -> Result<Json<audiobook::Rerender>, SynthesisError> {
+    let jobs = state.jobs.clone();
+    jobs.rerender(&state, &namespace, request_id, &id, n, edit).await.map(Json)
+}
+
+/// Joins a book's chunks again after some were re-rendered.
+async fn assemble_job(
+    State(state): State<AppState>,
+    Tenant(namespace): Tenant,
+    Path(id): Path<String>,
+) -> Result<Json<audiobook::JobStatus>, SynthesisError> {
+    let jobs = state.jobs.clone();
+    jobs.assemble(&state, &namespace, &id).await.map(Json)
+}
+
+/// The podcast feed of synthesized articles.
+async fn podcast_feed(State(state): State<AppState>) -> Result<Response, StatusCode> {
+    let station = state.podcast.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/rss+xml")
+        .body(axum::body::Body::from(station.feed_xml()))
+        .unwrap())
+}
+
+/// An episode's MP3, by the file name in the feed's enclosure.
+async fn podcast_episode(State(state): State<AppState>, Path(file): Path<String>) -> Result<Response, StatusCode> {
+    let station = state.podcast.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let path = station.episode_path(&file).ok_or(StatusCode::NOT_FOUND)?;
+    let mp3 = tokio::fs::read(path).await.map_err(|_| StatusCode::NOT_FOUND)?;
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "audio/mpeg")
+        .body(axum::body::Body::from(mp3))
+        .unwrap())
+}
+
+/// A job for `text` in `namespace`'s preset `voice` with default settings,
+/// as the chat bots and the podcast send them.
+async fn preset_job(
+    state: &AppState,
+    namespace: Arc<namespace::Namespace>,
+    voice: &str,
+    text: &str,
+) -> Result<TtsJob, SynthesisError> {
+    let current = namespace.voices.get(voice).ok_or(StatusCode::BAD_REQUEST)?;
+    let description = current.preset.description.clone();
+    let RequestId(request_id) = RequestId::next();
+    let is_phrase =
+        !current.preset.has_sampling() && state.config.quick_phrases.iter().any(|p| p.trim() == text.trim());
+    let quick_phrase = state.phrases.get(&description, text).filter(|_| is_phrase);
+    let pool = match &quick_phrase {
+        Some(_) => None,
+        None => Some(state.pool().await.map_err(SynthesisError::ModelLoad)?),
+    };
+    let args = Arc::new(CreateWavArgs {
+        request_id,
+        description,
+        prompt: text.to_string(),
+        temperature: current.preset.temperature,
+        seed: current.preset.seed,
+        top_p: current.preset.top_p,
+        post_process: PostProcess::default(),
+        tokens: TokenControls::default(),
+        sampler: SamplerKind::Stock,
+        stopping: state.config.max_steps.stopping(),
+        retry_degenerate: state.config.retry_degenerate,
+        wav_format: state.config.wav_format,
+        bleep: None,
+        progress: None,
+    });
+    println!("{}", args.log_line(state.config.log_prompts));
+    let now = unix_now();
+    Ok(TtsJob {
+        state: state.clone(),
+        namespace,
+        args,
+        pool,
+        quick_phrase,
+        is_phrase,
+        voice: Some(voice.to_string()),
+        voice_version: Some(current.version),
+        clip_id: format!("generated_audio_{now}_{request_id}"),
+        created_at: now,
+        downgraded_max_steps: None,
+        features: Vec::new(),
+        settings: BTreeMap::new(),
+        chunk_chars: state.config.chunk_chars,
+        slo: None,
+        self_check: state.self_check.as_ref().is_some_and(|c| c.always()),
+    })
+}
+
+/// Where a streamed response's audio goes, in the order it is to be sent.
+#[derive(Clone)]
+struct AudioSink {
+    sender: futures::channel::mpsc::UnboundedSender<std::io::Result<Vec<u8>>>,
+    format: audio::OutputFormat,
+    from_rate: u32,
+    to_rate: u32,
+    /// Send audio as it is decoded rather than chunk by chunk.
+    incremental: bool,
+    /// Frame the audio as server-sent events.
+    sse: bool,
+    /// The interactive mode's target for the first audio.
+    slo: Option<slo::Target>,
+    /// When the first audio went out.
+    first_audio: Arc<std::sync::OnceLock<std::time::Instant>>,
+}
+
+impl AudioSink {
+    /// Sends a piece of the clip. A client that went away just stops
+    /// receiving; the clip is still stored.
+    fn send(&self, samples: &[f32]) {
+        let samples = audio::resample(samples, self.from_rate, self.to_rate);
+        self.emit(self.format.encode_samples(&samples));
+        if !samples.is_empty() {
+            let _ = self.first_audio.set(std::time::Instant::now());
+        }
+    }
+
+    /// The interactive deadline, while no audio has gone out yet.
+    fn first_audio_due(&self) -> Option<std::time::Instant> {
+        self.slo.filter(|_| self.first_audio.get().is_none()).map(|slo| slo.deadline)
+    }
+
+    /// Sends encoded audio, as an `audio` event for event streams.
+    fn emit(&self, bytes: Vec<u8>) {
+        if !self.sse {
+            let _ = self.sender.unbounded_send(Ok(bytes));
+        } else if !bytes.is_empty() {
+            let data = base64::engine::general_purpose::STANDARD.encode(bytes);
+            let _ = self.sender.unbounded_send(Ok(format!("event: audio\ndata: {data}\n\n").into_bytes()));
+        }
+    }
+
+    fn event(&self, name: &str, data: &serde_json::Value) {
+        let _ = self.sender.unbounded_send(Ok(format!("event: {name}\ndata: {data}\n\n").into_bytes()));
+    }
+}
+
+/// A validated `/api/tts` request, ready to be rendered.
+struct TtsJob {
+    state: AppState,
+    namespace: Arc<namespace::Namespace>,
+    args: Arc<CreateWavArgs>,
+    /// Unset when the clip comes from the quick phrase cache.
+    pool: Option<Arc<EnginePool>>,
+    quick_phrase: Option<Arc<phrases::QuickPhrase>>,
+    /// Whether the request asks for a configured quick phrase.
+    is_phrase: bool,
+    voice: Option<String>,
+    /// Version of the preset `voice` at the time of the request.
+    voice_version: Option<u32>,
+    clip_id: String,
+    created_at: u64,
+    downgraded_max_steps: Option<usize>,
+    /// Experimental features the request turned on.
+    features: Vec<features::Feature>,
+    /// Form fields the history keeps for replays.
+    settings: BTreeMap<String, String>,
+    chunk_chars: usize,
+    /// Set in interactive mode.
+    slo: Option<slo::Target>,
+    /// Transcribe the clip and compare it with the prompt.
+    self_check: bool,
+}
+
+/// A stored clip, with what the response headers report about it.
+#[derive(Clone)]
+struct Rendered {
+    clip: GeneratedClip,
+    over_budget: Vec<Stage>,
+    speech_rate: Option<analysis::SpeechRate>,
+    self_check: Option<self_check::Report>,
+}
+
+impl TtsJob {
+    /// What makes two requests render the same clip.
+    fn coalesce_key(&self) -> String {
+        let args = &self.args;
+        format!(
+            "{}\0{:?}\0{:?}\0{:?}\0{:?}\0{}\0{}\0{:?} {:?} {:?} {:?} {:?} {:?} {:?} {} {:?} {} {} {}",
+            self.namespace.name,
+            self.pool.as_ref().map(|pool| pool.engine().version()),
+            self.voice,
+            self.voice_version,
+            self.features,
+            args.description,
+            args.prompt,
+            args.temperature,
+            args.seed,
+            args.top_p,
+            args.post_process,
+            args.tokens,
+            args.sampler,
+            args.stopping,
+            args.retry_degenerate,
+            args.bleep,
+            self.self_check,
+            self.chunk_chars,
+            // Interactive requests need their first audio on time.
+            self.slo.is_some(),
+        )
+    }
+
+    /// Generates the clip (also sending it to `sink` as it comes, when
+    /// given) or takes it from the quick phrase cache, and stores it in the
+    /// history.
+    async fn run(self, sink: Option<&AudioSink>) -> Result<Rendered, SynthesisError> {
+        let TtsJob {
+            state,
+            namespace,
+            args,
+            voice,
+            ..
+        } = &self;
+        let request_id = args.request_id;
+        let clip = match (&self.quick_phrase, &self.pool) {
+            (Some(phrase), _) => {
+                // Otherwise the clip is a joined template.
+                if self.is_phrase {
+                    println!("tts[{request_id}]: served from the quick phrase cache");
+                }
+                GeneratedClip::from_phrase(phrase)
+            }
+            (None, None) => unreachable!("jobs without a cached clip get a pool"),
+            (None, Some(pool)) => {
+                let tracker = memory::PeakTracker::start(pool.engine().gpus().to_vec());
+                let start = std::time::Instant::now();
+                let clip = create_wav_file(pool, args, self.chunk_chars, sink).await;
+                let elapsed = start.elapsed().as_secs_f64();
+                let peak = tokio::task::spawn_blocking(move || tracker.finish()).await.unwrap_or_default();
+                let mut clip = clip.map_err(|e| {
+                    println!("tts[{request_id}]: {e} ({})", e.code());
+                    if e.is_panic() {
+                        state.metrics.record_panic();
+                    }
+                    reporting::capture(
+                        e.code(),
+                        &e,
+                        &[
+                            ("request_id", request_id.to_string()),
+                            ("namespace", namespace.name.clone()),
+                            ("prompt", PromptLogging::Hashed.sanitize(&args.prompt)),
+                            ("voice", voice.clone().unwrap_or_default()),
+                            ("sampler", format!("{:?}", args.sampler)),
+                            ("seed", format!("{:?}", args.seed)),
+                            ("temperature", format!("{:?}", args.temperature)),
+                            ("max_steps", args.stopping.max_steps.to_string()),
+                        ],
+                    );
+                    e
+                })?;
+
+                clip.memory = peak;
+                let real_time_factor = (clip.duration_secs > 0.0).then(|| elapsed / clip.duration_secs);
+                state.metrics.record_generation(&peak, clip.secs_per_step, real_time_factor);
+                println!(
+                    "tts[{request_id}]: peak memory host={} MiB device={}",
+                    peak.host_bytes >> 20,
+                    peak.device_bytes.map_or("n/a".to_string(), |b| format!("{} MiB", b >> 20))
+                );
+                // Streamed clips are post-processed chunk by chunk, so they
+                // don't match a rendering made for the cache; cut-off ones
+                // aren't worth replaying.
+                let complete = self.downgraded_max_steps.is_none() && clip.finish != FinishReason::MaxSteps;
+                if self.is_phrase && sink.is_none() && complete {
+                    let (phrases, version) = (state.phrases.clone(), pool.engine().version().to_string());
+                    let (description, text) = (args.description.clone(), args.prompt.clone());
+                    let phrase = clip.to_phrase();
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(e) = phrases.insert(&version, &description, &text, phrase) {
+                            println!("quick phrases: storing {text:?} failed: {e:#}");
+                        }
+                    });
+                }
+                clip
+            }
+        };
+        let (clip, self_check) = self.checked(clip, sink.is_some()).await;
+
+        let over_budget = state.config.budgets.exceeded(&clip.timings);
+        if !over_budget.is_empty() {
+            let stages: Vec<_> = over_budget.iter().map(|s| s.as_str()).collect();
+            println!("tts[{request_id}]: over budget in {} ({:?})", stages.join(", "), clip.timings);
+            state.metrics.record_over_budget(&over_budget);
+        }
+
+        let speech_rate = analysis::speech_rate(&args.prompt, clip.duration_secs);
+        if let Some(rate) = speech_rate.filter(|r| r.suspicious) {
+            println!(
+                "tts[{request_id}]: implausible speech rate {:.0} wpm, output may be mumbled or truncated",
+                rate.words_per_minute
+            );
+        }
+
+        let policy = state.config.log_prompts;
+        let record = history::ClipRecord {
+            id: self.clip_id.clone(),
+            created_at: self.created_at,
+            prompt: policy.sanitize(&args.prompt),
+            description: policy.sanitize(&args.description),
+            temperature: args.temperature,
+            seed: args.seed,
+            top_p: args.top_p,
+            sample_rate: clip.sample_rate,
+            duration_secs: clip.duration_secs,
+            expires_at: namespace
+                .retention_for(voice.as_deref())
+                .map(|ttl| self.created_at + ttl.as_secs()),
+            voice: voice.clone(),
+            voice_version: self.voice_version,
+            speech_rate,
+            quality_retry: clip.quality_retry.clone(),
+            steps: Some(clip.steps),
+            finish_reason: Some(clip.finish),
+            memory: Some(clip.memory),
+            timings: Some(clip.timings),
+            over_budget: over_budget.clone(),
+            downgraded_max_steps: self.downgraded_max_steps,
+            features: self.features.clone(),
+            // Replays need the prompt as it was.
+            settings: Some(self.settings.clone()).filter(|_| policy == PromptLogging::Full),
+            self_check: self_check.clone().map(|report| self_check::Report {
+                transcript: policy.sanitize(&report.transcript),
+                ..report
+            }),
+        };
+        namespace.history.save(&record, &clip.wav).map_err(|e| {
+            println!("tts[{request_id}]: saving to history failed: {e:#}");
+            SynthesisError::Io(e)
+        })?;
+        // Cached quick phrases weren't generated for this request.
+        if let Some(tracker) = state.experiments.as_ref().filter(|_| self.quick_phrase.is_none()) {
+            tracker.log(
+                &record,
+                &[
+                    ("namespace", namespace.name.clone()),
+                    ("kind", "tts".to_string()),
+                    ("sampler", format!("{:?}", args.sampler)),
+                    ("max_steps", args.stopping.max_steps.to_string()),
+                ],
+            );
+        }
+        namespace.usage.record(args.prompt.chars().count(), clip.duration_secs);
+        Ok(Rendered {
+            clip,
+            over_budget,
+            speech_rate,
+            self_check,
+        })
+    }
+
+    /// Runs the self-check on `clip` when the job asks for one. While the
+    /// clip reads badly and `[self_check] retries` last, it is rendered
+    /// again with a new seed, from the second retry on in chunks half as
+    /// long; the attempt with the lowest word error rate is kept. Streamed
+    /// clips have gone out already and are only checked.
+    async fn checked(&self, clip: GeneratedClip, streamed: bool) -> (GeneratedClip, Option<self_check::Report>) {
+        let TtsJob { state, args, .. } = self;
+        let request_id = args.request_id;
+        // Cached quick phrases were checked when they were rendered, if at all.
+        let Some(checker) = state.self_check.as_ref().filter(|_| self.self_check && self.quick_phrase.is_none()) else {
+            return (clip, None);
+        };
+        let mut report = match checker.check(&clip.wav, &args.prompt).await {
+            Ok(report) => report,
+            Err(e) => {
+                println!("tts[{request_id}]: self-check failed: {e:#}");
+                return (clip, None);
+            }
+        };
+        let mut attempt = self_check::Attempt {
+            seed: args.seed.unwrap_or(0),
+            temperature: args.temperature.unwrap_or(0.0),
+            chunk_chars: self.chunk_chars,
+            word_error_rate: report.word_error_rate,
+        };
+        let mut attempts = vec![attempt.clone()];
+        let mut clip = clip;
+        let pool = self.pool.as_ref().filter(|_| !streamed);
+        while let Some(pool) = pool.filter(|_| checker.retries(&report, attempts.len() - 1)) {
+            attempt.seed = quality::retry_seed(attempt.seed);
+            // Greedy decoding ignores the seed, so a retry has to sample.
+            if attempt.temperature <= 0.0 {
+                attempt.temperature = 1.0;
+            }
+            if attempts.len() > 1 {
+                attempt.chunk_chars = (attempt.chunk_chars / 2).max(self_check::MIN_CHUNK_CHARS);
+            }
+            println!(
+                "tts[{request_id}]: word error rate {:.2}, rendering again with seed {} at temperature {} in chunks of {}",
+                report.word_error_rate, attempt.seed, attempt.temperature, attempt.chunk_chars
+            );
+            state.metrics.record_self_check_retry();
+            let retry_args = Arc::new(CreateWavArgs {
+                seed: Some(attempt.seed),
+                temperature: Some(attempt.temperature),
+                ..(**args).clone()
+            });
+            let retried = match create_wav_file(pool, &retry_args, attempt.chunk_chars, None).await {
+                Ok(retried) => retried,
+                Err(e) => {
+                    println!("tts[{request_id}]: self-check retry failed: {e} ({})", e.code());
+                    break;
+                }
+            };
+            let retried_report = match checker.check(&retried.wav, &args.prompt).await {
+                Ok(retried_report) => retried_report,
+                Err(e) => {
+                    println!("tts[{request_id}]: self-check failed: {e:#}");
+                    break;
+                }
+            };
+            attempt.word_error_rate = retried_report.word_error_rate;
+            attempts.push(attempt.clone());
+            if retried_report.word_error_rate < report.word_error_rate {
+                clip = GeneratedClip {
+                    memory: clip.memory,
+                    ..retried
+                };
+                report = retried_report;
+            }
+        }
+        if attempts.len() > 1 {
+            report.attempts = attempts;
+        }
+
+        state.metrics.record_self_check(report.flagged);
+        if report.flagged {
+            println!(
+                "tts[{request_id}]: word error rate {:.2} against the transcript, words may be skipped or garbled",
+                report.word_error_rate
+            );
+        }
+        (clip, Some(report))
+    }
+}
+
+/// Server-wide counters in the Prometheus text format.
+async fn metrics_report(State(state): State<AppState>) -> Response {
+    let gpus = state.loaded_pool().map(|pool| pool.engine().gpus().to_vec()).unwrap_or_default();
+    let body = tokio::task::spawn_blocking(move || state.metrics.render(&gpus))
+        .await
+        .unwrap_or_default();
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(axum::body::Body::from(body))
+        .unwrap()
+}
+
+/// Usage counters of the caller's namespace since server start.
+async fn usage_report(Tenant(namespace): Tenant) -> Json<namespace::UsageReport> {
+    Json(namespace.usage.report(&namespace.name))
+}
+
+#[derive(Serialize)]
+struct ServerStats {
+    /// Absent until the model has loaded, and for models without one.
+    tokenizer_cache: Option<token_cache::CacheStats>,
+}
+
+#[derive(Serialize)]
+struct InfoReport {
+    #[serde(flatten)]
+    info: info::ServerInfo,
+    /// Revision and dtype of the model serving requests; absent until it
+    /// has loaded.
+    model_version: Option<String>,
+    model_capabilities: Option<tts_model::Capabilities>,
+}
+
+/// The startup report, for clients adapting to what the server can do.
+async fn server_info(State(state): State<AppState>) -> Result<Json<InfoReport>, StatusCode> {
+    let info = state.info.get().cloned().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    Ok(Json(InfoReport {
+        info,
+        model_version: state.loaded_pool().map(|pool| pool.engine().version().to_string()),
+        model_capabilities: state.loaded_pool().map(|pool| pool.engine().capabilities()),
+    }))
+}
+
+/// Server-wide counters since the model loaded.
+async fn server_stats(State(state): State<AppState>) -> Json<ServerStats> {
+    Json(ServerStats {
+        tokenizer_cache: state.loaded_pool().and_then(|pool| pool.engine().token_cache_stats()),
+    })
+}
+
+/// Lists stored clips, newest first, including when each one expires.
+async fn list_history(Tenant(namespace): Tenant) -> Result<Json<Vec<history::ClipRecord>>, StatusCode> {
+    let history = namespace.history.clone();
+    tokio::task::spawn_blocking(move || history.records())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map(Json)
+        .map_err(|e| {
+            println!("history: listing failed: {e:#}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+/// The current version of each of the namespace's voice presets.
+async fn list_voices(
+    Tenant(namespace): Tenant,
+) -> Json<std::collections::BTreeMap<String, voices::PresetVersion>> {
+    Json(namespace.voices.current())
+}
+
+/// Stores a new version of a preset (or a new preset) from a JSON body
+/// shaped like a config entry.
+async fn update_voice(
+    Tenant(namespace): Tenant,
+    Path(name): Path<String>,
+    Json(preset): Json<config::VoicePreset>,
+) -> Result<Json<voices::PresetVersion>, StatusCode> {
+    if !history::valid_id(&name) || preset.description.trim().is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let version = namespace.voices.update(&name, preset).map_err(|e| {
+        println!("voices: storing {name:?} failed: {e:#}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    println!("voices: {name:?} is now version {} in namespace {}", version.version, namespace.name);
+    Ok(Json(version))
+}
+
+async fn voice_versions(
+    Tenant(namespace): Tenant,
+    Path(name): Path<String>,
+) -> Result<Json<Vec<voices::PresetVersion>>, StatusCode> {
+    namespace.voices.history(&name).map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Deserialize)]
+struct RollbackRequest {
+    version: u32,
+}
+
+/// Makes an old version of a preset current again, as a new version.
+async fn rollback_voice(
+    Tenant(namespace): Tenant,
+    Path(name): Path<String>,
+    Json(request): Json<RollbackRequest>,
+) -> Result<Json<voices::PresetVersion>, StatusCode> {
+    let version = namespace
+        .voices
+        .rollback(&name, request.version)
+        .map_err(|e| {
+            println!("voices: rolling back {name:?} failed: {e:#}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    println!(
+        "voices: {name:?} rolled back to version {} as version {} in namespace {}",
+        request.version, version.version, namespace.name
+    );
+    Ok(Json(version))
+}
+
+/// Streams a ZIP of the selected clips (all by default) with a manifest.
+async fn export_history(
+    Tenant(namespace): Tenant,
+    Query(selection): Query<export::Selection>,
+) -> Result<Response, StatusCode> {
+    let history = namespace.history.clone();
+    let records = tokio::task::spawn_blocking({
+        let history = history.clone();
+        move || history.records()
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|e| {
+        println!("history: listing failed: {e:#}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let records: Vec<_> = records.into_iter().filter(|r| selection.matches(r)).collect();
+    println!("history[{}]: exporting {} clips", namespace.name, records.len());
+
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let mut sender = export::ChunkSender::new(tx);
+        let written = export::write_archive(&history, records, &mut sender)
+            .and_then(|()| Ok(std::io::Write::flush(&mut sender)?));
+        if let Err(e) = written {
+            println!("history: export failed: {e:#}");
+            sender.fail(std::io::Error::other(e.to_string()));
+        }
+    });
+    let chunks = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+
+    Ok(Response::builder()
+        .status(200)
+        .header(header::CONTENT_TYPE, "application/zip")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}-history.zip\"", namespace.name),
+        )
+        .body(axum::body::Body::from_stream(chunks))
+        .unwrap())
+}
+
+/// Adds externally generated clips to the caller's history. Takes either an
+/// `archive` ZIP laid out like an export, or a `manifest` JSON array plus
+/// the audio files it names.
+async fn import_history(
+    State(state): State<AppState>,
+    Tenant(namespace): Tenant,
+    mut multipart: Multipart,
+) -> Result<Json<import::ImportReport>, StatusCode> {
+    let mut manifest = None;
+    let mut archives = Vec::new();
+    let mut files = std::collections::HashMap::new();
+    while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
+        let name = field.name().unwrap_or("").to_string();
+        let file_name = field.file_name().map(str::to_string);
+        let data = field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+        match name.as_str() {
+            "manifest" => {
+                manifest = Some(serde_json::from_slice(&data).map_err(|e| {
+                    println!("import: bad manifest: {e}");
+                    StatusCode::BAD_REQUEST
+                })?)
+            }
+            "archive" => archives.push(data),
+            _ => {
+                files.insert(file_name.unwrap_or(name), data.to_vec());
+            }
+        }
+    }
+
+    let policy = state.config.log_prompts;
+    let report = tokio::task::spawn_blocking(move || -> Result<_, StatusCode> {
+        // An archive's manifest takes the place of an uploaded one.
+        for archive in archives {
+            let (entries, archived) = import::unpack_archive(&archive, MAX_IMPORT_BYTES).map_err(|e| {
+                println!("import: bad archive: {e:#}");
+                StatusCode::BAD_REQUEST
+            })?;
+            manifest = Some(entries);
+            files.extend(archived);
+        }
+        let manifest = manifest.ok_or(StatusCode::BAD_REQUEST)?;
+        let context = import::ImportContext {
+            namespace: &namespace,
+            policy,
+            now: unix_now(),
+        };
+        let report = context.import(manifest, files);
+        println!(
+            "history[{}]: imported {} clips, skipped {}",
+            namespace.name,
+            report.imported.len(),
+            report.skipped.len()
+        );
+        Ok(report)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)??;
+    Ok(Json(report))
+}
+
+/// Serves a stored clip, decrypting it when encryption at rest is on.
+async fn history_audio(
+    State(state): State<AppState>,
+    Tenant(namespace): Tenant,
+    Path(id): Path<String>,
+) -> Result<Response, StatusCode> {
+    let history = namespace.history.clone();
+    let lookup = id.clone();
+    let audio = tokio::task::spawn_blocking(move || history.audio(&lookup))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| {
+            println!("history: reading {id} failed: {e:#}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let body = match &state.config.chaos {
+        Some(chaos) => chaos.body(&format!("history[{id}]"), audio),
+        None => axum::body::Body::from(audio),
+    };
+    Ok(Response::builder()
+        .status(200)
+        .header(header::CONTENT_TYPE, "audio/wav")
+        .body(body)
+        .unwrap())
+}
+
+#[derive(Deserialize)]
+struct TranscodeRequest {
+    /// History id of the clip.
+    id: String,
+    format: String,
+    sample_rate: Option<u32>,
+}
+
+/// A stored clip in another format or sample rate, without generating it
+/// again.
+async fn transcode_clip(
+    Tenant(namespace): Tenant,
+    Json(request): Json<TranscodeRequest>,
+) -> Result<Response, SynthesisError> {
+    let target = transcode::Target::parse(&request.format).ok_or(StatusCode::BAD_REQUEST)?;
+    if request
+        .sample_rate
+        .is_some_and(|rate| !(MIN_OUTPUT_RATE..=MAX_OUTPUT_RATE).contains(&rate))
+    {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    let history = namespace.history.clone();
+    let lookup = request.id.clone();
+    let wav = tokio::task::spawn_blocking(move || history.audio(&lookup))
+        .await
+        .map_err(|e| SynthesisError::Io(e.into()))?
+        .map_err(|e| {
+            println!("transcode: reading {} failed: {e:#}", request.id);
+            SynthesisError::Io(e)
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let pcm = audio::read_wav(&wav).map_err(SynthesisError::Decode)?;
+    let sample_rate = request.sample_rate.or(target.fixed_rate()).unwrap_or(pcm.sample_rate);
+    if !target.supports_rate(sample_rate) {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    let title = request.id.clone();
+    let audio = tokio::task::spawn_blocking(move || target.encode(&pcm, sample_rate, &title))
+        .await
+        .map_err(|e| SynthesisError::Encode(e.into()))?
+        .map_err(SynthesisError::Encode)?;
+    println!("transcode: {} as {} at {sample_rate} Hz", request.id, target.extension());
+    Ok(Response::builder()
+        .status(200)
+        .header(header::CONTENT_TYPE, target.content_type(sample_rate))
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.{}\"", request.id, target.extension()),
+        )
+        .body(axum::body::Body::from(audio))
+        .unwrap())
+}
+
+/// Clips a concatenation may join.
+const MAX_CONCAT_CLIPS: usize = 256;
+
+/// Longest gap or crossfade a concatenation may ask for.
+const MAX_CONCAT_GAP_SECS: f64 = 30.0;
+
+#[derive(Deserialize)]
+struct ConcatRequest {
+    clips: Vec<ConcatClip>,
+    /// Overlap of adjacent clips that have no gap between them.
+    #[serde(default)]
+    crossfade_secs: f64,
+    #[serde(default)]
+    format: String,
+    sample_rate: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct ConcatClip {
+    /// History id of the clip.
+    id: String,
+    /// Silence before the next clip.
+    #[serde(default)]
+    gap_secs: f64,
+}
+
+/// Stored clips joined into one file, e.g. the lines of a dialogue.
+async fn concat_clips(
+    Tenant(namespace): Tenant,
+    Json(request): Json<ConcatRequest>,
+) -> Result<Response, SynthesisError> {
+    let target = transcode::Target::parse(&request.format).ok_or(StatusCode::BAD_REQUEST)?;
+    let in_range = |secs: f64| secs.is_finite() && (0.0..=MAX_CONCAT_GAP_SECS).contains(&secs);
+    if request.clips.is_empty()
+        || request.clips.len() > MAX_CONCAT_CLIPS
+        || !in_range(request.crossfade_secs)
+        || !request.clips.iter().all(|clip| in_range(clip.gap_secs))
+        || request
+            .sample_rate
+            .is_some_and(|rate| !(MIN_OUTPUT_RATE..=MAX_OUTPUT_RATE).contains(&rate))
+    {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    let history = namespace.history.clone();
+    let ids: Vec<String> = request.clips.iter().map(|clip| clip.id.clone()).collect();
+    let wavs = tokio::task::spawn_blocking(move || {
+        ids.iter()
+            .map(|id| history.audio(id).map(|wav| wav.ok_or_else(|| id.clone())))
+            .collect::<anyhow::Result<Vec<_>>>()
+    })
+    .await
+    .map_err(|e| SynthesisError::Io(e.into()))?
+    .map_err(|e| {
+        println!("concat: reading clips failed: {e:#}");
+        SynthesisError::Io(e)
+    })?;
+    let mut clips = Vec::with_capacity(wavs.len());
+    for wav in wavs {
+        let wav = wav.map_err(|id| {
+            println!("concat: no clip {id}");
+            StatusCode::NOT_FOUND
+        })?;
+        clips.push(audio::read_wav(&wav).map_err(SynthesisError::Decode)?);
+    }
+    let sample_rate = request
+        .sample_rate
+        .or(target.fixed_rate())
+        .unwrap_or(clips[0].sample_rate);
+    if !target.supports_rate(sample_rate) {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    let gaps: Vec<f64> = request.clips.iter().map(|clip| clip.gap_secs).collect();
+    let title = format!("{}-joined", request.clips[0].id);
+    let filename = format!("{title}.{}", target.extension());
+    let crossfade_secs = request.crossfade_secs;
+    let audio = tokio::task::spawn_blocking(move || {
+        let samples = transcode::concat(&clips, &gaps, crossfade_secs, sample_rate);
+        let pcm = audio::Pcm { samples, sample_rate };
+        target.encode(&pcm, sample_rate, &title)
+    })
+    .await
+    .map_err(|e| SynthesisError::Encode(e.into()))?
+    .map_err(SynthesisError::Encode)?;
+    println!("concat: joined {} clips as {}", request.clips.len(), target.extension());
+    Ok(Response::builder()
+        .status(200)
+        .header(header::CONTENT_TYPE, target.content_type(sample_rate))
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{filename}\""))
+        .body(axum::body::Body::from(audio))
+        .unwrap())
+}
+
+/// A template's values rendered and its static parts taken from the
+/// cache, joined into one clip.
+async fn template_tts(
+    State(state): State<AppState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Tenant(namespace): Tenant,
+    Json(request): Json<templates::TemplateRequest>,
+) -> Result<Response, SynthesisError> {
+    let target = transcode::Target::parse(&request.format).ok_or(StatusCode::BAD_REQUEST)?;
+    let crossfade = request.crossfade_secs;
+    if !crossfade.is_finite()
+        || !(0.0..=MAX_CONCAT_GAP_SECS).contains(&crossfade)
+        || request
+            .sample_rate
+            .is_some_and(|rate| !(MIN_OUTPUT_RATE..=MAX_OUTPUT_RATE).contains(&rate) || !target.supports_rate(rate))
+    {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    let segments = templates::segments(&request.template, &request.values).map_err(|e| {
+        println!("tts[{request_id}]: template: {e}");
+        StatusCode::BAD_REQUEST
+    })?;
+    let announcement = templates::render(&state, namespace, request_id, &request, segments).await?;
+    let sample_rate = request
+        .sample_rate
+        .or(target.fixed_rate())
+        .unwrap_or(announcement.pcm.sample_rate);
+    if !target.supports_rate(sample_rate) {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    let filename = format!("{}.{}", announcement.clip_id, target.extension());
+    let title = announcement.clip_id.clone();
+    let pcm = announcement.pcm;
+    let audio = tokio::task::spawn_blocking(move || target.encode(&pcm, sample_rate, &title))
+        .await
+        .map_err(|e| SynthesisError::Encode(e.into()))?
+        .map_err(SynthesisError::Encode)?;
+    Ok(Response::builder()
+        .status(200)
+        .header(header::CONTENT_TYPE, target.content_type(sample_rate))
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{filename}\""))
+        .header("x-clip-id", &announcement.clip_id)
+        .header("x-template-parts", format!("{}/{}", announcement.cached, announcement.parts))
+        .body(axum::body::Body::from(audio))
+        .unwrap())
+}
+
+#[derive(Deserialize)]
+struct FragmentQuery {
+    voice: String,
+}
+
+/// Which of the fragment bank's numbers, weekdays and months are rendered
+/// in a voice.
+async fn fragment_bank(
+    State(state): State<AppState>,
+    Tenant(namespace): Tenant,
+    Query(query): Query<FragmentQuery>,
+) -> Result<Json<fragments::BankReport>, SynthesisError> {
+    fragments::report(&state, &namespace, &query.voice).await.map(Json)
+}
+
+/// Renders a voice's fragment bank again, in the background.
+async fn rebuild_fragment_bank(
+    State(state): State<AppState>,
+    Tenant(namespace): Tenant,
+    Query(query): Query<FragmentQuery>,
+) -> Result<Response, SynthesisError> {
+    let report = fragments::rebuild(&state, namespace, &query.voice).await?;
+    Ok(axum::response::IntoResponse::into_response((StatusCode::ACCEPTED, Json(report))))
+}
+
+async fn model_cache_report(
+    State(state): State<AppState>,
+) -> Result<Json<model_cache::CacheReport>, StatusCode> {
+    let cache_dir = state.config.hf_cache().path().clone();
+    tokio::task::spawn_blocking(move || model_cache::inspect(&cache_dir))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map(Json)
+        .map_err(|e| {
+            println!("model cache: inspection failed: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+/// Load of the serving model for autoscalers.
+async fn autoscale_signal(State(state): State<AppState>) -> Json<autoscale::Signal> {
+    let load = state.loaded_pool().map(|pool| pool.load());
+    Json(autoscale::signal(&state.config.autoscale, load, state.metrics.real_time_factor()))
+}
+
+#[derive(Serialize)]
+struct ModelStatus {
+    /// Unset until the model is loaded.
+    version: Option<String>,
+    /// Whether it was switched to at runtime rather than loaded at startup.
+    promoted: bool,
+    /// The candidate of a switch still running.
+    standby: Option<canary::Standby>,
+    last_canary: Option<canary::CanaryReport>,
+    /// The last switch that failed before its canary could be compared.
+    last_failure: Option<canary::SwitchFailure>,
+    /// `repo@revision` of the other checkpoints loaded for requests.
+    loaded: Vec<String>,
+}
+
+async fn model_status(State(state): State<AppState>) -> Json<ModelStatus> {
+    Json(ModelStatus {
+        version: state.loaded_pool().map(|pool| pool.engine().version().to_string()),
+        promoted: state.model_switch.promoted().is_some(),
+        standby: state.model_switch.standby(),
+        last_canary: state.model_switch.last_report(),
+        last_failure: state.model_switch.last_failure(),
+        loaded: state.models.loaded(),
+    })
+}
+
+#[derive(Deserialize)]
+struct SwitchModel {
+    repo: Option<String>,
+    revision: Option<String>,
+    /// Answer at once and warm the candidate up as a standby.
+    #[serde(default)]
+    background: bool,
+}
+
+/// Loads another checkpoint and serves it if its canary passes. Answers
+/// with the canary report: 200 when promoted, 422 when rejected; or, in
+/// the background, with the standby: 202.
+async fn switch_model(
+    State(state): State<AppState>,
+    Json(request): Json<SwitchModel>,
+) -> Result<Response, SynthesisError> {
+    let repo = request.repo.unwrap_or_else(|| state.config.models.repo.clone());
+    let revision = request.revision.unwrap_or_else(|| state.config.models.revision.clone());
+    if request.background {
+        let standby = state
+            .model_switch
+            .switch_in_background(&state, repo, revision)
+            .ok_or(StatusCode::CONFLICT)?;
+        return Ok(axum::response::IntoResponse::into_response((StatusCode::ACCEPTED, Json(standby))));
+    }
+    let report = state
+        .model_switch
+        .switch(&state, &repo, &revision)
+        .await
+        .ok_or(StatusCode::CONFLICT)??;
+    let status = if report.promoted { StatusCode::OK } else { StatusCode::UNPROCESSABLE_ENTITY };
+    Ok(axum::response::IntoResponse::into_response((status, Json(report))))
+}
+
+#[derive(Deserialize)]
+struct PurgeQuery {
+    repo: String,
+    /// Commit hash or ref name; the whole repo is removed when omitted.
+    revision: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PurgeResponse {
+    freed_bytes: u64,
+}
+
+async fn purge_model_cache(
+    State(state): State<AppState>,
+    Query(query): Query<PurgeQuery>,
+) -> Result<Json<PurgeResponse>, StatusCode> {
+    let cache_dir = state.config.hf_cache().path().clone();
+    let result = tokio::task::spawn_blocking(move || {
+        model_cache::purge(&cache_dir, &query.repo, query.revision.as_deref())
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    match result {
+        Ok(freed_bytes) => {
+            println!("model cache: purged {freed_bytes} bytes");
+            Ok(Json(PurgeResponse { freed_bytes }))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(StatusCode::NOT_FOUND),
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => Err(StatusCode::BAD_REQUEST),
+        Err(e) => {
+            println!("model cache: purge failed: {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DescribeResponse {
+    description: String,
+    analysis: analysis::VoiceAnalysis,
+}
+
+/// Drafts a voice description from an uploaded WAV sample (`audio` field).
+async fn describe_voice(mut multipart: Multipart) -> Result<Json<DescribeResponse>, StatusCode> {
+    let mut audio_bytes = None;
+    while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
+        if field.name() == Some("audio") {
+            audio_bytes = Some(field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?);
+        }
+    }
+    let audio_bytes = audio_bytes.ok_or(StatusCode::BAD_REQUEST)?;
+
+    let pcm = audio::read_wav(&audio_bytes).map_err(|e| {
+        println!("describe: rejected upload: {e}");
+        StatusCode::BAD_REQUEST
+    })?;
+    if pcm.duration_secs() < 1.0 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let analysis = tokio::task::spawn_blocking(move || analysis::analyze(&pcm))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let description = analysis::draft_description(&analysis);
+    println!("describe: {:?} -> {}", analysis, description);
+
+    Ok(Json(DescribeResponse {
+        description,
+        analysis,
+    }))
+}
+
+#[derive(Serialize)]
+struct SimilarityResponse {
+    /// 0 (unrelated) to 1 (identical embedding).
+    similarity: f64,
+    method: &'static str,
+}
+
+/// Scores how alike the speakers in two uploaded WAV clips (`a`, `b`) sound.
+async fn speaker_similarity(mut multipart: Multipart) -> Result<Json<SimilarityResponse>, StatusCode> {
+    let mut clip_a = None;
+    let mut clip_b = None;
+    while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
+        let name = field.name().unwrap_or("").to_string();
+        let bytes = field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+        match name.as_str() {
+            "a" => clip_a = Some(bytes),
+            "b" => clip_b = Some(bytes),
+            _ => {}
+        }
+    }
+    let (Some(clip_a), Some(clip_b)) = (clip_a, clip_b) else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    let decode = |bytes: &[u8]| {
+        audio::read_wav(bytes).map_err(|e| {
+            println!("similarity: rejected upload: {e}");
+            StatusCode::BAD_REQUEST
+        })
+    };
+    let (pcm_a, pcm_b) = (decode(&clip_a)?, decode(&clip_b)?);
+
+    let similarity = tokio::task::spawn_blocking(move || {
+        analysis::similarity(
+            &analysis::speaker_embedding(&pcm_a),
+            &analysis::speaker_embedding(&pcm_b),
+        )
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(SimilarityResponse {
+        similarity,
+        method: "mfcc-statistics",
+    }))
+}
+
+#[derive(Deserialize)]
+struct CompareQuery {
+    a: String,
+    b: String,
+}
+
+#[derive(Serialize)]
+struct CompareResponse {
+    #[serde(flatten)]
+    comparison: analysis::Comparison,
+    similarity_method: &'static str,
+}
+
+/// How history clip `b` differs from clip `a`: length, level, timbre and
+/// speaker, for checking a model or config change against earlier renders.
+async fn compare_clips(
+    Tenant(namespace): Tenant,
+    Query(query): Query<CompareQuery>,
+) -> Result<Json<CompareResponse>, StatusCode> {
+    let history = namespace.history.clone();
+    let comparison = tokio::task::spawn_blocking(move || {
+        let read = |id: &String| {
+            let wav = history
+                .audio(id)
+                .map_err(|e| {
+                    println!("history: reading {id} failed: {e:#}");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?
+                .ok_or(StatusCode::NOT_FOUND)?;
+            audio::read_wav(&wav).map_err(|e| {
+                println!("history: decoding {id} failed: {e:#}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })
+        };
+        Ok::<_, StatusCode>(analysis::compare(&read(&query.a)?, &read(&query.b)?))
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)??;
+    Ok(Json(CompareResponse {
+        comparison,
+        similarity_method: "mfcc-statistics",
+    }))
+}
+
+#[derive(Clone)]
+struct CreateWavArgs {
+    request_id: u64,
+    description: String,
+    prompt: String,
+    temperature: Option<f64>,
+    seed: Option<u64>,
+    top_p: Option<f64>,
+    post_process: PostProcess,
+    tokens: TokenControls,
+    sampler: SamplerKind,
+    stopping: Stopping,
+    /// Regenerate once with a new seed when the output looks broken.
+    retry_degenerate: bool,
+    wav_format: audio::WavFormat,
+    /// Words to bleep out, when the request asked for it.
+    bleep: Option<bleep::Bleep>,
+    /// Follows the render chunk by chunk, for queued jobs.
+    progress: Option<Arc<tts_jobs::Tracker>>,
+}
+
+impl CreateWavArgs {
+    /// Summary of the request with the text sanitized per `policy`.
+    fn log_line(&self, policy: PromptLogging) -> String {
+        let mut line = format!(
+            "tts[{}]: prompt={:?} description={:?} temperature={:?} seed={:?} top_p={:?} {:?}",
+            self.request_id,
+            policy.sanitize(&self.prompt),
+            policy.sanitize(&self.description),
+            self.temperature,
+            self.seed,
+            self.top_p,
+            self.post_process,
+        );
+        if self.sampler != SamplerKind::Stock {
+            line.push_str(&format!(" sampler={:?}", self.sampler));
+        }
+        if !self.tokens.is_empty() {
+            line.push_str(&format!(" {:?}", self.tokens));
+        }
+        line
+    }
+}
+
+/// Default loudness target, in LUFS.
+const NORMALIZE_REFERENCE_LUFS: f64 = -14.0;
+
+/// Peak level of the `peak_normalizer` feature, in dBFS.
+const PEAK_TARGET_DBFS: f64 = -1.0;
+
+/// Post-processing applied to the decoder output before it is written.
+#[derive(Debug, Clone)]
+struct PostProcess {
+    /// Skip every step below and return the decoder output untouched.
+    raw: bool,
+    normalize: bool,
+    /// Integrated loudness target in LUFS, used when `normalize` is set.
+    loudness_target: f64,
+    /// Soft-limit peaks with `tanh`.
+    compress: bool,
+    /// Normalize to `PEAK_TARGET_DBFS` instead of `loudness_target`.
+    peak_normalize: bool,
+}
+
+impl Default for PostProcess {
+    fn default() -> Self {
+        Self {
+            raw: false,
+            normalize: true,
+            loudness_target: NORMALIZE_REFERENCE_LUFS,
+            compress: true,
+            peak_normalize: false,
+        }
+    }
+}
+
+impl PostProcess {
+    fn apply(&self, pcm: &Tensor, sample_rate: u32) -> candle::Result<Tensor> {
+        if self.raw {
+            return Ok(pcm.clone());
+        }
+        let pcm = (pcm * self.gain(&pcm.to_vec1::<f32>()?, sample_rate))?;
+        if self.compress {
+            pcm.tanh()
+        } else {
+            Ok(pcm)
+        }
+    }
+
+    /// The normalizing gain for `samples`.
+    fn gain(&self, samples: &[f32], sample_rate: u32) -> f64 {
+        if self.raw || !self.normalize {
+            return 1.0;
+        }
+        if self.peak_normalize {
+            let peak = samples.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+            if peak >= 2e-3 {
+                10f64.powf(PEAK_TARGET_DBFS / 20.0) / peak as f64
+            } else {
+                1.0
+            }
+        } else {
+            loudness::normalize_gain(samples, sample_rate, self.loudness_target)
+        }
+    }
+
+    /// `apply` for one sample, given the gain.
+    fn shape(&self, sample: f32, gain: f64) -> f32 {
+        if self.raw {
+            return sample;
+        }
+        let sample = (sample as f64 * gain) as f32;
+        if self.compress {
+            sample.tanh()
+        } else {
+            sample
+        }
+    }
+}
+
+/// Audio held back while decoding incrementally before its gain is fixed:
+/// long enough to measure the loudness of speech.
+const LIVE_GAIN_SECS: f64 = 1.0;
+
+/// A chunk's audio sent on as the decoder produces it (`stream=incremental`).
+/// Post-processing can't see the whole chunk, so the gain is measured on
+/// the first `LIVE_GAIN_SECS` and kept for the rest.
+struct LiveAudio {
+    sink: AudioSink,
+    post_process: PostProcess,
+    sample_rate: u32,
+    held: Vec<f32>,
+    gain: Option<f64>,
+    /// The post-processed audio sent so far.
+    sent: Vec<f32>,
+}
+
+impl LiveAudio {
+    /// Starts with `gap` samples of silence when the chunk isn't the first.
+    fn new(sink: AudioSink, post_process: PostProcess, sample_rate: u32, gap: usize) -> Self {
+        if gap > 0 {
+            sink.send(&vec![0f32; gap]);
+        }
+        Self {
+            sink,
+            post_process,
+            sample_rate,
+            held: Vec::new(),
+            gain: None,
+            sent: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, pcm: &Tensor) {
+        let Ok(samples) = pcm.to_vec1::<f32>() else {
+            return;
+        };
+        if self.gain.is_some() {
+            self.send(samples);
+            return;
+        }
+        self.held.extend(samples);
+        // Holding back would miss the interactive deadline.
+        let due = self.sink.first_audio_due().is_some();
+        if due || self.held.len() as f64 >= LIVE_GAIN_SECS * self.sample_rate as f64 {
+            self.release();
+        }
+    }
+
+    /// Sends what is still held; the post-processed chunk.
+    fn finish(mut self) -> Vec<f32> {
+        if !self.held.is_empty() {
+            self.release();
+        }
+        self.sent
+    }
+
+    fn release(&mut self) {
+        let held = std::mem::take(&mut self.held);
+        self.gain = Some(self.post_process.gain(&held, self.sample_rate));
+        self.send(held);
+    }
+
+    fn send(&mut self, samples: Vec<f32>) {
+        let gain = self.gain.unwrap_or(1.0);
+        let pcm: Vec<f32> = samples.into_iter().map(|s| self.post_process.shape(s, gain)).collect();
+        self.sink.send(&pcm);
+        self.sent.extend(pcm);
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Builds the `sampler` choice from its name and the `typical_mass`,
+/// `mirostat_tau`, `mirostat_eta` and `temperature_end` fields.
+fn parse_sampler(name: &str, params: &[(String, f64)]) -> Option<SamplerKind> {
+    let param = |key: &str| params.iter().rev().find(|(k, _)| k == key).map(|(_, v)| *v);
+    let kind = match name {
+        "" | "stock" => SamplerKind::Stock,
+        "typical" => SamplerKind::Typical {
+            mass: param("typical_mass").unwrap_or(SamplerKind::DEFAULT_TYPICAL_MASS),
+        },
+        "mirostat" => SamplerKind::Mirostat {
+            tau: param("mirostat_tau").unwrap_or(SamplerKind::DEFAULT_MIROSTAT_TAU),
+            eta: param("mirostat_eta").unwrap_or(SamplerKind::DEFAULT_MIROSTAT_ETA),
+        },
+        "schedule" => SamplerKind::Schedule {
+            end_temperature: param("temperature_end")?,
+        },
+        _ => return None,
+    };
+    let valid = match kind {
+        SamplerKind::Stock => true,
+        SamplerKind::Typical { mass } => mass > 0.0 && mass <= 1.0,
+        SamplerKind::Mirostat { tau, eta } => tau > 0.0 && eta > 0.0,
+        SamplerKind::Schedule { end_temperature } => end_temperature >= 0.0,
+    };
+    valid.then_some(kind)
+}
+
+/// Parses a comma- or space-separated list of token ids.
+fn parse_token_ids(value: &str) -> Option<Vec<u32>> {
+    value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|id| !id.is_empty())
+        .map(|id| id.parse().ok())
+        .collect()
+}
+
+/// Parses a page range such as `12`, `3-40` or `5-` (to the end), 1-based.
+fn parse_page_range(value: &str) -> Option<std::ops::RangeInclusive<usize>> {
+    let value = value.trim();
+    let (first, last) = match value.split_once('-') {
+        Some((first, "")) => (first.trim().parse().ok()?, usize::MAX),
+        Some((first, last)) => (first.trim().parse().ok()?, last.trim().parse().ok()?),
+        None => {
+            let page = value.parse().ok()?;
+            (page, page)
+        }
+    };
+    (first >= 1 && first <= last).then_some(first..=last)
+}
+
+/// Parses a form flag such as `true`, `false`, `1` or `0`.
+/// `x-unsupported-characters`: `<position>:U+<code point>:<reason>` for
+/// the first few, comma-separated, so the header stays ASCII and short.
+fn unsupported_header(chars: &[tts_model::UnsupportedChar]) -> String {
+    let reason = |r: tts_model::Unsupported| match r {
+        tts_model::Unsupported::Unknown => "unknown",
+        tts_model::Unsupported::Dropped => "dropped",
+    };
+    chars
+        .iter()
+        .take(MAX_REPORTED_CHARS)
+        .map(|c| format!("{}:U+{:04X}:{}", c.position, c.character as u32, reason(c.reason)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn parse_flag(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// A generated clip, encoded as WAV.
+#[derive(Clone)]
+struct GeneratedClip {
+    wav: Vec<u8>,
+    sample_rate: u32,
+    duration_secs: f64,
+    /// Decoder steps over all chunks.
+    steps: usize,
+    /// `max_steps` when any chunk was cut off.
+    finish: FinishReason,
+    /// The first chunk that had to be regenerated, if any.
+    quality_retry: Option<QualityRetry>,
+    /// Filled in by the caller, which samples around the whole generation.
+    memory: memory::MemoryPeak,
+    timings: StageTimings,
+    /// Generation speed over the final attempts of all chunks.
+    secs_per_step: Option<f64>,
+}
+
+impl GeneratedClip {
+    fn to_phrase(&self) -> phrases::QuickPhrase {
+        phrases::QuickPhrase {
+            wav: self.wav.clone(),
+            sample_rate: self.sample_rate,
+            duration_secs: self.duration_secs,
+            steps: self.steps,
+            finish: self.finish,
+        }
+    }
+
+    fn from_phrase(phrase: &phrases::QuickPhrase) -> Self {
+        Self {
+            wav: phrase.wav.clone(),
+            sample_rate: phrase.sample_rate,
+            duration_secs: phrase.duration_secs,
+            steps: phrase.steps,
+            finish: phrase.finish,
+            quality_retry: None,
+            memory: memory::MemoryPeak::default(),
+            timings: StageTimings::default(),
+            secs_per_step: None,
+        }
+    }
+}
+
+/// Brings the quick phrase cache in line with the loaded model and the
+/// configured phrases and voice presets: renderings from another model
+/// version, of an edited voice or of a removed phrase are deleted, valid ones
+/// are loaded, and (with `rerender_quick_phrases`) missing ones rendered with
+/// the settings a request giving only `text` and `voice` would get.
+async fn render_quick_phrases(state: &AppState, pool: &Arc<EnginePool>) {
+    let descriptions: std::collections::BTreeSet<String> = state
+        .namespaces
+        .all()
+        .iter()
+        .flat_map(|namespace| {
+            let presets = namespace.voices.current().into_values().map(|current| current.preset);
+            presets.filter(|preset| !preset.has_sampling()).map(|preset| preset.description)
+        })
+        .collect();
+    let wanted: Vec<(String, String)> = descriptions
+        .iter()
+        .flat_map(|d| state.config.quick_phrases.iter().map(move |t| (d.clone(), t.trim().to_string())))
+        .collect();
+
+    let version = pool.engine().version().to_string();
+    let phrases = state.phrases.clone();
+    let sweep = {
+        let version = version.clone();
+        tokio::task::spawn_blocking(move || phrases.sweep(&version, &wanted)).await
+    };
+    let missing = match sweep {
+        Ok(Ok(sweep)) => {
+            if sweep.loaded + sweep.removed > 0 {
+                println!(
+                    "quick phrases: loaded {} from disk, removed {} stale",
+                    sweep.loaded, sweep.removed
+                );
+            }
+            sweep.missing
+        }
+        Ok(Err(e)) => {
+            println!("quick phrases: reading the cache failed: {e:#}");
+            return;
+        }
+        Err(e) => {
+            println!("quick phrases: cache sweep panicked: {e}");
+            return;
+        }
+    };
+    if missing.is_empty() || !state.config.rerender_quick_phrases {
+        return;
+    }
+
+    let start = std::time::Instant::now();
+    let mut rendered = 0;
+    for (description, text) in missing {
+        let args = Arc::new(CreateWavArgs {
+            request_id: 0,
+            description,
+            prompt: text,
+            temperature: None,
+            seed: None,
+            top_p: None,
+            post_process: PostProcess::default(),
+            tokens: TokenControls::default(),
+            sampler: SamplerKind::Stock,
+            stopping: state.config.max_steps.stopping(),
+            retry_degenerate: state.config.retry_degenerate,
+            wav_format: state.config.wav_format,
+            bleep: None,
+            progress: None,
+        });
+        let clip = match create_wav_file(pool, &args, state.config.chunk_chars, None).await {
+            Ok(clip) => clip,
+            Err(e) => {
+                println!("quick phrases: rendering {:?} failed: {e}", args.prompt);
+                continue;
+            }
+        };
+        let (phrases, version) = (state.phrases.clone(), version.clone());
+        let stored = tokio::task::spawn_blocking(move || {
+            phrases.insert(&version, &args.description, &args.prompt, clip.to_phrase())
+        })
+        .await;
+        match stored {
+            Ok(Ok(())) => rendered += 1,
+            Ok(Err(e)) => println!("quick phrases: storing a rendering failed: {e:#}"),
+            Err(e) => println!("quick phrases: storing a rendering panicked: {e}"),
+        }
+    }
+    println!("quick phrases: rendered {rendered} clips in {:?}", start.elapsed());
+}
+
+/// Silence inserted between the chunks of a long prompt.
+const CHUNK_GAP_SECS: f64 = 0.2;
+
+/// Generates the prompt, chunk by chunk when it is longer than
+/// `chunk_chars`. Chunks share the seed and voice and run on whichever pool
+/// workers are free; the audio is joined in the original order. An
+/// incremental stream renders them one after another instead, each sent as
+/// it is decoded.
+async fn create_wav_file(
+    pool: &Arc<EnginePool>,
+    create_wav_args: &Arc<CreateWavArgs>,
+    chunk_chars: usize,
+    sink: Option<&AudioSink>,
+) -> Result<GeneratedClip, SynthesisError> {
+    let id = create_wav_args.request_id;
+    let sample_rate = pool.engine().sample_rate();
+    let chunks = chunking::split(&create_wav_args.prompt, chunk_chars);
+    let count = chunks.len();
+    if count > 1 {
+        println!("tts[{id}]: split into {count} chunks over {} workers", pool.size());
+    }
+    let texts = chunks.clone();
+    let live = sink.filter(|sink| sink.incremental);
+    let gap_len = (CHUNK_GAP_SECS * sample_rate as f64) as usize;
+
+    let start = std::time::Instant::now();
+    let jobs = chunks.into_iter().enumerate().map(|(k, text)| {
+        let create_wav_args = create_wav_args.clone();
+        let live = live.cloned().map(|sink| (sink, if k > 0 { gap_len } else { 0 }));
+        async move {
+            let worker = pool.acquire().await;
+            let tag = if count > 1 {
+                format!("{id}.{}", k + 1)
+            } else {
+                id.to_string()
+            };
+            println!("tts[{tag}]: running on worker {}", worker.index());
+            worker
+                .run(move |engine| {
+                    let post_process = create_wav_args.post_process.clone();
+                    let mut live = live.map(|(sink, gap)| LiveAudio::new(sink, post_process, sample_rate, gap));
+                    let progress = create_wav_args.progress.as_ref().map(|tracker| tracker.chunk(k));
+                    let mut output = generate_chunk(engine, &create_wav_args, &tag, &text, live.as_mut(), progress)?;
+                    output.streamed = live.map(LiveAudio::finish);
+                    Ok(output)
+                })
+                .await
+        }
+    });
+    let gap = vec![0f32; gap_len];
+    let post_process = |samples: Vec<f32>| -> anyhow::Result<Vec<f32>> {
+        let pcm = Tensor::new(samples, &candle::Device::Cpu)?;
+        Ok(create_wav_args.post_process.apply(&pcm, sample_rate)?.to_vec1::<f32>()?)
+    };
+    let to_samples = |tensor: &Tensor| tensor.to_vec1::<f32>().map_err(|e| SynthesisError::Decode(e.into()));
+    // Each chunk's words are placed within the audio generated from it.
+    let bleep_chunk = |k: usize, pcm: &mut [f32]| {
+        if let Some(bleep) = &create_wav_args.bleep {
+            let found = bleep.apply(&texts[k], pcm, sample_rate);
+            if found > 0 {
+                println!("tts[{id}]: bleeped {found} words in chunk {}", k + 1);
+            }
+        }
+    };
+
+    // Chunks finish in any order but are taken in prompt order, so a stream
+    // gets each one as soon as everything before it is done. Streamed chunks
+    // are post-processed one by one.
+    let mut outputs = Vec::with_capacity(count);
+    let mut streamed = Vec::new();
+    let mut encode_time = std::time::Duration::ZERO;
+    let mut finished = futures::stream::iter(jobs).buffered(if live.is_some() { 1 } else { count });
+    while let Some(output) = finished.try_next().await? {
+        if let Some(pcm) = &output.streamed {
+            // The worker sent the chunk and the gap before it.
+            if !outputs.is_empty() {
+                streamed.extend_from_slice(&gap);
+            }
+            streamed.extend_from_slice(pcm);
+        } else if let Some(sink) = sink {
+            let encode_start = std::time::Instant::now();
+            let mut pcm = post_process(to_samples(&output.synthesis.pcm)?).map_err(SynthesisError::Encode)?;
+            bleep_chunk(outputs.len(), &mut pcm);
+            encode_time += encode_start.elapsed();
+            if !outputs.is_empty() {
+                streamed.extend_from_slice(&gap);
+                sink.send(&gap);
+            }
+            sink.send(&pcm);
+            streamed.extend(pcm);
+        }
+        if let Some(tracker) = &create_wav_args.progress {
+            tracker.chunk_done(outputs.len());
+        }
+        outputs.push(output);
+    }
+    if count > 1 {
+        println!("tts[{id}]: generated {count} chunks in {:?}", start.elapsed());
+    }
+
+    let mut spans = Vec::with_capacity(count);
+    let samples = match sink {
+        Some(_) => streamed,
+        None => {
+            let mut samples = Vec::new();
+            for (k, output) in outputs.iter().enumerate() {
+                if k > 0 {
+                    samples.extend_from_slice(&gap);
+                }
+                let start = samples.len();
+                samples.extend(to_samples(&output.synthesis.pcm)?);
+                spans.push(start..samples.len());
+            }
+            samples
+        }
+    };
+    let encode = || -> anyhow::Result<(Vec<f32>, Vec<u8>)> {
+        let pcm = match sink {
+            Some(_) => samples,
+            None => {
+                let mut pcm = post_process(samples)?;
+                for (k, span) in spans.into_iter().enumerate() {
+                    bleep_chunk(k, &mut pcm[span]);
+                }
+                pcm
+            }
+        };
+
+        let wav = audio::write_wav(&pcm, sample_rate, create_wav_args.wav_format);
+        Ok((pcm, wav))
+    };
+    if let Some(tracker) = &create_wav_args.progress {
+        tracker.phase(tts_model::Phase::Encoding);
+    }
+    let encode_start = std::time::Instant::now();
+    let (pcm, wav) = encode().map_err(SynthesisError::Encode)?;
+    let mut timings = outputs
+        .iter()
+        .fold(StageTimings::default(), |slowest, o| slowest.max(&o.timings));
+    timings.record(Stage::Encode, encode_time + encode_start.elapsed());
+
+    let steps: usize = outputs.iter().map(|o| o.synthesis.steps).sum();
+    let generate_secs: f64 = outputs.iter().map(|o| o.synthesis.timings.generate_secs).sum();
+    let truncated = outputs.iter().any(|o| o.synthesis.finish == FinishReason::MaxSteps);
+    Ok(GeneratedClip {
+        wav,
+        sample_rate,
+        duration_secs: pcm.len() as f64 / sample_rate as f64,
+        steps,
+        finish: if truncated { FinishReason::MaxSteps } else { FinishReason::Eos },
+        quality_retry: outputs.into_iter().find_map(|o| o.quality_retry),
+        memory: memory::MemoryPeak::default(),
+        timings,
+        secs_per_step: (steps > 0).then(|| generate_secs / steps as f64),
+    })
+}
+
+/// Raw decoder output for one chunk of a prompt.
+struct ChunkOutput {
+    synthesis: Synthesis,
+    quality_retry: Option<QualityRetry>,
+    /// Both attempts together when the chunk was retried.
+    timings: StageTimings,
+    /// The post-processed audio, when it was sent as it was decoded.
+    streamed: Option<Vec<f32>>,
+}
+
+/// Generates `text`, retrying once when the result is degenerate. `tag`
+/// prefixes the log lines. Audio going out `live` can't be taken back, so
+/// it isn't retried. `progress` is told the steps and phases.
+fn generate_chunk(
+    engine: &dyn TtsModel,
+    create_wav_args: &CreateWavArgs,
+    tag: &str,
+    text: &str,
+    mut live: Option<&mut LiveAudio>,
+    progress: Option<Arc<dyn tts_model::ProgressHook>>,
+) -> Result<ChunkOutput, SynthesisError> {
+    let mut sampling = Sampling {
+        temperature: create_wav_args.temperature.unwrap_or(0.0),
+        seed: create_wav_args.seed.unwrap_or(0),
+        top_p: create_wav_args.top_p,
+        stopping: create_wav_args.stopping.clone(),
+        tokens: create_wav_args.tokens.clone(),
+        sampler: create_wav_args.sampler.clone(),
+        first_audio_by: live.as_deref().and_then(|live| live.sink.first_audio_due()),
+        progress,
+    };
+
+    let sample_rate = engine.sample_rate();
+    let retry = create_wav_args.retry_degenerate && live.is_none();
+    let mut generate = |sampling: &Sampling| -> Result<(Synthesis, Option<Defect>), SynthesisError> {
+        let start = std::time::Instant::now();
+        let description = &create_wav_args.description;
+        let synthesis = match live.as_deref_mut() {
+            Some(live) => engine.stream(text, description, sampling, &mut |pcm| live.push(pcm))?,
+            None => engine.synthesize(text, description, sampling)?,
+        };
+        let samples = synthesis
+            .pcm
+            .to_vec1::<f32>()
+            .map_err(|e| SynthesisError::Decode(e.into()))?;
+        println!(
+            "tts[{tag}]: generated {} samples in {} steps ({}) in {:?}",
+            samples.len(),
+            synthesis.steps,
+            synthesis.finish.as_str(),
+            start.elapsed()
+        );
+        if synthesis.finish == FinishReason::MaxSteps && sampling.stopping.stop_on_eos {
+            println!(
+                "tts[{tag}]: hit max_steps ({}) before end-of-audio, output is probably truncated",
+                synthesis.max_steps
+            );
+        }
+        let defect = quality::assess(&samples, sample_rate, text);
+        Ok((synthesis, defect))
+    };
+
+    let (mut synthesis, defect) = generate(&sampling)?;
+    let mut timings = synthesis.timings;
+    let mut quality_retry = None;
+    if let Some(defect) = defect.filter(|_| retry) {
+        let first_seed = sampling.seed;
+        sampling.seed = quality::retry_seed(first_seed);
+        // Greedy decoding ignores the seed, so a retry has to sample.
+        if sampling.temperature <= 0.0 {
+            sampling.temperature = 1.0;
+        }
+        println!(
+            "tts[{tag}]: output looks degenerate ({}), retrying with seed {} at temperature {}",
+            defect.as_str(),
+            sampling.seed,
+            sampling.temperature
+        );
+        let (retried, still_defective) = generate(&sampling)?;
+        timings = timings.add(&retried.timings);
+        synthesis = retried;
+        quality_retry = Some(QualityRetry {
+            defect,
+            first_seed,
+            seed: sampling.seed,
+            temperature: sampling.temperature,
+            still_defective,
+        });
+    }
+    Ok(ChunkOutput {
+        synthesis,
+        quality_retry,
+        timings,
+        streamed: None,
+    })
 }
@@ -0,0 +1,106 @@
+//! Host and GPU memory readings, and a sampler that records the peak over
+//! one generation.
+//!
+//! Host memory is this process's resident set. GPU memory is what the driver
+//! reports as used on the engine's cards (via `nvidia-smi`), so it includes
+//! other processes and, with several workers, concurrent generations.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// How often the resident set is read while a generation runs.
+const HOST_INTERVAL: Duration = Duration::from_millis(50);
+/// GPU readings spawn `nvidia-smi`, so they are taken every this many host
+/// readings.
+const DEVICE_EVERY: u32 = 10;
+
+/// Highest readings seen during a generation, in bytes.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct MemoryPeak {
+    pub host_bytes: u64,
+    /// Unset on the CPU or when the driver can't be queried.
+    pub device_bytes: Option<u64>,
+}
+
+/// Resident set size of this process.
+pub fn host_rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find_map(|line| line.strip_prefix("VmRSS:"))?;
+    let kib: u64 = line.trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Memory in use on each of `gpus` (CUDA ordinals), summed.
+pub fn device_used_bytes(gpus: &[usize]) -> Option<u64> {
+    if gpus.is_empty() {
+        return None;
+    }
+    let output = std::process::Command::new("nvidia-smi")
+        .args(["--query-gpu=index,memory.used", "--format=csv,noheader,nounits"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())?;
+    let mut total = 0;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let (index, used) = line.split_once(',')?;
+        let index: usize = index.trim().parse().ok()?;
+        let mib: u64 = used.trim().parse().ok()?;
+        if gpus.contains(&index) {
+            total += mib * 1024 * 1024;
+        }
+    }
+    Some(total)
+}
+
+/// Samples memory on a background thread until [`PeakTracker::finish`].
+pub struct PeakTracker {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<MemoryPeak>>,
+}
+
+impl PeakTracker {
+    pub fn start(gpus: Vec<usize>) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handle = std::thread::spawn(move || {
+            let mut peak = MemoryPeak::default();
+            let mut tick = 0;
+            loop {
+                let done = stopped.load(Ordering::Relaxed);
+                peak.host_bytes = peak.host_bytes.max(host_rss_bytes().unwrap_or(0));
+                if done || tick % DEVICE_EVERY == 0 {
+                    if let Some(used) = device_used_bytes(&gpus) {
+                        peak.device_bytes = Some(peak.device_bytes.unwrap_or(0).max(used));
+                    }
+                }
+                if done {
+                    return peak;
+                }
+                tick += 1;
+                std::thread::sleep(HOST_INTERVAL);
+            }
+        });
+        Self {
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    /// Stops sampling, after one last reading. Blocks briefly.
+    pub fn finish(mut self) -> MemoryPeak {
+        self.stop.store(true, Ordering::Relaxed);
+        let handle = self.handle.take().expect("finish consumes the tracker");
+        handle.join().unwrap_or_default()
+    }
+}
+
+impl Drop for PeakTracker {
+    /// Stops the sampler of a request that was abandoned mid-generation.
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,211 @@
+//! Server-wide counters, exposed in the Prometheus text format.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::budget::Stage;
+use crate::memory::{self, MemoryPeak};
+
+#[derive(Default)]
+pub struct Metrics {
+    generations: AtomicU64,
+    panics: AtomicU64,
+    last_peak_host: AtomicU64,
+    max_peak_host: AtomicU64,
+    last_peak_device: AtomicU64,
+    max_peak_device: AtomicU64,
+    /// Moving average of generation speed, as `f64` bits; 0 until the
+    /// first generation.
+    secs_per_step: AtomicU64,
+    /// Moving average of generation time per second of audio, the same way.
+    real_time_factor: AtomicU64,
+    /// Indexed like [`Stage::ALL`].
+    over_budget: [AtomicU64; 4],
+    downgrades: AtomicU64,
+    /// Interactive requests, and those that missed their time to first audio.
+    slo_requests: AtomicU64,
+    slo_missed: AtomicU64,
+    /// Clips checked against their transcript, those flagged, and the
+    /// renderings made again for their error rate.
+    self_checks: AtomicU64,
+    self_checks_flagged: AtomicU64,
+    self_check_retries: AtomicU64,
+}
+
+/// Weight of the newest generation in the speed averages.
+const SPEED_SMOOTHING: f64 = 0.2;
+
+/// Folds `latest` into the moving average `average` holds as `f64` bits,
+/// where 0 means no value yet.
+pub fn update_average(average: &AtomicU64, latest: f64, weight: f64) {
+    let _ = average.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+        let current = f64::from_bits(bits);
+        let updated = if bits == 0 { latest } else { current + weight * (latest - current) };
+        Some(updated.to_bits())
+    });
+}
+
+pub fn read_average(average: &AtomicU64) -> Option<f64> {
+    let bits = average.load(Ordering::Relaxed);
+    (bits != 0).then(|| f64::from_bits(bits))
+}
+
+impl Metrics {
+    pub fn record_generation(&self, peak: &MemoryPeak, secs_per_step: Option<f64>, real_time_factor: Option<f64>) {
+        self.generations.fetch_add(1, Ordering::Relaxed);
+        if let Some(latest) = secs_per_step {
+            update_average(&self.secs_per_step, latest, SPEED_SMOOTHING);
+        }
+        if let Some(latest) = real_time_factor.filter(|f| *f > 0.0) {
+            update_average(&self.real_time_factor, latest, SPEED_SMOOTHING);
+        }
+        self.last_peak_host.store(peak.host_bytes, Ordering::Relaxed);
+        self.max_peak_host.fetch_max(peak.host_bytes, Ordering::Relaxed);
+        if let Some(device) = peak.device_bytes {
+            self.last_peak_device.store(device, Ordering::Relaxed);
+            self.max_peak_device.fetch_max(device, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_panic(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_over_budget(&self, stages: &[Stage]) {
+        for stage in stages {
+            let index = Stage::ALL.iter().position(|s| s == stage).unwrap_or_default();
+            self.over_budget[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_downgrade(&self) {
+        self.downgrades.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_self_check(&self, flagged: bool) {
+        self.self_checks.fetch_add(1, Ordering::Relaxed);
+        if flagged {
+            self.self_checks_flagged.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_self_check_retry(&self) {
+        self.self_check_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_slo(&self, missed: bool) {
+        self.slo_requests.fetch_add(1, Ordering::Relaxed);
+        if missed {
+            self.slo_missed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Recent seconds per decoder step, once anything has been generated.
+    pub fn secs_per_step(&self) -> Option<f64> {
+        read_average(&self.secs_per_step)
+    }
+
+    /// Recent seconds of generation per second of audio.
+    pub fn real_time_factor(&self) -> Option<f64> {
+        read_average(&self.real_time_factor)
+    }
+
+    /// The metrics page. `gpus` are the cards whose current usage is shown.
+    pub fn render(&self, gpus: &[usize]) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}");
+        };
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        metric(
+            "ttser_generations_total",
+            "counter",
+            "Completed generations.",
+            load(&self.generations),
+        );
+        metric(
+            "ttser_inference_panics_total",
+            "counter",
+            "Generations that failed with a panic inside the model.",
+            load(&self.panics),
+        );
+        metric(
+            "ttser_downgraded_total",
+            "counter",
+            "Generations whose max_steps was lowered to fit the generate budget.",
+            load(&self.downgrades),
+        );
+        metric(
+            "ttser_interactive_requests_total",
+            "counter",
+            "Requests in interactive mode.",
+            load(&self.slo_requests),
+        );
+        metric(
+            "ttser_slo_missed_total",
+            "counter",
+            "Interactive requests whose first audio came after the time-to-first-audio SLO.",
+            load(&self.slo_missed),
+        );
+        metric(
+            "ttser_self_checks_total",
+            "counter",
+            "Clips transcribed and compared with their prompt.",
+            load(&self.self_checks),
+        );
+        metric(
+            "ttser_self_checks_flagged_total",
+            "counter",
+            "Checked clips whose word error rate was above self_check.max_word_error_rate.",
+            load(&self.self_checks_flagged),
+        );
+        metric(
+            "ttser_self_check_retries_total",
+            "counter",
+            "Clips rendered again because their word error rate was above self_check.retry_above.",
+            load(&self.self_check_retries),
+        );
+        metric(
+            "ttser_host_memory_bytes",
+            "gauge",
+            "Resident set size of the server.",
+            memory::host_rss_bytes().unwrap_or(0),
+        );
+        metric(
+            "ttser_generation_peak_host_memory_bytes",
+            "gauge",
+            "Peak resident set size during the last generation.",
+            load(&self.last_peak_host),
+        );
+        metric(
+            "ttser_generation_peak_host_memory_max_bytes",
+            "gauge",
+            "Highest per-generation peak resident set size since startup.",
+            load(&self.max_peak_host),
+        );
+        if let Some(used) = memory::device_used_bytes(gpus) {
+            metric("ttser_device_memory_bytes", "gauge", "GPU memory in use.", used);
+            metric(
+                "ttser_generation_peak_device_memory_bytes",
+                "gauge",
+                "Peak GPU memory in use during the last generation.",
+                load(&self.last_peak_device),
+            );
+            metric(
+                "ttser_generation_peak_device_memory_max_bytes",
+                "gauge",
+                "Highest per-generation peak GPU memory since startup.",
+                load(&self.max_peak_device),
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP ttser_over_budget_total Generations with a stage past its time budget.\n\
+             # TYPE ttser_over_budget_total counter"
+        );
+        for (stage, counter) in Stage::ALL.iter().zip(&self.over_budget) {
+            let _ = writeln!(out, "ttser_over_budget_total{{stage=\"{}\"}} {}", stage.as_str(), load(counter));
+        }
+        out
+    }
+}
@@ -0,0 +1,472 @@
+//! The Parler-TTS model as the server runs it: candle's components, with
+//! the decoder optionally split across several GPUs.
+//!
+//! On one device this is exactly `parler_tts::Model`. With a device map the
+//! decoder layers are loaded in contiguous runs onto the listed GPUs and the
+//! hidden states move between cards at the run boundaries; the text encoder,
+//! embeddings, heads and audio codec stay on the first GPU. The sharded
+//! decoder mirrors candle's, whose layers are private.
+//!
+//! With quantized weights the decoder is built the same way, on one
+//! device, its attention and feed-forward projections and heads taking
+//! their weights from a GGUF file; everything else stays in the dtype.
+
+use candle::{DType, Device, IndexOp, Module, Result, Tensor};
+use candle_nn::{embedding, layer_norm, linear_b as linear, Activation, Embedding, LayerNorm, Linear, VarBuilder};
+use candle_transformers::models::parler_tts::{self, Config, DecoderConfig};
+use candle_transformers::models::{dac, t5};
+use candle_transformers::{quantized_nn, quantized_var_builder};
+
+#[derive(Debug, Clone)]
+pub struct Model {
+    pub embed_prompts: Embedding,
+    pub enc_to_dec_proj: Option<Linear>,
+    pub decoder: Decoder,
+    pub text_encoder: t5::T5EncoderModel,
+    pub decoder_start_token_id: u32,
+    pub pad_token_id: u32,
+    pub audio_encoder: dac::Model,
+}
+
+impl Model {
+    /// The whole model on `vb`'s device.
+    pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let parler_tts::Model {
+            embed_prompts,
+            enc_to_dec_proj,
+            decoder,
+            text_encoder,
+            decoder_start_token_id,
+            pad_token_id,
+            audio_encoder,
+        } = parler_tts::Model::new(cfg, vb)?;
+        Ok(Self {
+            embed_prompts,
+            enc_to_dec_proj,
+            decoder: Decoder::Single(decoder),
+            text_encoder,
+            decoder_start_token_id,
+            pad_token_id,
+            audio_encoder,
+        })
+    }
+
+    /// The decoder layers split over `shards`, each a builder on one device
+    /// and how many consecutive layers it holds. Everything else goes on
+    /// the first shard's device.
+    pub fn sharded(cfg: &Config, shards: &[(VarBuilder, usize)]) -> Result<Self> {
+        let shards: Vec<_> = shards
+            .iter()
+            .map(|(vb, layers)| (Weights::full(vb.clone()), *layers))
+            .collect();
+        Self::build(cfg, &shards)
+    }
+
+    /// The whole model on `vb`'s device, the decoder projections and heads
+    /// from `quantized` instead.
+    pub fn quantized(cfg: &Config, vb: VarBuilder, quantized: quantized_var_builder::VarBuilder) -> Result<Self> {
+        let layers = cfg.decoder.num_hidden_layers;
+        Self::build(
+            cfg,
+            &[(
+                Weights {
+                    full: vb,
+                    quantized: Some(quantized),
+                },
+                layers,
+            )],
+        )
+    }
+
+    fn build(cfg: &Config, shards: &[(Weights, usize)]) -> Result<Self> {
+        let Some((weights, _)) = shards.first() else {
+            candle::bail!("a sharded model needs at least one device");
+        };
+        let vb = &weights.full;
+        let text_encoder = t5::T5EncoderModel::load(vb.pp("text_encoder"), &cfg.text_encoder)?;
+        let decoder_shards: Vec<_> = shards
+            .iter()
+            .map(|(weights, layers)| (weights.pp("decoder"), *layers))
+            .collect();
+        let decoder = ShardedDecoder::new(&cfg.decoder, &decoder_shards)?;
+        let embed_prompts = embedding(cfg.vocab_size, cfg.decoder.hidden_size, vb.pp("embed_prompts"))?;
+        let enc_to_dec_proj = if cfg.text_encoder.d_model != cfg.decoder.hidden_size {
+            Some(linear(
+                cfg.text_encoder.d_model,
+                cfg.decoder.hidden_size,
+                true,
+                vb.pp("enc_to_dec_proj"),
+            )?)
+        } else {
+            None
+        };
+        let audio_encoder = dac::Model::new(&cfg.audio_encoder, vb.pp("audio_encoder.model"))?;
+        Ok(Self {
+            embed_prompts,
+            enc_to_dec_proj,
+            decoder: Decoder::Sharded(decoder),
+            text_encoder,
+            decoder_start_token_id: cfg.decoder_start_token_id,
+            pad_token_id: cfg.pad_token_id,
+            audio_encoder,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Decoder {
+    Single(parler_tts::Decoder),
+    Sharded(ShardedDecoder),
+}
+
+/// Where decoder weights come from: all from `full`, or the projections
+/// and heads from `quantized`.
+#[derive(Clone)]
+struct Weights<'a> {
+    full: VarBuilder<'a>,
+    quantized: Option<quantized_var_builder::VarBuilder>,
+}
+
+impl<'a> Weights<'a> {
+    fn full(full: VarBuilder<'a>) -> Self {
+        Self { full, quantized: None }
+    }
+
+    fn pp(&self, s: impl ToString) -> Self {
+        let s = s.to_string();
+        Self {
+            full: self.full.pp(&s),
+            quantized: self.quantized.as_ref().map(|q| q.pp(&s)),
+        }
+    }
+
+    /// A projection without bias. Tensors left out of the GGUF file (those
+    /// whose rows don't divide into its blocks) load in full.
+    fn proj(&self, in_dim: usize, out_dim: usize, s: &str) -> Result<Proj> {
+        match &self.quantized {
+            Some(q) if q.pp(s).contains_key("weight") => {
+                Ok(Proj::Quantized(quantized_nn::linear_b(in_dim, out_dim, false, q.pp(s))?))
+            }
+            _ => Ok(Proj::Full(linear(in_dim, out_dim, false, self.full.pp(s))?)),
+        }
+    }
+
+    fn device(&self) -> &Device {
+        self.full.device()
+    }
+}
+
+/// A decoder projection in the dtype or quantized.
+#[derive(Debug, Clone)]
+enum Proj {
+    Full(Linear),
+    Quantized(quantized_nn::Linear),
+}
+
+impl Module for Proj {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Full(proj) => proj.forward(xs),
+            Self::Quantized(proj) => proj.forward(xs),
+        }
+    }
+}
+
+impl Decoder {
+    /// Logits for each codebook, on the first device.
+    pub fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        prompt_hidden_states: Option<&Tensor>,
+        attention_mask: Option<&Tensor>,
+        encoder_xs: &Tensor,
+        encoder_attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Vec<Tensor>> {
+        match self {
+            Self::Single(decoder) => decoder.forward(
+                input_ids,
+                prompt_hidden_states,
+                attention_mask,
+                encoder_xs,
+                encoder_attention_mask,
+                seqlen_offset,
+            ),
+            Self::Sharded(decoder) => decoder.forward(
+                input_ids,
+                prompt_hidden_states,
+                attention_mask,
+                encoder_xs,
+                encoder_attention_mask,
+                seqlen_offset,
+            ),
+        }
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        match self {
+            Self::Single(decoder) => decoder.clear_kv_cache(),
+            Self::Sharded(decoder) => decoder.clear_kv_cache(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ShardedDecoder {
+    embed_tokens: Vec<Embedding>,
+    embed_positions: Tensor,
+    layers: Vec<DecoderLayer>,
+    layer_norm: LayerNorm,
+    num_codebooks: usize,
+    hidden_size: usize,
+    lm_heads: Vec<Proj>,
+    dtype: DType,
+    /// Where the embeddings and heads live, and where the output ends up.
+    device: Device,
+}
+
+impl ShardedDecoder {
+    fn new(cfg: &DecoderConfig, shards: &[(Weights, usize)]) -> Result<Self> {
+        let total: usize = shards.iter().map(|(_, layers)| layers).sum();
+        if total != cfg.num_hidden_layers {
+            candle::bail!(
+                "device map places {total} decoder layers, the model has {}",
+                cfg.num_hidden_layers
+            );
+        }
+        let weights = &shards[0].0;
+        let vb = &weights.full;
+        let vb_d = vb.pp("model.decoder");
+        let vb_e = vb_d.pp("embed_tokens");
+        let embed_tokens = (0..cfg.num_codebooks)
+            .map(|i| embedding(cfg.vocab_size + 1, cfg.hidden_size, vb_e.pp(i)))
+            .collect::<Result<Vec<_>>>()?;
+        let embed_positions = vb_d.get(
+            (cfg.max_position_embeddings, cfg.hidden_size),
+            "embed_positions.weights",
+        )?;
+
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        for (weights, count) in shards {
+            let vb_l = weights.pp("model.decoder.layers");
+            for _ in 0..*count {
+                layers.push(DecoderLayer::new(cfg, vb_l.pp(layers.len()))?);
+            }
+        }
+        let layer_norm = layer_norm(cfg.hidden_size, 1e-5, vb_d.pp("layer_norm"))?;
+        let vb_h = weights.pp("lm_heads");
+        let lm_heads = (0..cfg.num_codebooks)
+            .map(|i| vb_h.proj(cfg.hidden_size, cfg.vocab_size, &i.to_string()))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            embed_tokens,
+            embed_positions,
+            layers,
+            layer_norm,
+            num_codebooks: cfg.num_codebooks,
+            hidden_size: cfg.hidden_size,
+            lm_heads,
+            dtype: vb.dtype(),
+            device: vb.device().clone(),
+        })
+    }
+
+    fn forward(
+        &mut self,
+        input_ids: &Tensor,
+        prompt_hidden_states: Option<&Tensor>,
+        attention_mask: Option<&Tensor>,
+        encoder_xs: &Tensor,
+        encoder_attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Vec<Tensor>> {
+        let (b_sz, num_codebooks, seq_len) = input_ids.dims3()?;
+        if num_codebooks != self.num_codebooks {
+            candle::bail!("unexpected num codebooks in input {:?}", input_ids.shape())
+        }
+        let mut inputs_embeds = Tensor::zeros((b_sz, seq_len, self.hidden_size), self.dtype, input_ids.device())?;
+        for (idx, embs) in self.embed_tokens.iter().enumerate() {
+            inputs_embeds = (inputs_embeds + input_ids.i((.., idx))?.apply(embs)?)?;
+        }
+        let inputs_embeds = match prompt_hidden_states {
+            None => inputs_embeds,
+            Some(pis) => Tensor::cat(&[pis, &inputs_embeds], 1)?,
+        };
+        let embed_positions = self
+            .embed_positions
+            .i(seqlen_offset..seqlen_offset + inputs_embeds.dim(1)?)?;
+        let mut xs = (inputs_embeds + embed_positions.unsqueeze(0))?;
+
+        // The masks and encoder output follow the hidden states from card to card.
+        let mut attention_mask = attention_mask.cloned();
+        let mut encoder_xs = encoder_xs.clone();
+        let mut encoder_attention_mask = encoder_attention_mask.cloned();
+        for layer in self.layers.iter_mut() {
+            if !xs.device().same_device(&layer.device) {
+                let device = &layer.device;
+                xs = xs.to_device(device)?;
+                encoder_xs = encoder_xs.to_device(device)?;
+                attention_mask = attention_mask.map(|m| m.to_device(device)).transpose()?;
+                encoder_attention_mask = encoder_attention_mask.map(|m| m.to_device(device)).transpose()?;
+            }
+            xs = layer.forward(&xs, attention_mask.as_ref(), &encoder_xs, encoder_attention_mask.as_ref())?;
+        }
+        let xs = xs.to_device(&self.device)?.apply(&self.layer_norm)?;
+        self.lm_heads.iter().map(|head| xs.apply(head)).collect()
+    }
+
+    fn clear_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.clear_kv_cache()
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DecoderLayer {
+    self_attn: Attention,
+    self_attn_layer_norm: LayerNorm,
+    encoder_attn: Attention,
+    encoder_attn_layer_norm: LayerNorm,
+    fc1: Proj,
+    fc2: Proj,
+    final_layer_norm: LayerNorm,
+    activation: Activation,
+    device: Device,
+}
+
+impl DecoderLayer {
+    fn new(cfg: &DecoderConfig, weights: Weights) -> Result<Self> {
+        let kv_heads = cfg.num_key_value_heads.unwrap_or(cfg.num_attention_heads);
+        let kv_heads_cross = cfg.num_cross_attention_key_value_heads.unwrap_or(kv_heads);
+        let vb = &weights.full;
+        Ok(Self {
+            self_attn: Attention::new(kv_heads, true, cfg, weights.pp("self_attn"))?,
+            self_attn_layer_norm: layer_norm(cfg.hidden_size, 1e-5, vb.pp("self_attn_layer_norm"))?,
+            encoder_attn: Attention::new(kv_heads_cross, false, cfg, weights.pp("encoder_attn"))?,
+            encoder_attn_layer_norm: layer_norm(cfg.hidden_size, 1e-5, vb.pp("encoder_attn_layer_norm"))?,
+            fc1: weights.proj(cfg.hidden_size, cfg.ffn_dim, "fc1")?,
+            fc2: weights.proj(cfg.ffn_dim, cfg.hidden_size, "fc2")?,
+            final_layer_norm: layer_norm(cfg.hidden_size, 1e-5, vb.pp("final_layer_norm"))?,
+            activation: cfg.activation_function,
+            device: weights.device().clone(),
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        encoder_xs: &Tensor,
+        encoder_attention_mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = xs.apply(&self.self_attn_layer_norm)?;
+        let xs = (residual + self.self_attn.forward(&xs, None, attention_mask)?)?;
+
+        let residual = &xs;
+        let xs = xs.apply(&self.encoder_attn_layer_norm)?;
+        let xs = (residual
+            + self
+                .encoder_attn
+                .forward(&xs, Some(encoder_xs), encoder_attention_mask)?)?;
+
+        let residual = &xs;
+        let xs = xs
+            .apply(&self.final_layer_norm)?
+            .apply(&self.fc1)?
+            .apply(&self.activation)?
+            .apply(&self.fc2)?;
+        residual + xs
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.self_attn.kv_cache = None;
+        self.encoder_attn.kv_cache = None;
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    k_proj: Proj,
+    v_proj: Proj,
+    q_proj: Proj,
+    out_proj: Proj,
+    is_causal: bool,
+    kv_cache: Option<(Tensor, Tensor)>,
+    scaling: f64,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+}
+
+impl Attention {
+    fn new(num_kv_heads: usize, is_causal: bool, cfg: &DecoderConfig, weights: Weights) -> Result<Self> {
+        if cfg.rope_embeddings {
+            candle::bail!("rope embeddings are not supported");
+        }
+        let embed_dim = cfg.hidden_size;
+        let head_dim = embed_dim / cfg.num_attention_heads;
+        let kv_out_dim = num_kv_heads * head_dim;
+        Ok(Self {
+            k_proj: weights.proj(embed_dim, kv_out_dim, "k_proj")?,
+            v_proj: weights.proj(embed_dim, kv_out_dim, "v_proj")?,
+            q_proj: weights.proj(embed_dim, embed_dim, "q_proj")?,
+            out_proj: weights.proj(embed_dim, embed_dim, "out_proj")?,
+            is_causal,
+            kv_cache: None,
+            scaling: (head_dim as f64).powf(-0.5),
+            num_heads: cfg.num_attention_heads,
+            num_kv_heads,
+            num_kv_groups: cfg.num_attention_heads / num_kv_heads,
+            head_dim,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        key_value_states: Option<&Tensor>,
+        attention_mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let (b_sz, tgt_len, _) = xs.dims3()?;
+        let query_states = (xs.apply(&self.q_proj)? * self.scaling)?
+            .reshape((b_sz, tgt_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let kv_input = key_value_states.unwrap_or(xs);
+        let heads = |proj: &Proj| -> Result<Tensor> {
+            kv_input
+                .apply(proj)?
+                .reshape((b_sz, (), self.num_kv_heads, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()
+        };
+        let (key_states, value_states) = (heads(&self.k_proj)?, heads(&self.v_proj)?);
+
+        let (key_states, value_states) = match &self.kv_cache {
+            None => (key_states, value_states),
+            Some((prev_k, prev_v)) => (
+                Tensor::cat(&[prev_k, &key_states], 2)?,
+                Tensor::cat(&[prev_v, &value_states], 2)?,
+            ),
+        };
+        if self.is_causal {
+            self.kv_cache = Some((key_states.clone(), value_states.clone()));
+        }
+
+        let key_states = candle_transformers::utils::repeat_kv(key_states, self.num_kv_groups)?.contiguous()?;
+        let value_states = candle_transformers::utils::repeat_kv(value_states, self.num_kv_groups)?.contiguous()?;
+        let attn_weights = query_states.matmul(&key_states.transpose(2, 3)?)?;
+        let attn_weights = match attention_mask {
+            None => attn_weights,
+            Some(mask) => attn_weights.broadcast_add(mask)?,
+        };
+        candle_nn::ops::softmax_last_dim(&attn_weights)?
+            .matmul(&value_states)?
+            .transpose(1, 2)?
+            .reshape((b_sz, tgt_len, ()))?
+            .apply(&self.out_proj)
+    }
+}
@@ -0,0 +1,225 @@
+//! Inspection and cleanup of the Hugging Face hub cache, which holds several
+//! gigabytes of model weights per checkpoint.
+//!
+//! Layout (per repo): `<kind>s--<org>--<name>/` with `blobs/<hash>` holding
+//! the data, `snapshots/<commit>/<file>` symlinking into `blobs`, and
+//! `refs/<name>` containing the commit a ref points at.
+
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct CacheReport {
+    pub cache_dir: PathBuf,
+    pub total_bytes: u64,
+    pub repos: Vec<CachedRepo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CachedRepo {
+    pub repo: String,
+    pub kind: String,
+    /// Bytes on disk; blobs shared between revisions are counted once.
+    pub size_bytes: u64,
+    pub revisions: Vec<CachedRevision>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CachedRevision {
+    pub commit: String,
+    pub refs: Vec<String>,
+    pub files: Vec<CachedFile>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CachedFile {
+    pub path: String,
+    pub size_bytes: u64,
+}
+
+pub fn inspect(cache_dir: &Path) -> io::Result<CacheReport> {
+    let mut repos = Vec::new();
+    if cache_dir.is_dir() {
+        for entry in std::fs::read_dir(cache_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let Some((kind, repo)) = parse_folder_name(&name) else {
+                continue;
+            };
+            repos.push(inspect_repo(&entry.path(), kind, repo)?);
+        }
+    }
+    repos.sort_by(|a, b| a.repo.cmp(&b.repo));
+    Ok(CacheReport {
+        cache_dir: cache_dir.to_path_buf(),
+        total_bytes: repos.iter().map(|r| r.size_bytes).sum(),
+        repos,
+    })
+}
+
+fn inspect_repo(dir: &Path, kind: String, repo: String) -> io::Result<CachedRepo> {
+    let size_bytes = files_under(&dir.join("blobs"))?
+        .iter()
+        .filter_map(|p| std::fs::metadata(p).ok())
+        .map(|m| m.len())
+        .sum();
+
+    let refs = read_refs(dir)?;
+    let mut revisions = Vec::new();
+    let snapshots = dir.join("snapshots");
+    if snapshots.is_dir() {
+        for entry in std::fs::read_dir(&snapshots)? {
+            let entry = entry?;
+            let commit = entry.file_name().to_string_lossy().into_owned();
+            let root = entry.path();
+            let mut files: Vec<CachedFile> = files_under(&root)?
+                .into_iter()
+                .map(|path| CachedFile {
+                    // Dangling links (blob already purged) report zero bytes.
+                    size_bytes: std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0),
+                    path: path
+                        .strip_prefix(&root)
+                        .unwrap_or(&path)
+                        .to_string_lossy()
+                        .into_owned(),
+                })
+                .collect();
+            files.sort_by(|a, b| a.path.cmp(&b.path));
+            revisions.push(CachedRevision {
+                refs: refs
+                    .iter()
+                    .filter(|(_, c)| **c == commit)
+                    .map(|(r, _)| r.clone())
+                    .collect(),
+                commit,
+                files,
+            });
+        }
+    }
+    revisions.sort_by(|a, b| a.commit.cmp(&b.commit));
+    Ok(CachedRepo {
+        repo,
+        kind,
+        size_bytes,
+        revisions,
+    })
+}
+
+/// Removes a cached repo, or a single revision of it (commit hash or ref
+/// name). Returns the number of bytes freed.
+pub fn purge(cache_dir: &Path, repo: &str, revision: Option<&str>) -> io::Result<u64> {
+    if !valid_repo_id(repo) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid repo id"));
+    }
+    let dir = ["models", "datasets", "spaces"]
+        .iter()
+        .map(|kind| cache_dir.join(format!("{kind}--{}", repo.replace('/', "--"))))
+        .find(|d| d.is_dir())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "repo is not cached"))?;
+    let before = dir_size(&dir)?;
+
+    let Some(revision) = revision else {
+        std::fs::remove_dir_all(&dir)?;
+        return Ok(before);
+    };
+
+    let refs = read_refs(&dir)?;
+    let commit = refs.get(revision).cloned().unwrap_or_else(|| revision.to_string());
+    if commit.contains(['/', '\\']) || commit.starts_with('.') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid revision"));
+    }
+    let snapshot = dir.join("snapshots").join(&commit);
+    if !snapshot.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "revision is not cached"));
+    }
+    std::fs::remove_dir_all(&snapshot)?;
+    for (name, target) in &refs {
+        if *target == commit {
+            std::fs::remove_file(dir.join("refs").join(name))?;
+        }
+    }
+
+    // Drop blobs no remaining snapshot links to.
+    let mut referenced = HashSet::new();
+    for link in files_under(&dir.join("snapshots"))? {
+        if let Ok(target) = std::fs::read_link(&link) {
+            if let Some(name) = target.file_name() {
+                referenced.insert(name.to_os_string());
+            }
+        }
+    }
+    for blob in files_under(&dir.join("blobs"))? {
+        if blob.file_name().is_some_and(|n| !referenced.contains(n)) {
+            std::fs::remove_file(&blob)?;
+        }
+    }
+    if referenced.is_empty() {
+        std::fs::remove_dir_all(&dir)?;
+        return Ok(before);
+    }
+    Ok(before.saturating_sub(dir_size(&dir)?))
+}
+
+/// `models--org--name` -> (`model`, `org/name`).
+fn parse_folder_name(name: &str) -> Option<(String, String)> {
+    let (kind, rest) = name.split_once("--")?;
+    let kind = kind.strip_suffix('s')?;
+    if !matches!(kind, "model" | "dataset" | "space") || rest.is_empty() {
+        return None;
+    }
+    Some((kind.to_string(), rest.replace("--", "/")))
+}
+
+fn valid_repo_id(repo: &str) -> bool {
+    let parts: Vec<&str> = repo.split('/').collect();
+    parts.len() <= 2
+        && parts.iter().all(|p| {
+            !p.is_empty()
+                && !p.starts_with('.')
+                && p.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        })
+}
+
+/// Ref name (e.g. `main`, `refs/pr/1` as `pr/1`) -> commit.
+fn read_refs(repo_dir: &Path) -> io::Result<BTreeMap<String, String>> {
+    let root = repo_dir.join("refs");
+    let mut refs = BTreeMap::new();
+    for path in files_under(&root)? {
+        let name = path.strip_prefix(&root).unwrap_or(&path).to_string_lossy().into_owned();
+        refs.insert(name, std::fs::read_to_string(&path)?.trim().to_string());
+    }
+    Ok(refs)
+}
+
+/// Files and symlinks below `dir`, recursively; empty when `dir` is missing.
+fn files_under(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut out = Vec::new();
+    if !dir.is_dir() {
+        return Ok(out);
+    }
+    let mut stack = vec![dir.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                stack.push(entry.path());
+            } else {
+                out.push(entry.path());
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Bytes actually stored, not following snapshot symlinks.
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    Ok(files_under(dir)?
+        .iter()
+        .filter_map(|p| std::fs::symlink_metadata(p).ok())
+        .filter(|m| m.is_file())
+        .map(|m| m.len())
+        .sum())
+}
@@ -0,0 +1,139 @@
+//! Which checkpoints the server runs. The `[models]` section names the one
+//! served by default (Parler-TTS Large unless configured otherwise) and
+//! the others requests may ask for with the `model` field: mini for speed
+//! on CPUs, the multilingual checkpoints, or a fine-tune of one's own.
+//! Each is loaded on its first request and kept, the least recently used
+//! dropped beyond `loaded`.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use serde::Deserialize;
+use tokio::sync::OnceCell;
+
+use crate::config::ServerConfig;
+use crate::hub;
+use crate::pool::EnginePool;
+use crate::tts_model;
+
+/// The `[models]` config section.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Models {
+    /// Hub repo id, or a local directory laid out like one, served when a
+    /// request names no model.
+    pub repo: String,
+    pub revision: String,
+    /// Further checkpoints requests may name, as `repo` or `repo@revision`.
+    pub allowed: Vec<String>,
+    /// Allowed checkpoints kept loaded at once, besides the default.
+    pub loaded: usize,
+}
+
+impl Default for Models {
+    fn default() -> Self {
+        Self {
+            repo: hub::MODEL_REPO.to_string(),
+            revision: hub::MODEL_REVISION.to_string(),
+            allowed: Vec::new(),
+            loaded: 2,
+        }
+    }
+}
+
+impl Models {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.repo.trim().is_empty() || self.revision.trim().is_empty() {
+            anyhow::bail!("models.repo and models.revision must not be empty");
+        }
+        if self.allowed.iter().any(|entry| split(entry).0.is_empty()) {
+            anyhow::bail!("models.allowed entries must name a repo");
+        }
+        if !self.allowed.is_empty() && self.loaded == 0 {
+            anyhow::bail!("models.loaded must be at least 1 when models are allowed");
+        }
+        Ok(())
+    }
+
+    /// The checkpoint a request's `model` names: None for the default one,
+    /// else the allowed `(repo, revision)`. A model is named by its repo id
+    /// or just the name after the owner (`parler-tts-mini-v1`).
+    pub fn resolve(&self, name: &str) -> Result<Option<(&str, &str)>, String> {
+        let name = name.trim();
+        let matches = |repo: &str| repo == name || repo.rsplit('/').next() == Some(name);
+        if name.is_empty() || matches(&self.repo) {
+            return Ok(None);
+        }
+        self.allowed
+            .iter()
+            .map(|entry| split(entry))
+            .find(|(repo, _)| matches(repo))
+            .map(Some)
+            .ok_or_else(|| format!("model {name:?} is not allowed"))
+    }
+}
+
+/// `repo@revision` into its parts, `main` when no revision is given.
+fn split(entry: &str) -> (&str, &str) {
+    let entry = entry.trim();
+    match entry.rsplit_once('@') {
+        Some((repo, revision)) if !revision.is_empty() => (repo, revision),
+        _ => (entry, hub::MODEL_REVISION),
+    }
+}
+
+/// Loads `repo` at `revision` into a pool of the configured `workers`.
+pub async fn load(config: &Arc<ServerConfig>, repo: &str, revision: &str) -> anyhow::Result<EnginePool> {
+    let files = hub::fetch_repo_files(config, repo, revision).await?;
+    let server_config = config.clone();
+    let engine = tokio::task::spawn_blocking(move || tts_model::load(&server_config, &files)).await??;
+    Ok(EnginePool::new(engine, config.workers))
+}
+
+/// A checkpoint's pool, once loaded.
+type Slot = Arc<OnceCell<Arc<EnginePool>>>;
+
+/// The allowed checkpoints loaded so far, most recently used last.
+/// Requests for one that is loading wait for that load rather than
+/// starting their own.
+#[derive(Default)]
+pub struct Registry {
+    pools: Mutex<VecDeque<(String, Slot)>>,
+}
+
+impl Registry {
+    /// The pool serving `repo` at `revision`, loading it if need be.
+    pub async fn pool(&self, config: &Arc<ServerConfig>, repo: &str, revision: &str) -> anyhow::Result<Arc<EnginePool>> {
+        let key = format!("{repo}@{revision}");
+        let cell = {
+            let mut pools = self.pools.lock().unwrap();
+            let entry = match pools.iter().position(|(k, _)| *k == key) {
+                Some(at) => pools.remove(at).unwrap(),
+                None => (key, Arc::default()),
+            };
+            let cell = entry.1.clone();
+            pools.push_back(entry);
+            // Requests running on a dropped model finish on it.
+            while pools.len() > config.models.loaded {
+                if let Some((dropped, _)) = pools.pop_front() {
+                    println!("model: unloading {dropped}");
+                }
+            }
+            cell
+        };
+        cell.get_or_try_init(|| async {
+            println!("model: loading {repo}@{revision} on request");
+            let pool = load(config, repo, revision).await?;
+            println!("model: {repo}@{revision} loaded with {} generation workers", pool.size());
+            Ok(Arc::new(pool))
+        })
+        .await
+        .cloned()
+    }
+
+    /// `repo@revision` of each loaded checkpoint.
+    pub fn loaded(&self) -> Vec<String> {
+        let pools = self.pools.lock().unwrap();
+        pools.iter().filter(|(_, cell)| cell.initialized()).map(|(key, _)| key.clone()).collect()
+    }
+}
@@ -0,0 +1,169 @@
+//! Tenants of a shared server. Each namespace is reached through its API keys
+//! and has its own voice presets, history (in its own audio directory) and
+//! usage counters. Without configured namespaces everything runs in the
+//! anonymous `default` namespace.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::bail;
+use axum::extract::FromRequestParts;
+use axum::http::{header, request::Parts, StatusCode};
+use serde::Serialize;
+
+use crate::config::ServerConfig;
+use crate::crypto::Cipher;
+use crate::history::History;
+use crate::voices::Voices;
+use crate::AppState;
+
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// Header carrying the API key, as an alternative to `Authorization: Bearer`.
+const API_KEY_HEADER: &str = "x-api-key";
+
+pub struct Namespace {
+    pub name: String,
+    pub voices: Voices,
+    retention: Option<Duration>,
+    pub history: Arc<History>,
+    pub usage: Usage,
+}
+
+impl Namespace {
+    /// Retention for a clip generated with `voice` (if any).
+    pub fn retention_for(&self, voice: Option<&str>) -> Option<Duration> {
+        voice
+            .and_then(|v| self.voices.get(v))
+            .and_then(|current| current.preset.retention)
+            .or(self.retention)
+    }
+}
+
+/// Counters since server start.
+#[derive(Default)]
+pub struct Usage {
+    requests: AtomicU64,
+    characters: AtomicU64,
+    audio_millis: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageReport {
+    pub namespace: String,
+    pub requests: u64,
+    pub characters: u64,
+    pub audio_secs: f64,
+}
+
+impl Usage {
+    pub fn record(&self, characters: usize, audio_secs: f64) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.characters.fetch_add(characters as u64, Ordering::Relaxed);
+        self.audio_millis
+            .fetch_add((audio_secs * 1000.0) as u64, Ordering::Relaxed);
+    }
+
+    pub fn report(&self, namespace: &str) -> UsageReport {
+        UsageReport {
+            namespace: namespace.to_string(),
+            requests: self.requests.load(Ordering::Relaxed),
+            characters: self.characters.load(Ordering::Relaxed),
+            audio_secs: self.audio_millis.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+}
+
+pub struct Namespaces {
+    by_key: HashMap<String, Arc<Namespace>>,
+    /// Used for requests without an API key, when those are allowed.
+    anonymous: Option<Arc<Namespace>>,
+    all: Vec<Arc<Namespace>>,
+}
+
+impl Namespaces {
+    pub fn from_config(config: &ServerConfig, cipher: Option<Cipher>) -> anyhow::Result<Self> {
+        let mut by_key = HashMap::new();
+        let mut all = Vec::new();
+
+        let default = Arc::new(Namespace {
+            name: DEFAULT_NAMESPACE.to_string(),
+            voices: Voices::load(config.audio_dir.clone(), cipher.clone(), &config.voices)?,
+            retention: config.retention,
+            history: Arc::new(History::new(config.audio_dir.clone(), cipher.clone())),
+            usage: Usage::default(),
+        });
+        all.push(default.clone());
+
+        for (name, ns) in &config.namespaces {
+            if name == DEFAULT_NAMESPACE
+                || name.is_empty()
+                || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                bail!("invalid namespace name {name:?}");
+            }
+            if ns.api_keys.is_empty() {
+                bail!("namespace {name:?} has no api_keys");
+            }
+            let namespace = Arc::new(Namespace {
+                name: name.clone(),
+                voices: Voices::load(config.audio_dir.join(name), cipher.clone(), &ns.voices)?,
+                retention: ns.retention.or(config.retention),
+                history: Arc::new(History::new(config.audio_dir.join(name), cipher.clone())),
+                usage: Usage::default(),
+            });
+            for key in &ns.api_keys {
+                if by_key.insert(key.clone(), namespace.clone()).is_some() {
+                    bail!("api key of namespace {name:?} is used more than once");
+                }
+            }
+            all.push(namespace);
+        }
+
+        let anonymous = (config.namespaces.is_empty() || config.allow_anonymous).then_some(default);
+        Ok(Self {
+            by_key,
+            anonymous,
+            all,
+        })
+    }
+
+    pub fn all(&self) -> &[Arc<Namespace>] {
+        &self.all
+    }
+
+    fn resolve(&self, api_key: Option<&str>) -> Option<Arc<Namespace>> {
+        match api_key {
+            Some(key) => self.by_key.get(key).cloned(),
+            None => self.anonymous.clone(),
+        }
+    }
+}
+
+/// The namespace a request belongs to, from its API key.
+pub struct Tenant(pub Arc<Namespace>);
+
+impl FromRequestParts<AppState> for Tenant {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let bearer = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        let api_key = bearer.or_else(|| {
+            parts
+                .headers
+                .get(API_KEY_HEADER)
+                .and_then(|v| v.to_str().ok())
+        });
+        state
+            .namespaces
+            .resolve(api_key.map(str::trim))
+            .map(Tenant)
+            .ok_or(StatusCode::UNAUTHORIZED)
+    }
+}
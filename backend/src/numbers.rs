@@ -0,0 +1,819 @@
+//! Numbers and dates spelled out before synthesis. Parler-TTS reads digits
+//! unreliably, and the same digits mean different things per language:
+//! "1.234,56" is a thousand and more in German but not in English, and
+//! "12/05/2024" is in December in the US and in May elsewhere. A request's
+//! `locale` picks the separators, the date order and the words.
+//!
+//! Codes, call signs and serial numbers are better read character by
+//! character instead, which `spell` does.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    En,
+    De,
+    Fr,
+    Es,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Locale {
+    pub language: Language,
+    /// Whether `a/b/yyyy` is month first (US English) instead of day first.
+    month_first: bool,
+}
+
+/// Longest integer read as a number; longer runs (and ones with leading
+/// zeros, like codes) are read digit by digit.
+const MAX_DIGITS: usize = 15;
+
+impl Locale {
+    /// `en`, `de`, `fr` or `es`, optionally with a region (`en-GB`,
+    /// `de_AT`). English dates are month first unless the region says
+    /// otherwise.
+    pub fn parse(tag: &str) -> Option<Self> {
+        let tag = tag.trim().replace('_', "-").to_ascii_lowercase();
+        let (language, region) = tag.split_once('-').unwrap_or((&tag, ""));
+        let language = match language {
+            "en" => Language::En,
+            "de" => Language::De,
+            "fr" => Language::Fr,
+            "es" => Language::Es,
+            _ => return None,
+        };
+        let month_first = language == Language::En && matches!(region, "" | "us" | "ph" | "ca");
+        Some(Self { language, month_first })
+    }
+
+    fn decimal_separator(self) -> char {
+        match self.language {
+            Language::En => '.',
+            Language::De | Language::Fr | Language::Es => ',',
+        }
+    }
+
+    fn group_separators(self) -> &'static [char] {
+        match self.language {
+            Language::En => &[','],
+            Language::De => &['.', '\''],
+            Language::Fr => &['\u{a0}', '\u{202f}', '.'],
+            Language::Es => &['.', '\u{a0}', '\u{202f}'],
+        }
+    }
+
+    pub fn english() -> Self {
+        Self {
+            language: Language::En,
+            month_first: true,
+        }
+    }
+
+    pub fn cardinal(self, n: u64) -> String {
+        match self.language {
+            Language::En => en::cardinal(n),
+            Language::De => de::cardinal(n),
+            Language::Fr => fr::cardinal(n),
+            Language::Es => es::cardinal(n),
+        }
+    }
+
+    /// January to December.
+    pub fn months(self) -> [&'static str; 12] {
+        self.words().months
+    }
+
+    fn words(self) -> Words {
+        match self.language {
+            Language::En => Words {
+                minus: "minus",
+                point: "point",
+                percent: "percent",
+                months: en::MONTHS,
+            },
+            Language::De => Words {
+                minus: "minus",
+                point: "Komma",
+                percent: "Prozent",
+                months: de::MONTHS,
+            },
+            Language::Fr => Words {
+                minus: "moins",
+                point: "virgule",
+                percent: "pour cent",
+                months: fr::MONTHS,
+            },
+            Language::Es => Words {
+                minus: "menos",
+                point: "coma",
+                percent: "por ciento",
+                months: es::MONTHS,
+            },
+        }
+    }
+}
+
+struct Words {
+    minus: &'static str,
+    point: &'static str,
+    percent: &'static str,
+    months: [&'static str; 12],
+}
+
+/// The `[spelling]` config section: how `spell=true` requests read
+/// letters and symbols.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Spelling {
+    /// Pronunciations by letter, e.g. `{ A = "ay", Z = "zed" }`, for letters
+    /// the model reads as a word. Others are read as the capital letter.
+    pub letters: BTreeMap<String, String>,
+    /// Words by symbol, replacing or adding to the built-in ones of the
+    /// request's language (`-` "dash", `/` "slash", `.` "dot", ...).
+    pub symbols: BTreeMap<String, String>,
+}
+
+impl Spelling {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (key, word) in self.letters.iter().chain(&self.symbols) {
+            if key.chars().count() != 1 {
+                anyhow::bail!("spelling keys must be single characters, not {key:?}");
+            }
+            if word.trim().is_empty() {
+                anyhow::bail!("spelling.{key:?} must not be empty");
+            }
+        }
+        if let Some(key) = self.letters.keys().find(|k| !k.chars().all(char::is_alphabetic)) {
+            anyhow::bail!("spelling.letters has {key:?}, which is not a letter");
+        }
+        Ok(())
+    }
+
+    fn letter(&self, letter: char) -> Option<&str> {
+        let upper: String = letter.to_uppercase().collect();
+        let lower: String = letter.to_lowercase().collect();
+        self.letters.get(&upper).or_else(|| self.letters.get(&lower)).map(String::as_str)
+    }
+}
+
+/// `text` read character by character: letters as capitals (or their
+/// configured pronunciation), digits and symbols in words, and a pause
+/// where the text has a space. "ABC-123" becomes "A B C dash one two three".
+/// Punctuation at the end of a word is left out.
+pub fn spell(text: &str, locale: Locale, spelling: &Spelling) -> String {
+    let mut groups = Vec::new();
+    for group in text.split_whitespace() {
+        // Punctuation ending a word ends the sentence, not the code.
+        let group = group.trim_end_matches([',', ';', ':', '!', '?', '.']);
+        let mut words = Vec::new();
+        for c in group.chars() {
+            let mut key = [0; 4];
+            let key: &str = c.encode_utf8(&mut key);
+            if c.is_alphabetic() {
+                match spelling.letter(c) {
+                    Some(word) => words.push(word.to_string()),
+                    None => words.push(c.to_uppercase().collect()),
+                }
+            } else if let Some(digit) = c.to_digit(10) {
+                words.push(locale.cardinal(digit as u64));
+            } else if let Some(word) = spelling.symbols.get(key).map(String::as_str).or_else(|| symbol(c, locale)) {
+                words.push(word.to_string());
+            }
+        }
+        if !words.is_empty() {
+            groups.push(words.join(" "));
+        }
+    }
+    groups.join(", ")
+}
+
+/// The built-in word for a symbol; others are left out when spelling.
+fn symbol(c: char, locale: Locale) -> Option<&'static str> {
+    let words: [&str; 12] = match locale.language {
+        Language::En => [
+            "dash", "slash", "dot", "at", "plus", "underscore", "hash", "and", "colon", "star", "comma", "backslash",
+        ],
+        Language::De => [
+            "Bindestrich", "Schrägstrich", "Punkt", "at", "plus", "Unterstrich", "Raute", "und", "Doppelpunkt",
+            "Stern", "Komma", "Backslash",
+        ],
+        Language::Fr => [
+            "tiret", "barre oblique", "point", "arobase", "plus", "tiret bas", "dièse", "et", "deux-points",
+            "étoile", "virgule", "barre oblique inversée",
+        ],
+        Language::Es => [
+            "guion", "barra", "punto", "arroba", "más", "guion bajo", "almohadilla", "y", "dos puntos", "asterisco",
+            "coma", "barra invertida",
+        ],
+    };
+    let n = "-/.@+_#&:*,\\".chars().position(|s| s == c)?;
+    Some(words[n])
+}
+
+/// `text` with its numbers, percentages, ordinals and dates in words.
+pub fn verbalize(text: &str, locale: Locale) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < chars.len() {
+        if !chars[i].is_ascii_digit() {
+            out.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        // A sign counts only at the start of a word, so ranges and phone
+        // numbers like "555-1234" keep their dash.
+        let negative = i >= 1
+            && matches!(chars[i - 1], '-' | '−')
+            && (i == 1 || chars[i - 2].is_whitespace() || chars[i - 2] == '(');
+        let previous = out.split_whitespace().last().unwrap_or("").to_lowercase();
+        let found = date(&chars, i, locale, &previous).or_else(|| dotted(&chars, i, locale));
+        let (mut words, end) = found.unwrap_or_else(|| number(&chars, i, locale));
+        if negative {
+            out.pop();
+            words = format!("{} {words}", locale.words().minus);
+        }
+        if out.chars().last().is_some_and(char::is_alphanumeric) {
+            out.push(' ');
+        }
+        out.push_str(&words);
+        i = end;
+        if chars.get(i).is_some_and(|c| c.is_alphanumeric()) {
+            out.push(' ');
+        }
+    }
+    out
+}
+
+/// The run of ASCII digits starting at `i`.
+fn digits(chars: &[char], i: usize) -> &[char] {
+    let len = chars[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+    &chars[i..i + len]
+}
+
+/// Saturates on runs too long for a u64, which are spelled digit by digit
+/// anyway.
+fn value(digits: &[char]) -> u64 {
+    digits
+        .iter()
+        .fold(0u64, |n, d| n.saturating_mul(10).saturating_add(d.to_digit(10).unwrap_or(0) as u64))
+}
+
+/// A date starting at `i`: `yyyy-mm-dd`, or `a/b/yyyy`, `a.b.yyyy` or
+/// `a-b-yyyy` with day and month in the locale's order (always day first
+/// with dots).
+fn date(chars: &[char], i: usize, locale: Locale, previous: &str) -> Option<(String, usize)> {
+    let first = digits(chars, i);
+    let sep = *chars.get(i + first.len())?;
+    if !matches!(sep, '/' | '.' | '-') {
+        return None;
+    }
+    let j = i + first.len() + 1;
+    let second = digits(chars, j).to_vec();
+    if second.is_empty() || chars.get(j + second.len()) != Some(&sep) {
+        return None;
+    }
+    let k = j + second.len() + 1;
+    let third = digits(chars, k);
+    let end = k + third.len();
+    // Not part of a longer dotted run, like a version or an IP address.
+    let continues = chars.get(end) == Some(&sep) && chars.get(end + 1).is_some_and(char::is_ascii_digit);
+    if third.is_empty() || continues {
+        return None;
+    }
+    let (year, month, day) = if first.len() == 4 && sep == '-' && second.len() <= 2 && third.len() <= 2 {
+        (value(first), value(&second), value(third))
+    } else if first.len() <= 2 && second.len() <= 2 && third.len() == 4 {
+        if locale.month_first && sep == '/' {
+            (value(third), value(first), value(&second))
+        } else {
+            (value(third), value(&second), value(first))
+        }
+    } else {
+        return None;
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let month_name = locale.words().months[month as usize - 1];
+    let words = match locale.language {
+        Language::En if locale.month_first => format!("{month_name} {}, {}", en::ordinal(day), en::year(year)),
+        Language::En => format!("the {} of {month_name}, {}", en::ordinal(day), en::year(year)),
+        Language::De => {
+            // "am zwölften Mai", but "der zwölfte Mai".
+            let ending = if matches!(previous, "der" | "ist") { "" } else { "n" };
+            format!("{}{ending} {month_name} {}", de::ordinal(day), de::year(year))
+        }
+        Language::Fr => {
+            let day = if day == 1 { "premier".to_string() } else { fr::cardinal(day) };
+            format!("{day} {month_name} {}", fr::cardinal(year))
+        }
+        Language::Es => format!("{} de {month_name} de {}", es::cardinal(day), es::cardinal(year)),
+    };
+    Some((words, end))
+}
+
+/// A version number or IP address starting at `i`: three or more groups of
+/// digits joined by dots, read group by group ("one dot two dot three").
+/// German thousands such as "1.000.000" are left to [`number`].
+fn dotted(chars: &[char], i: usize, locale: Locale) -> Option<(String, usize)> {
+    let mut groups = vec![digits(chars, i)];
+    let mut end = i + groups[0].len();
+    while chars.get(end) == Some(&'.') && chars.get(end + 1).is_some_and(char::is_ascii_digit) {
+        let group = digits(chars, end + 1);
+        groups.push(group);
+        end += 1 + group.len();
+    }
+    let grouped = locale.group_separators().contains(&'.')
+        && groups[0].len() <= 3
+        && groups[1..].iter().all(|group| group.len() == 3);
+    if groups.len() < 3 || grouped {
+        return None;
+    }
+    let dot = format!(" {} ", symbol('.', locale)?);
+    let words: Vec<String> = groups
+        .iter()
+        .map(|group| match group.len() > 3 || (group.len() > 1 && group[0] == '0') {
+            true => spell_digits(group, locale),
+            false => locale.cardinal(value(group)),
+        })
+        .collect();
+    Some((words.join(&dot), end))
+}
+
+/// A number starting at `i`, with the locale's group and decimal
+/// separators, and a following `%` or ordinal suffix.
+fn number(chars: &[char], i: usize, locale: Locale) -> (String, usize) {
+    let words = locale.words();
+    let mut integer = digits(chars, i).to_vec();
+    let mut end = i + integer.len();
+    // Groups of exactly three digits after a separator.
+    if integer.len() <= 3 {
+        while chars.get(end).is_some_and(|c| locale.group_separators().contains(c)) {
+            let group = digits(chars, end + 1);
+            if group.len() != 3 {
+                break;
+            }
+            integer.extend_from_slice(group);
+            end += 4;
+        }
+    }
+    let mut decimals = None;
+    if chars.get(end) == Some(&locale.decimal_separator()) {
+        let fraction = digits(chars, end + 1);
+        if !fraction.is_empty() {
+            decimals = Some(fraction.to_vec());
+            end += 1 + fraction.len();
+        }
+    }
+
+    let n = value(&integer);
+    let spelled = (integer.len() > 1 && integer[0] == '0') || integer.len() > MAX_DIGITS;
+    let mut text = if spelled {
+        spell_digits(&integer, locale)
+    } else {
+        locale.cardinal(n)
+    };
+    if let Some(decimals) = &decimals {
+        text = format!("{text} {} {}", words.point, spell_digits(decimals, locale));
+    }
+
+    // "5%", and "5 %" as German and French write it.
+    let percent_at = match chars.get(end) {
+        Some('%') => Some(end),
+        Some('\u{a0}' | '\u{202f}' | ' ') if chars.get(end + 1) == Some(&'%') => Some(end + 1),
+        _ => None,
+    };
+    if let Some(at) = percent_at {
+        return (format!("{text} {}", words.percent), at + 1);
+    }
+    if decimals.is_none() && !spelled {
+        if let Some((ordinal, len)) = ordinal(chars, end, n, locale) {
+            return (ordinal, end + len);
+        }
+        // A bare four-digit number in this range is nearly always a year,
+        // unless a dash joins it to digits before, as in "555-1234".
+        let joined = i >= 2 && chars[i - 1] == '-' && chars[i - 2].is_ascii_digit();
+        if integer.len() == end - i && !joined && (1100..=2099).contains(&n) {
+            match locale.language {
+                Language::En => text = en::year(n),
+                Language::De => text = de::year(n),
+                Language::Fr | Language::Es => {}
+            }
+        }
+    }
+    (text, end)
+}
+
+/// An ordinal suffix at `at` ("1st", "1er", "1.º"): the ordinal of `n` and
+/// the suffix length.
+fn ordinal(chars: &[char], at: usize, n: u64, locale: Locale) -> Option<(String, usize)> {
+    let suffix: String = chars[at..].iter().take_while(|c| c.is_alphabetic() || matches!(c, 'º' | 'ª')).collect();
+    let suffix_len = suffix.chars().count();
+    let lower = suffix.to_lowercase();
+    match locale.language {
+        Language::En if matches!(lower.as_str(), "st" | "nd" | "rd" | "th") => Some((en::ordinal(n), suffix_len)),
+        Language::Fr if n == 1 && matches!(lower.as_str(), "er" | "re") => {
+            Some((if lower == "er" { "premier" } else { "première" }.to_string(), suffix_len))
+        }
+        Language::Fr if n > 1 && matches!(lower.as_str(), "e" | "ème" | "eme") => Some((fr::ordinal(n), suffix_len)),
+        Language::Es => {
+            // "1º" or "1.º".
+            let (dot, letter) = if chars.get(at) == Some(&'.') { (1, chars.get(at + 1)) } else { (0, chars.get(at)) };
+            let feminine = match letter {
+                Some('º') => false,
+                Some('ª') => true,
+                _ => return None,
+            };
+            if chars.get(at + dot + 1).is_some_and(|c| c.is_alphanumeric()) {
+                return None;
+            }
+            Some((es::ordinal(n, feminine), dot + 1))
+        }
+        _ => None,
+    }
+}
+
+fn spell_digits(digits: &[char], locale: Locale) -> String {
+    digits
+        .iter()
+        .map(|d| locale.cardinal(d.to_digit(10).unwrap_or(0) as u64))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+mod en {
+    pub const MONTHS: [&str; 12] = [
+        "January", "February", "March", "April", "May", "June", "July", "August", "September", "October", "November",
+        "December",
+    ];
+    const ONES: [&str; 20] = [
+        "zero", "one", "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten", "eleven", "twelve",
+        "thirteen", "fourteen", "fifteen", "sixteen", "seventeen", "eighteen", "nineteen",
+    ];
+    const TENS: [&str; 10] = ["", "", "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety"];
+    const SCALES: [(u64, &str); 4] = [
+        (1_000_000_000_000, "trillion"),
+        (1_000_000_000, "billion"),
+        (1_000_000, "million"),
+        (1_000, "thousand"),
+    ];
+
+    pub fn cardinal(mut n: u64) -> String {
+        if n == 0 {
+            return ONES[0].to_string();
+        }
+        let mut parts = Vec::new();
+        for (scale, name) in SCALES {
+            if n >= scale {
+                parts.push(format!("{} {name}", below_thousand(n / scale)));
+                n %= scale;
+            }
+        }
+        if n > 0 {
+            parts.push(below_thousand(n));
+        }
+        parts.join(" ")
+    }
+
+    fn below_thousand(n: u64) -> String {
+        let rest = below_hundred(n % 100);
+        match (n / 100, rest.is_empty()) {
+            (0, _) => rest,
+            (h, true) => format!("{} hundred", ONES[h as usize]),
+            (h, false) => format!("{} hundred {rest}", ONES[h as usize]),
+        }
+    }
+
+    fn below_hundred(n: u64) -> String {
+        match n {
+            0 => String::new(),
+            1..=19 => ONES[n as usize].to_string(),
+            _ if n.is_multiple_of(10) => TENS[n as usize / 10].to_string(),
+            _ => format!("{}-{}", TENS[n as usize / 10], ONES[n as usize % 10]),
+        }
+    }
+
+    pub fn ordinal(n: u64) -> String {
+        let cardinal = cardinal(n);
+        let cut = cardinal.rfind([' ', '-']).map_or(0, |i| i + 1);
+        let (head, last) = cardinal.split_at(cut);
+        let last = match last {
+            "one" => "first".to_string(),
+            "two" => "second".to_string(),
+            "three" => "third".to_string(),
+            "five" => "fifth".to_string(),
+            "eight" => "eighth".to_string(),
+            "nine" => "ninth".to_string(),
+            "twelve" => "twelfth".to_string(),
+            word if word.ends_with('y') => format!("{}ieth", &word[..word.len() - 1]),
+            word => format!("{word}th"),
+        };
+        format!("{head}{last}")
+    }
+
+    /// "nineteen ninety-nine", "two thousand five", "twenty twenty-four".
+    pub fn year(n: u64) -> String {
+        let (century, rest) = (n / 100, n % 100);
+        match n {
+            2000..=2009 => cardinal(n),
+            1000..=9999 if n.is_multiple_of(1000) => cardinal(n),
+            1000..=9999 if rest == 0 => format!("{} hundred", cardinal(century)),
+            1000..=9999 if rest < 10 => format!("{} oh {}", cardinal(century), cardinal(rest)),
+            1000..=9999 => format!("{} {}", cardinal(century), cardinal(rest)),
+            _ => cardinal(n),
+        }
+    }
+}
+
+mod de {
+    pub const MONTHS: [&str; 12] = [
+        "Januar", "Februar", "März", "April", "Mai", "Juni", "Juli", "August", "September", "Oktober", "November",
+        "Dezember",
+    ];
+    const ONES: [&str; 20] = [
+        "null", "eins", "zwei", "drei", "vier", "fünf", "sechs", "sieben", "acht", "neun", "zehn", "elf", "zwölf",
+        "dreizehn", "vierzehn", "fünfzehn", "sechzehn", "siebzehn", "achtzehn", "neunzehn",
+    ];
+    const TENS: [&str; 10] = [
+        "", "", "zwanzig", "dreißig", "vierzig", "fünfzig", "sechzig", "siebzig", "achtzig", "neunzig",
+    ];
+    const SCALES: [(u64, &str, &str); 3] = [
+        (1_000_000_000_000, "Billion", "Billionen"),
+        (1_000_000_000, "Milliarde", "Milliarden"),
+        (1_000_000, "Million", "Millionen"),
+    ];
+
+    pub fn cardinal(mut n: u64) -> String {
+        if n == 0 {
+            return ONES[0].to_string();
+        }
+        let mut parts = Vec::new();
+        for (scale, one, many) in SCALES {
+            match n / scale {
+                0 => {}
+                1 => parts.push(format!("eine {one}")),
+                count => parts.push(format!("{} {many}", below_million(count, false))),
+            }
+            n %= scale;
+        }
+        if n > 0 {
+            parts.push(below_million(n, false));
+        }
+        parts.join(" ")
+    }
+
+    /// Written as one word. `compound`: a final 1 is "ein", as before
+    /// "hundert".
+    fn below_million(n: u64, compound: bool) -> String {
+        let mut word = String::new();
+        if n >= 1000 {
+            word.push_str(&below_thousand(n / 1000, true));
+            word.push_str("tausend");
+        }
+        if !n.is_multiple_of(1000) {
+            word.push_str(&below_thousand(n % 1000, compound));
+        }
+        word
+    }
+
+    fn below_thousand(n: u64, compound: bool) -> String {
+        let mut word = String::new();
+        if n >= 100 {
+            word.push_str(&below_hundred(n / 100, true));
+            word.push_str("hundert");
+        }
+        if !n.is_multiple_of(100) {
+            word.push_str(&below_hundred(n % 100, compound));
+        }
+        word
+    }
+
+    fn below_hundred(n: u64, compound: bool) -> String {
+        let one = |digit: u64| if digit == 1 { "ein" } else { ONES[digit as usize] };
+        match n {
+            1 if compound => "ein".to_string(),
+            0..=19 => ONES[n as usize].to_string(),
+            _ if n.is_multiple_of(10) => TENS[n as usize / 10].to_string(),
+            _ => format!("{}und{}", one(n % 10), TENS[n as usize / 10]),
+        }
+    }
+
+    /// The ordinal's stem as in "der zwölfte".
+    pub fn ordinal(n: u64) -> String {
+        match n {
+            1 => "erste".to_string(),
+            3 => "dritte".to_string(),
+            7 => "siebte".to_string(),
+            8 => "achte".to_string(),
+            2..=19 => format!("{}te", cardinal(n)),
+            _ => format!("{}ste", cardinal(n)),
+        }
+    }
+
+    /// "neunzehnhundertneunundneunzig", "zweitausendvierundzwanzig".
+    pub fn year(n: u64) -> String {
+        match n {
+            1100..=1999 => {
+                let rest = if !n.is_multiple_of(100) { below_hundred(n % 100, false) } else { String::new() };
+                format!("{}hundert{rest}", below_hundred(n / 100, true))
+            }
+            _ => cardinal(n),
+        }
+    }
+}
+
+mod fr {
+    pub const MONTHS: [&str; 12] = [
+        "janvier", "février", "mars", "avril", "mai", "juin", "juillet", "août", "septembre", "octobre", "novembre",
+        "décembre",
+    ];
+    const ONES: [&str; 17] = [
+        "zéro", "un", "deux", "trois", "quatre", "cinq", "six", "sept", "huit", "neuf", "dix", "onze", "douze", "treize",
+        "quatorze", "quinze", "seize",
+    ];
+    const TENS: [&str; 7] = ["", "", "vingt", "trente", "quarante", "cinquante", "soixante"];
+    const SCALES: [(u64, &str); 3] = [
+        (1_000_000_000_000, "billion"),
+        (1_000_000_000, "milliard"),
+        (1_000_000, "million"),
+    ];
+
+    pub fn cardinal(mut n: u64) -> String {
+        if n == 0 {
+            return ONES[0].to_string();
+        }
+        let mut parts = Vec::new();
+        for (scale, name) in SCALES {
+            match n / scale {
+                0 => {}
+                1 => parts.push(format!("un {name}")),
+                count => parts.push(format!("{} {name}s", below_thousand(count, true))),
+            }
+            n %= scale;
+        }
+        match n / 1000 {
+            0 => {}
+            1 => parts.push("mille".to_string()),
+            count => parts.push(format!("{} mille", below_thousand(count, false))),
+        }
+        if !n.is_multiple_of(1000) {
+            parts.push(below_thousand(n % 1000, true));
+        }
+        parts.join(" ")
+    }
+
+    /// `last`: nothing but a noun follows, so "cents" and "quatre-vingts"
+    /// keep their plural s.
+    fn below_thousand(n: u64, last: bool) -> String {
+        let (hundreds, rest) = (n / 100, n % 100);
+        let mut word = match hundreds {
+            0 => String::new(),
+            1 => "cent".to_string(),
+            h => format!("{} cent", ONES[h as usize]),
+        };
+        if rest > 0 {
+            if !word.is_empty() {
+                word.push(' ');
+            }
+            word.push_str(&below_hundred(rest));
+        } else if hundreds > 1 && last {
+            word.push('s');
+        }
+        if rest == 80 && !last {
+            word.pop();
+        }
+        word
+    }
+
+    fn below_hundred(n: u64) -> String {
+        match n {
+            0..=16 => ONES[n as usize].to_string(),
+            17..=19 => format!("dix-{}", ONES[n as usize - 10]),
+            20..=69 => match n % 10 {
+                0 => TENS[n as usize / 10].to_string(),
+                1 => format!("{} et un", TENS[n as usize / 10]),
+                o => format!("{}-{}", TENS[n as usize / 10], ONES[o as usize]),
+            },
+            71 => "soixante et onze".to_string(),
+            70..=79 => format!("soixante-{}", below_hundred(n - 60)),
+            80 => "quatre-vingts".to_string(),
+            _ => format!("quatre-vingt-{}", below_hundred(n - 80)),
+        }
+    }
+
+    pub fn ordinal(n: u64) -> String {
+        let cardinal = cardinal(n);
+        let cut = cardinal.rfind([' ', '-']).map_or(0, |i| i + 1);
+        let (head, last) = cardinal.split_at(cut);
+        let stem = match last {
+            "un" => "un".to_string(),
+            "cinq" => "cinqu".to_string(),
+            "neuf" => "neuv".to_string(),
+            // "quatre", "mille"; the plural s of "cents" and "vingts".
+            word => word
+                .strip_suffix('e')
+                .or_else(|| word.strip_suffix('s').filter(|w| w.ends_with('t')))
+                .unwrap_or(word)
+                .to_string(),
+        };
+        format!("{head}{stem}ième")
+    }
+}
+
+mod es {
+    pub const MONTHS: [&str; 12] = [
+        "enero", "febrero", "marzo", "abril", "mayo", "junio", "julio", "agosto", "septiembre", "octubre", "noviembre",
+        "diciembre",
+    ];
+    const ONES: [&str; 30] = [
+        "cero", "uno", "dos", "tres", "cuatro", "cinco", "seis", "siete", "ocho", "nueve", "diez", "once", "doce",
+        "trece", "catorce", "quince", "dieciséis", "diecisiete", "dieciocho", "diecinueve", "veinte", "veintiuno",
+        "veintidós", "veintitrés", "veinticuatro", "veinticinco", "veintiséis", "veintisiete", "veintiocho",
+        "veintinueve",
+    ];
+    const TENS: [&str; 10] = [
+        "", "", "", "treinta", "cuarenta", "cincuenta", "sesenta", "setenta", "ochenta", "noventa",
+    ];
+    const HUNDREDS: [&str; 10] = [
+        "", "ciento", "doscientos", "trescientos", "cuatrocientos", "quinientos", "seiscientos", "setecientos",
+        "ochocientos", "novecientos",
+    ];
+    const ORDINALS: [&str; 11] = [
+        "", "primer", "segund", "tercer", "cuart", "quint", "sext", "séptim", "octav", "noven", "décim",
+    ];
+
+    pub fn cardinal(n: u64) -> String {
+        if n == 0 {
+            return ONES[0].to_string();
+        }
+        let (billions, millions, rest) = (n / 1_000_000_000_000, n / 1_000_000 % 1_000_000, n % 1_000_000);
+        let mut parts = Vec::new();
+        match billions {
+            0 => {}
+            1 => parts.push("un billón".to_string()),
+            count => parts.push(format!("{} billones", apocope(below_million(count)))),
+        }
+        match millions {
+            0 => {}
+            1 => parts.push("un millón".to_string()),
+            count => parts.push(format!("{} millones", apocope(below_million(count)))),
+        }
+        if rest > 0 {
+            parts.push(below_million(rest));
+        }
+        parts.join(" ")
+    }
+
+    fn below_million(n: u64) -> String {
+        let mut parts = Vec::new();
+        match n / 1000 {
+            0 => {}
+            1 => parts.push("mil".to_string()),
+            count => parts.push(format!("{} mil", apocope(below_thousand(count)))),
+        }
+        if !n.is_multiple_of(1000) {
+            parts.push(below_thousand(n % 1000));
+        }
+        parts.join(" ")
+    }
+
+    fn below_thousand(n: u64) -> String {
+        if n == 100 {
+            return "cien".to_string();
+        }
+        let rest = n % 100;
+        let rest = match rest {
+            0 => String::new(),
+            1..=29 => ONES[rest as usize].to_string(),
+            _ if rest.is_multiple_of(10) => TENS[rest as usize / 10].to_string(),
+            _ => format!("{} y {}", TENS[rest as usize / 10], ONES[rest as usize % 10]),
+        };
+        match (HUNDREDS[n as usize / 100], rest.is_empty()) {
+            ("", _) => rest,
+            (hundreds, true) => hundreds.to_string(),
+            (hundreds, false) => format!("{hundreds} {rest}"),
+        }
+    }
+
+    /// "uno" shortened before a noun: "veintiún mil", "treinta y un millones".
+    fn apocope(words: String) -> String {
+        if let Some(head) = words.strip_suffix("veintiuno") {
+            format!("{head}veintiún")
+        } else if let Some(head) = words.strip_suffix("uno") {
+            format!("{head}un")
+        } else {
+            words
+        }
+    }
+
+    /// "primero", "décima"; cardinals above ten, as usually said.
+    pub fn ordinal(n: u64, feminine: bool) -> String {
+        match ORDINALS.get(n as usize).filter(|_| n > 0) {
+            Some(stem) => format!("{stem}{}", if feminine { "a" } else { "o" }),
+            None => cardinal(n),
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! `POST /v1/audio/speech`, OpenAI's text-to-speech API, so its SDKs and
+//! the tools built on them can point here unchanged. A request becomes an
+//! `/api/tts` one: `voice` names a voice preset (directly or through the
+//! `[openai] voices` map), `instructions` stands in for the description,
+//! and `speed` is asked of the model in the description, as Parler takes
+//! pace from there.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::audio;
+use crate::namespace::Namespace;
+use crate::transcode;
+
+/// OpenAI's speed range.
+const SPEEDS: std::ops::RangeInclusive<f64> = 0.25..=4.0;
+
+/// OpenAI's `pcm` is 16-bit mono at 24 kHz.
+const PCM_RATE: u32 = 24000;
+
+/// The `[openai]` config section.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenAi {
+    /// Voice preset for each OpenAI voice name (`alloy`, `nova`, ...) that
+    /// isn't a preset itself.
+    pub voices: BTreeMap<String, String>,
+}
+
+impl OpenAi {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some((name, _)) = self.voices.iter().find(|(_, preset)| preset.trim().is_empty()) {
+            anyhow::bail!("openai.voices.{name} must name a voice preset");
+        }
+        Ok(())
+    }
+}
+
+/// The request body. Fields OpenAI adds later are ignored.
+#[derive(Debug, Deserialize)]
+pub struct SpeechRequest {
+    /// `tts-1`, `tts-1-hd`, ...: accepted, but there is one model.
+    #[serde(default)]
+    pub model: String,
+    pub input: String,
+    #[serde(default)]
+    pub voice: String,
+    pub response_format: Option<String>,
+    pub speed: Option<f64>,
+    /// How to speak, in words; used as the description.
+    pub instructions: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Mp3,
+    Wav,
+    Pcm,
+    /// AAC (in MP4) and Opus, through [`transcode`].
+    Transcoded(transcode::Target),
+}
+
+impl ResponseFormat {
+    /// OpenAI's default is `mp3`. `flac` isn't offered.
+    pub fn parse(name: Option<&str>) -> Option<Self> {
+        match name.map(|n| n.trim().to_ascii_lowercase()).as_deref() {
+            None | Some("mp3") => Some(Self::Mp3),
+            Some("wav") => Some(Self::Wav),
+            Some("pcm") => Some(Self::Pcm),
+            Some(name @ ("aac" | "opus")) => transcode::Target::parse(name).map(Self::Transcoded),
+            Some(_) => None,
+        }
+    }
+
+    pub fn content_type(self, sample_rate: u32) -> String {
+        match self {
+            Self::Mp3 => "audio/mpeg".to_string(),
+            Self::Wav => "audio/wav".to_string(),
+            Self::Pcm => audio::OutputFormat::Pcm16le.content_type(PCM_RATE),
+            Self::Transcoded(target) => target.content_type(sample_rate),
+        }
+    }
+
+    /// `/api/tts` fields that make its response this format directly, or
+    /// WAV to convert. Raw PCM streams as it is generated, as OpenAI's does.
+    fn tts_fields(self) -> Vec<(String, String)> {
+        let field = |name: &str, value: &str| (name.to_string(), value.to_string());
+        match self {
+            Self::Pcm => vec![
+                field("format", "pcm16le"),
+                field("sample_rate", &PCM_RATE.to_string()),
+                field("stream", "true"),
+            ],
+            _ => vec![field("format", "wav")],
+        }
+    }
+
+    /// Whether `/api/tts` answers in another format, to be converted.
+    pub fn converts(self) -> bool {
+        !matches!(self, Self::Wav | Self::Pcm)
+    }
+
+    /// The `/api/tts` WAV `wav` in this format, and its sample rate.
+    pub fn convert(self, wav: &[u8]) -> anyhow::Result<(Vec<u8>, u32)> {
+        let pcm = audio::read_wav(wav)?;
+        match self {
+            Self::Wav => Ok((wav.to_vec(), pcm.sample_rate)),
+            Self::Pcm => {
+                let samples = audio::resample(&pcm.samples, pcm.sample_rate, PCM_RATE);
+                Ok((audio::OutputFormat::Pcm16le.encode_clip(&samples, PCM_RATE), PCM_RATE))
+            }
+            Self::Mp3 => Ok((audio::encode_mp3(&pcm.samples, pcm.sample_rate)?, pcm.sample_rate)),
+            Self::Transcoded(target) => {
+                let rate = target.fixed_rate().unwrap_or(pcm.sample_rate);
+                Ok((target.encode(&pcm, rate, "speech")?, rate))
+            }
+        }
+    }
+}
+
+/// The `/api/tts` form fields for `request`, or why it can't be served.
+pub fn tts_fields(
+    config: &OpenAi,
+    namespace: &Namespace,
+    default_description: &str,
+    request: &SpeechRequest,
+    format: ResponseFormat,
+) -> Result<Vec<(String, String)>, String> {
+    let speed = request.speed.unwrap_or(1.0);
+    if !SPEEDS.contains(&speed) {
+        return Err(format!("speed must be between {} and {}", SPEEDS.start(), SPEEDS.end()));
+    }
+    let name = request.voice.trim();
+    let preset = Some(name)
+        .filter(|name| namespace.voices.get(name).is_some())
+        .or_else(|| config.voices.get(name).map(String::as_str))
+        .filter(|preset| namespace.voices.get(preset).is_some());
+    let mut fields = format.tts_fields();
+    fields.push(("text".to_string(), request.input.clone()));
+    if let Some(preset) = preset {
+        fields.push(("voice".to_string(), preset.to_string()));
+    }
+    let instructions = request.instructions.as_deref().map(str::trim).filter(|i| !i.is_empty());
+    let pace = pace(speed);
+    if instructions.is_some() || pace.is_some() {
+        // A description replaces the preset's, so the preset's is the base.
+        let base = match (instructions, preset.and_then(|p| namespace.voices.get(p))) {
+            (Some(instructions), _) => instructions.to_string(),
+            (None, Some(current)) => current.preset.description.clone(),
+            (None, None) => default_description.to_string(),
+        };
+        let description = match pace {
+            Some(pace) => format!("{} The speaker speaks {pace}.", base.trim_end()),
+            None => base,
+        };
+        fields.push(("description".to_string(), description));
+    }
+    Ok(fields)
+}
+
+/// How `speed` is put to the model; None about normal speed.
+fn pace(speed: f64) -> Option<&'static str> {
+    match speed {
+        s if s < 0.6 => Some("very slowly"),
+        s if s < 0.8 => Some("slowly"),
+        s if s < 0.9 => Some("slightly slowly"),
+        s if s <= 1.15 => None,
+        s if s <= 1.5 => Some("slightly fast"),
+        s if s <= 2.2 => Some("fast"),
+        _ => Some("very fast"),
+    }
+}
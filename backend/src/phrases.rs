@@ -0,0 +1,143 @@
+//! Canned prompts (greetings, error messages, digits) rendered for every
+//! voice preset, so requests for them are answered from memory instead of
+//! the model. Renderings are also kept on disk, named after the model
+//! version, voice description and text, so restarts don't render them
+//! again, and an upgraded model or edited voice invalidates them.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, RwLock};
+
+use sha2::{Digest, Sha256};
+
+use crate::generation::FinishReason;
+use crate::history::{ClipRecord, History};
+
+/// A rendered phrase, encoded as WAV.
+pub struct QuickPhrase {
+    pub wav: Vec<u8>,
+    pub sample_rate: u32,
+    pub duration_secs: f64,
+    pub steps: usize,
+    pub finish: FinishReason,
+}
+
+/// Phrases by voice description and text.
+pub struct PhraseCache {
+    clips: RwLock<HashMap<(String, String), Arc<QuickPhrase>>>,
+    store: History,
+}
+
+/// Outcome of [`PhraseCache::sweep`].
+pub struct Sweep {
+    pub loaded: usize,
+    pub removed: usize,
+    /// `(description, text)` pairs with no valid rendering on disk.
+    pub missing: Vec<(String, String)>,
+}
+
+impl PhraseCache {
+    pub fn new(store: History) -> Self {
+        Self {
+            clips: RwLock::default(),
+            store,
+        }
+    }
+
+    /// The rendering of `text` in the voice `description`. Text must match
+    /// the configured phrase exactly, apart from surrounding whitespace.
+    pub fn get(&self, description: &str, text: &str) -> Option<Arc<QuickPhrase>> {
+        let key = (description.to_string(), text.trim().to_string());
+        self.clips.read().unwrap().get(&key).cloned()
+    }
+
+    /// Keeps a rendering made by model `version`, in memory and on disk.
+    pub fn insert(&self, version: &str, description: &str, text: &str, phrase: QuickPhrase) -> anyhow::Result<()> {
+        let record = ClipRecord {
+            id: fingerprint(version, description, text),
+            created_at: crate::unix_now(),
+            prompt: text.trim().to_string(),
+            description: description.to_string(),
+            temperature: None,
+            seed: None,
+            top_p: None,
+            sample_rate: phrase.sample_rate,
+            duration_secs: phrase.duration_secs,
+            voice: None,
+            voice_version: None,
+            expires_at: None,
+            speech_rate: None,
+            quality_retry: None,
+            steps: Some(phrase.steps),
+            finish_reason: Some(phrase.finish),
+            memory: None,
+            timings: None,
+            over_budget: Vec::new(),
+            downgraded_max_steps: None,
+            features: Vec::new(),
+            settings: None,
+            self_check: None,
+        };
+        let saved = self.store.save(&record, &phrase.wav);
+        let key = (description.to_string(), text.trim().to_string());
+        self.clips.write().unwrap().insert(key, Arc::new(phrase));
+        saved
+    }
+
+    /// Drops every rendering that isn't one of `wanted` as made by model
+    /// `version`, from memory and disk, and loads the ones still valid.
+    pub fn sweep(&self, version: &str, wanted: &[(String, String)]) -> anyhow::Result<Sweep> {
+        let by_id: HashMap<String, &(String, String)> = wanted
+            .iter()
+            .map(|pair| (fingerprint(version, &pair.0, &pair.1), pair))
+            .collect();
+        let mut sweep = Sweep {
+            loaded: 0,
+            removed: 0,
+            missing: Vec::new(),
+        };
+        let mut clips = HashMap::new();
+        let mut found = BTreeSet::new();
+        for record in self.store.records()? {
+            let pair = by_id.get(&record.id);
+            let wav = match pair {
+                Some(_) => self.store.audio(&record.id)?,
+                None => None,
+            };
+            match (pair, wav) {
+                (Some(&(description, text)), Some(wav)) => {
+                    let phrase = QuickPhrase {
+                        wav,
+                        sample_rate: record.sample_rate,
+                        duration_secs: record.duration_secs,
+                        steps: record.steps.unwrap_or_default(),
+                        finish: record.finish_reason.unwrap_or(FinishReason::Eos),
+                    };
+                    clips.insert((description.clone(), text.trim().to_string()), Arc::new(phrase));
+                    found.insert(record.id);
+                    sweep.loaded += 1;
+                }
+                _ => {
+                    self.store.remove(&record.id)?;
+                    sweep.removed += 1;
+                }
+            }
+        }
+        sweep.missing = by_id
+            .into_iter()
+            .filter(|(id, _)| !found.contains(id))
+            .map(|(_, pair)| pair.clone())
+            .collect();
+        *self.clips.write().unwrap() = clips;
+        Ok(sweep)
+    }
+}
+
+/// File name of a rendering; it changes with anything that changes the audio.
+pub fn fingerprint(version: &str, description: &str, text: &str) -> String {
+    let mut hasher = Sha256::new();
+    for part in [version, description, text.trim()] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    hasher.finalize()[..16].iter().map(|b| format!("{b:02x}")).collect()
+}
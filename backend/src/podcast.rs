@@ -0,0 +1,402 @@
+//! Podcast of synthesized articles: configured RSS feeds are polled, each new
+//! item's article is read from its page and spoken in a voice preset, and the
+//! MP3s are published as a podcast feed at `/podcast/feed.xml`.
+//!
+//! Episodes live in `<audio_dir>/.podcast` next to an `episodes.json` index,
+//! unencrypted since the feed is public anyway. Each clip is also stored in
+//! the namespace's history, like any other generation.
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+
+use crate::namespace::Namespace;
+use crate::{audio, extract, fetch, AppState};
+
+/// Subdirectory of `audio_dir` holding the episodes.
+const PODCAST_DIR: &str = ".podcast";
+const INDEX_FILE: &str = "episodes.json";
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The `[podcast]` config section.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Podcast {
+    /// URL the server is reached at from outside, for the enclosure links.
+    pub base_url: String,
+    pub title: String,
+    pub description: String,
+    /// How often the feeds are polled, e.g. `30m` or `1h`.
+    #[serde(deserialize_with = "de_interval")]
+    pub interval: Duration,
+    /// Namespace whose voice presets, history and usage the episodes use.
+    pub namespace: String,
+    /// Preset for feeds that don't name their own.
+    pub voice: String,
+    pub feeds: Vec<FeedSource>,
+    /// Newest items looked at per feed and poll; older ones are left out.
+    pub max_items: usize,
+    /// Articles are cut off at the last sentence end before this length.
+    pub max_chars: usize,
+    /// Episodes kept in the feed; older ones are deleted.
+    pub keep: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeedSource {
+    pub url: String,
+    #[serde(default)]
+    pub voice: Option<String>,
+}
+
+impl Default for Podcast {
+    fn default() -> Self {
+        Self {
+            base_url: String::new(),
+            title: "ttser".to_string(),
+            description: "Articles read aloud by ttser.".to_string(),
+            interval: Duration::from_secs(60 * 60),
+            namespace: crate::namespace::DEFAULT_NAMESPACE.to_string(),
+            voice: String::new(),
+            feeds: Vec::new(),
+            max_items: 5,
+            max_chars: 5000,
+            keep: 50,
+        }
+    }
+}
+
+impl Podcast {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.base_url.starts_with("http://") && !self.base_url.starts_with("https://") {
+            bail!("podcast.base_url must be an http(s) URL");
+        }
+        if self.voice.is_empty() {
+            bail!("podcast.voice must name a voice preset");
+        }
+        if self.feeds.is_empty() {
+            bail!("podcast.feeds is empty");
+        }
+        if self.interval.is_zero() {
+            bail!("podcast.interval must be positive");
+        }
+        if self.max_items == 0 || self.max_chars == 0 || self.keep == 0 {
+            bail!("podcast.max_items, max_chars and keep must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+fn de_interval<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    crate::config::parse_duration(&text)
+        .ok_or_else(|| D::Error::custom(format!("invalid duration {text:?}, expected e.g. 30m or 1h")))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Episode {
+    /// The source item's guid (or link), so it is read only once.
+    guid: String,
+    title: String,
+    link: Option<String>,
+    /// Unix time it was published here, seconds.
+    published: u64,
+    /// `<clip id>.mp3` in the podcast directory.
+    file: String,
+    bytes: u64,
+    duration_secs: f64,
+}
+
+/// The published episodes, newest first.
+pub struct Station {
+    config: Podcast,
+    dir: PathBuf,
+    http: reqwest::Client,
+    episodes: Mutex<Vec<Episode>>,
+    /// Items that couldn't be read or synthesized, not retried until a
+    /// restart.
+    failed: Mutex<HashSet<String>>,
+}
+
+impl Station {
+    /// Opens the podcast directory under `audio_dir`, picking up the
+    /// episodes published before a restart.
+    pub fn new(config: &Podcast, audio_dir: &std::path::Path) -> anyhow::Result<Self> {
+        let dir = audio_dir.join(PODCAST_DIR);
+        let episodes = match std::fs::read(dir.join(INDEX_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes).context("reading the podcast episode index")?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).context("reading the podcast episode index"),
+        };
+        let http = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+        Ok(Self {
+            config: config.clone(),
+            dir,
+            http,
+            episodes: Mutex::new(episodes),
+            failed: Mutex::default(),
+        })
+    }
+
+    /// The podcast feed: one item per episode with its MP3 as enclosure.
+    pub fn feed_xml(&self) -> String {
+        use rss::extension::itunes::{self, ITunesChannelExtensionBuilder, ITunesItemExtensionBuilder};
+        use rss::{ChannelBuilder, EnclosureBuilder, GuidBuilder, ItemBuilder};
+
+        let base = self.config.base_url.trim_end_matches('/');
+        let items: Vec<_> = self
+            .episodes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|episode| {
+                let enclosure = EnclosureBuilder::default()
+                    .url(format!("{base}/podcast/episodes/{}", episode.file))
+                    .length(episode.bytes.to_string())
+                    .mime_type("audio/mpeg".to_string())
+                    .build();
+                let guid = GuidBuilder::default().value(episode.guid.clone()).permalink(false).build();
+                let itunes = ITunesItemExtensionBuilder::default()
+                    .duration(Some(clock(episode.duration_secs.round() as u64)))
+                    .build();
+                ItemBuilder::default()
+                    .title(Some(episode.title.clone()))
+                    .link(episode.link.clone())
+                    .enclosure(Some(enclosure))
+                    .guid(Some(guid))
+                    .pub_date(Some(rfc2822(episode.published)))
+                    .itunes_ext(Some(itunes))
+                    .build()
+            })
+            .collect();
+        let itunes = ITunesChannelExtensionBuilder::default()
+            .summary(Some(self.config.description.clone()))
+            .build();
+        ChannelBuilder::default()
+            .title(self.config.title.clone())
+            .link(format!("{base}/podcast/feed.xml"))
+            .description(self.config.description.clone())
+            .namespaces(BTreeMap::from([("itunes".to_string(), itunes::NAMESPACE.to_string())]))
+            .itunes_ext(Some(itunes))
+            .items(items)
+            .build()
+            .to_string()
+    }
+
+    /// Path of a published episode, for serving it. Anything not in the
+    /// index is refused.
+    pub fn episode_path(&self, file: &str) -> Option<PathBuf> {
+        let episodes = self.episodes.lock().unwrap();
+        episodes.iter().any(|e| e.file == file).then(|| self.dir.join(file))
+    }
+
+    async fn poll(&self, state: &AppState, namespace: &Arc<Namespace>) {
+        for source in &self.config.feeds {
+            let channel = match self.fetch(&source.url).await.and_then(|body| Ok(rss::Channel::read_from(&body[..])?)) {
+                Ok(channel) => channel,
+                Err(e) => {
+                    println!("podcast: reading feed {} failed: {e:#}", source.url);
+                    continue;
+                }
+            };
+            // Feeds list the newest first; publishing oldest first keeps
+            // that order in ours.
+            for item in channel.items().iter().take(self.config.max_items).rev() {
+                let Some(guid) = item.guid().map(|g| g.value()).or(item.link()).or(item.title()) else {
+                    continue;
+                };
+                let known = self.episodes.lock().unwrap().iter().any(|e| e.guid == guid)
+                    || self.failed.lock().unwrap().contains(guid);
+                if known {
+                    continue;
+                }
+                let voice = source.voice.as_deref().unwrap_or(&self.config.voice);
+                match self.publish(state, namespace, voice, guid, item).await {
+                    Ok(title) => println!("podcast: published {title:?}"),
+                    Err(e) => {
+                        println!("podcast: item {guid} of {} failed: {e:#}", source.url);
+                        self.failed.lock().unwrap().insert(guid.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads, synthesizes and adds one item. Returns its title.
+    async fn publish(
+        &self,
+        state: &AppState,
+        namespace: &Arc<Namespace>,
+        voice: &str,
+        guid: &str,
+        item: &rss::Item,
+    ) -> anyhow::Result<String> {
+        let article = self.article(item).await;
+        if article.text.is_empty() {
+            bail!("no article text");
+        }
+        let title = item
+            .title()
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(str::to_string)
+            .or(article.title)
+            .unwrap_or_else(|| "Untitled".to_string());
+        let prompt = truncate(&format!("{title}. {}", article.text), self.config.max_chars);
+
+        let job = crate::preset_job(state, namespace.clone(), voice, &prompt)
+            .await
+            .map_err(|e| anyhow::anyhow!("{e} ({})", e.code()))?;
+        let clip_id = job.clip_id.clone();
+        let clip = job.run(None).await.map_err(|e| anyhow::anyhow!("{e} ({})", e.code()))?.clip;
+        let duration_secs = clip.duration_secs;
+        let mp3 = tokio::task::spawn_blocking(move || {
+            let pcm = audio::read_wav(&clip.wav)?;
+            audio::encode_mp3(&pcm.samples, pcm.sample_rate)
+        })
+        .await??;
+
+        let episode = Episode {
+            guid: guid.to_string(),
+            title: title.clone(),
+            link: item.link().map(str::to_string),
+            published: crate::unix_now(),
+            file: format!("{clip_id}.mp3"),
+            bytes: mp3.len() as u64,
+            duration_secs,
+        };
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(self.dir.join(&episode.file), &mp3)?;
+        let dropped = {
+            let mut episodes = self.episodes.lock().unwrap();
+            episodes.insert(0, episode);
+            let keep = episodes.len().min(self.config.keep);
+            let dropped = episodes.split_off(keep);
+            self.save(&episodes)?;
+            dropped
+        };
+        for old in dropped {
+            let _ = std::fs::remove_file(self.dir.join(&old.file));
+        }
+        Ok(title)
+    }
+
+    /// The article the item links to, or else the item's own content.
+    async fn article(&self, item: &rss::Item) -> extract::Article {
+        if let Some(link) = item.link() {
+            match self.fetch(link).await.map(|page| String::from_utf8_lossy(&page).into_owned()) {
+                Ok(page) => {
+                    let article = extract::extract(&page);
+                    if !article.text.is_empty() {
+                        return article;
+                    }
+                }
+                Err(e) => println!("podcast: fetching {link} failed, reading the feed's text: {e:#}"),
+            }
+        }
+        let fragment = item.content().or(item.description()).unwrap_or_default();
+        extract::extract(fragment)
+    }
+
+    async fn fetch(&self, url: &str) -> anyhow::Result<Vec<u8>> {
+        fetch::read(&self.http, url, fetch::MAX_PAGE_BYTES).await
+    }
+
+    fn save(&self, episodes: &[Episode]) -> anyhow::Result<()> {
+        let path = self.dir.join(INDEX_FILE);
+        let partial = path.with_extension("partial");
+        std::fs::write(&partial, serde_json::to_vec_pretty(episodes)?)?;
+        std::fs::rename(&partial, &path)?;
+        Ok(())
+    }
+}
+
+/// Checks the config against the namespaces and starts polling the feeds
+/// in the background, right away and then every `interval`.
+pub fn start(state: &AppState, station: Arc<Station>) -> anyhow::Result<()> {
+    let config = &station.config;
+    let namespace = state
+        .namespaces
+        .all()
+        .iter()
+        .find(|n| n.name == config.namespace)
+        .cloned()
+        .with_context(|| format!("podcast.namespace {:?} does not exist", config.namespace))?;
+    let voices = std::iter::once(&config.voice).chain(config.feeds.iter().filter_map(|f| f.voice.as_ref()));
+    for voice in voices {
+        if !namespace.voices.contains(voice) {
+            bail!("podcast voice {voice:?} is not a preset of namespace {:?}", namespace.name);
+        }
+    }
+    let state = state.clone();
+    tokio::spawn(async move {
+        println!(
+            "podcast: polling {} feeds every {:?} into namespace {}",
+            station.config.feeds.len(),
+            station.config.interval,
+            namespace.name
+        );
+        let mut interval = tokio::time::interval(station.config.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            station.poll(&state, &namespace).await;
+        }
+    });
+    Ok(())
+}
+
+/// `text` cut to at most `max_chars`, at the last sentence end when there
+/// is one.
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    let Some((end, _)) = text.char_indices().nth(max_chars) else {
+        return text.to_string();
+    };
+    let head = &text[..end];
+    match head.rfind(['.', '!', '?']) {
+        Some(stop) => head[..=stop].to_string(),
+        None => head.to_string(),
+    }
+}
+
+/// `3725` as `1:02:05`, the form podcast apps show.
+fn clock(secs: u64) -> String {
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Unix time as an RFC 2822 date, as RSS wants them.
+fn rfc2822(unix: u64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let days = (unix / 86400) as i64;
+    let secs = unix % 86400;
+    let (year, month, day) = civil_date(days);
+    format!(
+        "{}, {day:02} {} {year} {:02}:{:02}:{:02} +0000",
+        DAYS[(days % 7) as usize],
+        MONTHS[(month - 1) as usize],
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Year, month and day of the date `days` after the Unix epoch (Howard
+/// Hinnant's algorithm).
+pub fn civil_date(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
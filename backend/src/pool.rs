@@ -0,0 +1,168 @@
+//! A fixed set of generation workers shared by all requests.
+//!
+//! Each worker runs one generation at a time. Chunks of a long prompt are
+//! queued on the pool like any other job, so they spread over idle workers
+//! while the pool as a whole bounds how many generations run at once.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::error::SynthesisError;
+use crate::metrics;
+use crate::tts_model::TtsModel;
+
+pub struct EnginePool {
+    workers: Vec<Arc<dyn TtsModel>>,
+    /// Indices into `workers` that are not running a generation.
+    idle: Mutex<Vec<usize>>,
+    available: Arc<Semaphore>,
+    /// Jobs waiting for a worker.
+    queued: AtomicUsize,
+    /// Moving average of job run time in seconds, as `f64` bits; 0 until
+    /// the first job.
+    job_secs: AtomicU64,
+}
+
+/// How busy the pool is right now.
+#[derive(Debug, Clone, Copy)]
+pub struct PoolLoad {
+    pub workers: usize,
+    pub busy: usize,
+    pub queued: usize,
+    /// Recent run time of one job, once any has finished.
+    pub job_secs: Option<f64>,
+}
+
+/// Weight of the newest job in the `job_secs` average.
+const JOB_SMOOTHING: f64 = 0.2;
+
+/// A worker checked out of the pool; returned when dropped. Owned, so it
+/// can move into the blocking task that runs the generation.
+pub struct Worker {
+    pool: Arc<EnginePool>,
+    index: usize,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl EnginePool {
+    /// `workers` slots over one resident engine. The weights are shared;
+    /// each generation clones only the KV caches.
+    pub fn new(engine: Arc<dyn TtsModel>, workers: usize) -> Self {
+        Self::from_workers(vec![engine; workers.max(1)])
+    }
+
+    fn from_workers(workers: Vec<Arc<dyn TtsModel>>) -> Self {
+        Self {
+            idle: Mutex::new((0..workers.len()).rev().collect()),
+            available: Arc::new(Semaphore::new(workers.len())),
+            queued: AtomicUsize::new(0),
+            job_secs: AtomicU64::new(0),
+            workers,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Any worker, for properties that are the same on all of them.
+    pub fn engine(&self) -> &dyn TtsModel {
+        &*self.workers[0]
+    }
+
+    pub fn load(&self) -> PoolLoad {
+        PoolLoad {
+            workers: self.workers.len(),
+            busy: self.workers.len() - self.available.available_permits(),
+            queued: self.queued.load(Ordering::Relaxed),
+            job_secs: metrics::read_average(&self.job_secs),
+        }
+    }
+
+    /// Waits for an idle worker.
+    pub async fn acquire(self: &Arc<Self>) -> Worker {
+        // Counted until a worker is free or the request gives up waiting.
+        struct Queued<'a>(&'a AtomicUsize);
+        impl Drop for Queued<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        let queued = Queued(&self.queued);
+        let permit = self
+            .available
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("pool semaphore is never closed");
+        drop(queued);
+        let index = self
+            .idle
+            .lock()
+            .unwrap()
+            .pop()
+            .expect("a permit guarantees an idle worker");
+        Worker {
+            pool: self.clone(),
+            index,
+            _permit: permit,
+        }
+    }
+}
+
+impl Worker {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Runs `job` on this worker's engine on the blocking pool. A panic
+    /// inside candle (say, on a shape it didn't expect from a malformed
+    /// request) fails only this job, with an [`InferencePanic`]; the worker
+    /// goes back to the pool and the server keeps running.
+    pub async fn run<T, F>(self, job: F) -> Result<T, SynthesisError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn TtsModel) -> Result<T, SynthesisError> + Send + 'static,
+    {
+        let (index, pool) = (self.index, self.pool.clone());
+        let start = Instant::now();
+        let result = tokio::task::spawn_blocking(move || job(&*self.pool.workers[self.index])).await;
+        metrics::update_average(&pool.job_secs, start.elapsed().as_secs_f64(), JOB_SMOOTHING);
+        match result {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => {
+                let payload = e.into_panic();
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                println!("worker {index}: inference panicked: {message}");
+                Err(SynthesisError::Generate(InferencePanic(message).into()))
+            }
+            Err(e) => Err(SynthesisError::Generate(e.into())),
+        }
+    }
+}
+
+/// A generation that panicked instead of returning an error.
+#[derive(Debug)]
+pub struct InferencePanic(pub String);
+
+impl std::fmt::Display for InferencePanic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "inference panicked: {}", self.0)
+    }
+}
+
+impl std::error::Error for InferencePanic {}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.pool.idle.lock().unwrap().push(self.index);
+    }
+}
@@ -0,0 +1,37 @@
+//! How user-supplied text (prompts and voice descriptions) may appear in logs
+//! and anything else the server writes down about a request.
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PromptLogging {
+    /// Keep the text as sent.
+    #[default]
+    Full,
+    /// Replace the text with a short digest, so repeats can still be spotted.
+    Hashed,
+    /// Keep only the length.
+    Off,
+}
+
+impl PromptLogging {
+    /// `text` as it may be recorded under this policy.
+    pub fn sanitize(self, text: &str) -> String {
+        match self {
+            Self::Full => text.to_string(),
+            Self::Hashed => format!("sha256:{} ({} chars)", digest(text), text.chars().count()),
+            Self::Off => format!("<{} chars>", text.chars().count()),
+        }
+    }
+}
+
+/// First 12 hex digits of the SHA-256 of `text`.
+fn digest(text: &str) -> String {
+    Sha256::digest(text.as_bytes())
+        .iter()
+        .take(6)
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
@@ -0,0 +1,75 @@
+//! Cheap checks for generations that are obviously broken, so they can be
+//! retried instead of handed back to the user.
+
+use serde::{Deserialize, Serialize};
+
+use crate::analysis::speech_rate;
+
+/// Below this RMS (about -60 dBFS) a clip is effectively silent.
+const SILENCE_RMS: f32 = 1e-3;
+/// Samples at or above this magnitude count as clipped.
+const CLIP_LEVEL: f32 = 0.99;
+/// Fraction of clipped samples that marks a clip as distorted.
+const MAX_CLIPPED_FRACTION: f64 = 0.01;
+/// Far wider than `analysis::PLAUSIBLE_WPM`: only rates no real reading of
+/// the prompt could produce.
+const MIN_WPM: f64 = 40.0;
+const MAX_WPM: f64 = 400.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Defect {
+    NearSilence,
+    Clipping,
+    DurationMismatch,
+}
+
+impl Defect {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::NearSilence => "near_silence",
+            Self::Clipping => "clipping",
+            Self::DurationMismatch => "duration_mismatch",
+        }
+    }
+}
+
+/// Retry made because the first attempt was defective.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QualityRetry {
+    /// What was wrong with the first attempt.
+    pub defect: Defect,
+    pub first_seed: u64,
+    /// Seed and temperature that produced the returned clip.
+    pub seed: u64,
+    pub temperature: f64,
+    /// Set when the retry was defective too (it is returned regardless).
+    pub still_defective: Option<Defect>,
+}
+
+/// The first defect found in raw decoder output, if any.
+pub fn assess(samples: &[f32], sample_rate: u32, prompt: &str) -> Option<Defect> {
+    if samples.is_empty() {
+        return Some(Defect::NearSilence);
+    }
+    let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt();
+    if rms < SILENCE_RMS {
+        return Some(Defect::NearSilence);
+    }
+    let clipped = samples.iter().filter(|s| s.abs() >= CLIP_LEVEL).count();
+    if clipped as f64 / samples.len() as f64 > MAX_CLIPPED_FRACTION {
+        return Some(Defect::Clipping);
+    }
+    let duration_secs = samples.len() as f64 / sample_rate as f64;
+    if speech_rate(prompt, duration_secs)
+        .is_some_and(|rate| !(MIN_WPM..=MAX_WPM).contains(&rate.words_per_minute))
+    {
+        return Some(Defect::DurationMismatch);
+    }
+    None
+}
+
+/// Seed for a retry: far from the original so the two runs are unrelated.
+pub fn retry_seed(seed: u64) -> u64 {
+    seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407)
+}
@@ -0,0 +1,254 @@
+//! `GET /api/tts/relay`: text in, speech out, over one WebSocket. The
+//! client sends text as it comes (words from live speech recognition,
+//! tokens from an LLM) and each sentence is spoken as soon as it is
+//! complete, so a voice agent starts talking long before the reply is.
+//!
+//! Client messages are JSON, `{"text": "...", "flush": false, "end": false}`
+//! (all optional): `text` is appended to what came before, `flush` speaks
+//! the rest even without a sentence end, `end` does so and closes the
+//! socket once everything is spoken. Each sentence is rendered like an
+//! `/api/tts` request with the query string's fields and answered with a
+//! `sentence` event, then its audio in one binary message.
+
+use std::sync::Arc;
+
+use axum::extract::ws::{Message, WebSocket};
+use axum::http::HeaderMap;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::access_log::RequestId;
+use crate::namespace::Namespace;
+use crate::AppState;
+
+/// `/api/tts` fields the query string can't set: the text comes over the
+/// socket, and the audio always does.
+pub const NOT_SETTINGS: &[&str] = &["text", "stream", "webrtc_session"];
+
+/// Sentence ends; a piece of text is complete once whitespace follows one.
+const ENDS: &[char] = &['.', '!', '?', ';', '…'];
+
+/// Full-width sentence ends, which end a sentence by themselves since no
+/// space follows them.
+const WIDE_ENDS: &[char] = &['。', '！', '？', '；'];
+
+/// Kept with the sentence end they follow, as in `"Stop!" she said`.
+const CLOSERS: &[char] = &['"', '\'', '”', '’', ')', ']', '»'];
+
+/// Sentences read ahead of the one being spoken. Once this many wait,
+/// the socket isn't read until the speaker catches up.
+const READ_AHEAD: usize = 16;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ClientMessage {
+    text: String,
+    flush: bool,
+    end: bool,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event {
+    /// Sent before the sentence's audio.
+    Sentence { index: usize, text: String, clip_id: Option<String> },
+    /// The sentence wasn't spoken; the relay carries on with the next.
+    Error { index: Option<usize>, code: String, message: String },
+    /// Everything sent before `end` has been spoken.
+    Done { sentences: usize },
+}
+
+/// What the reading half hands the speaking half.
+enum Input {
+    Sentence(String),
+    Invalid(String),
+}
+
+/// Relays until the client ends or goes away. `settings` are `/api/tts`
+/// form fields applied to every sentence.
+pub async fn run(
+    socket: WebSocket,
+    state: AppState,
+    namespace: Arc<Namespace>,
+    headers: HeaderMap,
+    settings: Vec<(String, String)>,
+) {
+    let (mut sender, mut receiver) = socket.split();
+    let (sentences, mut pending) = tokio::sync::mpsc::channel(READ_AHEAD);
+    let max_chars = state.config.chunk_chars;
+    // Reading goes on while sentences are spoken, so text that arrives
+    // meanwhile is ready when the model is, up to `READ_AHEAD` sentences.
+    // Sends fail only once the speaker is gone.
+    let reader = tokio::spawn(async move {
+        let mut buffer = String::new();
+        while let Some(Ok(message)) = receiver.next().await {
+            let text = match message {
+                Message::Text(text) => text,
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            let message: ClientMessage = match serde_json::from_str(text.as_str()) {
+                Ok(message) => message,
+                Err(e) => {
+                    if sentences.send(Input::Invalid(e.to_string())).await.is_err() {
+                        return false;
+                    }
+                    continue;
+                }
+            };
+            buffer.push_str(&message.text);
+            let mut complete = take_sentences(&mut buffer, max_chars);
+            if message.flush || message.end {
+                complete.extend(Some(std::mem::take(&mut buffer).trim().to_string()).filter(|s| !s.is_empty()));
+            }
+            for sentence in complete {
+                if sentences.send(Input::Sentence(sentence)).await.is_err() {
+                    return false;
+                }
+            }
+            if message.end {
+                return true;
+            }
+        }
+        false
+    });
+
+    let mut spoken = 0;
+    while let Some(input) = pending.recv().await {
+        let sentence = match input {
+            Input::Sentence(sentence) => sentence,
+            Input::Invalid(message) => {
+                let event = Event::Error {
+                    index: None,
+                    code: "invalid_message".to_string(),
+                    message,
+                };
+                if send_event(&mut sender, &event).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+        let index = spoken;
+        spoken += 1;
+        let RequestId(request_id) = RequestId::next();
+        println!("tts[{request_id}]: relay sentence {} of namespace {}", index + 1, namespace.name);
+        let mut fields = settings.clone();
+        fields.push(("text".to_string(), sentence.clone()));
+        fields.push(("stream".to_string(), "false".to_string()));
+        let response = crate::synthesize(state.clone(), request_id, namespace.clone(), &headers, fields).await;
+        let sent = match response {
+            Ok(response) => {
+                let clip_id = response
+                    .headers()
+                    .get("x-clip-id")
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string);
+                match axum::body::to_bytes(response.into_body(), usize::MAX).await {
+                    Ok(audio) => {
+                        let event = Event::Sentence {
+                            index,
+                            text: sentence,
+                            clip_id,
+                        };
+                        match send_event(&mut sender, &event).await {
+                            Ok(()) => sender.send(Message::Binary(audio)).await,
+                            Err(e) => Err(e),
+                        }
+                    }
+                    Err(e) => {
+                        println!("tts[{request_id}]: relay sentence failed: {e}");
+                        let event = Event::Error {
+                            index: Some(index),
+                            code: "encode_failed".to_string(),
+                            message: "encoding audio failed".to_string(),
+                        };
+                        send_event(&mut sender, &event).await
+                    }
+                }
+            }
+            Err(e) => {
+                println!("tts[{request_id}]: relay sentence failed: {e}");
+                let event = Event::Error {
+                    index: Some(index),
+                    code: e.code().to_string(),
+                    message: e.message(),
+                };
+                send_event(&mut sender, &event).await
+            }
+        };
+        if sent.is_err() {
+            // The client went away; what it sent is not spoken.
+            reader.abort();
+            return;
+        }
+    }
+    if let Ok(true) = reader.await {
+        let _ = send_event(&mut sender, &Event::Done { sentences: spoken }).await;
+        let _ = sender.send(Message::Close(None)).await;
+    }
+}
+
+async fn send_event(
+    sender: &mut futures::stream::SplitSink<WebSocket, Message>,
+    event: &Event,
+) -> Result<(), axum::Error> {
+    let json = serde_json::to_string(event).unwrap_or_default();
+    sender.send(Message::Text(json.into())).await
+}
+
+/// Removes the complete sentences from the front of `buffer`: those ending
+/// in sentence punctuation followed by whitespace, or in a line break.
+/// Text running past `max_chars` without one is cut at a word instead, so
+/// speech doesn't stall on run-on input.
+fn take_sentences(buffer: &mut String, max_chars: usize) -> Vec<String> {
+    let mut sentences = Vec::new();
+    loop {
+        let Some(end) = sentence_end(buffer).or_else(|| overlong_end(buffer, max_chars)) else {
+            return sentences;
+        };
+        let sentence = buffer[..end].trim().to_string();
+        buffer.drain(..end);
+        if !sentence.is_empty() {
+            sentences.push(sentence);
+        }
+    }
+}
+
+/// Byte offset just past the first complete sentence of `text`.
+fn sentence_end(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == '\n' {
+            return Some(i + 1);
+        }
+        if !ENDS.contains(&c) && !WIDE_ENDS.contains(&c) {
+            continue;
+        }
+        let mut end = i + c.len_utf8();
+        let mut wide = WIDE_ENDS.contains(&c);
+        while let Some(&(j, next)) = chars.peek() {
+            if ENDS.contains(&next) || WIDE_ENDS.contains(&next) || CLOSERS.contains(&next) {
+                wide |= WIDE_ENDS.contains(&next);
+                end = j + next.len_utf8();
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        // Without whitespace after it the end may be a decimal point or an
+        // abbreviation still being typed.
+        if wide || chars.peek().is_some_and(|&(_, next)| next.is_whitespace()) {
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// When `text` is longer than `max_chars`, the byte offset of the last
+/// word break within them.
+fn overlong_end(text: &str, max_chars: usize) -> Option<usize> {
+    let (limit, _) = text.char_indices().nth(max_chars)?;
+    let head = &text[..limit];
+    Some(head.rfind(char::is_whitespace).filter(|&i| i > 0).unwrap_or(limit))
+}
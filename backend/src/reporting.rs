@@ -0,0 +1,58 @@
+//! Optional error reporting to Sentry, or anything that speaks its protocol
+//! (e.g. GlitchTip): panics and failed generations, with context that never
+//! includes prompt text.
+
+use anyhow::Context;
+use serde::Deserialize;
+
+/// The `[error_reporting]` config section. Reporting is off without a DSN.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ErrorReporting {
+    pub dsn: Option<String>,
+    /// Tag for telling deployments apart, e.g. `production`.
+    pub environment: Option<String>,
+    /// Fraction of errors sent.
+    pub sample_rate: f32,
+}
+
+impl Default for ErrorReporting {
+    fn default() -> Self {
+        Self {
+            dsn: None,
+            environment: None,
+            sample_rate: 1.0,
+        }
+    }
+}
+
+/// Starts the client; reports are flushed when the guard is dropped.
+pub fn init(config: &ErrorReporting) -> anyhow::Result<Option<sentry::ClientInitGuard>> {
+    let Some(dsn) = &config.dsn else {
+        return Ok(None);
+    };
+    let guard = sentry::init(sentry::ClientOptions {
+        dsn: Some(dsn.parse().context("invalid error_reporting.dsn")?),
+        environment: config.environment.clone().map(Into::into),
+        sample_rate: config.sample_rate,
+        release: sentry::release_name!(),
+        send_default_pii: false,
+        ..Default::default()
+    });
+    println!("reporting errors to {}", guard.dsn().map_or("?".to_string(), |d| d.host().to_string()));
+    Ok(Some(guard))
+}
+
+/// Reports `error` with `context` attached as tags. A no-op when reporting
+/// is off.
+pub fn capture(message: &str, error: &dyn std::fmt::Display, context: &[(&str, String)]) {
+    sentry::with_scope(
+        |scope| {
+            for (key, value) in context {
+                scope.set_tag(key, value);
+            }
+            scope.set_extra("error", error.to_string().into());
+        },
+        || sentry::capture_message(message, sentry::Level::Error),
+    );
+}
@@ -0,0 +1,385 @@
+//! Live audio over WebRTC. A peer negotiates a session through
+//! `POST /api/webrtc/offer`; `/api/tts` requests naming that session are
+//! then spoken into its Opus track as they are generated, instead of coming
+//! back in the response. Needs a build with the `webrtc` feature.
+//!
+//! Agents say the same short things over and over ("One moment.", "Sure."),
+//! so the Opus packets of short sentences are kept, keyed like the quick
+//! phrase cache, and a sentence spoken before goes out from them without
+//! the model or the encoder.
+
+use serde::Deserialize;
+
+/// The `[webrtc]` config section.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebRtc {
+    /// STUN/TURN servers for peers behind NAT; none are needed on a LAN.
+    pub ice_servers: Vec<IceServer>,
+    /// Sessions each namespace may hold open at once.
+    pub max_sessions: usize,
+    /// Sentences whose Opus packets are kept; 0 keeps none.
+    pub cached_sentences: usize,
+}
+
+impl Default for WebRtc {
+    fn default() -> Self {
+        Self {
+            ice_servers: Vec::new(),
+            max_sessions: 4,
+            cached_sentences: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "webrtc"), allow(dead_code))]
+pub struct IceServer {
+    /// e.g. `stun:stun.l.google.com:19302` or `turn:turn.example.com:3478`.
+    pub urls: Vec<String>,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub credential: String,
+}
+
+impl WebRtc {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !cfg!(feature = "webrtc") {
+            anyhow::bail!("the [webrtc] section needs a build with `--features webrtc`");
+        }
+        if self.max_sessions == 0 {
+            anyhow::bail!("webrtc.max_sessions must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "webrtc")]
+pub use live::{CachedSentence, Packetizer, Sessions, SAMPLE_RATE};
+
+#[cfg(feature = "webrtc")]
+mod live {
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use axum::body::Bytes;
+    use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+    use futures::StreamExt;
+    use webrtc::api::interceptor_registry::register_default_interceptors;
+    use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_OPUS};
+    use webrtc::api::{APIBuilder, API};
+    use webrtc::ice_transport::ice_server::RTCIceServer;
+    use webrtc::interceptor::registry::Registry;
+    use webrtc::media::Sample;
+    use webrtc::peer_connection::configuration::RTCConfiguration;
+    use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+    use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+    use webrtc::peer_connection::RTCPeerConnection;
+    use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+    use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+    use webrtc::track::track_local::TrackLocal;
+
+    use super::WebRtc;
+
+    /// Opus runs at 48 kHz; audio for a session is resampled to it.
+    pub const SAMPLE_RATE: u32 = 48000;
+
+    /// One Opus packet's worth of audio.
+    const FRAME: Duration = Duration::from_millis(20);
+    const FRAME_SAMPLES: usize = SAMPLE_RATE as usize / 50;
+
+    /// Audio for a session: 16-bit little-endian PCM at [`SAMPLE_RATE`].
+    pub type AudioSender = UnboundedSender<std::io::Result<Vec<u8>>>;
+
+    /// Audio for a session encoded already: Opus packets of one frame each.
+    pub type PacketSender = UnboundedSender<Vec<Bytes>>;
+
+    struct Session {
+        namespace: String,
+        peer: Arc<RTCPeerConnection>,
+        audio: AudioSender,
+        packets: PacketSender,
+    }
+
+    /// A sentence's packets, and the clip they were encoded from.
+    pub struct CachedSentence {
+        pub clip_id: String,
+        pub duration_secs: f64,
+        pub packets: Vec<Bytes>,
+    }
+
+    pub struct Sessions {
+        api: API,
+        config: WebRtc,
+        open: Arc<Mutex<HashMap<String, Session>>>,
+        /// Most recently used last.
+        sentences: Mutex<VecDeque<(String, Arc<CachedSentence>)>>,
+    }
+
+    impl Sessions {
+        pub fn new(config: &WebRtc) -> anyhow::Result<Self> {
+            let mut media = MediaEngine::default();
+            media.register_default_codecs()?;
+            let interceptors = register_default_interceptors(Registry::new(), &mut media)?;
+            let api = APIBuilder::new()
+                .with_media_engine(media)
+                .with_interceptor_registry(interceptors)
+                .build();
+            Ok(Self {
+                api,
+                config: config.clone(),
+                open: Arc::default(),
+                sentences: Mutex::default(),
+            })
+        }
+
+        /// Whether `namespace` may open another session.
+        pub fn has_room(&self, namespace: &str) -> bool {
+            let open = self.open.lock().unwrap();
+            open.values().filter(|s| s.namespace == namespace).count() < self.config.max_sessions
+        }
+
+        /// Answers `offer` with a peer connection carrying one audio track.
+        /// Candidates are gathered before answering, so the peer needs no
+        /// trickle ICE. Returns the session id and the answer.
+        pub async fn open(
+            &self,
+            namespace: &str,
+            offer: RTCSessionDescription,
+        ) -> anyhow::Result<(String, RTCSessionDescription)> {
+            if !self.has_room(namespace) {
+                anyhow::bail!("{namespace} already holds {} WebRTC sessions", self.config.max_sessions);
+            }
+
+            let ice_servers = self
+                .config
+                .ice_servers
+                .iter()
+                .map(|server| RTCIceServer {
+                    urls: server.urls.clone(),
+                    username: server.username.clone(),
+                    credential: server.credential.clone(),
+                })
+                .collect();
+            let peer = Arc::new(
+                self.api
+                    .new_peer_connection(RTCConfiguration {
+                        ice_servers,
+                        ..Default::default()
+                    })
+                    .await?,
+            );
+            let track = Arc::new(TrackLocalStaticSample::new(
+                RTCRtpCodecCapability {
+                    mime_type: MIME_TYPE_OPUS.to_string(),
+                    clock_rate: SAMPLE_RATE,
+                    channels: 1,
+                    ..Default::default()
+                },
+                "speech".to_string(),
+                "ttser".to_string(),
+            ));
+            let sender = peer.add_track(track.clone() as Arc<dyn TrackLocal + Send + Sync>).await?;
+            // RTCP has to be read for the interceptors (NACK, reports) to work.
+            tokio::spawn(async move {
+                let mut buffer = vec![0u8; 1500];
+                while sender.read(&mut buffer).await.is_ok() {}
+            });
+
+            peer.set_remote_description(offer).await?;
+            let answer = peer.create_answer(None).await?;
+            let mut gathered = peer.gathering_complete_promise().await;
+            peer.set_local_description(answer).await?;
+            let _ = gathered.recv().await;
+            let Some(answer) = peer.local_description().await else {
+                anyhow::bail!("no local description after gathering");
+            };
+
+            let id: String = (0..16).map(|_| format!("{:02x}", rand::random::<u8>())).collect();
+            let (audio, receiver) = mpsc::unbounded();
+            let (packets, encoded) = mpsc::unbounded();
+            tokio::spawn(pump(id.clone(), track, receiver, encoded));
+
+            let (open, closing) = (self.open.clone(), id.clone());
+            peer.on_peer_connection_state_change(Box::new(move |state| {
+                println!("webrtc[{closing}]: {state}");
+                if matches!(
+                    state,
+                    RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed
+                ) {
+                    // Dropping the session ends its pump.
+                    if let Some(session) = open.lock().unwrap().remove(&closing) {
+                        return Box::pin(async move {
+                            let _ = session.peer.close().await;
+                        });
+                    }
+                }
+                Box::pin(async {})
+            }));
+            self.open.lock().unwrap().insert(
+                id.clone(),
+                Session {
+                    namespace: namespace.to_string(),
+                    peer,
+                    audio,
+                    packets,
+                },
+            );
+            println!("webrtc[{id}]: opened for {namespace}");
+            Ok((id, answer))
+        }
+
+        /// Where to send audio for session `id`, if `namespace` holds it.
+        pub fn audio(&self, namespace: &str, id: &str) -> Option<AudioSender> {
+            let open = self.open.lock().unwrap();
+            open.get(id).filter(|s| s.namespace == namespace).map(|s| s.audio.clone())
+        }
+
+        /// Where to send encoded audio for session `id`, if `namespace`
+        /// holds it.
+        pub fn packets(&self, namespace: &str, id: &str) -> Option<PacketSender> {
+            let open = self.open.lock().unwrap();
+            open.get(id).filter(|s| s.namespace == namespace).map(|s| s.packets.clone())
+        }
+
+        /// The packets kept for the sentence `key`.
+        pub fn cached(&self, key: &str) -> Option<Arc<CachedSentence>> {
+            let mut sentences = self.sentences.lock().unwrap();
+            let at = sentences.iter().position(|(k, _)| k == key)?;
+            let entry = sentences.remove(at)?;
+            let cached = entry.1.clone();
+            sentences.push_back(entry);
+            Some(cached)
+        }
+
+        /// Keeps the packets of sentence `key`, dropping the least recently
+        /// used beyond `cached_sentences`.
+        pub fn cache(&self, key: String, sentence: CachedSentence) {
+            let mut sentences = self.sentences.lock().unwrap();
+            sentences.retain(|(k, _)| *k != key);
+            sentences.push_back((key, Arc::new(sentence)));
+            while sentences.len() > self.config.cached_sentences {
+                sentences.pop_front();
+            }
+        }
+
+        pub fn caches_sentences(&self) -> bool {
+            self.config.cached_sentences > 0
+        }
+
+        /// Hangs up session `id`. Returns whether `namespace` held it.
+        pub async fn close(&self, namespace: &str, id: &str) -> bool {
+            let session = {
+                let mut open = self.open.lock().unwrap();
+                match open.get(id) {
+                    Some(s) if s.namespace == namespace => open.remove(id),
+                    _ => None,
+                }
+            };
+            match session {
+                Some(session) => {
+                    if let Err(e) = session.peer.close().await {
+                        println!("webrtc[{id}]: closing failed: {e}");
+                    }
+                    true
+                }
+                None => false,
+            }
+        }
+    }
+
+    /// Cuts 16-bit PCM at [`SAMPLE_RATE`] into frames and encodes each as
+    /// an Opus packet.
+    pub struct Packetizer {
+        id: String,
+        encoder: opus::Encoder,
+        pending: Vec<i16>,
+        packet: Vec<u8>,
+    }
+
+    impl Packetizer {
+        /// `id` names the session in log lines.
+        pub fn new(id: &str) -> Result<Self, opus::Error> {
+            Ok(Self {
+                id: id.to_string(),
+                encoder: opus::Encoder::new(SAMPLE_RATE, opus::Channels::Mono, opus::Application::Voip)?,
+                pending: Vec::new(),
+                packet: vec![0u8; 4000],
+            })
+        }
+
+        /// The packets of the whole frames so far, with `bytes` added; the
+        /// rest waits for more.
+        pub fn push(&mut self, bytes: &[u8]) -> Vec<Bytes> {
+            self.pending.extend(bytes.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])));
+            self.encode()
+        }
+
+        /// The last partial frame, padded with silence.
+        pub fn finish(&mut self) -> Vec<Bytes> {
+            let padded = self.pending.len().next_multiple_of(FRAME_SAMPLES);
+            self.pending.resize(padded, 0);
+            self.encode()
+        }
+
+        fn encode(&mut self) -> Vec<Bytes> {
+            let mut packets = Vec::new();
+            while self.pending.len() >= FRAME_SAMPLES {
+                let frame: Vec<i16> = self.pending.drain(..FRAME_SAMPLES).collect();
+                match self.encoder.encode(&frame, &mut self.packet) {
+                    Ok(len) => packets.push(Bytes::copy_from_slice(&self.packet[..len])),
+                    Err(e) => println!("webrtc[{}]: opus encoding failed: {e}", self.id),
+                }
+            }
+            packets
+        }
+    }
+
+    /// Encodes a session's audio into Opus packets and writes them, and the
+    /// packets sent encoded already, to the track in real time. A clip's
+    /// last partial frame is padded with silence once nothing more arrives
+    /// for a frame.
+    async fn pump(
+        id: String,
+        track: Arc<TrackLocalStaticSample>,
+        mut audio: UnboundedReceiver<std::io::Result<Vec<u8>>>,
+        mut encoded: UnboundedReceiver<Vec<Bytes>>,
+    ) {
+        let mut packetizer = match Packetizer::new(&id) {
+            Ok(packetizer) => packetizer,
+            Err(e) => {
+                println!("webrtc[{id}]: opus encoder failed: {e}");
+                return;
+            }
+        };
+        let mut ticker = tokio::time::interval(FRAME);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            let packets = tokio::select! {
+                received = tokio::time::timeout(FRAME, audio.next()) => match received {
+                    Ok(Some(Ok(bytes))) => packetizer.push(&bytes),
+                    // A failed clip just stops; the session stays usable.
+                    Ok(Some(Err(_))) => Vec::new(),
+                    Ok(None) => return,
+                    Err(_) => packetizer.finish(),
+                },
+                Some(packets) = encoded.next() => packets,
+            };
+            for data in packets {
+                ticker.tick().await;
+                let sample = Sample {
+                    data,
+                    duration: FRAME,
+                    ..Default::default()
+                };
+                if let Err(e) = track.write_sample(&sample).await {
+                    println!("webrtc[{id}]: writing audio failed: {e}");
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Strategies for picking each audio token from the decoder's logits.
+//!
+//! `stock` is candle's `LogitsProcessor` (greedy at temperature 0, otherwise
+//! temperature with optional top-p). The others sample from the softmax
+//! themselves: locally typical sampling, mirostat (v2) and a temperature
+//! that moves linearly from `temperature` to `temperature_end` over the run.
+
+use candle::{DType, Result, Tensor};
+use candle_transformers::generation::LogitsProcessor;
+use rand::distr::{weighted::WeightedIndex, Distribution};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+pub trait Sampler {
+    /// Picks the next token of `codebook` from its 1-D `logits` at decoding
+    /// `step`.
+    fn sample(&mut self, logits: &Tensor, codebook: usize, step: usize) -> Result<u32>;
+}
+
+impl Sampler for LogitsProcessor {
+    fn sample(&mut self, logits: &Tensor, _codebook: usize, _step: usize) -> Result<u32> {
+        LogitsProcessor::sample(self, logits)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SamplerKind {
+    Stock,
+    /// Keep the tokens whose surprise is closest to the entropy, up to
+    /// `mass` of probability.
+    Typical { mass: f64 },
+    /// Adapt truncation so the average surprise (bits) tracks `tau`.
+    Mirostat { tau: f64, eta: f64 },
+    /// Temperature interpolated from the request's to `end_temperature`.
+    Schedule { end_temperature: f64 },
+}
+
+impl SamplerKind {
+    pub const DEFAULT_TYPICAL_MASS: f64 = 0.95;
+    pub const DEFAULT_MIROSTAT_TAU: f64 = 3.0;
+    pub const DEFAULT_MIROSTAT_ETA: f64 = 0.1;
+
+    /// Builds the sampler for one generation. `temperature` at or below
+    /// zero means greedy for `stock` and 1.0 for the others.
+    pub fn build(
+        &self,
+        seed: u64,
+        temperature: f64,
+        top_p: Option<f64>,
+        max_steps: usize,
+    ) -> Box<dyn Sampler + Send> {
+        let rng = StdRng::seed_from_u64(seed);
+        let temperature_or_one = if temperature > 0.0 { temperature } else { 1.0 };
+        match *self {
+            Self::Stock => Box::new(LogitsProcessor::new(seed, Some(temperature), top_p)),
+            Self::Typical { mass } => Box::new(Typical {
+                rng,
+                mass,
+                temperature: temperature_or_one,
+            }),
+            Self::Mirostat { tau, eta } => Box::new(Mirostat {
+                rng,
+                tau,
+                eta,
+                temperature: temperature_or_one,
+                mu: Vec::new(),
+            }),
+            Self::Schedule { end_temperature } => Box::new(Schedule {
+                rng,
+                start: temperature_or_one,
+                end: end_temperature,
+                steps: max_steps.max(1),
+                top_p,
+            }),
+        }
+    }
+}
+
+struct Typical {
+    rng: StdRng,
+    mass: f64,
+    temperature: f64,
+}
+
+impl Sampler for Typical {
+    fn sample(&mut self, logits: &Tensor, _codebook: usize, _step: usize) -> Result<u32> {
+        let mut prs = softmax(logits, self.temperature)?;
+        let entropy: f32 = prs.iter().filter(|&&p| p > 0.0).map(|&p| -p * p.ln()).sum();
+        let mut order: Vec<usize> = (0..prs.len()).collect();
+        let distance = |p: f32| (-p.ln() - entropy).abs();
+        order.sort_by(|&a, &b| distance(prs[a]).total_cmp(&distance(prs[b])));
+        keep_until_mass(&mut prs, &order, self.mass as f32);
+        draw(&mut self.rng, &prs)
+    }
+}
+
+struct Mirostat {
+    rng: StdRng,
+    tau: f64,
+    eta: f64,
+    temperature: f64,
+    /// Per-codebook truncation threshold, in bits.
+    mu: Vec<f64>,
+}
+
+impl Sampler for Mirostat {
+    fn sample(&mut self, logits: &Tensor, codebook: usize, _step: usize) -> Result<u32> {
+        if self.mu.len() <= codebook {
+            self.mu.resize(codebook + 1, 2.0 * self.tau);
+        }
+        let mu = self.mu[codebook];
+        let prs = softmax(logits, self.temperature)?;
+        let surprise = |p: f32| -(p as f64).log2();
+
+        let mut truncated = prs.clone();
+        let best = argmax(&prs);
+        for (i, p) in truncated.iter_mut().enumerate() {
+            if i != best && surprise(*p) > mu {
+                *p = 0.0;
+            }
+        }
+        let token = draw(&mut self.rng, &truncated)?;
+        self.mu[codebook] = mu - self.eta * (surprise(prs[token as usize]) - self.tau);
+        Ok(token)
+    }
+}
+
+struct Schedule {
+    rng: StdRng,
+    start: f64,
+    end: f64,
+    steps: usize,
+    top_p: Option<f64>,
+}
+
+impl Sampler for Schedule {
+    fn sample(&mut self, logits: &Tensor, _codebook: usize, step: usize) -> Result<u32> {
+        let progress = (step as f64 / self.steps as f64).min(1.0);
+        let temperature = self.start + (self.end - self.start) * progress;
+        if temperature <= 0.0 {
+            return Ok(argmax(&logits.to_dtype(DType::F32)?.to_vec1::<f32>()?) as u32);
+        }
+        let mut prs = softmax(logits, temperature)?;
+        if let Some(p) = self.top_p {
+            let mut order: Vec<usize> = (0..prs.len()).collect();
+            order.sort_by(|&a, &b| prs[b].total_cmp(&prs[a]));
+            keep_until_mass(&mut prs, &order, p as f32);
+        }
+        draw(&mut self.rng, &prs)
+    }
+}
+
+fn softmax(logits: &Tensor, temperature: f64) -> Result<Vec<f32>> {
+    let logits = (logits.to_dtype(DType::F32)? / temperature)?;
+    candle_nn::ops::softmax_last_dim(&logits)?.to_vec1::<f32>()
+}
+
+/// Zeroes every probability after the first tokens of `order` that together
+/// reach `mass`.
+fn keep_until_mass(prs: &mut [f32], order: &[usize], mass: f32) {
+    let mut cumsum = 0.0;
+    for &i in order {
+        if cumsum >= mass {
+            prs[i] = 0.0;
+        } else {
+            cumsum += prs[i];
+        }
+    }
+}
+
+fn argmax(values: &[f32]) -> usize {
+    values
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+fn draw(rng: &mut StdRng, prs: &[f32]) -> Result<u32> {
+    let distr = WeightedIndex::new(prs).map_err(candle::Error::wrap)?;
+    Ok(distr.sample(rng) as u32)
+}
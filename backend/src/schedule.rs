@@ -0,0 +1,352 @@
+//! Synthesis jobs run on a cron schedule, such as a nightly re-render of an
+//! announcement whose text changes: the `[[schedules]]` config entries each
+//! speak a template, optionally filled with the text of a URL fetched at run
+//! time, in one of a namespace's voice presets. The clips are stored in the
+//! namespace's history like any other generation, and a webhook is told how
+//! each run went.
+//!
+//! Cron expressions have the usual five fields (minute, hour, day of month,
+//! month, day of week) and are read in UTC.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::audiobook::JobError;
+use crate::error::SynthesisError;
+use crate::namespace::Namespace;
+use crate::{extract, fetch, podcast, AppState};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How far ahead a cron expression is searched for its next run: long
+/// enough to reach a leap day.
+const SEARCH_DAYS: i64 = 8 * 366;
+
+pub const WEEKDAYS: [&str; 7] = ["Sunday", "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday"];
+
+/// One `[[schedules]]` config entry.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schedule {
+    /// Names the schedule in logs and webhooks.
+    pub name: String,
+    /// e.g. `0 5 * * 1-5` for 05:00 UTC on weekdays.
+    pub cron: Cron,
+    /// Namespace whose voice presets, history and usage the clips use.
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    pub voice: String,
+    /// What is spoken. `{date}`, `{time}` and `{weekday}` become the run's
+    /// (UTC), `{content}` the text read from `url`. Defaults to `{content}`.
+    #[serde(default)]
+    pub text: Option<String>,
+    /// Fetched on every run; pages have their article extracted, plain
+    /// text is read as it is.
+    #[serde(default)]
+    pub url: Option<String>,
+    /// The text is cut off at the last sentence end before this length.
+    #[serde(default = "default_max_chars")]
+    pub max_chars: usize,
+    /// Gets a JSON POST after every run, done or failed.
+    #[serde(default)]
+    pub webhook: Option<String>,
+}
+
+fn default_namespace() -> String {
+    crate::namespace::DEFAULT_NAMESPACE.to_string()
+}
+
+fn default_max_chars() -> usize {
+    5000
+}
+
+impl Schedule {
+    fn template(&self) -> &str {
+        self.text.as_deref().unwrap_or("{content}")
+    }
+}
+
+/// Checks the `[[schedules]]` entries.
+pub fn validate(schedules: &[Schedule]) -> anyhow::Result<()> {
+    for (i, schedule) in schedules.iter().enumerate() {
+        let name = &schedule.name;
+        if name.trim().is_empty() {
+            bail!("schedules[{i}].name must not be empty");
+        }
+        if schedules[..i].iter().any(|other| other.name == *name) {
+            bail!("schedule {name:?} is configured twice");
+        }
+        if schedule.voice.is_empty() {
+            bail!("schedule {name:?} must name a voice preset");
+        }
+        if schedule.text.is_none() && schedule.url.is_none() {
+            bail!("schedule {name:?} needs a text or a url");
+        }
+        let uses_content = schedule.template().contains("{content}");
+        match &schedule.url {
+            Some(url) if !url.starts_with("http://") && !url.starts_with("https://") => {
+                bail!("schedule {name:?}: url must be an http(s) URL")
+            }
+            Some(_) if !uses_content => bail!("schedule {name:?}: text must use {{content}} when url is set"),
+            None if uses_content => bail!("schedule {name:?}: text uses {{content}} without a url"),
+            _ => {}
+        }
+        if let Some(webhook) = &schedule.webhook {
+            if !webhook.starts_with("http://") && !webhook.starts_with("https://") {
+                bail!("schedule {name:?}: webhook must be an http(s) URL");
+            }
+        }
+        if schedule.max_chars == 0 {
+            bail!("schedule {name:?}: max_chars must be at least 1");
+        }
+    }
+    Ok(())
+}
+
+/// A parsed cron expression: the minutes, hours, days, months and weekdays
+/// it fires on, as bit sets.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cron {
+    text: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    /// Sunday is bit 0 (and `7` another name for it).
+    weekdays: u64,
+    /// Whether the day of month or the day of week field is `*`. When
+    /// neither is, a day matching either one fires, as in cron.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl TryFrom<String> for Cron {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, String> {
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!("cron expression {text:?} must have 5 fields"));
+        };
+        let field = |field: &str, min: u32, max: u32| {
+            bits(field, min, max).ok_or_else(|| format!("invalid cron field {field:?} in {text:?}, expected {min}-{max}"))
+        };
+        let mut weekdays = field(weekday, 0, 7)?;
+        if weekdays & 1 << 7 != 0 {
+            weekdays = weekdays & !(1 << 7) | 1;
+        }
+        let cron = Self {
+            minutes: field(minute, 0, 59)?,
+            hours: field(hour, 0, 23)?,
+            days: field(day, 1, 31)?,
+            months: field(month, 1, 12)?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+            text,
+        };
+        if cron.next_after(0).is_none() {
+            return Err(format!("cron expression {:?} never fires", cron.text));
+        }
+        Ok(cron)
+    }
+}
+
+/// One cron field as a bit set: `*`, numbers, ranges `a-b` and steps `/n`,
+/// separated by commas.
+fn bits(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut set = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().ok().filter(|&step| step > 0)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+                // `5/15` runs from 5 to the end.
+                None if step > 1 => (range.parse().ok()?, max),
+                None => {
+                    let at = range.parse().ok()?;
+                    (at, at)
+                }
+            },
+        };
+        if start < min || end > max || start > end {
+            return None;
+        }
+        for at in (start..=end).step_by(step) {
+            set |= 1 << at;
+        }
+    }
+    Some(set)
+}
+
+impl Cron {
+    /// The first minute after the Unix time `after` it fires on, as Unix
+    /// time.
+    fn next_after(&self, after: u64) -> Option<u64> {
+        let from = after / 60 + 1;
+        let first_day = (from / (24 * 60)) as i64;
+        for day in first_day..first_day + SEARCH_DAYS {
+            let (_, month, date) = podcast::civil_date(day);
+            let weekday = (day + 4).rem_euclid(7);
+            let day_matches = self.days & 1 << date != 0;
+            let weekday_matches = self.weekdays & 1 << weekday != 0;
+            let matches = match (self.any_day, self.any_weekday) {
+                (false, false) => day_matches || weekday_matches,
+                _ => day_matches && weekday_matches,
+            };
+            if !matches || self.months & 1 << month == 0 {
+                continue;
+            }
+            let start = day as u64 * 24 * 60;
+            for minute in from.max(start)..start + 24 * 60 {
+                let of_day = minute - start;
+                if self.hours & 1 << (of_day / 60) != 0 && self.minutes & 1 << (of_day % 60) != 0 {
+                    return Some(minute * 60);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// What a schedule's webhook is sent after each run.
+#[derive(Debug, Serialize)]
+struct Run<'a> {
+    schedule: &'a str,
+    namespace: &'a str,
+    voice: &'a str,
+    state: &'static str,
+    /// History id of the clip, once it is done.
+    clip_id: Option<String>,
+    duration_secs: Option<f64>,
+    error: Option<JobError>,
+    started_at: u64,
+    finished_at: u64,
+}
+
+/// Checks the schedules against the namespaces and starts each one's timer
+/// in the background.
+pub fn start(state: &AppState, schedules: &[Schedule]) -> anyhow::Result<()> {
+    let http = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+    for schedule in schedules {
+        let namespace = state
+            .namespaces
+            .all()
+            .iter()
+            .find(|n| n.name == schedule.namespace)
+            .cloned()
+            .with_context(|| format!("schedule {:?}: namespace {:?} does not exist", schedule.name, schedule.namespace))?;
+        if !namespace.voices.contains(&schedule.voice) {
+            bail!(
+                "schedule {:?}: voice {:?} is not a preset of namespace {:?}",
+                schedule.name,
+                schedule.voice,
+                namespace.name
+            );
+        }
+        let (state, schedule, http) = (state.clone(), schedule.clone(), http.clone());
+        tokio::spawn(async move {
+            println!("schedule[{}]: running at `{}` UTC in namespace {}", schedule.name, schedule.cron.text, namespace.name);
+            loop {
+                // Runs missed while the previous one was still going are
+                // skipped, not made up for.
+                let now = crate::unix_now();
+                let Some(next) = schedule.cron.next_after(now) else {
+                    break;
+                };
+                tokio::time::sleep(Duration::from_secs(next - now)).await;
+                run(&state, &namespace, &schedule, &http, next).await;
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Renders one run of `schedule`, due at `due`, and tells the webhook.
+async fn run(state: &AppState, namespace: &Arc<Namespace>, schedule: &Schedule, http: &reqwest::Client, due: u64) {
+    let name = &schedule.name;
+    let started_at = crate::unix_now();
+    println!("schedule[{name}]: started");
+    let outcome = render(state, namespace, schedule, http, due).await;
+    match &outcome {
+        Ok((clip_id, _)) => println!("schedule[{name}]: done, clip {clip_id}"),
+        Err(e) => println!("schedule[{name}]: failed: {} ({})", e.message, e.code),
+    }
+    let Some(webhook) = &schedule.webhook else {
+        return;
+    };
+    let (clip_id, duration_secs, error) = match outcome {
+        Ok((clip_id, duration_secs)) => (Some(clip_id), Some(duration_secs), None),
+        Err(e) => (None, None, Some(e)),
+    };
+    let report = Run {
+        schedule: name,
+        namespace: &namespace.name,
+        voice: &schedule.voice,
+        state: if error.is_none() { "done" } else { "failed" },
+        clip_id,
+        duration_secs,
+        error,
+        started_at,
+        finished_at: crate::unix_now(),
+    };
+    let sent = http.post(webhook).json(&report).send().await.and_then(|r| r.error_for_status());
+    if let Err(e) = sent {
+        println!("schedule[{name}]: webhook failed: {e}");
+    }
+}
+
+/// The run's clip id and duration.
+async fn render(
+    state: &AppState,
+    namespace: &Arc<Namespace>,
+    schedule: &Schedule,
+    http: &reqwest::Client,
+    due: u64,
+) -> Result<(String, f64), JobError> {
+    let content = match &schedule.url {
+        Some(url) => fetch_text(http, url).await.map_err(|e| JobError {
+            code: "fetch_failed",
+            message: format!("fetching {url} failed: {e:#}"),
+        })?,
+        None => String::new(),
+    };
+    let (year, month, date) = podcast::civil_date((due / 86400) as i64);
+    let weekday = WEEKDAYS[((due / 86400 + 4) % 7) as usize];
+    let text = schedule
+        .template()
+        .replace("{date}", &format!("{year}-{month:02}-{date:02}"))
+        .replace("{time}", &format!("{:02}:{:02}", due / 3600 % 24, due / 60 % 60))
+        .replace("{weekday}", weekday)
+        .replace("{content}", &content);
+    let prompt = podcast::truncate(text.trim(), schedule.max_chars);
+    if prompt.is_empty() {
+        return Err(JobError {
+            code: "empty_text",
+            message: "there is nothing to speak".to_string(),
+        });
+    }
+    // The webhook only gets the error's fixed message.
+    let failed = |e: SynthesisError| {
+        println!("schedule[{}]: {e} ({})", schedule.name, e.code());
+        JobError::from(e)
+    };
+    let job = crate::preset_job(state, namespace.clone(), &schedule.voice, &prompt).await.map_err(failed)?;
+    let clip_id = job.clip_id.clone();
+    let rendered = job.run(None).await.map_err(failed)?;
+    Ok((clip_id, rendered.clip.duration_secs))
+}
+
+/// The text of the page at `url`.
+async fn fetch_text(http: &reqwest::Client, url: &str) -> anyhow::Result<String> {
+    let body = fetch::read(http, url, fetch::MAX_PAGE_BYTES).await?;
+    Ok(extract::extract(&String::from_utf8_lossy(&body)).text)
+}
@@ -0,0 +1,188 @@
+//! An optional check of what a clip actually says. The clip goes to a
+//! Whisper server (anything serving OpenAI's `POST /v1/audio/transcriptions`:
+//! whisper.cpp's server, faster-whisper-server, OpenAI itself) and the
+//! transcript is compared with the prompt word by word. A high word error
+//! rate means the model skipped, repeated or garbled words, which the
+//! quality gate's level and duration checks can't see. With `retries`,
+//! such clips are rendered again until one reads well or the budget is
+//! spent.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Retries halve the chunk size down to this, about a short sentence.
+pub const MIN_CHUNK_CHARS: usize = 40;
+
+/// The `[self_check]` config section. Checking is off without a URL.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelfCheck {
+    /// The transcription endpoint, e.g.
+    /// `http://whisper:8000/v1/audio/transcriptions`.
+    pub url: Option<String>,
+    /// Sent as the `model` form field.
+    pub model: String,
+    /// Sent as a bearer token.
+    pub token: Option<String>,
+    /// ISO 639-1 code of the prompts, so Whisper doesn't have to guess.
+    pub language: Option<String>,
+    /// Clips with a higher word error rate are flagged.
+    pub max_word_error_rate: f64,
+    /// Check every `/api/tts` clip, not only those asking with `self_check`.
+    pub always: bool,
+    pub timeout_secs: f64,
+    /// Renderings of a clip after the first, while its word error rate is
+    /// above `retry_above`. 0 only reports.
+    pub retries: usize,
+    /// Defaults to `max_word_error_rate`.
+    pub retry_above: Option<f64>,
+}
+
+impl Default for SelfCheck {
+    fn default() -> Self {
+        Self {
+            url: None,
+            model: "whisper-1".to_string(),
+            token: None,
+            language: None,
+            max_word_error_rate: 0.2,
+            always: false,
+            timeout_secs: 30.0,
+            retries: 0,
+            retry_above: None,
+        }
+    }
+}
+
+impl SelfCheck {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.always && self.url.is_none() {
+            anyhow::bail!("self_check.always needs self_check.url");
+        }
+        if !self.max_word_error_rate.is_finite() || self.max_word_error_rate < 0.0 {
+            anyhow::bail!("self_check.max_word_error_rate must not be negative");
+        }
+        if self.retry_above.is_some_and(|rate| !rate.is_finite() || rate < 0.0) {
+            anyhow::bail!("self_check.retry_above must not be negative");
+        }
+        if !self.timeout_secs.is_finite() || self.timeout_secs <= 0.0 {
+            anyhow::bail!("self_check.timeout_secs must be positive");
+        }
+        Ok(())
+    }
+}
+
+/// How a clip did, as kept in its history record.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    pub transcript: String,
+    /// Word substitutions, deletions and insertions over the prompt's
+    /// word count.
+    pub word_error_rate: f64,
+    pub flagged: bool,
+    /// Every rendering checked, in order, when the clip was rendered again
+    /// for its error rate; the clip is the one with the lowest.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attempts: Vec<Attempt>,
+}
+
+/// One rendering of a clip and how it read.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attempt {
+    pub seed: u64,
+    pub temperature: f64,
+    pub chunk_chars: usize,
+    pub word_error_rate: f64,
+}
+
+#[derive(Deserialize)]
+struct Transcription {
+    text: String,
+}
+
+pub struct Checker {
+    config: SelfCheck,
+    http: reqwest::Client,
+}
+
+impl Checker {
+    /// None when no URL is configured.
+    pub fn new(config: &SelfCheck) -> anyhow::Result<Option<Self>> {
+        let Some(url) = &config.url else {
+            return Ok(None);
+        };
+        println!("checking clips against their transcripts from {url}");
+        Ok(Some(Self {
+            config: config.clone(),
+            http: reqwest::Client::builder()
+                .timeout(Duration::from_secs_f64(config.timeout_secs))
+                .build()?,
+        }))
+    }
+
+    pub fn always(&self) -> bool {
+        self.config.always
+    }
+
+    /// Whether a clip reading as `report` after `retried` retries gets
+    /// another.
+    pub fn retries(&self, report: &Report, retried: usize) -> bool {
+        let above = self.config.retry_above.unwrap_or(self.config.max_word_error_rate);
+        retried < self.config.retries && report.word_error_rate > above
+    }
+
+    /// Transcribes the WAV `wav` and compares it with `prompt`.
+    pub async fn check(&self, wav: &[u8], prompt: &str) -> anyhow::Result<Report> {
+        let url = self.config.url.as_deref().unwrap_or_default();
+        let file = reqwest::multipart::Part::bytes(wav.to_vec())
+            .file_name("clip.wav")
+            .mime_str("audio/wav")?;
+        let mut form = reqwest::multipart::Form::new()
+            .part("file", file)
+            .text("model", self.config.model.clone())
+            .text("response_format", "json");
+        if let Some(language) = &self.config.language {
+            form = form.text("language", language.clone());
+        }
+        let mut request = self.http.post(url).multipart(form);
+        if let Some(token) = &self.config.token {
+            request = request.bearer_auth(token);
+        }
+        let transcription: Transcription = request.send().await?.error_for_status()?.json().await?;
+        let word_error_rate = word_error_rate(prompt, &transcription.text);
+        Ok(Report {
+            transcript: transcription.text.trim().to_string(),
+            word_error_rate,
+            flagged: word_error_rate > self.config.max_word_error_rate,
+            attempts: Vec::new(),
+        })
+    }
+}
+
+/// Lowercase words without punctuation, so only the words themselves count.
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric() && c != '\'')
+        .map(|word| word.trim_matches('\'').to_lowercase())
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+/// Word-level edit distance from `reference` to `hypothesis`, over the
+/// reference's word count.
+fn word_error_rate(reference: &str, hypothesis: &str) -> f64 {
+    let (reference, hypothesis) = (words(reference), words(hypothesis));
+    if reference.is_empty() {
+        return if hypothesis.is_empty() { 0.0 } else { 1.0 };
+    }
+    let mut previous: Vec<usize> = (0..=hypothesis.len()).collect();
+    for (i, expected) in reference.iter().enumerate() {
+        let mut row = vec![i + 1; hypothesis.len() + 1];
+        for (j, heard) in hypothesis.iter().enumerate() {
+            let substitution = previous[j] + usize::from(expected != heard);
+            row[j + 1] = substitution.min(previous[j + 1] + 1).min(row[j] + 1);
+        }
+        previous = row;
+    }
+    previous[hypothesis.len()] as f64 / reference.len() as f64
+}
@@ -0,0 +1,101 @@
+//! The interactive mode of `/api/tts` (`interactive=true`), for voice UIs
+//! where what matters is how soon the first sound comes: the text is split
+//! into sentence-sized chunks, each chunk's steps are bounded, and the
+//! audio streams out as it is decoded, the first piece early enough to
+//! meet the time-to-first-audio target. Requests that miss it are logged
+//! and counted. With `model` set they also render on a smaller checkpoint,
+//! such as Parler-TTS Mini, unless they name their own.
+
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::Metrics;
+use crate::models::Models;
+
+/// The `[slo]` config section.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Slo {
+    /// Time from receiving a request to sending its first audio, in seconds.
+    pub time_to_first_audio_secs: f64,
+    /// Chunk size in interactive mode, when smaller than `chunk_chars`.
+    pub chunk_chars: usize,
+    /// Step limit of each chunk in interactive mode, when lower than the
+    /// request's.
+    pub max_steps: usize,
+    /// Checkpoint for interactive requests that name no `model`: the
+    /// default one or one of `[models] allowed`, named like the field.
+    pub model: Option<String>,
+}
+
+impl Default for Slo {
+    fn default() -> Self {
+        Self {
+            time_to_first_audio_secs: 1.0,
+            chunk_chars: 120,
+            // About 9 s of audio, plenty for a sentence.
+            max_steps: 800,
+            model: None,
+        }
+    }
+}
+
+impl Slo {
+    pub fn validate(&self, models: &Models) -> anyhow::Result<()> {
+        if !self.time_to_first_audio_secs.is_finite() || self.time_to_first_audio_secs <= 0.0 {
+            anyhow::bail!("slo.time_to_first_audio_secs must be positive");
+        }
+        if self.chunk_chars == 0 || self.max_steps == 0 {
+            anyhow::bail!("slo.chunk_chars and slo.max_steps must be positive");
+        }
+        if let Some(Err(e)) = self.model.as_deref().map(|name| models.resolve(name)) {
+            anyhow::bail!("slo.model: {e}");
+        }
+        Ok(())
+    }
+
+    /// The target of a request received at `received`.
+    pub fn target(&self, received: Instant) -> Target {
+        Target {
+            received,
+            deadline: received + std::time::Duration::from_secs_f64(self.time_to_first_audio_secs),
+        }
+    }
+}
+
+/// When an interactive request's first audio is due.
+#[derive(Debug, Clone, Copy)]
+pub struct Target {
+    pub received: Instant,
+    pub deadline: Instant,
+}
+
+/// How a request did against its target.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Outcome {
+    /// Absent when no audio went out at all.
+    pub time_to_first_audio_secs: Option<f64>,
+    pub slo_missed: bool,
+}
+
+impl Target {
+    /// Judges a request whose first audio went out at `first_audio`,
+    /// logging and counting a miss.
+    pub fn report(&self, metrics: &Metrics, request_id: u64, first_audio: Option<Instant>) -> Outcome {
+        let missed = first_audio.is_none_or(|at| at > self.deadline);
+        let time_to_first_audio_secs = first_audio.map(|at| (at - self.received).as_secs_f64());
+        metrics.record_slo(missed);
+        if missed {
+            let target = (self.deadline - self.received).as_secs_f64();
+            match time_to_first_audio_secs {
+                Some(secs) => println!("tts[{request_id}]: first audio after {secs:.2} s, missing the {target} s SLO"),
+                None => println!("tts[{request_id}]: no audio went out, missing the {target} s SLO"),
+            }
+        }
+        Outcome {
+            time_to_first_audio_secs,
+            slo_missed: missed,
+        }
+    }
+}
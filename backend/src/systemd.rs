@@ -0,0 +1,70 @@
+//! systemd integration: readiness notification (`Type=notify`) and socket
+//! activation. Both are no-ops when not started by systemd.
+
+use std::os::unix::net::UnixDatagram;
+
+use crate::listener::Listener;
+
+/// Sends `state` (e.g. `READY=1`) to the service manager, if there is one.
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        let path = path.to_string_lossy();
+        match path.strip_prefix('@') {
+            Some(name) => send_abstract(&socket, name, state),
+            None => socket.send_to(state.as_bytes(), &*path),
+        }
+    });
+    if let Err(e) = result {
+        println!("systemd: notify {state:?} failed: {e}");
+    }
+}
+
+/// Sends `state` to the abstract socket `name` (`@name`), which only
+/// Linux has.
+#[cfg(target_os = "linux")]
+fn send_abstract(socket: &UnixDatagram, name: &str, state: &str) -> std::io::Result<usize> {
+    use std::os::linux::net::SocketAddrExt;
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    socket.send_to_addr(state.as_bytes(), &addr)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_: &UnixDatagram, _: &str, _: &str) -> std::io::Result<usize> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "abstract (@) notify sockets are only supported on Linux",
+    ))
+}
+
+/// The listening sockets passed by systemd socket activation, in order.
+/// Both `ListenStream=<port>` and `ListenStream=<path>` sockets work.
+pub fn activated_listeners() -> anyhow::Result<Vec<Listener>> {
+    /// First descriptor systemd passes (`SD_LISTEN_FDS_START`).
+    const FIRST_FD: i32 = 3;
+
+    let ours = std::env::var("LISTEN_PID").is_ok_and(|pid| pid == std::process::id().to_string());
+    let count: i32 = std::env::var("LISTEN_FDS").ok().and_then(|n| n.parse().ok()).unwrap_or(0);
+    if !ours {
+        return Ok(Vec::new());
+    }
+    (FIRST_FD..FIRST_FD + count).map(adopt).collect()
+}
+
+fn adopt(fd: i32) -> anyhow::Result<Listener> {
+    use std::os::fd::{FromRawFd, IntoRawFd};
+
+    // Safety: systemd hands these descriptors to this process, which
+    // doesn't otherwise use them.
+    let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    // A Unix socket has no IP address to report.
+    if tcp.local_addr().is_ok() {
+        tcp.set_nonblocking(true)?;
+        return Ok(Listener::Tcp(tokio::net::TcpListener::from_std(tcp)?));
+    }
+    let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+    unix.set_nonblocking(true)?;
+    Ok(Listener::Unix(tokio::net::UnixListener::from_std(unix)?))
+}
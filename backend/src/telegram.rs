@@ -0,0 +1,339 @@
+//! Telegram bot replying to text messages with voice notes. Messages are
+//! synthesized in a voice preset of one namespace, like `/api/tts` requests
+//! giving `text` and `voice`, and sent back as OGG/Opus. Needs a build with
+//! the `telegram` feature.
+
+use serde::Deserialize;
+
+/// Environment variable that overrides `telegram.token`.
+pub const TOKEN_ENV: &str = "TTSER_TELEGRAM_TOKEN";
+
+/// The `[telegram]` config section.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Telegram {
+    /// Bot token from @BotFather. Prefer setting `TTSER_TELEGRAM_TOKEN`.
+    pub token: String,
+    /// Namespace whose voice presets, history and usage the bot uses.
+    pub namespace: String,
+    /// Preset for chats that haven't picked one with `/voice`.
+    pub voice: String,
+    /// Chats the bot answers; empty answers everyone who finds it.
+    pub allowed_chats: Vec<i64>,
+    /// Longer messages are refused rather than synthesized.
+    pub max_chars: usize,
+    /// Messages answered at once, one per chat; the rest wait on Telegram.
+    pub concurrency: usize,
+}
+
+impl Default for Telegram {
+    fn default() -> Self {
+        Self {
+            token: String::new(),
+            namespace: crate::namespace::DEFAULT_NAMESPACE.to_string(),
+            voice: String::new(),
+            allowed_chats: Vec::new(),
+            max_chars: 1000,
+            concurrency: 2,
+        }
+    }
+}
+
+impl Telegram {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !cfg!(feature = "telegram") {
+            anyhow::bail!("the [telegram] section needs a build with `--features telegram`");
+        }
+        if self.token.is_empty() {
+            anyhow::bail!("telegram.token (or {TOKEN_ENV}) is not set");
+        }
+        if self.voice.is_empty() {
+            anyhow::bail!("telegram.voice must name a voice preset");
+        }
+        if self.max_chars == 0 {
+            anyhow::bail!("telegram.max_chars must be at least 1");
+        }
+        if self.concurrency == 0 {
+            anyhow::bail!("telegram.concurrency must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "telegram")]
+pub use bot::start;
+
+#[cfg(feature = "telegram")]
+mod bot {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use anyhow::{bail, Context};
+    use serde::Deserialize;
+    use tokio::sync::Semaphore;
+
+    use super::Telegram;
+    use crate::namespace::Namespace;
+    use crate::{audio, AppState};
+
+    /// How long one `getUpdates` call waits for messages.
+    const POLL_SECS: u64 = 50;
+    /// Pause after a failed poll, so an outage doesn't spin.
+    const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+    const HELP: &str =
+        "Send me text and I'll answer with a voice message. /voices lists the voices, /voice <name> picks one.";
+    const BUSY: &str = "Still working on your last message; send this one again once it's answered.";
+
+    #[derive(Deserialize)]
+    struct Reply<T> {
+        ok: bool,
+        #[serde(default)]
+        description: String,
+        result: Option<T>,
+    }
+
+    #[derive(Deserialize)]
+    struct Update {
+        update_id: i64,
+        message: Option<Message>,
+    }
+
+    #[derive(Deserialize)]
+    struct Message {
+        message_id: i64,
+        chat: Chat,
+        text: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct Chat {
+        id: i64,
+    }
+
+    struct Bot {
+        state: AppState,
+        config: Telegram,
+        namespace: Arc<Namespace>,
+        http: reqwest::Client,
+        /// Bot API base URL. It holds the token, so request errors drop
+        /// their URL (`without_url`) before they can reach the log.
+        api: String,
+        /// Presets picked with `/voice`, by chat. Forgotten on restart.
+        voices: Mutex<HashMap<i64, String>>,
+        /// One per message being answered, `concurrency` in all.
+        slots: Arc<Semaphore>,
+        /// Chats with a message being answered.
+        busy: Mutex<HashSet<i64>>,
+    }
+
+    /// Checks `config` against the namespaces and starts polling for
+    /// messages in the background.
+    pub fn start(state: &AppState, config: &Telegram) -> anyhow::Result<()> {
+        let namespace = state
+            .namespaces
+            .all()
+            .iter()
+            .find(|n| n.name == config.namespace)
+            .cloned()
+            .with_context(|| format!("telegram.namespace {:?} does not exist", config.namespace))?;
+        if !namespace.voices.contains(&config.voice) {
+            bail!("telegram.voice {:?} is not a preset of namespace {:?}", config.voice, namespace.name);
+        }
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(POLL_SECS + 30))
+            .build()?;
+        let bot = Arc::new(Bot {
+            state: state.clone(),
+            api: format!("https://api.telegram.org/bot{}", config.token),
+            config: config.clone(),
+            namespace,
+            http,
+            voices: Mutex::default(),
+            slots: Arc::new(Semaphore::new(config.concurrency)),
+            busy: Mutex::default(),
+        });
+        tokio::spawn(bot.poll());
+        Ok(())
+    }
+
+    impl Bot {
+        async fn poll(self: Arc<Self>) {
+            println!("telegram: answering messages in namespace {}", self.namespace.name);
+            let mut offset = 0;
+            loop {
+                let updates = match self.updates(offset).await {
+                    Ok(updates) => updates,
+                    Err(e) => {
+                        println!("telegram: polling failed: {e:#}");
+                        tokio::time::sleep(RETRY_DELAY).await;
+                        continue;
+                    }
+                };
+                for update in updates {
+                    offset = offset.max(update.update_id + 1);
+                    let Some(message) = update.message else {
+                        continue;
+                    };
+                    let chat = message.chat.id;
+                    if !self.config.allowed_chats.is_empty() && !self.config.allowed_chats.contains(&chat) {
+                        continue;
+                    }
+                    // One message per chat at a time, so no chat takes every slot.
+                    if !self.busy.lock().unwrap().insert(chat) {
+                        if let Err(e) = self.send_text(chat, BUSY).await {
+                            println!("telegram[{chat}]: replying failed: {e:#}");
+                        }
+                        continue;
+                    }
+                    // Generation takes a while; the next poll only waits for a
+                    // free slot, and Telegram holds later messages until then.
+                    let slot = self.slots.clone().acquire_owned().await.expect("telegram semaphore is never closed");
+                    let bot = self.clone();
+                    tokio::spawn(async move {
+                        bot.answer(message).await;
+                        bot.busy.lock().unwrap().remove(&chat);
+                        drop(slot);
+                    });
+                }
+            }
+        }
+
+        async fn updates(&self, offset: i64) -> anyhow::Result<Vec<Update>> {
+            let response = self
+                .http
+                .get(format!("{}/getUpdates", self.api))
+                .query(&[
+                    ("offset", offset.to_string()),
+                    ("timeout", POLL_SECS.to_string()),
+                    ("allowed_updates", r#"["message"]"#.to_string()),
+                ])
+                .send()
+                .await
+                .map_err(reqwest::Error::without_url)?;
+            unwrap(response).await
+        }
+
+        async fn answer(&self, message: Message) {
+            let chat = message.chat.id;
+            let Some(text) = message.text.as_deref().map(str::trim).filter(|t| !t.is_empty()) else {
+                return;
+            };
+            let (command, argument) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+            // Groups address commands as /voice@SomeBot.
+            let command = command.split('@').next().unwrap_or(command);
+            let outcome = match (command, argument) {
+                ("/start" | "/help", _) => self.send_text(chat, HELP).await,
+                ("/voices", _) => self.send_text(chat, &self.voice_list(chat)).await,
+                ("/voice", name) => {
+                    let reply = self.pick_voice(chat, name.trim());
+                    self.send_text(chat, &reply).await
+                }
+                _ if text.chars().count() > self.config.max_chars => {
+                    let reply = format!("That's too long; keep it under {} characters.", self.config.max_chars);
+                    self.send_text(chat, &reply).await
+                }
+                _ => self.speak(chat, message.message_id, text).await,
+            };
+            if let Err(e) = outcome {
+                println!("telegram[{chat}]: replying failed: {e:#}");
+            }
+        }
+
+        fn voice_of(&self, chat: i64) -> String {
+            let voices = self.voices.lock().unwrap();
+            voices.get(&chat).cloned().unwrap_or_else(|| self.config.voice.clone())
+        }
+
+        fn voice_list(&self, chat: i64) -> String {
+            let current = self.voice_of(chat);
+            let names: Vec<_> = self
+                .namespace
+                .voices
+                .current()
+                .into_keys()
+                .map(|name| if *name == current { format!("{name} (current)") } else { name.clone() })
+                .collect();
+            format!("Voices: {}", names.join(", "))
+        }
+
+        fn pick_voice(&self, chat: i64, name: &str) -> String {
+            if !self.namespace.voices.contains(name) {
+                return format!("There's no voice {name:?}. {}", self.voice_list(chat));
+            }
+            self.voices.lock().unwrap().insert(chat, name.to_string());
+            format!("Now speaking as {name}.")
+        }
+
+        async fn speak(&self, chat: i64, reply_to: i64, text: &str) -> anyhow::Result<()> {
+            let _ = self
+                .http
+                .post(format!("{}/sendChatAction", self.api))
+                .form(&[("chat_id", chat.to_string()), ("action", "record_voice".to_string())])
+                .send()
+                .await;
+
+            let voice = self.voice_of(chat);
+            let rendered = match crate::preset_job(&self.state, self.namespace.clone(), &voice, text).await {
+                Ok(job) => {
+                    let clip_id = job.clip_id.clone();
+                    job.run(None).await.map(|rendered| (clip_id, rendered.clip))
+                }
+                Err(e) => Err(e),
+            };
+            let (clip_id, clip) = match rendered {
+                Ok(rendered) => rendered,
+                Err(e) => {
+                    println!("telegram[{chat}]: synthesis failed: {e} ({})", e.code());
+                    return self.send_text(chat, "Sorry, that didn't work. Try again in a bit.").await;
+                }
+            };
+            let pcm = audio::read_wav(&clip.wav)?;
+            // OGG/Opus is the format Telegram shows as a voice message.
+            let note = audio::encode_ogg_opus(&audio::resample(&pcm.samples, pcm.sample_rate, audio::OPUS_RATE))?;
+
+            let file = reqwest::multipart::Part::bytes(note)
+                .file_name(format!("{clip_id}.ogg"))
+                .mime_str("audio/ogg")?;
+            let form = reqwest::multipart::Form::new()
+                .text("chat_id", chat.to_string())
+                .text("duration", (clip.duration_secs.ceil() as u64).to_string())
+                .text("reply_parameters", format!(r#"{{"message_id":{reply_to}}}"#))
+                .part("voice", file);
+            let response = self
+                .http
+                .post(format!("{}/sendVoice", self.api))
+                .multipart(form)
+                .send()
+                .await
+                .map_err(reqwest::Error::without_url)?;
+            unwrap::<serde_json::Value>(response).await?;
+            println!("telegram[{chat}]: sent clip {clip_id}");
+            Ok(())
+        }
+
+        async fn send_text(&self, chat: i64, text: &str) -> anyhow::Result<()> {
+            let response = self
+                .http
+                .post(format!("{}/sendMessage", self.api))
+                .form(&[("chat_id", chat.to_string()), ("text", text.to_string())])
+                .send()
+                .await
+                .map_err(reqwest::Error::without_url)?;
+            unwrap::<serde_json::Value>(response).await.map(|_| ())
+        }
+    }
+
+    /// The `result` of a Bot API reply, or its error description.
+    async fn unwrap<T: serde::de::DeserializeOwned>(response: reqwest::Response) -> anyhow::Result<T> {
+        let status = response.status();
+        let body = response.bytes().await.map_err(reqwest::Error::without_url)?;
+        let reply: Reply<T> =
+            serde_json::from_slice(&body).with_context(|| format!("unexpected Bot API reply ({status})"))?;
+        match reply.result {
+            Some(result) if reply.ok => Ok(result),
+            _ => bail!("Bot API error ({status}): {}", reply.description),
+        }
+    }
+}
@@ -0,0 +1,313 @@
+//! `POST /api/tts/template`: announcements built from a template such as
+//! "Train {number} departs from platform {platform}." and its values. The
+//! static text between the placeholders is rendered once per model version
+//! and voice and kept, so a request only has the model speak the values,
+//! and the parts are joined into one clip. For IVR menus and station
+//! announcements, whose wording hardly changes, that is most of the audio;
+//! values made of numbers, weekdays and months come from the [`fragments`]
+//! bank as well.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use axum::http::StatusCode;
+use serde::Deserialize;
+
+use crate::audio::{self, Pcm};
+use crate::error::SynthesisError;
+use crate::namespace::Namespace;
+use crate::{fragments, phrases, transcode, AppState, GeneratedClip};
+
+/// Static parts kept in memory, the least recently used dropped first.
+const MAX_CACHED_PARTS: usize = 512;
+
+/// Longest placeholder name.
+const MAX_NAME_CHARS: usize = 64;
+
+/// The request body.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateRequest {
+    /// Text with `{name}` placeholders; `{{` and `}}` are literal braces.
+    pub template: String,
+    /// A value for every placeholder.
+    #[serde(default)]
+    pub values: BTreeMap<String, String>,
+    /// Voice preset of the namespace; static parts are cached per voice.
+    pub voice: String,
+    /// Overlap of adjacent parts, smoothing the joins.
+    #[serde(default = "default_crossfade_secs")]
+    pub crossfade_secs: f64,
+    #[serde(default)]
+    pub format: String,
+    pub sample_rate: Option<u32>,
+}
+
+fn default_crossfade_secs() -> f64 {
+    0.02
+}
+
+/// A stretch of the filled-in template, rendered on its own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub text: String,
+    /// Static text, the same for every request with the template.
+    pub cached: bool,
+}
+
+/// `template` with `values` filled in, as the segments to render. Text
+/// without any letters or digits (the "." after a placeholder, the space
+/// between two) goes with the segment next to it, as the model can't speak
+/// it alone, and neighbouring values are rendered together.
+pub fn segments(template: &str, values: &BTreeMap<String, String>) -> Result<Vec<Segment>, String> {
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut used = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(format!("unclosed placeholder {{{name}")),
+                    }
+                }
+                let valid = !name.is_empty()
+                    && name.chars().count() <= MAX_NAME_CHARS
+                    && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+                if !valid {
+                    return Err(format!("invalid placeholder {{{name}}}"));
+                }
+                let value = values.get(&name).ok_or_else(|| format!("no value for {{{name}}}"))?;
+                push(&mut segments, std::mem::take(&mut text), true);
+                push(&mut segments, value.clone(), false);
+                used.push(name);
+            }
+            '}' => return Err("unmatched } (write }} for a literal one)".to_string()),
+            c => text.push(c),
+        }
+    }
+    push(&mut segments, text, true);
+    if let Some(name) = values.keys().find(|name| !used.contains(name)) {
+        return Err(format!("the template has no {{{name}}}"));
+    }
+    let segments: Vec<_> = segments
+        .into_iter()
+        .filter(|segment| spoken(&segment.text))
+        .map(|segment| Segment {
+            text: segment.text.trim().to_string(),
+            ..segment
+        })
+        .collect();
+    if segments.is_empty() {
+        return Err("there is nothing to speak".to_string());
+    }
+    Ok(segments)
+}
+
+fn spoken(text: &str) -> bool {
+    text.chars().any(char::is_alphanumeric)
+}
+
+/// Adds `text` to the end of `segments`.
+fn push(segments: &mut Vec<Segment>, mut text: String, cached: bool) {
+    match segments.last_mut() {
+        Some(last) if !spoken(&text) || (!cached && !last.cached) => last.text.push_str(&text),
+        // Leading punctuation goes with what follows it.
+        Some(last) if !spoken(&last.text) => {
+            last.text.push_str(&text);
+            last.cached = cached;
+        }
+        Some(last) => {
+            // And the comma after a value with the value.
+            let start = text.find(char::is_alphanumeric).unwrap_or_default();
+            last.text.extend(text.drain(..start));
+            segments.push(Segment { text, cached });
+        }
+        None => segments.push(Segment { text, cached }),
+    }
+}
+
+/// The rendered static parts, by [`phrases::fingerprint`] of the model
+/// version, description and text, most recently used last. Values made of
+/// bank fragments are kept in the [`fragments`] bank instead.
+#[derive(Default)]
+pub struct PartCache {
+    parts: Mutex<VecDeque<(String, Arc<Pcm>)>>,
+}
+
+impl PartCache {
+    fn get(&self, key: &str) -> Option<Arc<Pcm>> {
+        let mut parts = self.parts.lock().unwrap();
+        let at = parts.iter().position(|(k, _)| k == key)?;
+        let entry = parts.remove(at).unwrap();
+        let pcm = entry.1.clone();
+        parts.push_back(entry);
+        Some(pcm)
+    }
+
+    fn insert(&self, key: String, pcm: Arc<Pcm>) {
+        let mut parts = self.parts.lock().unwrap();
+        parts.retain(|(k, _)| *k != key);
+        parts.push_back((key, pcm));
+        while parts.len() > MAX_CACHED_PARTS {
+            parts.pop_front();
+        }
+    }
+}
+
+/// A rendered template: the clip, stored in the history, and how many of
+/// its parts came from the part cache or the fragment bank.
+pub struct Announcement {
+    pub clip_id: String,
+    pub pcm: Pcm,
+    pub cached: usize,
+    pub parts: usize,
+}
+
+/// Where a part of an announcement is kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Source {
+    /// Static text, in the [`PartCache`].
+    Part,
+    /// A value made of bank fragments, one part each.
+    Fragment,
+    /// A value rendered for this request only.
+    Model,
+}
+
+/// Renders the template's parts not kept already, joins them all and
+/// stores the result in `namespace`'s history.
+pub async fn render(
+    state: &AppState,
+    namespace: Arc<Namespace>,
+    request_id: u64,
+    request: &TemplateRequest,
+    segments: Vec<Segment>,
+) -> Result<Announcement, SynthesisError> {
+    let description = match namespace.voices.get(&request.voice) {
+        Some(current) => current.preset.description.clone(),
+        None => return Err(StatusCode::BAD_REQUEST.into()),
+    };
+    let pool = state.pool().await.map_err(SynthesisError::ModelLoad)?;
+    let version = pool.engine().version().to_string();
+    let mut pieces: Vec<(Source, String, String)> = Vec::new();
+    for segment in &segments {
+        let texts = match (segment.cached, fragments::split(&segment.text)) {
+            (true, _) => vec![(Source::Part, segment.text.clone())],
+            (false, Some(words)) => words.into_iter().map(|word| (Source::Fragment, word)).collect(),
+            (false, None) => vec![(Source::Model, segment.text.clone())],
+        };
+        for (source, text) in texts {
+            let key = phrases::fingerprint(&version, &description, &text);
+            pieces.push((source, text, key));
+        }
+    }
+    let lookups: Vec<(Source, String)> = pieces.iter().map(|(source, _, key)| (*source, key.clone())).collect();
+    let (cache, bank) = (state.template_parts.clone(), state.fragments.clone());
+    let found = tokio::task::spawn_blocking(move || {
+        lookups
+            .iter()
+            .map(|(source, key)| match source {
+                Source::Part => Ok(cache.get(key)),
+                Source::Fragment => bank.get(key),
+                Source::Model => Ok(None),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+    })
+    .await
+    .map_err(|e| SynthesisError::Io(e.into()))?
+    .map_err(SynthesisError::Io)?;
+    let cached = found.iter().flatten().count();
+    println!("tts[{request_id}]: template of {} parts, {cached} cached", pieces.len());
+
+    // The rest renders at once, sharing the workers.
+    let renders = pieces.iter().zip(&found).filter(|(_, pcm)| pcm.is_none()).map(|((_, text, _), _)| {
+        render_part(state, namespace.clone(), &request.voice, text)
+    });
+    let mut rendered = futures::future::try_join_all(renders).await?.into_iter();
+    let mut parts = Vec::with_capacity(pieces.len());
+    for ((source, text, key), pcm) in pieces.into_iter().zip(found) {
+        if let Some(pcm) = pcm {
+            parts.push(pcm);
+            continue;
+        }
+        let (wav, pcm) = rendered.next().expect("a rendering for every part not kept");
+        match source {
+            Source::Part => state.template_parts.insert(key, pcm.clone()),
+            Source::Fragment => {
+                let (bank, description, pcm) = (state.fragments.clone(), description.clone(), pcm.clone());
+                tokio::task::spawn_blocking(move || {
+                    if let Err(e) = bank.insert(&key, &description, &text, &wav, pcm) {
+                        println!("fragments: storing {text:?} failed: {e:#}");
+                    }
+                });
+            }
+            Source::Model => {}
+        }
+        parts.push(pcm);
+    }
+
+    let sample_rate = parts[0].sample_rate;
+    let crossfade_secs = request.crossfade_secs;
+    let parts_count = parts.len();
+    let pcms: Vec<Pcm> = parts.iter().map(|pcm| (**pcm).clone()).collect();
+    let gaps = vec![0.0; pcms.len()];
+    let wav_format = state.config.wav_format;
+    let (samples, wav) = tokio::task::spawn_blocking(move || {
+        let samples = transcode::concat(&pcms, &gaps, crossfade_secs, sample_rate);
+        let wav = audio::write_wav(&samples, sample_rate, wav_format);
+        (samples, wav)
+    })
+    .await
+    .map_err(|e| SynthesisError::Encode(e.into()))?;
+
+    // Stored like a cached quick phrase: the audio is there already.
+    let text = segments.iter().map(|segment| segment.text.as_str()).collect::<Vec<_>>().join(" ");
+    let mut job = crate::preset_job(state, namespace, &request.voice, &text).await?;
+    job.quick_phrase = Some(Arc::new(phrases::QuickPhrase {
+        duration_secs: samples.len() as f64 / sample_rate as f64,
+        wav,
+        sample_rate,
+        steps: 0,
+        finish: crate::generation::FinishReason::Eos,
+    }));
+    job.pool = None;
+    job.is_phrase = false;
+    let clip_id = job.clip_id.clone();
+    job.run(None).await?;
+    Ok(Announcement {
+        clip_id,
+        pcm: Pcm { samples, sample_rate },
+        cached,
+        parts: parts_count,
+    })
+}
+
+/// `text` rendered on its own in the preset `voice`, as WAV and decoded.
+pub async fn render_part(
+    state: &AppState,
+    namespace: Arc<Namespace>,
+    voice: &str,
+    text: &str,
+) -> Result<(Vec<u8>, Arc<Pcm>), SynthesisError> {
+    let job = crate::preset_job(state, namespace, voice, text).await?;
+    let clip = match (&job.quick_phrase, &job.pool) {
+        (Some(phrase), _) => GeneratedClip::from_phrase(phrase),
+        (None, Some(pool)) => crate::create_wav_file(pool, &job.args, job.chunk_chars, None).await?,
+        (None, None) => unreachable!("jobs without a cached clip get a pool"),
+    };
+    let pcm = audio::read_wav(&clip.wav).map_err(SynthesisError::Decode)?;
+    Ok((clip.wav, Arc::new(pcm)))
+}
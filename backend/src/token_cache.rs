@@ -0,0 +1,90 @@
+//! Token ids of recently tokenized texts. Sweeps, batches and quality
+//! retries send the same description (and often the same prompt) again and
+//! again; the least recently used entries go once the cache is full.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+pub struct TokenCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Token ids and when they were last used, by text.
+    entries: HashMap<String, (Arc<[u32]>, u64)>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+/// What `/api/stats` reports under `tokenizer_cache`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Hits out of all lookups; 0 before the first one.
+    pub hit_rate: f64,
+}
+
+impl TokenCache {
+    /// Keeps up to `capacity` texts; 0 turns caching off.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::default(),
+        }
+    }
+
+    /// The ids of `text`, from the cache or from `tokenize`.
+    pub fn get_or_insert<E>(&self, text: &str, tokenize: impl FnOnce() -> Result<Vec<u32>, E>) -> Result<Arc<[u32]>, E> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.clock += 1;
+            let now = inner.clock;
+            if let Some((ids, used)) = inner.entries.get_mut(text) {
+                *used = now;
+                let ids = ids.clone();
+                inner.hits += 1;
+                return Ok(ids);
+            }
+            inner.misses += 1;
+        }
+        // Tokenized outside the lock, so lookups don't queue behind it.
+        let ids: Arc<[u32]> = tokenize()?.into();
+        if self.capacity == 0 {
+            return Ok(ids);
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries.len() >= self.capacity && !inner.entries.contains_key(text) {
+            let oldest = inner.entries.iter().min_by_key(|(_, (_, used))| *used).map(|(text, _)| text.clone());
+            if let Some(oldest) = oldest {
+                inner.entries.remove(&oldest);
+                inner.evictions += 1;
+            }
+        }
+        let now = inner.clock;
+        inner.entries.insert(text.to_string(), (ids.clone(), now));
+        Ok(ids)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap();
+        let lookups = inner.hits + inner.misses;
+        CacheStats {
+            entries: inner.entries.len(),
+            capacity: self.capacity,
+            hits: inner.hits,
+            misses: inner.misses,
+            evictions: inner.evictions,
+            hit_rate: if lookups == 0 { 0.0 } else { inner.hits as f64 / lookups as f64 },
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Stored clips re-encoded on request, for `/api/transcode` and
+//! `/api/audio/concat`: the history keeps every clip as a WAV at the
+//! model's rate, and this turns one, or several joined together, into any
+//! format and rate the server can write, without generating them again.
+
+use anyhow::Result;
+
+use crate::audio::{self, OutputFormat};
+use crate::m4b;
+
+/// Sample rates the AAC encoder takes.
+const AAC_RATES: &[u32] = &[8000, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// One of the formats `/api/tts` answers in.
+    Clip(OutputFormat),
+    /// AAC in an MP4 container.
+    M4a,
+    /// Opus in an OGG container (builds with the `opus` feature).
+    #[cfg(feature = "opus")]
+    OggOpus,
+}
+
+impl Target {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "m4a" | "aac" => Some(Self::M4a),
+            #[cfg(feature = "opus")]
+            "opus" | "ogg" => Some(Self::OggOpus),
+            other => OutputFormat::parse(other).map(Self::Clip),
+        }
+    }
+
+    /// The only sample rate the format allows, if it is fixed.
+    pub fn fixed_rate(self) -> Option<u32> {
+        match self {
+            Self::Clip(format) => format.fixed_rate(),
+            Self::M4a => None,
+            #[cfg(feature = "opus")]
+            Self::OggOpus => Some(audio::OPUS_RATE),
+        }
+    }
+
+    pub fn supports_rate(self, sample_rate: u32) -> bool {
+        match self {
+            Self::M4a => AAC_RATES.contains(&sample_rate),
+            _ => self.fixed_rate().is_none_or(|rate| rate == sample_rate),
+        }
+    }
+
+    pub fn content_type(self, sample_rate: u32) -> String {
+        match self {
+            Self::Clip(format) => format.content_type(sample_rate),
+            Self::M4a => "audio/mp4".to_string(),
+            #[cfg(feature = "opus")]
+            Self::OggOpus => "audio/ogg; codecs=opus".to_string(),
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Clip(format) => format.extension(),
+            Self::M4a => "m4a",
+            #[cfg(feature = "opus")]
+            Self::OggOpus => "opus",
+        }
+    }
+
+    /// `pcm` as this format at `sample_rate`. `title` names the clip in
+    /// formats with metadata.
+    pub fn encode(self, pcm: &audio::Pcm, sample_rate: u32, title: &str) -> Result<Vec<u8>> {
+        let samples = audio::resample(&pcm.samples, pcm.sample_rate, sample_rate);
+        match self {
+            Self::Clip(format) => Ok(format.encode_clip(&samples, sample_rate)),
+            Self::M4a => {
+                let metadata = m4b::Metadata {
+                    title: title.to_string(),
+                    artist: None,
+                };
+                m4b::encode(&samples, sample_rate, &[], &metadata)
+            }
+            #[cfg(feature = "opus")]
+            Self::OggOpus => audio::encode_ogg_opus(&samples),
+        }
+    }
+}
+
+/// `clips` one after another at `sample_rate`, with `gaps_secs[k]` of
+/// silence between clip `k` and the next. Where a gap is zero, the two
+/// clips overlap by `crossfade_secs` with an equal-power fade instead.
+pub fn concat(clips: &[audio::Pcm], gaps_secs: &[f64], crossfade_secs: f64, sample_rate: u32) -> Vec<f32> {
+    let mut joined: Vec<f32> = Vec::new();
+    for (k, clip) in clips.iter().enumerate() {
+        let samples = audio::resample(&clip.samples, clip.sample_rate, sample_rate);
+        let gap = if k == 0 { 0.0 } else { gaps_secs.get(k - 1).copied().unwrap_or(0.0) };
+        if gap > 0.0 {
+            joined.extend(std::iter::repeat_n(0.0, (gap * sample_rate as f64) as usize));
+        }
+        let overlap = if k == 0 || gap > 0.0 {
+            0
+        } else {
+            ((crossfade_secs * sample_rate as f64) as usize).min(joined.len()).min(samples.len())
+        };
+        let start = joined.len() - overlap;
+        for (n, sample) in samples[..overlap].iter().enumerate() {
+            let t = (n as f32 + 0.5) / overlap as f32 * std::f32::consts::FRAC_PI_2;
+            joined[start + n] = joined[start + n] * t.cos() + sample * t.sin();
+        }
+        joined.extend_from_slice(&samples[overlap..]);
+    }
+    joined
+}
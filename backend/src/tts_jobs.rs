@@ -0,0 +1,527 @@
+//! `POST /api/tts/jobs`: `/api/tts` requests rendered in the background,
+//! for generations that outlast a proxy's timeout. A submission is checked
+//! like an `/api/tts` request, then waits in a bounded queue for one of the
+//! `[queue] workers`; `/api/tts/jobs/{id}` reports where it is, and
+//! `/api/tts/jobs/{id}/audio` serves the clip once it is done. A full queue
+//! turns new jobs away with `503` instead of growing without bound.
+//! `/api/tts/jobs/{id}/events` streams the render's generation steps and
+//! phases as server-sent events, for a progress bar.
+//!
+//! Jobs live in memory and are forgotten `retention_secs` after they finish
+//! (or on restart); their audio stays in the history.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::http::StatusCode;
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, watch};
+
+use crate::audio::{self, OutputFormat};
+use crate::audiobook::JobError;
+use crate::error::SynthesisError;
+use crate::namespace::Namespace;
+use crate::tts_model::{Phase, ProgressHook};
+use crate::{CreateWavArgs, TtsJob};
+
+/// Progress events go out at most this often; phase changes in between
+/// wait for the next one.
+const EVENT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The `[queue]` config section.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Queue {
+    /// Jobs waiting for a worker; further submissions get a `503`.
+    pub capacity: usize,
+    /// Jobs rendered at once. They share the model's generation workers
+    /// with `/api/tts`, so more of them only help when those sit idle.
+    pub workers: usize,
+    /// Finished jobs are forgotten after this long.
+    pub retention_secs: u64,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Self {
+            capacity: 64,
+            workers: 2,
+            retention_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+impl Queue {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.capacity == 0 || self.workers == 0 {
+            anyhow::bail!("queue.capacity and queue.workers must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Progress {
+    /// Chunks rendered so far, out of `total`.
+    pub done: usize,
+    pub total: usize,
+}
+
+/// What `/api/tts/jobs/{id}` reports.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub id: String,
+    pub state: JobState,
+    /// Jobs ahead of this one, while it is queued.
+    pub position: Option<usize>,
+    pub progress: Progress,
+    /// History id the clip is stored under once the job is done.
+    pub clip_id: String,
+    pub duration_secs: Option<f64>,
+    pub error: Option<JobError>,
+    pub created_at: u64,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+}
+
+struct Job {
+    namespace: String,
+    /// Submission order, for queue positions.
+    seq: u64,
+    status: Mutex<JobStatus>,
+    /// Fed by the render.
+    tracker: Arc<Tracker>,
+    /// What the audio is served as.
+    format: OutputFormat,
+    sample_rate: Option<u32>,
+}
+
+impl Job {
+    fn update(&self, change: impl FnOnce(&mut JobStatus)) {
+        change(&mut self.status.lock().unwrap());
+        self.tracker.changed();
+    }
+}
+
+/// What the render of a job has got to, chunk by chunk.
+#[derive(Debug)]
+pub struct Tracker {
+    inner: Mutex<Tracked>,
+    /// Bumped on every change, waking the event streams.
+    changes: watch::Sender<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Tracked {
+    /// When the first chunk started.
+    started: Option<Instant>,
+    chunks: Vec<ChunkProgress>,
+    phases: Vec<PhaseChange>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ChunkProgress {
+    step: usize,
+    /// 0 until the chunk starts generating.
+    max_steps: usize,
+    done: bool,
+}
+
+/// A `phase` event.
+#[derive(Debug, Clone, Serialize)]
+pub struct PhaseChange {
+    pub phase: Phase,
+    /// Counted from 1, for prompts split into chunks.
+    pub chunk: Option<usize>,
+    /// Since the render started.
+    pub at_secs: f64,
+}
+
+/// A `progress` event.
+#[derive(Debug, Clone, Serialize)]
+pub struct StepProgress {
+    pub state: JobState,
+    /// The latest phase of any chunk.
+    pub phase: Option<Phase>,
+    /// Generation steps of the chunks started so far, out of their
+    /// `max_steps` limits.
+    pub step: usize,
+    pub max_steps: usize,
+    pub chunks: Progress,
+    pub elapsed_secs: f64,
+    /// At the pace so far, with the step limits of the chunks still going
+    /// scaled by how much of theirs the finished ones used.
+    pub eta_secs: Option<f64>,
+}
+
+impl Tracker {
+    fn new(chunks: usize) -> Self {
+        Self {
+            inner: Mutex::new(Tracked {
+                chunks: vec![ChunkProgress::default(); chunks],
+                ..Tracked::default()
+            }),
+            changes: watch::Sender::new(0),
+        }
+    }
+
+    /// The hook for chunk `chunk` (from 0) of the prompt.
+    pub fn chunk(self: &Arc<Self>, chunk: usize) -> Arc<dyn ProgressHook> {
+        Arc::new(ChunkHook {
+            tracker: self.clone(),
+            chunk,
+        })
+    }
+
+    /// Marks chunk `chunk` rendered.
+    pub fn chunk_done(&self, chunk: usize) {
+        self.record(|tracked| {
+            if let Some(progress) = tracked.chunks.get_mut(chunk) {
+                progress.done = true;
+            }
+        });
+    }
+
+    /// A phase of the whole render rather than of one chunk.
+    pub fn phase(&self, phase: Phase) {
+        self.record(|tracked| tracked.push_phase(phase, None));
+    }
+
+    fn record(&self, change: impl FnOnce(&mut Tracked)) {
+        {
+            let mut tracked = self.inner.lock().unwrap();
+            tracked.started.get_or_insert_with(Instant::now);
+            change(&mut tracked);
+        }
+        self.changed();
+    }
+
+    fn changed(&self) {
+        self.changes.send_modify(|n| *n += 1);
+    }
+
+    fn done(&self) -> usize {
+        self.inner.lock().unwrap().chunks.iter().filter(|chunk| chunk.done).count()
+    }
+
+    fn phases_since(&self, seen: usize) -> Vec<PhaseChange> {
+        self.inner.lock().unwrap().phases.get(seen..).unwrap_or_default().to_vec()
+    }
+
+    fn progress(&self, state: JobState) -> StepProgress {
+        let tracked = self.inner.lock().unwrap();
+        let chunks = &tracked.chunks;
+        let elapsed_secs = tracked.started.map_or(0.0, |started| started.elapsed().as_secs_f64());
+        let step: usize = chunks.iter().map(|chunk| chunk.step).sum();
+        let max_steps: usize = chunks.iter().map(|chunk| chunk.max_steps).sum();
+
+        let finished = chunks.iter().filter(|chunk| chunk.done && chunk.max_steps > 0);
+        let (used, allowed) = finished.fold((0, 0), |(used, allowed), chunk| (used + chunk.step, allowed + chunk.max_steps));
+        let share = if allowed > 0 { used as f64 / allowed as f64 } else { 1.0 };
+        let started = chunks.iter().filter(|chunk| chunk.max_steps > 0).count();
+        let typical_max = if started > 0 { max_steps as f64 / started as f64 } else { 0.0 };
+        let steps_left: f64 = chunks
+            .iter()
+            .filter(|chunk| !chunk.done)
+            .map(|chunk| {
+                let max = if chunk.max_steps > 0 { chunk.max_steps as f64 } else { typical_max };
+                (max * share - chunk.step as f64).max(0.0)
+            })
+            .sum();
+        let eta_secs = match state {
+            JobState::Running if step > 0 => Some(steps_left * elapsed_secs / step as f64),
+            JobState::Done | JobState::Failed => Some(0.0),
+            _ => None,
+        };
+        StepProgress {
+            state,
+            phase: tracked.phases.last().map(|change| change.phase),
+            step,
+            max_steps,
+            chunks: Progress {
+                done: chunks.iter().filter(|chunk| chunk.done).count(),
+                total: chunks.len(),
+            },
+            elapsed_secs,
+            eta_secs,
+        }
+    }
+}
+
+impl Tracked {
+    fn push_phase(&mut self, phase: Phase, chunk: Option<usize>) {
+        let at_secs = self.started.map_or(0.0, |started| started.elapsed().as_secs_f64());
+        self.phases.push(PhaseChange { phase, chunk, at_secs });
+    }
+}
+
+/// One chunk's [`ProgressHook`].
+#[derive(Debug)]
+struct ChunkHook {
+    tracker: Arc<Tracker>,
+    chunk: usize,
+}
+
+impl ProgressHook for ChunkHook {
+    fn phase(&self, phase: Phase) {
+        let chunk = self.chunk;
+        self.tracker.record(|tracked| {
+            // Only numbered when there is more than one.
+            let numbered = (tracked.chunks.len() > 1).then_some(chunk + 1);
+            tracked.push_phase(phase, numbered);
+        });
+    }
+
+    fn step(&self, step: usize, max_steps: usize) {
+        self.tracker.record(|tracked| {
+            if let Some(progress) = tracked.chunks.get_mut(self.chunk) {
+                progress.step = step;
+                progress.max_steps = max_steps;
+            }
+        });
+    }
+}
+
+/// The TTS jobs submitted since startup, by id, and the queue feeding the
+/// workers.
+pub struct TtsJobs {
+    jobs: Mutex<HashMap<String, Arc<Job>>>,
+    queue: mpsc::Sender<(Arc<Job>, TtsJob)>,
+    next_seq: AtomicU64,
+    retention_secs: u64,
+}
+
+impl TtsJobs {
+    /// Starts the workers.
+    pub fn new(config: &Queue) -> Self {
+        let (queue, receiver) = mpsc::channel::<(Arc<Job>, TtsJob)>(config.capacity);
+        let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+        for _ in 0..config.workers {
+            let receiver = receiver.clone();
+            tokio::spawn(async move {
+                loop {
+                    // The lock is only held while waiting, not while rendering.
+                    let next = receiver.lock().await.recv().await;
+                    let Some((job, tts_job)) = next else {
+                        break;
+                    };
+                    work(&job, tts_job).await;
+                }
+            });
+        }
+        Self {
+            jobs: Mutex::default(),
+            queue,
+            next_seq: AtomicU64::new(0),
+            retention_secs: config.retention_secs,
+        }
+    }
+
+    /// Queues `tts_job`, to be served as `format` at `sample_rate` (the
+    /// model's own when None). `chunks` is how many the prompt splits into.
+    /// Returns its status as queued, or `503` when the queue is full.
+    pub fn submit(
+        &self,
+        mut tts_job: TtsJob,
+        format: OutputFormat,
+        sample_rate: Option<u32>,
+        chunks: usize,
+    ) -> Result<JobStatus, SynthesisError> {
+        let now = crate::unix_now();
+        let request_id = tts_job.args.request_id;
+        let tracker = Arc::new(Tracker::new(chunks));
+        tts_job.args = Arc::new(CreateWavArgs {
+            progress: Some(tracker.clone()),
+            ..(*tts_job.args).clone()
+        });
+        let status = JobStatus {
+            id: format!("tts_{now}_{request_id}"),
+            state: JobState::Queued,
+            position: None,
+            progress: Progress { done: 0, total: chunks },
+            clip_id: tts_job.clip_id.clone(),
+            duration_secs: None,
+            error: None,
+            created_at: now,
+            started_at: None,
+            finished_at: None,
+        };
+        let job = Arc::new(Job {
+            namespace: tts_job.namespace.name.clone(),
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+            status: Mutex::new(status.clone()),
+            tracker,
+            format,
+            sample_rate,
+        });
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, job| {
+            let finished = job.status.lock().unwrap().finished_at;
+            finished.is_none_or(|at| at + self.retention_secs > now)
+        });
+        if self.queue.try_send((job.clone(), tts_job)).is_err() {
+            println!("tts[{request_id}]: job queue full, turning the job away");
+            return Err(StatusCode::SERVICE_UNAVAILABLE.into());
+        }
+        jobs.insert(status.id.clone(), job.clone());
+        println!("tts[{request_id}]: queued as job {} in namespace {}", status.id, job.namespace);
+        Ok(self.report(&jobs, &job))
+    }
+
+    /// The job's status, when it belongs to `namespace`.
+    pub fn status(&self, namespace: &str, id: &str) -> Option<JobStatus> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(id).filter(|job| job.namespace == namespace)?;
+        Some(self.report(&jobs, job))
+    }
+
+    /// The job's progress as server-sent events, when it belongs to
+    /// `namespace`: a `phase` event for each phase a chunk enters,
+    /// `progress` events as it goes, and `done` (or `error`) once it
+    /// finishes, which ends the stream.
+    pub fn events(&self, namespace: &str, id: &str) -> Option<impl Stream<Item = Result<Vec<u8>, Infallible>> + use<>> {
+        let job = self.jobs.lock().unwrap().get(id).filter(|job| job.namespace == namespace)?.clone();
+        let changes = job.tracker.changes.subscribe();
+        let events = Events {
+            job,
+            changes,
+            phases_seen: 0,
+            first: true,
+            finished: false,
+        };
+        Some(futures::stream::unfold(events, |mut events| async move {
+            if events.finished {
+                return None;
+            }
+            if !std::mem::take(&mut events.first) {
+                // The job's own sender lives as long as the job.
+                let _ = events.changes.changed().await;
+                tokio::time::sleep(EVENT_INTERVAL).await;
+            }
+            events.changes.borrow_and_update();
+            let batch = events.next_batch();
+            Some((Ok(batch), events))
+        }))
+    }
+
+    /// A finished job's audio and its content type: `404` for jobs not
+    /// known in `namespace`, `409` for ones not done.
+    pub fn audio(&self, namespace: &Namespace, id: &str) -> Result<(Vec<u8>, String), SynthesisError> {
+        let job = {
+            let jobs = self.jobs.lock().unwrap();
+            let job = jobs.get(id).filter(|job| job.namespace == namespace.name);
+            job.cloned().ok_or(StatusCode::NOT_FOUND)?
+        };
+        let status = job.status.lock().unwrap().clone();
+        if status.state != JobState::Done {
+            return Err(StatusCode::CONFLICT.into());
+        }
+        // The clip may have expired from the history since.
+        let wav = namespace.history.audio(&status.clip_id).map_err(SynthesisError::Io)?;
+        let wav = wav.ok_or(StatusCode::NOT_FOUND)?;
+        let pcm = audio::read_wav(&wav).map_err(SynthesisError::Encode)?;
+        let rate = job.sample_rate.unwrap_or(pcm.sample_rate);
+        let content_type = job.format.content_type(rate);
+        if job.format == OutputFormat::Wav && rate == pcm.sample_rate {
+            return Ok((wav, content_type));
+        }
+        let samples = audio::resample(&pcm.samples, pcm.sample_rate, rate);
+        Ok((job.format.encode_clip(&samples, rate), content_type))
+    }
+
+    fn report(&self, jobs: &HashMap<String, Arc<Job>>, job: &Job) -> JobStatus {
+        let mut status = job.status.lock().unwrap().clone();
+        status.progress.done = job.tracker.done();
+        if status.state == JobState::Queued {
+            let ahead = jobs
+                .values()
+                .filter(|other| other.seq < job.seq)
+                .filter(|other| other.status.lock().unwrap().state == JobState::Queued)
+                .count();
+            status.position = Some(ahead);
+        }
+        status
+    }
+}
+
+/// A client's `/api/tts/jobs/{id}/events` stream.
+struct Events {
+    job: Arc<Job>,
+    changes: watch::Receiver<u64>,
+    phases_seen: usize,
+    /// Nothing has gone out yet.
+    first: bool,
+    finished: bool,
+}
+
+impl Events {
+    /// The events since the last batch, and the current progress.
+    fn next_batch(&mut self) -> Vec<u8> {
+        let status = self.job.status.lock().unwrap().clone();
+        let tracker = &self.job.tracker;
+        let mut batch = String::new();
+        let mut event = |name: &str, data: serde_json::Value| {
+            batch.push_str(&format!("event: {name}\ndata: {data}\n\n"));
+        };
+        let phases = tracker.phases_since(self.phases_seen);
+        self.phases_seen += phases.len();
+        for change in &phases {
+            event("phase", serde_json::json!(change));
+        }
+        event("progress", serde_json::json!(tracker.progress(status.state)));
+        match status.state {
+            JobState::Done => {
+                event("done", serde_json::json!({"clip_id": status.clip_id, "duration_secs": status.duration_secs}));
+                self.finished = true;
+            }
+            JobState::Failed => {
+                event("error", serde_json::json!(status.error));
+                self.finished = true;
+            }
+            JobState::Queued | JobState::Running => {}
+        }
+        batch.into_bytes()
+    }
+}
+
+/// Renders one job, recording how it went.
+async fn work(job: &Job, tts_job: TtsJob) {
+    let id = job.status.lock().unwrap().id.clone();
+    let request_id = tts_job.args.request_id;
+    println!("tts[{request_id}]: job {id} started");
+    job.update(|status| {
+        status.state = JobState::Running;
+        status.started_at = Some(crate::unix_now());
+    });
+    let outcome = tts_job.run(None).await;
+    match &outcome {
+        Ok(_) => println!("tts[{request_id}]: job {id} done"),
+        Err(e) => println!("tts[{request_id}]: job {id} failed: {e} ({})", e.code()),
+    }
+    job.update(|status| {
+        status.finished_at = Some(crate::unix_now());
+        match outcome {
+            Ok(rendered) => {
+                status.state = JobState::Done;
+                status.duration_secs = Some(rendered.clip.duration_secs);
+            }
+            Err(e) => {
+                status.state = JobState::Failed;
+                status.error = Some(e.into());
+            }
+        }
+    });
+}
@@ -0,0 +1,163 @@
+//! What the HTTP API needs from a text-to-speech model. Parler-TTS
+//! ([`ParlerEngine`]) is the only implementation today; another candle
+//! model implements [`TtsModel`] and is picked in [`load`], and the routes,
+//! voice presets, chunking and post-processing work with it unchanged.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use candle::Tensor;
+use serde::Serialize;
+
+use crate::budget::StageTimings;
+use crate::config::ServerConfig;
+use crate::engine::ParlerEngine;
+use crate::error::SynthesisError;
+use crate::generation::{FinishReason, Stopping, TokenControls};
+use crate::hub::ModelFiles;
+use crate::sampler::SamplerKind;
+use crate::token_cache::CacheStats;
+
+/// A loaded model, shared by every worker of a pool.
+pub trait TtsModel: Send + Sync {
+    fn load(server_config: &ServerConfig, files: &ModelFiles) -> anyhow::Result<Self>
+    where
+        Self: Sized;
+
+    fn capabilities(&self) -> Capabilities;
+
+    /// Output sample rate, in Hz.
+    fn sample_rate(&self) -> u32 {
+        self.capabilities().sample_rate
+    }
+
+    /// Changes whenever the same request could sound different: on a model
+    /// upgrade or a dtype change.
+    fn version(&self) -> &str;
+
+    /// CUDA ordinals the model occupies; empty on other devices.
+    fn gpus(&self) -> &[usize] {
+        &[]
+    }
+
+    /// Where the model runs, e.g. `cpu` or `cuda:1`.
+    fn device(&self) -> &str {
+        "cpu"
+    }
+
+    /// Generates mono PCM for `prompt` spoken in the voice of `description`,
+    /// handing it to `on_pcm` as it is decoded: piece by piece for models
+    /// with [`Capabilities::incremental_stream`], otherwise whole, once.
+    fn stream(
+        &self,
+        prompt: &str,
+        description: &str,
+        sampling: &Sampling,
+        on_pcm: &mut dyn FnMut(&Tensor),
+    ) -> Result<Synthesis, SynthesisError>;
+
+    fn synthesize(&self, prompt: &str, description: &str, sampling: &Sampling) -> Result<Synthesis, SynthesisError> {
+        self.stream(prompt, description, sampling, &mut |_| {})
+    }
+
+    /// The characters of `text`, whitespace aside, the model can't read, in
+    /// order.
+    fn unsupported(&self, _text: &str) -> anyhow::Result<Vec<UnsupportedChar>> {
+        Ok(Vec::new())
+    }
+
+    /// For models that cache tokenized prompts.
+    fn token_cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+}
+
+/// What a model takes and produces, for validating requests and for
+/// clients through `/api/info`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    /// Which implementation, e.g. `parler-tts`.
+    pub name: &'static str,
+    pub sample_rate: u32,
+    /// Audio token ids per codebook, for models sampling discrete codes;
+    /// `banned_tokens` and `forced_tokens` are refused without one.
+    pub audio_vocab_size: Option<usize>,
+    /// The voice is a free-text description (which voice presets fill in)
+    /// rather than a fixed speaker.
+    pub voice_descriptions: bool,
+    /// [`TtsModel::stream`] hands out audio before the generation ends.
+    pub incremental_stream: bool,
+}
+
+/// Loads the model `files` hold.
+pub fn load(server_config: &ServerConfig, files: &ModelFiles) -> anyhow::Result<Arc<dyn TtsModel>> {
+    Ok(Arc::new(ParlerEngine::load(server_config, files)?))
+}
+
+/// Sampling settings for one generation.
+#[derive(Debug, Clone)]
+pub struct Sampling {
+    pub temperature: f64,
+    pub seed: u64,
+    pub top_p: Option<f64>,
+    pub stopping: Stopping,
+    pub tokens: TokenControls,
+    pub sampler: SamplerKind,
+    /// When streaming, get the first piece out by then, decoding a shorter
+    /// window than usual if need be.
+    pub first_audio_by: Option<Instant>,
+    /// Told how the generation is getting on, for queued jobs.
+    pub progress: Option<Arc<dyn ProgressHook>>,
+}
+
+/// Where a generation is at, as told to a [`ProgressHook`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Tokenizing,
+    Generating,
+    Decoding,
+    /// Writing the WAV, after the model is done with every chunk.
+    Encoding,
+}
+
+/// Called from the worker running a generation, so it has to be quick.
+pub trait ProgressHook: Send + Sync + std::fmt::Debug {
+    fn phase(&self, phase: Phase);
+    /// After each generation step; `max_steps` is the limit for the prompt.
+    fn step(&self, step: usize, max_steps: usize);
+}
+
+/// Output of one generation.
+pub struct Synthesis {
+    /// Mono PCM, F32.
+    pub pcm: Tensor,
+    /// The audio codes it was decoded from, `(num_codebooks, frames)`, on
+    /// the CPU.
+    pub codes: Tensor,
+    pub steps: usize,
+    /// The step limit it ran under.
+    pub max_steps: usize,
+    pub finish: FinishReason,
+    /// Tokenize, generate and decode times; encoding happens later.
+    pub timings: StageTimings,
+}
+
+/// Why a character of a prompt doesn't reach the model as written.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Unsupported {
+    /// Part of a token the vocabulary doesn't have; read as `<unk>`.
+    Unknown,
+    /// Removed by the tokenizer's normalizer.
+    Dropped,
+}
+
+/// A character of a prompt the model can't read.
+#[derive(Debug, Clone, Serialize)]
+pub struct UnsupportedChar {
+    /// Index in the prompt, in Unicode code points.
+    pub position: usize,
+    pub character: char,
+    pub reason: Unsupported,
+}
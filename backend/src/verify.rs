@@ -0,0 +1,306 @@
+//! `ttser-backend verify`: renders a pinned set of prompts with fixed seeds
+//! and checks them against golden renders recorded earlier, so drift after
+//! a candle, model or config upgrade shows up before users hear it. Run
+//! with `--update` once on a known-good build to record the golden set.
+//!
+//! Each golden render is a manifest entry (the codes' digest and the audio
+//! statistics) plus its WAV, for the spectral and speaker comparisons.
+
+use std::path::Path;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::analysis::{self, ClipLevels};
+use crate::audio;
+use crate::config::{ServerConfig, VerifyArgs};
+use crate::generation::{FinishReason, Stopping, TokenControls};
+use crate::hub;
+use crate::sampler::SamplerKind;
+use crate::tts_model::{self, Sampling, TtsModel};
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Rendered when `[verify] prompts` is empty: short and long, plain and
+/// with numbers and punctuation.
+const PROMPTS: &[&str] = &[
+    "Hello, and welcome.",
+    "The quick brown fox jumps over the lazy dog.",
+    "Your order number is 4 8 1 5, and it will arrive on Tuesday the 12th.",
+    "Did you remember to lock the door before you left this morning?",
+    "Turn left in two hundred meters, then keep right at the fork and follow the signs for the city centre.",
+];
+
+/// The `[verify]` config section.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Verify {
+    /// Where the golden manifest and clips are kept.
+    pub golden_dir: std::path::PathBuf,
+    /// Prompts rendered; a built-in set when empty.
+    pub prompts: Vec<String>,
+    pub description: String,
+    pub seed: u64,
+    /// Above 0 so the renders are sampled and the seed matters.
+    pub temperature: f64,
+    /// Fail when the codes differ at all, not only the audio statistics.
+    /// Only holds on the same device and dtype the golden set was made on.
+    pub require_identical_codes: bool,
+    /// Largest factor by which a clip may be longer or shorter than its
+    /// golden render.
+    pub max_duration_ratio: f64,
+    /// Largest change in RMS level, in dB.
+    pub max_loudness_change_db: f64,
+    /// Largest `spectral_distance_db` of `/api/history/compare`.
+    pub max_spectral_distance_db: f64,
+    /// Lowest speaker similarity to the golden render.
+    pub min_speaker_similarity: f64,
+}
+
+impl Default for Verify {
+    fn default() -> Self {
+        Self {
+            golden_dir: "./golden".into(),
+            prompts: Vec::new(),
+            description: "A female speaker delivers her words in a clear, moderately paced voice, with very clear audio."
+                .to_string(),
+            seed: 0,
+            temperature: 1.0,
+            require_identical_codes: false,
+            max_duration_ratio: 1.2,
+            max_loudness_change_db: 3.0,
+            max_spectral_distance_db: 3.0,
+            min_speaker_similarity: 0.9,
+        }
+    }
+}
+
+impl Verify {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.description.trim().is_empty() || self.prompts.iter().any(|p| p.trim().is_empty()) {
+            anyhow::bail!("verify.description and verify.prompts must not be empty");
+        }
+        if !self.temperature.is_finite() || self.temperature < 0.0 {
+            anyhow::bail!("verify.temperature must not be negative");
+        }
+        if !self.max_duration_ratio.is_finite() || self.max_duration_ratio < 1.0 {
+            anyhow::bail!("verify.max_duration_ratio must be at least 1");
+        }
+        if !(self.max_loudness_change_db > 0.0 && self.max_spectral_distance_db > 0.0) {
+            anyhow::bail!("verify.max_loudness_change_db and verify.max_spectral_distance_db must be positive");
+        }
+        if !(0.0..=1.0).contains(&self.min_speaker_similarity) {
+            anyhow::bail!("verify.min_speaker_similarity must be between 0 and 1");
+        }
+        Ok(())
+    }
+
+    fn prompts(&self) -> Vec<String> {
+        if self.prompts.is_empty() {
+            PROMPTS.iter().map(|p| p.to_string()).collect()
+        } else {
+            self.prompts.clone()
+        }
+    }
+}
+
+/// What the golden set was rendered with, and the renders.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    model_version: String,
+    dtype: String,
+    /// Unix time, seconds.
+    created_at: u64,
+    clips: Vec<Golden>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Golden {
+    /// File name of the WAV, without the extension.
+    id: String,
+    prompt: String,
+    description: String,
+    seed: u64,
+    temperature: f64,
+    /// SHA-256 of the audio codes, codebook by codebook.
+    codes_sha256: String,
+    steps: usize,
+    finish_reason: FinishReason,
+    #[serde(flatten)]
+    levels: ClipLevels,
+}
+
+/// Loads the model, renders the prompts, and records them (`--update`) or
+/// checks them against the golden set. Any drift is an error, so the exit
+/// status tells scripts whether the build passed.
+pub async fn run(config: &ServerConfig, args: &VerifyArgs) -> anyhow::Result<()> {
+    let verify = config.verify.clone();
+    let files = hub::fetch_model_files(config).await?;
+    let server_config = config.clone();
+    let engine = tokio::task::spawn_blocking(move || tts_model::load(&server_config, &files)).await??;
+    let (update, dtype) = (args.update, config.dtype.clone());
+    let stopping = config.max_steps.stopping();
+    tokio::task::spawn_blocking(move || {
+        let dir = &verify.golden_dir;
+        let render_prompt = |prompt: &str| render(&*engine, &verify, &stopping, prompt);
+        if update {
+            record(&*engine, &verify, dir, &dtype, render_prompt)
+        } else {
+            check(&*engine, &verify, dir, &dtype, render_prompt)
+        }
+    })
+    .await?
+}
+
+/// A render's manifest entry and samples.
+fn render(
+    engine: &dyn TtsModel,
+    verify: &Verify,
+    stopping: &Stopping,
+    prompt: &str,
+) -> anyhow::Result<(Golden, audio::Pcm)> {
+    let sampling = Sampling {
+        temperature: verify.temperature,
+        seed: verify.seed,
+        top_p: None,
+        stopping: stopping.clone(),
+        tokens: TokenControls::default(),
+        sampler: SamplerKind::Stock,
+        first_audio_by: None,
+        progress: None,
+    };
+    let synthesis = engine.synthesize(prompt, &verify.description, &sampling)?;
+    let codes: Vec<u32> = synthesis.codes.flatten_all()?.to_vec1()?;
+    let codes: Vec<u8> = codes.iter().flat_map(|c| c.to_le_bytes()).collect();
+    let pcm = audio::Pcm {
+        samples: synthesis.pcm.to_vec1()?,
+        sample_rate: engine.sample_rate(),
+    };
+    let golden = Golden {
+        id: digest(format!("{prompt}\0{}\0{}\0{}", verify.description, verify.seed, verify.temperature).as_bytes())[..12]
+            .to_string(),
+        prompt: prompt.to_string(),
+        description: verify.description.clone(),
+        seed: verify.seed,
+        temperature: verify.temperature,
+        codes_sha256: digest(&codes),
+        steps: synthesis.steps,
+        finish_reason: synthesis.finish,
+        levels: analysis::levels(&pcm),
+    };
+    Ok((golden, pcm))
+}
+
+fn record(
+    engine: &dyn TtsModel,
+    verify: &Verify,
+    dir: &Path,
+    dtype: &str,
+    render: impl Fn(&str) -> anyhow::Result<(Golden, audio::Pcm)>,
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    let mut clips = Vec::new();
+    for prompt in verify.prompts() {
+        let (golden, pcm) = render(&prompt)?;
+        let wav = audio::OutputFormat::Wav.encode_clip(&pcm.samples, pcm.sample_rate);
+        std::fs::write(dir.join(format!("{}.wav", golden.id)), wav)?;
+        println!(
+            "verify: recorded {} ({:.2} s, {} steps) {prompt:?}",
+            golden.id, golden.levels.duration_secs, golden.steps
+        );
+        clips.push(golden);
+    }
+    let manifest = Manifest {
+        model_version: engine.version().to_string(),
+        dtype: dtype.to_string(),
+        created_at: crate::unix_now(),
+        clips,
+    };
+    std::fs::write(dir.join(MANIFEST_FILE), serde_json::to_vec_pretty(&manifest)?)?;
+    println!("verify: wrote {} golden renders to {}", manifest.clips.len(), dir.display());
+    Ok(())
+}
+
+fn check(
+    engine: &dyn TtsModel,
+    verify: &Verify,
+    dir: &Path,
+    dtype: &str,
+    render: impl Fn(&str) -> anyhow::Result<(Golden, audio::Pcm)>,
+) -> anyhow::Result<()> {
+    let path = dir.join(MANIFEST_FILE);
+    let manifest: Manifest = serde_json::from_slice(
+        &std::fs::read(&path).with_context(|| format!("reading {}; record one with --update", path.display()))?,
+    )?;
+    println!(
+        "verify: checking against {} (model {}, {}) with model {}, {dtype}",
+        dir.display(),
+        manifest.model_version,
+        manifest.dtype,
+        engine.version()
+    );
+    let mut failed = 0;
+    for prompt in verify.prompts() {
+        let (current, pcm) = render(&prompt)?;
+        let Some(golden) = manifest.clips.iter().find(|g| g.id == current.id) else {
+            println!("verify: MISSING {} {prompt:?}: no golden render; record one with --update", current.id);
+            failed += 1;
+            continue;
+        };
+        let wav = std::fs::read(dir.join(format!("{}.wav", golden.id)))?;
+        let comparison = analysis::compare(&audio::read_wav(&wav)?, &pcm);
+        let problems = drift(verify, golden, &current, &comparison);
+        let codes = if golden.codes_sha256 == current.codes_sha256 { "identical codes" } else { "codes differ" };
+        if problems.is_empty() {
+            println!("verify: ok {} ({codes}) {prompt:?}", golden.id);
+        } else {
+            println!("verify: DRIFT {} ({codes}) {prompt:?}: {}", golden.id, problems.join("; "));
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} renders drifted from the golden set", verify.prompts().len());
+    }
+    println!("verify: all renders match the golden set");
+    Ok(())
+}
+
+/// How `current` is off its golden render, beyond the configured limits.
+fn drift(verify: &Verify, golden: &Golden, current: &Golden, comparison: &analysis::Comparison) -> Vec<String> {
+    let mut problems = Vec::new();
+    if verify.require_identical_codes && golden.codes_sha256 != current.codes_sha256 {
+        problems.push("the codes differ".to_string());
+    }
+    if golden.finish_reason != current.finish_reason {
+        problems.push(format!(
+            "it finished on {} instead of {}",
+            current.finish_reason.as_str(),
+            golden.finish_reason.as_str()
+        ));
+    }
+    let ratio = current.levels.duration_secs / golden.levels.duration_secs.max(1e-3);
+    if ratio > verify.max_duration_ratio || ratio < 1.0 / verify.max_duration_ratio {
+        problems.push(format!(
+            "it lasts {:.2} s against {:.2} s",
+            current.levels.duration_secs, golden.levels.duration_secs
+        ));
+    }
+    if comparison.loudness_delta_db.abs() > verify.max_loudness_change_db {
+        problems.push(format!("its level is {:+.1} dB off", comparison.loudness_delta_db));
+    }
+    match comparison.spectral_distance_db {
+        Some(distance) if distance > verify.max_spectral_distance_db => {
+            problems.push(format!("its spectrum is {distance:.1} dB off"))
+        }
+        Some(_) => {}
+        None => problems.push("one of the clips has no speech".to_string()),
+    }
+    if comparison.speaker_similarity < verify.min_speaker_similarity {
+        problems.push(format!("speaker similarity is {:.3}", comparison.speaker_similarity));
+    }
+    problems
+}
+
+fn digest(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|b| format!("{b:02x}")).collect()
+}
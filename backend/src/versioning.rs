@@ -0,0 +1,106 @@
+//! API versions. Every route under `/api` is also served under `/api/v1`,
+//! the first version; a breaking change (renamed fields, JSON bodies
+//! instead of forms) goes into a new version while clients of the old one
+//! keep working. Unversioned paths stay as an alias of the current version,
+//! marked deprecated so clients notice before it moves on.
+//!
+//! Clients on unversioned paths can pin a version with `X-Api-Version`;
+//! every response says which version answered it.
+
+use std::sync::Arc;
+
+use axum::extract::{Request, State};
+use axum::http::{HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+use serde_json::json;
+
+pub const HEADER: &str = "x-api-version";
+
+/// The version the routes implement.
+pub const CURRENT: u32 = 1;
+
+/// Versions requests may ask for.
+pub const SUPPORTED: &[u32] = &[1];
+
+/// The `[api]` config section.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiVersioning {
+    /// Send `Deprecation` and a `Link` to the versioned path on responses
+    /// to unversioned `/api/...` paths.
+    pub deprecate_unversioned: bool,
+    /// HTTP date after which unversioned paths may go away, sent as
+    /// `Sunset` (e.g. `Wed, 30 Jun 2027 00:00:00 GMT`).
+    pub sunset: Option<String>,
+}
+
+impl Default for ApiVersioning {
+    fn default() -> Self {
+        Self {
+            deprecate_unversioned: true,
+            sunset: None,
+        }
+    }
+}
+
+impl ApiVersioning {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(sunset) = &self.sunset {
+            if HeaderValue::from_str(sunset).is_err() || !sunset.ends_with("GMT") {
+                anyhow::bail!("api.sunset must be an HTTP date such as \"Wed, 30 Jun 2027 00:00:00 GMT\"");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Middleware checking the requested version and labeling the response.
+pub async fn layer(State(config): State<Arc<ApiVersioning>>, request: Request, next: Next) -> Response {
+    let path = request.uri().path().to_string();
+    let Some(rest) = path.strip_prefix("/api/") else {
+        return next.run(request).await;
+    };
+    let versioned = rest.strip_prefix('v').and_then(|r| r.split_once('/')).and_then(|(n, _)| n.parse::<u32>().ok());
+    let requested = match request.headers().get(HEADER) {
+        Some(value) => match value.to_str().ok().and_then(|v| v.trim().trim_start_matches('v').parse::<u32>().ok()) {
+            Some(version) => Some(version),
+            None => return unsupported("X-Api-Version must be a version number"),
+        },
+        None => None,
+    };
+    let version = match (versioned, requested) {
+        (Some(in_path), Some(in_header)) if in_path != in_header => {
+            return unsupported("X-Api-Version doesn't match the path's version");
+        }
+        (in_path, in_header) => in_path.or(in_header),
+    };
+    if let Some(version) = version.filter(|v| !SUPPORTED.contains(v)) {
+        return unsupported(&format!("API version {version} is not supported"));
+    }
+
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(HEADER, HeaderValue::from(version.unwrap_or(CURRENT)));
+    if versioned.is_none() && config.deprecate_unversioned {
+        headers.insert("deprecation", HeaderValue::from_static("true"));
+        if let Ok(link) = HeaderValue::from_str(&format!("</api/v{CURRENT}/{rest}>; rel=\"successor-version\"")) {
+            headers.insert("link", link);
+        }
+        if let Some(sunset) = config.sunset.as_deref().and_then(|s| HeaderValue::from_str(s).ok()) {
+            headers.insert("sunset", sunset);
+        }
+    }
+    response
+}
+
+/// `400` in the shape of the other API errors, listing the versions that
+/// are served.
+fn unsupported(message: &str) -> Response {
+    let body = json!({
+        "error": { "code": "unsupported_api_version", "message": message, "supported": SUPPORTED }
+    });
+    (StatusCode::BAD_REQUEST, Json(body)).into_response()
+}
@@ -0,0 +1,171 @@
+//! Versioned voice presets. Every change to a namespace's preset, whether
+//! from the config file or through `/api/voices`, is a new numbered version
+//! and the old ones are kept, so an edit can be rolled back and a clip's
+//! record names the version that produced it.
+//!
+//! Versions live in `.voices/versions.json` in the namespace's audio
+//! directory, sealed like the history when encryption is configured. On
+//! startup a preset whose config entry changed since it was last read gets
+//! a new version; presets edited through the API keep their edits until
+//! the config entry itself changes.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::VoicePreset;
+use crate::crypto::{Cipher, SEALED_EXTENSION};
+
+const VOICES_DIR: &str = ".voices";
+const VERSIONS_FILE: &str = "versions.json";
+
+/// Where a version came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Origin {
+    Config,
+    Api,
+    /// A copy of an older version, made by rolling back to it.
+    Rollback { from: u32 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresetVersion {
+    /// Counts from 1 for each preset.
+    pub version: u32,
+    /// Unix time, seconds.
+    pub created_at: u64,
+    pub origin: Origin,
+    pub preset: VoicePreset,
+}
+
+/// A namespace's presets, each with all its versions in order.
+pub struct Voices {
+    path: PathBuf,
+    cipher: Option<Cipher>,
+    versions: RwLock<BTreeMap<String, Vec<PresetVersion>>>,
+}
+
+impl Voices {
+    /// Reads the stored versions in `dir` and adds a version for every
+    /// preset in `config` that is new or changed there.
+    pub fn load(dir: PathBuf, cipher: Option<Cipher>, config: &BTreeMap<String, VoicePreset>) -> anyhow::Result<Self> {
+        let name = match &cipher {
+            Some(_) => format!("{VERSIONS_FILE}.{SEALED_EXTENSION}"),
+            None => VERSIONS_FILE.to_string(),
+        };
+        let path = dir.join(VOICES_DIR).join(name);
+        let mut versions: BTreeMap<String, Vec<PresetVersion>> = match std::fs::read(&path) {
+            Ok(bytes) => {
+                let bytes = match &cipher {
+                    Some(cipher) => cipher.open(&bytes)?,
+                    None => bytes,
+                };
+                serde_json::from_slice(&bytes)?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let now = crate::unix_now();
+        let mut changed = false;
+        for (name, preset) in config {
+            let history = versions.entry(name.clone()).or_default();
+            let last_config = history.iter().rev().find(|v| v.origin == Origin::Config);
+            if last_config.is_some_and(|v| v.preset == *preset) {
+                continue;
+            }
+            history.push(PresetVersion {
+                version: history.len() as u32 + 1,
+                created_at: now,
+                origin: Origin::Config,
+                preset: preset.clone(),
+            });
+            changed = true;
+        }
+        let voices = Self {
+            path,
+            cipher,
+            versions: RwLock::new(versions),
+        };
+        if changed {
+            voices.save(&voices.versions.read().unwrap())?;
+        }
+        Ok(voices)
+    }
+
+    /// The current version of preset `name`.
+    pub fn get(&self, name: &str) -> Option<PresetVersion> {
+        self.versions.read().unwrap().get(name)?.last().cloned()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.versions.read().unwrap().contains_key(name)
+    }
+
+    /// The current version of every preset, by name.
+    pub fn current(&self) -> BTreeMap<String, PresetVersion> {
+        let versions = self.versions.read().unwrap();
+        versions
+            .iter()
+            .filter_map(|(name, history)| Some((name.clone(), history.last()?.clone())))
+            .collect()
+    }
+
+    /// All versions of preset `name`, oldest first.
+    pub fn history(&self, name: &str) -> Option<Vec<PresetVersion>> {
+        self.versions.read().unwrap().get(name).cloned()
+    }
+
+    /// Makes `preset` the current version of `name`, creating the preset if
+    /// there is none by that name.
+    pub fn update(&self, name: &str, preset: VoicePreset) -> anyhow::Result<PresetVersion> {
+        self.push(name, preset, Origin::Api)
+    }
+
+    /// Makes a copy of version `version` of `name` its current version.
+    /// `None` when there is no such version.
+    pub fn rollback(&self, name: &str, version: u32) -> anyhow::Result<Option<PresetVersion>> {
+        let Some(old) = self
+            .history(name)
+            .and_then(|history| history.into_iter().find(|v| v.version == version))
+        else {
+            return Ok(None);
+        };
+        self.push(name, old.preset, Origin::Rollback { from: version }).map(Some)
+    }
+
+    fn push(&self, name: &str, preset: VoicePreset, origin: Origin) -> anyhow::Result<PresetVersion> {
+        let mut versions = self.versions.write().unwrap();
+        // Only what was stored takes effect.
+        let mut updated = versions.clone();
+        let history = updated.entry(name.to_string()).or_default();
+        let version = PresetVersion {
+            version: history.len() as u32 + 1,
+            created_at: crate::unix_now(),
+            origin,
+            preset,
+        };
+        history.push(version.clone());
+        self.save(&updated)?;
+        *versions = updated;
+        Ok(version)
+    }
+
+    fn save(&self, versions: &BTreeMap<String, Vec<PresetVersion>>) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_vec_pretty(versions)?;
+        let bytes = match &self.cipher {
+            Some(cipher) => cipher.seal(&json)?,
+            None => json,
+        };
+        let partial = self.path.with_extension("partial");
+        std::fs::write(&partial, bytes)?;
+        std::fs::rename(&partial, &self.path)?;
+        Ok(())
+    }
+}
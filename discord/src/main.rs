@@ -0,0 +1,169 @@
+//! Discord bot that speaks ttser output in voice channels. `/say` joins the
+//! caller's channel and streams the clip from a running ttser server, so the
+//! bot needs no GPU of its own.
+
+use std::sync::Arc;
+
+use clap::Parser;
+use serenity::all::{
+    Command, CommandInteraction, CommandOptionType, Context, CreateCommand, CreateCommandOption,
+    EditInteractionResponse, EventHandler, GatewayIntents, GuildId, Interaction, Ready,
+    ResolvedValue,
+};
+use serenity::async_trait;
+use serenity::Client;
+use songbird::SerenityInit;
+
+mod tts;
+
+use tts::TtsClient;
+
+const DESCRIPTION: &str =
+    "A female speaker delivers a slightly expressive and animated speech with a moderate speed and pitch. The recording is of very high quality, with the speaker's voice sounding clear and very close up.";
+
+#[derive(Parser, Debug)]
+#[command(about = "Discord bot speaking ttser output in voice channels")]
+struct Args {
+    /// Bot token from the Discord developer portal.
+    #[arg(long, env = "DISCORD_TOKEN", hide_env_values = true)]
+    token: String,
+
+    /// ttser server root URL.
+    #[arg(long, env = "TTSER_URL", default_value = "http://127.0.0.1:8039")]
+    url: String,
+
+    /// API key for a ttser namespace, sent as a bearer token.
+    #[arg(long, env = "TTSER_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+
+    /// Voice description for `/say` without a `voice`.
+    #[arg(long, default_value = DESCRIPTION)]
+    description: String,
+
+    /// Register the commands in this guild only, where they show up at once
+    /// (global commands can take a while to appear).
+    #[arg(long)]
+    guild: Option<u64>,
+}
+
+struct Handler {
+    tts: Arc<TtsClient>,
+    guild: Option<GuildId>,
+}
+
+#[async_trait]
+impl EventHandler for Handler {
+    async fn ready(&self, ctx: Context, ready: Ready) {
+        let commands = vec![
+            CreateCommand::new("say")
+                .description("Speak text in your voice channel")
+                .add_option(
+                    CreateCommandOption::new(CommandOptionType::String, "text", "What to say")
+                        .required(true),
+                )
+                .add_option(CreateCommandOption::new(
+                    CommandOptionType::String,
+                    "voice",
+                    "A voice preset configured on the ttser server",
+                )),
+            CreateCommand::new("skip").description("Stop the clip being spoken"),
+            CreateCommand::new("leave").description("Leave the voice channel"),
+        ];
+        let registered = match self.guild {
+            Some(guild) => guild.set_commands(&ctx.http, commands).await,
+            None => Command::set_global_commands(&ctx.http, commands).await,
+        };
+        match registered {
+            Ok(_) => println!("discord: logged in as {}", ready.user.name),
+            Err(e) => println!("discord: registering commands failed: {e}"),
+        }
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        let Interaction::Command(command) = interaction else {
+            return;
+        };
+        // Generation takes longer than the three seconds Discord waits for
+        // a first reply.
+        if let Err(e) = command.defer(&ctx.http).await {
+            println!("discord: deferring /{} failed: {e}", command.data.name);
+            return;
+        }
+        let reply = match command.data.name.as_str() {
+            "say" => self.say(&ctx, &command).await,
+            "skip" => skip(&ctx, &command).await,
+            "leave" => leave(&ctx, &command).await,
+            _ => Ok("Unknown command.".to_string()),
+        }
+        .unwrap_or_else(|e| format!("Failed: {e:#}"));
+        let response = EditInteractionResponse::new().content(reply);
+        if let Err(e) = command.edit_response(&ctx.http, response).await {
+            println!("discord: replying to /{} failed: {e}", command.data.name);
+        }
+    }
+}
+
+impl Handler {
+    async fn say(&self, ctx: &Context, command: &CommandInteraction) -> anyhow::Result<String> {
+        let (mut text, mut voice) = (None, None);
+        for option in command.data.options() {
+            match (option.name, option.value) {
+                ("text", ResolvedValue::String(value)) => text = Some(value.to_string()),
+                ("voice", ResolvedValue::String(value)) => voice = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        let text = text.filter(|t| !t.trim().is_empty()).ok_or_else(|| anyhow::anyhow!("nothing to say"))?;
+        let guild = command.guild_id.ok_or_else(|| anyhow::anyhow!("/say only works in a server"))?;
+        let channel = ctx
+            .cache
+            .guild(guild)
+            .and_then(|g| g.voice_states.get(&command.user.id).and_then(|state| state.channel_id))
+            .ok_or_else(|| anyhow::anyhow!("join a voice channel first"))?;
+
+        let speech = self.tts.speak(&text, voice.as_deref()).await?;
+        let voices = songbird::get(ctx).await.expect("songbird is registered at startup");
+        let call = voices.join(guild, channel).await?;
+        // Clips queue up, so several /say in a row play one after another.
+        call.lock().await.enqueue_input(speech.input).await;
+        println!("discord: clip {} queued in {guild}", speech.clip_id);
+        Ok(format!("Speaking clip `{}`.", speech.clip_id))
+    }
+}
+
+async fn skip(ctx: &Context, command: &CommandInteraction) -> anyhow::Result<String> {
+    let guild = command.guild_id.ok_or_else(|| anyhow::anyhow!("not in a server"))?;
+    let voices = songbird::get(ctx).await.expect("songbird is registered at startup");
+    let Some(call) = voices.get(guild) else {
+        return Ok("Nothing is playing.".to_string());
+    };
+    call.lock().await.queue().skip()?;
+    Ok("Skipped.".to_string())
+}
+
+async fn leave(ctx: &Context, command: &CommandInteraction) -> anyhow::Result<String> {
+    let guild = command.guild_id.ok_or_else(|| anyhow::anyhow!("not in a server"))?;
+    let voices = songbird::get(ctx).await.expect("songbird is registered at startup");
+    if voices.get(guild).is_none() {
+        return Ok("Not in a voice channel.".to_string());
+    }
+    voices.remove(guild).await?;
+    Ok("Left the voice channel.".to_string())
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let handler = Handler {
+        tts: Arc::new(TtsClient::new(&args.url, args.api_key, args.description)),
+        guild: args.guild.map(GuildId::new),
+    };
+    let intents = GatewayIntents::GUILDS | GatewayIntents::GUILD_VOICE_STATES;
+    let mut client = Client::builder(&args.token, intents)
+        .event_handler(handler)
+        .register_songbird()
+        .await?;
+    println!("discord: speaking through {}", args.url);
+    client.start().await?;
+    Ok(())
+}
@@ -0,0 +1,146 @@
+//! Client for a ttser server's streaming `/api/tts`, turned into songbird
+//! input that starts playing with the first chunk.
+
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use anyhow::{bail, Context as _};
+use async_trait::async_trait;
+use futures::StreamExt;
+use songbird::input::{AsyncAdapterStream, AsyncMediaSource, Input, RawAdapter};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWriteExt, DuplexStream, ReadBuf};
+
+/// Discord plays 48 kHz audio; asking for it saves songbird a resampling.
+const SAMPLE_RATE: u32 = 48000;
+
+/// Audio held between the HTTP response and the mixer.
+const BUFFER_BYTES: usize = 256 * 1024;
+
+pub struct TtsClient {
+    http: reqwest::Client,
+    endpoint: String,
+    api_key: Option<String>,
+    description: String,
+}
+
+/// A clip being generated, ready to be queued on a call.
+pub struct Speech {
+    pub clip_id: String,
+    pub input: Input,
+}
+
+impl TtsClient {
+    pub fn new(url: &str, api_key: Option<String>, description: String) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            endpoint: format!("{}/api/tts", url.trim_end_matches('/')),
+            api_key,
+            description,
+        }
+    }
+
+    /// Starts generating `text`, in preset `voice` or else the default
+    /// description. Returns once the server has accepted the request; the
+    /// audio arrives while it is played.
+    pub async fn speak(&self, text: &str, voice: Option<&str>) -> anyhow::Result<Speech> {
+        let mut form = reqwest::multipart::Form::new()
+            .text("text", text.to_string())
+            .text("stream", "true")
+            .text("format", "pcm16le")
+            .text("sample_rate", SAMPLE_RATE.to_string());
+        form = match voice {
+            Some(voice) => form.text("voice", voice.to_string()),
+            None => form.text("description", self.description.clone()),
+        };
+        let mut request = self.http.post(&self.endpoint).multipart(form);
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+        let response = request.send().await.context("ttser is unreachable")?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body: serde_json::Value = response.json().await.unwrap_or_default();
+            match body["error"]["message"].as_str() {
+                Some(message) => bail!("{message}"),
+                None => bail!("ttser answered {status}"),
+            }
+        }
+        let clip_id = response
+            .headers()
+            .get("x-clip-id")
+            .and_then(|id| id.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+
+        let (mut writer, reader) = tokio::io::duplex(BUFFER_BYTES);
+        let feeding = clip_id.clone();
+        tokio::spawn(async move {
+            let mut body = response.bytes_stream();
+            let mut carry = Vec::new();
+            while let Some(chunk) = body.next().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    // ttser breaks the connection off when generation fails
+                    // partway; what came so far still plays.
+                    Err(e) => {
+                        println!("clip {feeding}: stream ended early: {e}");
+                        break;
+                    }
+                };
+                // Stops when the track is gone, e.g. after /skip.
+                if writer.write_all(&to_f32le(&mut carry, &chunk)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let stream = AsyncAdapterStream::new(Box::new(PcmStream(reader)), BUFFER_BYTES);
+        let input = RawAdapter::new(stream, SAMPLE_RATE, 1).into();
+        Ok(Speech { clip_id, input })
+    }
+}
+
+/// 16-bit PCM as the 32-bit floats songbird's raw input takes. A sample
+/// split across chunks waits in `carry` for its second byte.
+fn to_f32le(carry: &mut Vec<u8>, chunk: &[u8]) -> Vec<u8> {
+    carry.extend_from_slice(chunk);
+    let whole = carry.len() & !1;
+    let out = carry[..whole]
+        .chunks_exact(2)
+        .flat_map(|b| (i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0).to_le_bytes())
+        .collect();
+    carry.drain(..whole);
+    out
+}
+
+/// The converted audio, readable once and in order.
+struct PcmStream(DuplexStream);
+
+impl AsyncRead for PcmStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncSeek for PcmStream {
+    fn start_seek(self: Pin<&mut Self>, _position: SeekFrom) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(Err(std::io::ErrorKind::Unsupported.into()))
+    }
+}
+
+#[async_trait]
+impl AsyncMediaSource for PcmStream {
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    async fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
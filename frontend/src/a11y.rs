@@ -0,0 +1,474 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use wasm_bindgen::JsCast;
+use wasm_bindgen::prelude::*;
+use web_sys::*;
+
+/// Hides the live regions visually while keeping them in the accessibility
+/// tree (`display: none` would silence them).
+const VISUALLY_HIDDEN: &str = "position:absolute;width:1px;height:1px;margin:-1px;padding:0;\
+    overflow:hidden;clip:rect(0,0,0,0);white-space:nowrap;border:0";
+
+/// Time between clearing a live region and writing the message, so screen
+/// readers announce a message again when it repeats.
+const ANNOUNCE_DELAY_MS: i32 = 100;
+
+/// Screen reader announcements and focus handling for host pages.
+///
+/// Two visually hidden live regions are added to the page: `announce`
+/// speaks through the polite one (`role="status"`) and `alert` through the
+/// assertive one (`role="alert"`). `recording_state` takes the states
+/// `PushToTalk` reports and `generation_state` takes `"queued"`,
+/// `"generating"`, `"playing"`, `"done"` or `"error"`, announcing each in
+/// words that `set_message(state, text)` can replace (an empty text keeps a
+/// state quiet). `remember_focus()` / `restore_focus()` bracket dialogs,
+/// `focus(id)` moves focus to any element, and `on_focus` hears the id of
+/// every element focused this way. The regions are removed on `free()`.
+#[wasm_bindgen]
+pub struct Announcer {
+    polite: HtmlElement,
+    assertive: HtmlElement,
+    state: Rc<RefCell<AnnouncerState>>,
+}
+
+struct AnnouncerState {
+    messages: HashMap<String, String>,
+    /// The last recording state, so `"idle"` is only announced after a
+    /// recording.
+    recording: String,
+    remembered: Option<HtmlElement>,
+    on_focus: Option<js_sys::Function>,
+}
+
+#[wasm_bindgen]
+impl Announcer {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Result<Announcer, JsValue> {
+        let document = web_sys::window()
+            .and_then(|w| w.document())
+            .ok_or("no document")?;
+        let body = document.body().ok_or("no body")?;
+        let region = |role: &str, live: &str| -> Result<HtmlElement, JsValue> {
+            let element: HtmlElement = document.create_element("div")?.dyn_into()?;
+            element.set_attribute("role", role)?;
+            element.set_attribute("aria-live", live)?;
+            element.set_attribute("aria-atomic", "true")?;
+            element.set_attribute("style", VISUALLY_HIDDEN)?;
+            body.append_child(&element)?;
+            Ok(element)
+        };
+        let messages = [
+            ("arming", "Waiting for microphone access"),
+            ("recording", "Recording"),
+            ("idle", "Recording stopped"),
+            ("queued", "Speech queued"),
+            ("generating", "Generating speech"),
+            ("playing", "Playing"),
+            ("done", "Speech ready"),
+            ("error", "Speech generation failed"),
+        ];
+        Ok(Announcer {
+            polite: region("status", "polite")?,
+            assertive: region("alert", "assertive")?,
+            state: Rc::new(RefCell::new(AnnouncerState {
+                messages: messages
+                    .into_iter()
+                    .map(|(state, text)| (state.to_string(), text.to_string()))
+                    .collect(),
+                recording: "idle".to_string(),
+                remembered: None,
+                on_focus: None,
+            })),
+        })
+    }
+
+    /// Speaks `message` once the screen reader is idle.
+    #[wasm_bindgen]
+    pub fn announce(&self, message: &str) {
+        write_region(&self.polite, message);
+    }
+
+    /// Speaks `message` at once, interrupting the screen reader.
+    #[wasm_bindgen]
+    pub fn alert(&self, message: &str) {
+        write_region(&self.assertive, message);
+    }
+
+    /// Replaces the words announced for `state`.
+    #[wasm_bindgen]
+    pub fn set_message(&self, state: &str, text: &str) {
+        self.state.borrow_mut().messages.insert(state.to_string(), text.to_string());
+    }
+
+    /// Announces a `PushToTalk` state (`"arming"`, `"recording"`, `"idle"`).
+    #[wasm_bindgen]
+    pub fn recording_state(&self, state: &str) {
+        let previous = std::mem::replace(&mut self.state.borrow_mut().recording, state.to_string());
+        if previous == state || (state == "idle" && previous != "recording") {
+            return;
+        }
+        self.announce_state(state);
+    }
+
+    /// Announces a generation state; `"error"` interrupts, the others wait.
+    /// `detail`, if given, follows the message (e.g. the error text).
+    #[wasm_bindgen]
+    pub fn generation_state(&self, state: &str, detail: Option<String>) {
+        let Some(mut message) = self.message(state) else {
+            return;
+        };
+        if let Some(detail) = detail.filter(|d| !d.is_empty()) {
+            message = format!("{message}: {detail}");
+        }
+        if state == "error" {
+            self.alert(&message);
+        } else {
+            self.announce(&message);
+        }
+    }
+
+    /// Announces progress through a multi-part job, e.g. from
+    /// `Audiobook.on_progress` or `Project.on_progress`.
+    #[wasm_bindgen]
+    pub fn progress(&self, done: u32, total: u32) {
+        self.announce(&format!("{done} of {total} done"));
+    }
+
+    #[wasm_bindgen]
+    pub fn on_focus(&self, callback: js_sys::Function) {
+        self.state.borrow_mut().on_focus = Some(callback);
+    }
+
+    /// Moves focus to the element with id `id`, making it focusable first
+    /// if it isn't (e.g. a heading or a result panel).
+    #[wasm_bindgen]
+    pub fn focus(&self, id: &str) -> Result<(), JsValue> {
+        let element: HtmlElement = web_sys::window()
+            .and_then(|w| w.document())
+            .and_then(|d| d.get_element_by_id(id))
+            .ok_or_else(|| JsValue::from_str(&format!("no element #{id}")))?
+            .dyn_into()?;
+        if element.tab_index() < 0 && !element.has_attribute("tabindex") {
+            element.set_attribute("tabindex", "-1")?;
+        }
+        element.focus()?;
+        let callback = self.state.borrow().on_focus.clone();
+        if let Some(callback) = callback {
+            let _ = callback.call1(&JsValue::NULL, &JsValue::from_str(id));
+        }
+        Ok(())
+    }
+
+    /// Keeps the focused element, e.g. before opening a dialog.
+    #[wasm_bindgen]
+    pub fn remember_focus(&self) {
+        let active = web_sys::window()
+            .and_then(|w| w.document())
+            .and_then(|d| d.active_element())
+            .and_then(|element| element.dyn_into::<HtmlElement>().ok());
+        self.state.borrow_mut().remembered = active;
+    }
+
+    /// Focuses the element kept by `remember_focus`, if it is still on the
+    /// page. Returns whether focus moved.
+    #[wasm_bindgen]
+    pub fn restore_focus(&self) -> bool {
+        let Some(element) = self.state.borrow_mut().remembered.take() else {
+            return false;
+        };
+        element.is_connected() && element.focus().is_ok()
+    }
+}
+
+impl Announcer {
+    fn message(&self, state: &str) -> Option<String> {
+        self.state
+            .borrow()
+            .messages
+            .get(state)
+            .filter(|text| !text.is_empty())
+            .cloned()
+    }
+
+    fn announce_state(&self, state: &str) {
+        if let Some(message) = self.message(state) {
+            self.announce(&message);
+        }
+    }
+}
+
+impl Drop for Announcer {
+    fn drop(&mut self) {
+        self.polite.remove();
+        self.assertive.remove();
+    }
+}
+
+fn write_region(region: &HtmlElement, message: &str) {
+    region.set_text_content(None);
+    let Some(window) = web_sys::window() else {
+        return;
+    };
+    let region = region.clone();
+    let message = message.to_string();
+    let write = Closure::once_into_js(move || region.set_text_content(Some(&message)));
+    let _ = window.set_timeout_with_callback_and_timeout_and_arguments_0(
+        write.unchecked_ref(),
+        ANNOUNCE_DELAY_MS,
+    );
+}
+
+/// Keyboard shortcuts for the widget's controls.
+///
+/// Shortcuts are written like `"Ctrl+Enter"`, `"Alt+KeyR"` or `"Escape"`:
+/// modifiers (`Ctrl`, `Alt`, `Shift`, `Meta`) joined by `+` to a
+/// `KeyboardEvent.code` or `KeyboardEvent.key`. `map(shortcut, action)`
+/// binds one; the defaults are `Ctrl+Enter` for `"generate"`, `Escape` for
+/// `"stop"` and `Alt+KeyR` for `"record"`. Once `bind()` is called, each
+/// shortcut pressed calls `on_action(action)`; returning `false` from it
+/// lets the key through. Shortcuts without `Ctrl`, `Alt` or `Meta` are
+/// ignored while typing in a form field. `label(id, action)` sets
+/// `aria-keyshortcuts` on the control so screen readers read out its keys,
+/// and `shortcuts()` lists the bindings for a help screen.
+#[wasm_bindgen]
+pub struct KeyboardControls {
+    state: Rc<RefCell<ControlsState>>,
+    keydown: Option<Closure<dyn FnMut(KeyboardEvent)>>,
+}
+
+struct ControlsState {
+    shortcuts: Vec<(Shortcut, String)>,
+    on_action: Option<js_sys::Function>,
+}
+
+#[derive(Clone, PartialEq)]
+struct Shortcut {
+    ctrl: bool,
+    alt: bool,
+    shift: bool,
+    meta: bool,
+    key: String,
+}
+
+impl Default for KeyboardControls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+impl KeyboardControls {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> KeyboardControls {
+        let shortcuts = [("Ctrl+Enter", "generate"), ("Escape", "stop"), ("Alt+KeyR", "record")]
+            .into_iter()
+            .filter_map(|(shortcut, action)| Some((Shortcut::parse(shortcut)?, action.to_string())))
+            .collect();
+        KeyboardControls {
+            state: Rc::new(RefCell::new(ControlsState {
+                shortcuts,
+                on_action: None,
+            })),
+            keydown: None,
+        }
+    }
+
+    /// Binds `shortcut` to `action`, replacing what it did before.
+    #[wasm_bindgen]
+    pub fn map(&self, shortcut: &str, action: &str) -> Result<(), JsValue> {
+        let shortcut = Shortcut::parse(shortcut)
+            .ok_or_else(|| JsValue::from_str(&format!("invalid shortcut {shortcut:?}")))?;
+        let mut state = self.state.borrow_mut();
+        state.shortcuts.retain(|(bound, _)| *bound != shortcut);
+        state.shortcuts.push((shortcut, action.to_string()));
+        Ok(())
+    }
+
+    /// Removes every shortcut of `action`.
+    #[wasm_bindgen]
+    pub fn unmap(&self, action: &str) {
+        self.state.borrow_mut().shortcuts.retain(|(_, bound)| bound != action);
+    }
+
+    #[wasm_bindgen]
+    pub fn on_action(&self, callback: js_sys::Function) {
+        self.state.borrow_mut().on_action = Some(callback);
+    }
+
+    /// Runs `action` as if its shortcut had been pressed.
+    #[wasm_bindgen]
+    pub fn trigger(&self, action: &str) -> bool {
+        run_action(&self.state, action)
+    }
+
+    /// The bindings: `[{ shortcut, action }]`.
+    #[wasm_bindgen]
+    pub fn shortcuts(&self) -> Result<js_sys::Array, JsValue> {
+        self.state
+            .borrow()
+            .shortcuts
+            .iter()
+            .map(|(shortcut, action)| {
+                let entry = js_sys::Object::new();
+                js_sys::Reflect::set(&entry, &"shortcut".into(), &shortcut.to_string().into())?;
+                js_sys::Reflect::set(&entry, &"action".into(), &action.as_str().into())?;
+                Ok::<JsValue, JsValue>(entry.into())
+            })
+            .collect()
+    }
+
+    /// Sets `aria-keyshortcuts` on the element with id `id` to the
+    /// shortcuts of `action`.
+    #[wasm_bindgen]
+    pub fn label(&self, id: &str, action: &str) -> Result<(), JsValue> {
+        let element = web_sys::window()
+            .and_then(|w| w.document())
+            .and_then(|d| d.get_element_by_id(id))
+            .ok_or_else(|| JsValue::from_str(&format!("no element #{id}")))?;
+        let keys: Vec<String> = self
+            .state
+            .borrow()
+            .shortcuts
+            .iter()
+            .filter(|(_, bound)| bound == action)
+            .map(|(shortcut, _)| shortcut.aria())
+            .collect();
+        if keys.is_empty() {
+            element.remove_attribute("aria-keyshortcuts")
+        } else {
+            element.set_attribute("aria-keyshortcuts", &keys.join(" "))
+        }
+    }
+
+    /// Starts listening for the shortcuts on `window`.
+    #[wasm_bindgen]
+    pub fn bind(&mut self) -> Result<(), JsValue> {
+        if self.keydown.is_some() {
+            return Ok(());
+        }
+        let window = web_sys::window().ok_or("no window")?;
+        let state = self.state.clone();
+        let keydown = Closure::wrap(Box::new(move |event: KeyboardEvent| {
+            let action = state
+                .borrow()
+                .shortcuts
+                .iter()
+                .find(|(shortcut, _)| shortcut.matches(&event))
+                .map(|(shortcut, action)| (shortcut.clone(), action.clone()));
+            let Some((shortcut, action)) = action else {
+                return;
+            };
+            let modified = shortcut.ctrl || shortcut.alt || shortcut.meta;
+            if (!modified && crate::ptt::typing_target(&event)) || event.repeat() {
+                return;
+            }
+            if run_action(&state, &action) {
+                event.prevent_default();
+            }
+        }) as Box<dyn FnMut(KeyboardEvent)>);
+        window.add_event_listener_with_callback("keydown", keydown.as_ref().unchecked_ref())?;
+        self.keydown = Some(keydown);
+        Ok(())
+    }
+
+    #[wasm_bindgen]
+    pub fn unbind(&mut self) {
+        if let (Some(keydown), Some(window)) = (self.keydown.take(), web_sys::window()) {
+            let _ = window
+                .remove_event_listener_with_callback("keydown", keydown.as_ref().unchecked_ref());
+        }
+    }
+}
+
+impl Drop for KeyboardControls {
+    fn drop(&mut self) {
+        self.unbind();
+    }
+}
+
+/// Calls `on_action`; whether the action was handled.
+fn run_action(state: &Rc<RefCell<ControlsState>>, action: &str) -> bool {
+    let callback = state.borrow().on_action.clone();
+    let Some(callback) = callback else {
+        return false;
+    };
+    match callback.call1(&JsValue::NULL, &JsValue::from_str(action)) {
+        Ok(handled) => handled.as_bool() != Some(false),
+        Err(err) => {
+            console_log!("Keyboard action {} failed: {:?}", action, err);
+            false
+        }
+    }
+}
+
+impl Shortcut {
+    fn parse(shortcut: &str) -> Option<Shortcut> {
+        let mut parsed = Shortcut {
+            ctrl: false,
+            alt: false,
+            shift: false,
+            meta: false,
+            key: String::new(),
+        };
+        let mut parts: Vec<&str> = shortcut.split('+').map(str::trim).collect();
+        // `Ctrl++` ends in an empty part for the plus key itself.
+        if shortcut.ends_with("++") {
+            parts.pop();
+            parts.pop();
+            parts.push("+");
+        }
+        let (key, modifiers) = parts.split_last()?;
+        for modifier in modifiers {
+            match modifier.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => parsed.ctrl = true,
+                "alt" | "option" => parsed.alt = true,
+                "shift" => parsed.shift = true,
+                "meta" | "cmd" | "command" => parsed.meta = true,
+                _ => return None,
+            }
+        }
+        if key.is_empty() {
+            return None;
+        }
+        parsed.key = key.to_string();
+        Some(parsed)
+    }
+
+    fn matches(&self, event: &KeyboardEvent) -> bool {
+        event.ctrl_key() == self.ctrl
+            && event.alt_key() == self.alt
+            && event.shift_key() == self.shift
+            && event.meta_key() == self.meta
+            && (event.code() == self.key || event.key().eq_ignore_ascii_case(&self.key))
+    }
+
+    /// The shortcut as `aria-keyshortcuts` spells it, e.g. `Control+R`.
+    fn aria(&self) -> String {
+        let mut keys = self.modifiers("Control");
+        let key = self
+            .key
+            .strip_prefix("Key")
+            .or_else(|| self.key.strip_prefix("Digit"))
+            .unwrap_or(&self.key);
+        keys.push(key.to_string());
+        keys.join("+")
+    }
+
+    fn modifiers(&self, ctrl: &str) -> Vec<String> {
+        [(self.ctrl, ctrl), (self.alt, "Alt"), (self.shift, "Shift"), (self.meta, "Meta")]
+            .into_iter()
+            .filter(|(held, _)| *held)
+            .map(|(_, name)| name.to_string())
+            .collect()
+    }
+}
+
+impl std::fmt::Display for Shortcut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut keys = self.modifiers("Ctrl");
+        keys.push(self.key.clone());
+        f.write_str(&keys.join("+"))
+    }
+}
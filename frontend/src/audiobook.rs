@@ -0,0 +1,242 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use wasm_bindgen::JsCast;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::*;
+
+/// How often a running job's status is fetched.
+const POLL_MS: i32 = 1000;
+
+/// Client for the server's audiobook jobs, which render long texts or web
+/// articles in the background.
+///
+/// `submit_url(url)` has the server fetch a page and read its article text,
+/// `submit_text(text)` sends text as it is; both resolve to the job id.
+/// `wait(id)` polls the job, passing each status (`{ state, progress: {
+/// done, total }, title, clip_id, ... }`) to the `on_progress` callback,
+/// and resolves to the final one once the book is done. `audio_url(id)`
+/// is where its audio can then be played from. `estimate(text)` tells how
+/// long a text would play before it is submitted. A finished job's chunks
+/// can be listed, re-rendered one by one and assembled into the book again.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct Audiobook {
+    state: Rc<RefCell<BookState>>,
+}
+
+struct BookState {
+    description: String,
+    params: Vec<(String, String)>,
+    on_progress: Option<js_sys::Function>,
+}
+
+#[wasm_bindgen]
+impl Audiobook {
+    #[wasm_bindgen(constructor)]
+    pub fn new(description: &str) -> Audiobook {
+        Audiobook {
+            state: Rc::new(RefCell::new(BookState {
+                description: description.to_string(),
+                params: Vec::new(),
+                on_progress: None,
+            })),
+        }
+    }
+
+    #[wasm_bindgen]
+    pub fn set_description(&self, description: &str) {
+        self.state.borrow_mut().description = description.to_string();
+    }
+
+    /// Sets an extra form field (e.g. `voice`, `seed`, `title`) sent with
+    /// every submission.
+    #[wasm_bindgen]
+    pub fn set_param(&self, name: &str, value: &str) {
+        let mut state = self.state.borrow_mut();
+        state.params.retain(|(n, _)| n != name);
+        state.params.push((name.to_string(), value.to_string()));
+    }
+
+    #[wasm_bindgen]
+    pub fn on_progress(&self, callback: js_sys::Function) {
+        self.state.borrow_mut().on_progress = Some(callback);
+    }
+
+    /// Has the server read the article at `url`. Resolves to the job id.
+    #[wasm_bindgen]
+    pub async fn submit_url(&self, url: &str) -> Result<String, JsValue> {
+        self.submit(&[("url", url.trim())]).await
+    }
+
+    /// Resolves to the job id.
+    #[wasm_bindgen]
+    pub async fn submit_text(&self, text: &str) -> Result<String, JsValue> {
+        self.submit(&[("text", text)]).await
+    }
+
+    /// Resolves to `{ characters, words, chunks, duration_secs }` for
+    /// `text`, without rendering it.
+    #[wasm_bindgen]
+    pub async fn estimate(&self, text: &str) -> Result<JsValue, JsValue> {
+        let body = js_sys::Object::new();
+        js_sys::Reflect::set(&body, &"text".into(), &text.into())?;
+        post_json("/api/estimate", &body).await
+    }
+
+    /// Polls job `id` until it is done and resolves to its final status;
+    /// rejects with the server's message when it fails.
+    #[wasm_bindgen]
+    pub async fn wait(&self, id: &str) -> Result<JsValue, JsValue> {
+        let callback = self.state.borrow().on_progress.clone();
+        self.watch(id, |status| {
+            if let Some(callback) = &callback {
+                let _ = callback.call1(&JsValue::NULL, status);
+            }
+        })
+        .await
+    }
+
+    /// The job's current status object.
+    #[wasm_bindgen]
+    pub async fn status(&self, id: &str) -> Result<JsValue, JsValue> {
+        let window = web_sys::window().ok_or("no window")?;
+        let response: Response = JsFuture::from(window.fetch_with_str(&format!("/api/jobs/{id}")))
+            .await?
+            .dyn_into()?;
+        if !response.ok() {
+            return Err(JsValue::from_str(&format!(
+                "Job status request failed with status: {}",
+                response.status()
+            )));
+        }
+        JsFuture::from(response.json()?).await
+    }
+
+    /// Resolves to the chunks of a finished job, `[{ index, page, text,
+    /// seed, edited }]`.
+    #[wasm_bindgen]
+    pub async fn chunks(&self, id: &str) -> Result<JsValue, JsValue> {
+        let window = web_sys::window().ok_or("no window")?;
+        let response: Response = JsFuture::from(window.fetch_with_str(&format!("/api/jobs/{id}/chunks")))
+            .await?
+            .dyn_into()?;
+        if !response.ok() {
+            return Err(JsValue::from_str(&format!(
+                "Chunk list request failed with status: {}",
+                response.status()
+            )));
+        }
+        JsFuture::from(response.json()?).await
+    }
+
+    /// Renders chunk `n` of a finished job again from `text`. The job's
+    /// audio keeps the old chunk until `assemble(id)`.
+    #[wasm_bindgen]
+    pub async fn rerender_chunk(&self, id: &str, n: usize, text: &str) -> Result<JsValue, JsValue> {
+        let body = js_sys::Object::new();
+        js_sys::Reflect::set(&body, &"text".into(), &text.into())?;
+        post_json(&format!("/api/jobs/{id}/chunks/{n}/rerender"), &body).await
+    }
+
+    /// Joins the job's chunks again, re-rendered ones included, and
+    /// resolves to its status.
+    #[wasm_bindgen]
+    pub async fn assemble(&self, id: &str) -> Result<JsValue, JsValue> {
+        post_json(&format!("/api/jobs/{id}/assemble"), &js_sys::Object::new()).await
+    }
+
+    /// Where a finished job's audio is served.
+    #[wasm_bindgen]
+    pub fn audio_url(&self, id: &str) -> String {
+        format!("/api/jobs/{id}/audio")
+    }
+
+    /// Like `wait`, reporting each status to `report`.
+    pub(crate) async fn watch(&self, id: &str, report: impl Fn(&JsValue)) -> Result<JsValue, JsValue> {
+        loop {
+            let status = self.status(id).await?;
+            report(&status);
+            let state = js_sys::Reflect::get(&status, &"state".into())?.as_string().unwrap_or_default();
+            match state.as_str() {
+                "done" => return Ok(status),
+                "failed" => {
+                    let error = js_sys::Reflect::get(&status, &"error".into())?;
+                    let message = js_sys::Reflect::get(&error, &"message".into())?;
+                    return Err(message);
+                }
+                _ => sleep(POLL_MS).await?,
+            }
+        }
+    }
+
+    /// Starts a job from `fields` plus the description and extra params.
+    /// Resolves to the job id.
+    pub(crate) async fn submit(&self, fields: &[(&str, &str)]) -> Result<String, JsValue> {
+        let window = web_sys::window().ok_or("no window")?;
+        let form_data = FormData::new()?;
+        for (name, value) in fields {
+            form_data.append_with_str(name, value)?;
+        }
+        {
+            let state = self.state.borrow();
+            form_data.append_with_str("description", &state.description)?;
+            for (name, value) in &state.params {
+                form_data.append_with_str(name, value)?;
+            }
+        }
+        let opts = RequestInit::new();
+        opts.set_method("POST");
+        opts.set_body(&form_data);
+        let request = Request::new_with_str_and_init("/api/audiobooks", &opts)?;
+
+        let response: Response = JsFuture::from(window.fetch_with_request(&request))
+            .await?
+            .dyn_into()?;
+        if !response.ok() {
+            return Err(JsValue::from_str(&format!(
+                "Audiobook request failed with status: {}",
+                response.status()
+            )));
+        }
+        let status = JsFuture::from(response.json()?).await?;
+        let id = js_sys::Reflect::get(&status, &"id".into())?
+            .as_string()
+            .ok_or("the server's answer has no job id")?;
+        console_log!("Audiobook job {} started", id);
+        Ok(id)
+    }
+}
+
+/// Posts `body` as JSON to `url` and resolves to the JSON answer.
+async fn post_json(url: &str, body: &js_sys::Object) -> Result<JsValue, JsValue> {
+    let window = web_sys::window().ok_or("no window")?;
+    let headers = js_sys::Object::new();
+    js_sys::Reflect::set(&headers, &"Content-Type".into(), &"application/json".into())?;
+    let opts = RequestInit::new();
+    opts.set_method("POST");
+    opts.set_headers(&headers);
+    opts.set_body(&js_sys::JSON::stringify(body)?.into());
+    let request = Request::new_with_str_and_init(url, &opts)?;
+
+    let response: Response = JsFuture::from(window.fetch_with_request(&request))
+        .await?
+        .dyn_into()?;
+    if !response.ok() {
+        return Err(JsValue::from_str(&format!(
+            "Request to {url} failed with status: {}",
+            response.status()
+        )));
+    }
+    JsFuture::from(response.json()?).await
+}
+
+/// Resolves after `ms` milliseconds.
+async fn sleep(ms: i32) -> Result<(), JsValue> {
+    let window = web_sys::window().ok_or("no window")?;
+    let promise = js_sys::Promise::new(&mut |resolve, _| {
+        let _ = window.set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, ms);
+    });
+    JsFuture::from(promise).await.map(|_| ())
+}
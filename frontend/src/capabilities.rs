@@ -0,0 +1,187 @@
+use std::cell::RefCell;
+
+use wasm_bindgen::JsCast;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::*;
+
+/// Request size servers without `/api/info` accept (axum's default).
+const FALLBACK_MAX_TEXT_BYTES: u32 = 2 * 1024 * 1024;
+
+thread_local! {
+    static NEGOTIATED: RefCell<Option<Capabilities>> = const { RefCell::new(None) };
+}
+
+/// What the server can do, as `negotiate()` found out. Once negotiated,
+/// `AudioQueue` and `speak` adapt to it: texts the server would refuse as
+/// too long fail at once through `on_error`, and voices the namespace
+/// doesn't have fall back to the queue's description.
+#[wasm_bindgen]
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    version: Option<String>,
+    tts_formats: Vec<String>,
+    transcode_formats: Vec<String>,
+    streaming: bool,
+    webrtc: bool,
+    max_text_bytes: u32,
+    chunk_chars: Option<u32>,
+    /// None when the voice list couldn't be fetched.
+    voices: Option<Vec<String>>,
+    request_features: Vec<String>,
+    model_loaded: bool,
+}
+
+#[wasm_bindgen]
+impl Capabilities {
+    /// The server's version; undefined for servers older than `/api/info`.
+    #[wasm_bindgen(getter)]
+    pub fn version(&self) -> Option<String> {
+        self.version.clone()
+    }
+
+    /// `format`s `/api/tts` answers in.
+    #[wasm_bindgen(getter)]
+    pub fn tts_formats(&self) -> Vec<String> {
+        self.tts_formats.clone()
+    }
+
+    /// `format`s `/api/transcode` and `/api/audio/concat` produce.
+    #[wasm_bindgen(getter)]
+    pub fn transcode_formats(&self) -> Vec<String> {
+        self.transcode_formats.clone()
+    }
+
+    /// Whether `/api/tts` takes `stream=true`.
+    #[wasm_bindgen(getter)]
+    pub fn streaming(&self) -> bool {
+        self.streaming
+    }
+
+    /// Whether the server was built with WebRTC, for `RtcPlayer`.
+    #[wasm_bindgen(getter)]
+    pub fn webrtc(&self) -> bool {
+        self.webrtc
+    }
+
+    /// Longest text a request may carry, in UTF-8 bytes.
+    #[wasm_bindgen(getter)]
+    pub fn max_text_bytes(&self) -> u32 {
+        self.max_text_bytes
+    }
+
+    /// Length above which the server splits a prompt into chunks.
+    #[wasm_bindgen(getter)]
+    pub fn chunk_chars(&self) -> Option<u32> {
+        self.chunk_chars
+    }
+
+    /// The namespace's voice presets; empty when they couldn't be listed.
+    #[wasm_bindgen(getter)]
+    pub fn voices(&self) -> Vec<String> {
+        self.voices.clone().unwrap_or_default()
+    }
+
+    /// Experimental features requests may turn on.
+    #[wasm_bindgen(getter)]
+    pub fn request_features(&self) -> Vec<String> {
+        self.request_features.clone()
+    }
+
+    /// Whether the model had loaded when the capabilities were fetched.
+    #[wasm_bindgen(getter)]
+    pub fn model_loaded(&self) -> bool {
+        self.model_loaded
+    }
+
+    /// Whether `format` can be asked of `/api/tts` or `/api/transcode`.
+    #[wasm_bindgen]
+    pub fn supports_format(&self, format: &str) -> bool {
+        let format = format.trim().to_ascii_lowercase();
+        self.tts_formats.iter().chain(&self.transcode_formats).any(|f| *f == format)
+    }
+
+    /// Whether the namespace has the voice preset `name`. True when the
+    /// voices couldn't be listed, since the server may still have it.
+    #[wasm_bindgen]
+    pub fn has_voice(&self, name: &str) -> bool {
+        self.voices.as_ref().is_none_or(|voices| voices.iter().any(|v| v == name))
+    }
+}
+
+/// Fetches what the server can do from `/api/info` (and the voices from
+/// `/api/voices`), and remembers it for `AudioQueue` and `speak`. Servers
+/// without `/api/info` get conservative defaults: WAV only, no streaming.
+#[wasm_bindgen]
+pub async fn negotiate() -> Result<Capabilities, JsValue> {
+    let info = fetch_json("/api/info").await?;
+    let voices = fetch_json("/api/voices")
+        .await?
+        .map(|voices| js_sys::Object::keys(&voices.unchecked_into()).iter().filter_map(|k| k.as_string()).collect());
+    let capabilities = match info {
+        Some(info) => {
+            let formats = get(&info, "formats");
+            let limits = get(&info, "limits");
+            let build_features = strings(&get(&info, "build_features"));
+            Capabilities {
+                version: get(&info, "version").as_string(),
+                tts_formats: strings(&get(&formats, "tts")),
+                transcode_formats: strings(&get(&formats, "transcode")),
+                streaming: true,
+                webrtc: build_features.iter().any(|f| f == "webrtc"),
+                max_text_bytes: get(&limits, "max_tts_bytes").as_f64().map_or(FALLBACK_MAX_TEXT_BYTES, |n| n as u32),
+                chunk_chars: get(&limits, "chunk_chars").as_f64().map(|n| n as u32),
+                voices,
+                request_features: strings(&get(&info, "request_features")),
+                model_loaded: get(&info, "model_version").as_string().is_some(),
+            }
+        }
+        None => Capabilities {
+            version: None,
+            tts_formats: vec!["wav".to_string()],
+            transcode_formats: Vec::new(),
+            streaming: false,
+            webrtc: false,
+            max_text_bytes: FALLBACK_MAX_TEXT_BYTES,
+            chunk_chars: None,
+            voices,
+            request_features: Vec::new(),
+            model_loaded: false,
+        },
+    };
+    console_log!(
+        "negotiated server capabilities: version {:?}, formats {:?}, {} voices",
+        capabilities.version,
+        capabilities.tts_formats,
+        capabilities.voices.as_ref().map_or(0, Vec::len)
+    );
+    NEGOTIATED.with(|negotiated| *negotiated.borrow_mut() = Some(capabilities.clone()));
+    Ok(capabilities)
+}
+
+/// The capabilities from the last `negotiate()`, if it has run.
+#[wasm_bindgen]
+pub fn capabilities() -> Option<Capabilities> {
+    NEGOTIATED.with(|negotiated| negotiated.borrow().clone())
+}
+
+/// The JSON at `url`, or None when the server doesn't serve it.
+async fn fetch_json(url: &str) -> Result<Option<JsValue>, JsValue> {
+    let window = web_sys::window().ok_or("no window")?;
+    let response: Response = JsFuture::from(window.fetch_with_str(url)).await?.dyn_into()?;
+    if !response.ok() {
+        return Ok(None);
+    }
+    Ok(Some(JsFuture::from(response.json()?).await?))
+}
+
+fn get(value: &JsValue, key: &str) -> JsValue {
+    js_sys::Reflect::get(value, &key.into()).unwrap_or(JsValue::UNDEFINED)
+}
+
+fn strings(value: &JsValue) -> Vec<String> {
+    value
+        .dyn_ref::<js_sys::Array>()
+        .map(|array| array.iter().filter_map(|v| v.as_string()).collect())
+        .unwrap_or_default()
+}
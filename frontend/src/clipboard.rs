@@ -0,0 +1,51 @@
+use wasm_bindgen::JsCast;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Navigator, PermissionState, PermissionStatus};
+
+use crate::player::AudioQueue;
+use crate::reader::split_sentences;
+
+#[wasm_bindgen]
+impl AudioQueue {
+    /// Reads text from the clipboard and queues it sentence by sentence.
+    ///
+    /// Must be called from a user gesture (e.g. a click handler); browsers
+    /// reject clipboard reads otherwise. Returns the number of sentences queued.
+    #[wasm_bindgen]
+    pub async fn speak_clipboard(&self) -> Result<usize, JsValue> {
+        let navigator = web_sys::window().ok_or("no window")?.navigator();
+        if clipboard_permission(&navigator).await == Some(PermissionState::Denied) {
+            return Err(JsValue::from_str(
+                "Clipboard access was denied; allow it in the site settings to read copied text",
+            ));
+        }
+
+        let text = match JsFuture::from(navigator.clipboard().read_text()).await {
+            Ok(text) => text.as_string().unwrap_or_default(),
+            Err(err) => {
+                console_log!("Clipboard read failed: {:?}", err);
+                return Err(JsValue::from_str(
+                    "Could not read the clipboard (permission refused or page not focused)",
+                ));
+            }
+        };
+        if text.trim().is_empty() {
+            return Err(JsValue::from_str("Clipboard does not contain any text"));
+        }
+
+        let count = self.enqueue_all(split_sentences(&text));
+        console_log!("Clipboard queued {} sentences", count);
+        Ok(count)
+    }
+}
+
+/// State of the `clipboard-read` permission, or `None` where the browser
+/// cannot query it (Firefox and Safari only prompt on `readText`).
+async fn clipboard_permission(navigator: &Navigator) -> Option<PermissionState> {
+    let permissions = navigator.permissions().ok()?;
+    let query = js_sys::Object::new();
+    js_sys::Reflect::set(&query, &"name".into(), &"clipboard-read".into()).ok()?;
+    let status = JsFuture::from(permissions.query(&query).ok()?).await.ok()?;
+    Some(status.dyn_into::<PermissionStatus>().ok()?.state())
+}
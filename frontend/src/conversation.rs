@@ -0,0 +1,327 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use wasm_bindgen::JsCast;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::*;
+
+/// Marks exported sessions, like projects' `format`.
+const EXPORT_FORMAT: &str = "ttser-conversation";
+const EXPORT_VERSION: u32 = 1;
+
+/// Milliseconds of microphone audio the recorder hands over at a time, so
+/// a tab that crashes mid-session loses little.
+const TIMESLICE_MS: i32 = 1000;
+
+/// Records a voice-agent conversation for review: the microphone for the
+/// whole session, every reply spoken by the server, and a transcript with
+/// the time of each turn.
+///
+/// `start()` opens the microphone and starts the clock. `add_user_turn(text,
+/// audio)` logs what the user said (from speech recognition, with its own
+/// recording when there is one, e.g. from `PushToTalk`);
+/// `add_reply(text, audio)` logs a reply and its `/api/tts` audio `Blob`.
+/// `stop()` releases the microphone, and `export()` returns a ZIP `Blob`
+/// holding `session.json`, `microphone.<ext>` and `turns/<n>-<role>.<ext>`.
+/// Times in the transcript are seconds from `start()`, so they line up
+/// with the microphone recording.
+#[wasm_bindgen]
+pub struct ConversationRecorder {
+    state: Rc<RefCell<SessionState>>,
+}
+
+struct SessionState {
+    /// Milliseconds since the epoch; `None` until `start()`.
+    started_at: Option<f64>,
+    stopped_at: Option<f64>,
+    recorder: Option<MediaRecorder>,
+    stream: Option<MediaStream>,
+    microphone: js_sys::Array,
+    microphone_type: String,
+    /// Resolves once the recorder has handed over its last audio.
+    stopped: Option<js_sys::Promise>,
+    turns: Vec<Turn>,
+}
+
+struct Turn {
+    role: &'static str,
+    text: String,
+    at_secs: f64,
+    audio: Option<Blob>,
+}
+
+#[wasm_bindgen]
+impl ConversationRecorder {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> ConversationRecorder {
+        ConversationRecorder {
+            state: Rc::new(RefCell::new(SessionState {
+                started_at: None,
+                stopped_at: None,
+                recorder: None,
+                stream: None,
+                microphone: js_sys::Array::new(),
+                microphone_type: String::new(),
+                stopped: None,
+                turns: Vec::new(),
+            })),
+        }
+    }
+
+    /// Opens the microphone and starts the session, dropping anything
+    /// recorded before.
+    #[wasm_bindgen]
+    pub async fn start(&self) -> Result<(), JsValue> {
+        self.stop();
+        let media_devices = web_sys::window().ok_or("no window")?.navigator().media_devices()?;
+        let constraints = MediaStreamConstraints::new();
+        constraints.set_audio(&JsValue::from(true));
+        constraints.set_video(&JsValue::from(false));
+        let stream = JsFuture::from(media_devices.get_user_media_with_constraints(&constraints)?).await?;
+        let stream: MediaStream = stream.dyn_into()?;
+        let recorder = MediaRecorder::new_with_media_stream(&stream)?;
+
+        let chunks = js_sys::Array::new();
+        let kept = chunks.clone();
+        let ondataavailable = Closure::wrap(Box::new(move |event: BlobEvent| {
+            if let Some(blob) = event.data() {
+                kept.push(&blob);
+            }
+        }) as Box<dyn FnMut(BlobEvent)>);
+        recorder.set_ondataavailable(Some(ondataavailable.as_ref().unchecked_ref()));
+        ondataavailable.forget();
+
+        // The last audio arrives just before `stop`, which `export` waits for.
+        let stopped = js_sys::Promise::new(&mut |resolve, _| {
+            let onstop = Closure::once_into_js(move |_: Event| {
+                let _ = resolve.call0(&JsValue::NULL);
+            });
+            recorder.set_onstop(Some(onstop.unchecked_ref()));
+        });
+
+        recorder.start_with_time_slice(TIMESLICE_MS)?;
+        let mut state = self.state.borrow_mut();
+        state.started_at = Some(js_sys::Date::now());
+        state.stopped_at = None;
+        state.microphone = chunks;
+        state.microphone_type = recorder.mime_type();
+        state.recorder = Some(recorder);
+        state.stream = Some(stream);
+        state.stopped = Some(stopped);
+        state.turns.clear();
+        console_log!("Conversation recording started");
+        Ok(())
+    }
+
+    /// Logs something the user said, with its recording when there is one.
+    #[wasm_bindgen]
+    pub fn add_user_turn(&self, text: &str, audio: Option<Blob>) -> Result<(), JsValue> {
+        self.add_turn("user", text, audio)
+    }
+
+    /// Logs a reply and the audio it was spoken with.
+    #[wasm_bindgen]
+    pub fn add_reply(&self, text: &str, audio: Blob) -> Result<(), JsValue> {
+        self.add_turn("assistant", text, Some(audio))
+    }
+
+    /// Turns logged so far.
+    #[wasm_bindgen]
+    pub fn turn_count(&self) -> usize {
+        self.state.borrow().turns.len()
+    }
+
+    /// Ends the session and releases the microphone. What was recorded is
+    /// kept for `export`.
+    #[wasm_bindgen]
+    pub fn stop(&self) {
+        let mut state = self.state.borrow_mut();
+        if let Some(recorder) = state.recorder.take() {
+            let _ = recorder.stop();
+            state.stopped_at = Some(js_sys::Date::now());
+        }
+        if let Some(stream) = state.stream.take() {
+            for track in stream.get_tracks().iter() {
+                if let Ok(track) = track.dyn_into::<MediaStreamTrack>() {
+                    track.stop();
+                }
+            }
+        }
+    }
+
+    /// The session as a ZIP `Blob`, stopping it first if it is running.
+    #[wasm_bindgen]
+    pub async fn export(&self) -> Result<Blob, JsValue> {
+        self.stop();
+        let stopped = self.state.borrow().stopped.clone();
+        if let Some(stopped) = stopped {
+            JsFuture::from(stopped).await?;
+        }
+        let (started_at, stopped_at, microphone, microphone_type, turns) = {
+            let state = self.state.borrow();
+            let started_at = state.started_at.ok_or("the session was never started")?;
+            let turns: Vec<(&'static str, String, f64, Option<Blob>)> = state
+                .turns
+                .iter()
+                .map(|t| (t.role, t.text.clone(), t.at_secs, t.audio.clone()))
+                .collect();
+            (
+                started_at,
+                state.stopped_at.unwrap_or(started_at),
+                state.microphone.clone(),
+                state.microphone_type.clone(),
+                turns,
+            )
+        };
+
+        let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+        let log = js_sys::Object::new();
+        js_sys::Reflect::set(&log, &"format".into(), &EXPORT_FORMAT.into())?;
+        js_sys::Reflect::set(&log, &"version".into(), &EXPORT_VERSION.into())?;
+        let started = js_sys::Date::new(&started_at.into());
+        js_sys::Reflect::set(&log, &"started_at".into(), &started.to_iso_string().into())?;
+        js_sys::Reflect::set(&log, &"duration_secs".into(), &((stopped_at - started_at) / 1000.0).into())?;
+
+        if microphone.length() > 0 {
+            let options = BlobPropertyBag::new();
+            options.set_type(&microphone_type);
+            let recording = Blob::new_with_blob_sequence_and_options(&microphone, &options)?;
+            let name = format!("microphone.{}", extension(&microphone_type));
+            js_sys::Reflect::set(&log, &"microphone".into(), &name.as_str().into())?;
+            files.push((name, blob_bytes(&recording).await?));
+        }
+
+        let entries = js_sys::Array::new();
+        for (n, (role, text, at_secs, audio)) in turns.into_iter().enumerate() {
+            let entry = js_sys::Object::new();
+            js_sys::Reflect::set(&entry, &"role".into(), &role.into())?;
+            js_sys::Reflect::set(&entry, &"text".into(), &text.into())?;
+            js_sys::Reflect::set(&entry, &"at_secs".into(), &at_secs.into())?;
+            if let Some(audio) = audio {
+                let name = format!("turns/{:03}-{role}.{}", n + 1, extension(&audio.type_()));
+                js_sys::Reflect::set(&entry, &"audio".into(), &name.as_str().into())?;
+                files.push((name, blob_bytes(&audio).await?));
+            }
+            entries.push(&entry);
+        }
+        js_sys::Reflect::set(&log, &"turns".into(), &entries)?;
+        let json = js_sys::JSON::stringify_with_replacer_and_space(&log, &JsValue::NULL, &2.into())?
+            .as_string()
+            .ok_or("the session log could not be serialized")?;
+        files.insert(0, ("session.json".to_string(), json.into_bytes()));
+
+        let archive = zip(&files, &started);
+        let parts = js_sys::Array::new();
+        parts.push(&js_sys::Uint8Array::from(archive.as_slice()));
+        let options = BlobPropertyBag::new();
+        options.set_type("application/zip");
+        Blob::new_with_u8_array_sequence_and_options(&parts, &options)
+    }
+}
+
+impl Default for ConversationRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConversationRecorder {
+    fn add_turn(&self, role: &'static str, text: &str, audio: Option<Blob>) -> Result<(), JsValue> {
+        let mut state = self.state.borrow_mut();
+        let started_at = state.started_at.ok_or("the session is not started")?;
+        state.turns.push(Turn {
+            role,
+            text: text.to_string(),
+            at_secs: (js_sys::Date::now() - started_at) / 1000.0,
+            audio,
+        });
+        Ok(())
+    }
+}
+
+async fn blob_bytes(blob: &Blob) -> Result<Vec<u8>, JsValue> {
+    let buffer = JsFuture::from(blob.array_buffer()).await?;
+    Ok(js_sys::Uint8Array::new(&buffer).to_vec())
+}
+
+/// File extension for a recording's MIME type.
+fn extension(mime_type: &str) -> &'static str {
+    let essence = mime_type.split(';').next().unwrap_or("").trim();
+    match essence {
+        "audio/webm" | "video/webm" => "webm",
+        "audio/ogg" => "ogg",
+        "audio/mp4" | "video/mp4" => "m4a",
+        "audio/wav" | "audio/x-wav" | "audio/wave" => "wav",
+        "audio/mpeg" => "mp3",
+        "audio/pcm" => "pcm",
+        _ => "bin",
+    }
+}
+
+/// A ZIP archive of `files`, stored uncompressed (audio doesn't compress,
+/// and the log is small), all dated `modified`.
+fn zip(files: &[(String, Vec<u8>)], modified: &js_sys::Date) -> Vec<u8> {
+    let time = (modified.get_hours() << 11 | modified.get_minutes() << 5 | (modified.get_seconds() / 2)) as u16;
+    let year = modified.get_full_year().saturating_sub(1980);
+    let date = (year << 9 | (modified.get_month() + 1) << 5 | modified.get_date()) as u16;
+    let mut out = Vec::new();
+    let mut directory = Vec::new();
+    for (name, data) in files {
+        let offset = out.len() as u32;
+        let crc = crc32(data);
+        let size = data.len() as u32;
+        // Local file header.
+        out.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        out.extend_from_slice(&header_fields(time, date, crc, size, name));
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(data);
+        // Central directory entry.
+        directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        directory.extend_from_slice(&20u16.to_le_bytes());
+        directory.extend_from_slice(&header_fields(time, date, crc, size, name));
+        // Comment length, disk, internal and external attributes.
+        directory.extend_from_slice(&[0; 10]);
+        directory.extend_from_slice(&offset.to_le_bytes());
+        directory.extend_from_slice(name.as_bytes());
+    }
+    let directory_offset = out.len() as u32;
+    let count = files.len() as u16;
+    out.extend_from_slice(&directory);
+    out.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&count.to_le_bytes());
+    out.extend_from_slice(&count.to_le_bytes());
+    out.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    out.extend_from_slice(&directory_offset.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes());
+    out
+}
+
+/// The fields local headers and directory entries share, from the version
+/// needed to the extra field length: stored, with UTF-8 names.
+fn header_fields(time: u16, date: u16, crc: u32, size: u32, name: &str) -> Vec<u8> {
+    let mut fields = Vec::with_capacity(26);
+    fields.extend_from_slice(&20u16.to_le_bytes());
+    fields.extend_from_slice(&0x0800u16.to_le_bytes());
+    fields.extend_from_slice(&0u16.to_le_bytes());
+    fields.extend_from_slice(&time.to_le_bytes());
+    fields.extend_from_slice(&date.to_le_bytes());
+    fields.extend_from_slice(&crc.to_le_bytes());
+    fields.extend_from_slice(&size.to_le_bytes());
+    fields.extend_from_slice(&size.to_le_bytes());
+    fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    fields.extend_from_slice(&0u16.to_le_bytes());
+    fields
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { crc >> 1 ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,66 @@
+use wasm_bindgen::JsCast;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::*;
+
+/// Uploads a recording (any format the browser can decode, e.g. the `Blob`
+/// from `PushToTalk`) to `/api/describe` and resolves to the server's
+/// `{ description, analysis }` object.
+#[wasm_bindgen]
+pub async fn draft_description(recording: Blob) -> Result<JsValue, JsValue> {
+    let window = web_sys::window().ok_or("no window")?;
+
+    // MediaRecorder output is usually webm/ogg; the server only reads WAV.
+    let encoded = JsFuture::from(recording.array_buffer()).await?;
+    let context = AudioContext::new()?;
+    let decoded = JsFuture::from(context.decode_audio_data(&encoded.dyn_into()?)?).await;
+    let _ = context.close();
+    let buffer: AudioBuffer = decoded?.dyn_into()?;
+    let wav = encode_wav(&buffer.get_channel_data(0)?, buffer.sample_rate() as u32);
+
+    let parts = js_sys::Array::new();
+    parts.push(&js_sys::Uint8Array::from(wav.as_slice()));
+    let options = BlobPropertyBag::new();
+    options.set_type("audio/wav");
+    let wav_blob = Blob::new_with_u8_array_sequence_and_options(&parts, &options)?;
+
+    let form_data = FormData::new()?;
+    form_data.append_with_blob("audio", &wav_blob)?;
+    let opts = RequestInit::new();
+    opts.set_method("POST");
+    opts.set_body(&form_data);
+    let request = Request::new_with_str_and_init("/api/describe", &opts)?;
+
+    let response: Response = JsFuture::from(window.fetch_with_request(&request))
+        .await?
+        .dyn_into()?;
+    if !response.ok() {
+        return Err(JsValue::from_str(&format!(
+            "Describe request failed with status: {}",
+            response.status()
+        )));
+    }
+    JsFuture::from(response.json()?).await
+}
+
+/// 16-bit mono PCM WAV.
+pub(crate) fn encode_wav(samples: &[f32], sample_rate: u32) -> Vec<u8> {
+    let data_len = (samples.len() * 2) as u32;
+    let mut out = Vec::with_capacity(44 + data_len as usize);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + data_len).to_le_bytes());
+    out.extend_from_slice(b"WAVEfmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    out.extend_from_slice(&1u16.to_le_bytes()); // mono
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+    out.extend_from_slice(&2u16.to_le_bytes());
+    out.extend_from_slice(&16u16.to_le_bytes());
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_len.to_le_bytes());
+    for s in samples {
+        out.extend_from_slice(&((s.clamp(-1.0, 1.0) * 32767.0) as i16).to_le_bytes());
+    }
+    out
+}
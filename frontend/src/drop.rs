@@ -0,0 +1,232 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use wasm_bindgen::JsCast;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::{JsFuture, spawn_local};
+use web_sys::*;
+
+use crate::audiobook::Audiobook;
+
+/// Files read as text; anything else dropped is reported and skipped.
+const EXTENSIONS: &[&str] = &[".txt", ".md", ".markdown"];
+
+/// Class the element has while files are dragged over it.
+const DRAGOVER_CLASS: &str = "dragover";
+
+/// Drop zone turning `.txt` and `.md` files into audiobook jobs.
+///
+/// Each file dropped on the element (or passed to `submit_file`, e.g. from
+/// an `<input type="file">`) is read with the File API, and its estimate
+/// (`{ characters, words, chunks, duration_secs }`) passed to
+/// `on_estimate(name, estimate)`; the file is submitted unless that
+/// callback returns `false`. `on_progress(name, status)` then receives each
+/// job status as with `Audiobook.wait`, `on_done(name, status, audio_url)`
+/// the final one, and `on_error(name, message)` whatever went wrong. The
+/// file name without its extension becomes the book's title.
+#[wasm_bindgen]
+pub struct FileDrop {
+    target: HtmlElement,
+    state: Rc<RefCell<DropState>>,
+    listeners: Option<Listeners>,
+}
+
+struct DropState {
+    book: Audiobook,
+    on_estimate: Option<js_sys::Function>,
+    on_progress: Option<js_sys::Function>,
+    on_done: Option<js_sys::Function>,
+    on_error: Option<js_sys::Function>,
+}
+
+struct Listeners {
+    dragover: Closure<dyn FnMut(DragEvent)>,
+    dragleave: Closure<dyn FnMut(DragEvent)>,
+    drop: Closure<dyn FnMut(DragEvent)>,
+}
+
+#[wasm_bindgen]
+impl FileDrop {
+    /// A drop zone on the element with the given id; call `bind()` to
+    /// start accepting files.
+    #[wasm_bindgen(constructor)]
+    pub fn new(id: &str, description: &str) -> Result<FileDrop, JsValue> {
+        let document = web_sys::window()
+            .and_then(|w| w.document())
+            .ok_or("no document")?;
+        let target = document
+            .get_element_by_id(id)
+            .ok_or_else(|| JsValue::from_str(&format!("no element with id '{id}'")))?
+            .dyn_into::<HtmlElement>()?;
+        Ok(FileDrop {
+            target,
+            state: Rc::new(RefCell::new(DropState {
+                book: Audiobook::new(description),
+                on_estimate: None,
+                on_progress: None,
+                on_done: None,
+                on_error: None,
+            })),
+            listeners: None,
+        })
+    }
+
+    /// The client submitting the jobs, e.g. to set the voice or `format`.
+    #[wasm_bindgen(getter)]
+    pub fn book(&self) -> Audiobook {
+        self.state.borrow().book.clone()
+    }
+
+    #[wasm_bindgen]
+    pub fn on_estimate(&self, callback: js_sys::Function) {
+        self.state.borrow_mut().on_estimate = Some(callback);
+    }
+
+    #[wasm_bindgen]
+    pub fn on_progress(&self, callback: js_sys::Function) {
+        self.state.borrow_mut().on_progress = Some(callback);
+    }
+
+    #[wasm_bindgen]
+    pub fn on_done(&self, callback: js_sys::Function) {
+        self.state.borrow_mut().on_done = Some(callback);
+    }
+
+    #[wasm_bindgen]
+    pub fn on_error(&self, callback: js_sys::Function) {
+        self.state.borrow_mut().on_error = Some(callback);
+    }
+
+    /// Starts accepting files dropped on the element.
+    #[wasm_bindgen]
+    pub fn bind(&mut self) -> Result<(), JsValue> {
+        if self.listeners.is_some() {
+            return Ok(());
+        }
+
+        let target = self.target.clone();
+        let dragover = Closure::wrap(Box::new(move |event: DragEvent| {
+            // Without this the browser opens the file instead of dropping it.
+            event.prevent_default();
+            let _ = target.class_list().add_1(DRAGOVER_CLASS);
+        }) as Box<dyn FnMut(DragEvent)>);
+
+        let target = self.target.clone();
+        let dragleave = Closure::wrap(Box::new(move |_: DragEvent| {
+            let _ = target.class_list().remove_1(DRAGOVER_CLASS);
+        }) as Box<dyn FnMut(DragEvent)>);
+
+        let target = self.target.clone();
+        let state = self.state.clone();
+        let drop = Closure::wrap(Box::new(move |event: DragEvent| {
+            event.prevent_default();
+            let _ = target.class_list().remove_1(DRAGOVER_CLASS);
+            let Some(files) = event.data_transfer().and_then(|d| d.files()) else {
+                return;
+            };
+            for file in (0..files.length()).filter_map(|k| files.get(k)) {
+                spawn_local(process(state.clone(), file));
+            }
+        }) as Box<dyn FnMut(DragEvent)>);
+
+        self.target
+            .add_event_listener_with_callback("dragover", dragover.as_ref().unchecked_ref())?;
+        self.target
+            .add_event_listener_with_callback("dragleave", dragleave.as_ref().unchecked_ref())?;
+        self.target
+            .add_event_listener_with_callback("drop", drop.as_ref().unchecked_ref())?;
+        self.listeners = Some(Listeners {
+            dragover,
+            dragleave,
+            drop,
+        });
+        Ok(())
+    }
+
+    /// Stops accepting dropped files. Jobs already submitted keep running.
+    #[wasm_bindgen]
+    pub fn unbind(&mut self) {
+        if let Some(listeners) = self.listeners.take() {
+            let _ = self.target.remove_event_listener_with_callback(
+                "dragover",
+                listeners.dragover.as_ref().unchecked_ref(),
+            );
+            let _ = self.target.remove_event_listener_with_callback(
+                "dragleave",
+                listeners.dragleave.as_ref().unchecked_ref(),
+            );
+            let _ = self.target.remove_event_listener_with_callback(
+                "drop",
+                listeners.drop.as_ref().unchecked_ref(),
+            );
+        }
+        let _ = self.target.class_list().remove_1(DRAGOVER_CLASS);
+    }
+
+    /// Handles `file` as if it had been dropped.
+    #[wasm_bindgen]
+    pub fn submit_file(&self, file: File) {
+        spawn_local(process(self.state.clone(), file));
+    }
+}
+
+impl Drop for FileDrop {
+    fn drop(&mut self) {
+        self.unbind();
+    }
+}
+
+/// Estimates, submits and follows one file, reporting failures to
+/// `on_error`.
+async fn process(state: Rc<RefCell<DropState>>, file: File) {
+    let name = file.name();
+    if let Err(e) = render_file(&state, &file, &name).await {
+        let message = e
+            .as_string()
+            .or_else(|| e.dyn_ref::<js_sys::Error>().map(|e| e.message().into()))
+            .unwrap_or_else(|| format!("{e:?}"));
+        console_log!("Dropped file {} failed: {}", name, message);
+        let callback = state.borrow().on_error.clone();
+        if let Some(callback) = callback {
+            let _ = callback.call2(&JsValue::NULL, &name.into(), &message.into());
+        }
+    }
+}
+
+async fn render_file(state: &Rc<RefCell<DropState>>, file: &File, name: &str) -> Result<(), JsValue> {
+    let lower = name.to_lowercase();
+    let Some(extension) = EXTENSIONS.iter().find(|e| lower.ends_with(*e)) else {
+        return Err(JsValue::from_str(&format!("{name} is not a .txt or .md file")));
+    };
+    let title = &name[..name.len() - extension.len()];
+    let text = JsFuture::from(file.text())
+        .await?
+        .as_string()
+        .ok_or("the file could not be read as text")?;
+    let book = state.borrow().book.clone();
+
+    let estimate = book.estimate(&text).await?;
+    let callback = state.borrow().on_estimate.clone();
+    if let Some(callback) = callback {
+        let answer = callback.call2(&JsValue::NULL, &name.into(), &estimate)?;
+        if answer == JsValue::FALSE {
+            console_log!("Dropped file {} skipped", name);
+            return Ok(());
+        }
+    }
+
+    let id = book.submit(&[("text", &text), ("title", title)]).await?;
+    let status = book
+        .watch(&id, |status| {
+            if let Some(callback) = &state.borrow().on_progress {
+                let _ = callback.call2(&JsValue::NULL, &name.into(), status);
+            }
+        })
+        .await?;
+    let callback = state.borrow().on_done.clone();
+    if let Some(callback) = callback {
+        let url = book.audio_url(&id);
+        let _ = callback.call3(&JsValue::NULL, &name.into(), &status, &url.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,270 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use wasm_bindgen::JsCast;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::*;
+
+use crate::describe::encode_wav;
+use crate::player::fetch_speech;
+
+/// A long text rendered chunk by chunk, as the server splits it, so one
+/// chunk can be edited and rendered again without redoing the rest.
+///
+/// `load(text)` asks the server for the chunks and resolves to their texts;
+/// `render()` renders the chunks that have no audio yet, reporting
+/// `on_progress(done, total)`; `rerender(n, text)` replaces chunk `n`'s
+/// text and audio; `assembled()` is the whole WAV with the edits spliced
+/// in, and `chunk_audio(n)` a single chunk's. Set a `seed` param so edited
+/// chunks keep the voice of the others.
+#[wasm_bindgen]
+pub struct ChunkEditor {
+    state: Rc<RefCell<EditorState>>,
+}
+
+struct EditorState {
+    description: String,
+    params: Vec<(String, String)>,
+    chunks: Vec<Chunk>,
+    gap_secs: f64,
+    on_progress: Option<js_sys::Function>,
+}
+
+struct Chunk {
+    text: String,
+    /// Samples and their rate, once rendered.
+    audio: Option<(Vec<f32>, u32)>,
+}
+
+#[wasm_bindgen]
+impl ChunkEditor {
+    #[wasm_bindgen(constructor)]
+    pub fn new(description: &str) -> ChunkEditor {
+        ChunkEditor {
+            state: Rc::new(RefCell::new(EditorState {
+                description: description.to_string(),
+                params: Vec::new(),
+                chunks: Vec::new(),
+                gap_secs: 0.0,
+                on_progress: None,
+            })),
+        }
+    }
+
+    /// Applies to chunks rendered from now on.
+    #[wasm_bindgen]
+    pub fn set_description(&self, description: &str) {
+        self.state.borrow_mut().description = description.to_string();
+    }
+
+    /// Sets an extra form field (e.g. `seed`, `voice`) sent with every
+    /// chunk's request.
+    #[wasm_bindgen]
+    pub fn set_param(&self, name: &str, value: &str) {
+        let mut state = self.state.borrow_mut();
+        state.params.retain(|(n, _)| n != name);
+        state.params.push((name.to_string(), value.to_string()));
+    }
+
+    #[wasm_bindgen]
+    pub fn on_progress(&self, callback: js_sys::Function) {
+        self.state.borrow_mut().on_progress = Some(callback);
+    }
+
+    /// Splits `text` the way the server does, dropping any audio rendered
+    /// so far. Resolves to the chunk texts.
+    #[wasm_bindgen]
+    pub async fn load(&self, text: &str) -> Result<js_sys::Array, JsValue> {
+        let window = web_sys::window().ok_or("no window")?;
+        let body = js_sys::Object::new();
+        js_sys::Reflect::set(&body, &"text".into(), &text.into())?;
+        let headers = js_sys::Object::new();
+        js_sys::Reflect::set(&headers, &"Content-Type".into(), &"application/json".into())?;
+        let opts = RequestInit::new();
+        opts.set_method("POST");
+        opts.set_headers(&headers);
+        opts.set_body(&js_sys::JSON::stringify(&body)?.into());
+        let request = Request::new_with_str_and_init("/api/chunks", &opts)?;
+
+        let response: Response = JsFuture::from(window.fetch_with_request(&request))
+            .await?
+            .dyn_into()?;
+        if !response.ok() {
+            return Err(JsValue::from_str(&format!(
+                "Chunk request failed with status: {}",
+                response.status()
+            )));
+        }
+        let reply = JsFuture::from(response.json()?).await?;
+        let chunks: js_sys::Array = js_sys::Reflect::get(&reply, &"chunks".into())?.dyn_into()?;
+        let gap_secs = js_sys::Reflect::get(&reply, &"gap_secs".into())?
+            .as_f64()
+            .unwrap_or(0.0);
+        {
+            let mut state = self.state.borrow_mut();
+            state.gap_secs = gap_secs;
+            state.chunks = chunks
+                .iter()
+                .map(|text| Chunk {
+                    text: text.as_string().unwrap_or_default(),
+                    audio: None,
+                })
+                .collect();
+        }
+        console_log!("Chunk editor loaded {} chunks", chunks.length());
+        Ok(chunks)
+    }
+
+    /// The current chunk texts, edits included.
+    #[wasm_bindgen]
+    pub fn chunks(&self) -> js_sys::Array {
+        self.state
+            .borrow()
+            .chunks
+            .iter()
+            .map(|chunk| JsValue::from_str(&chunk.text))
+            .collect()
+    }
+
+    /// Whether chunk `n` has audio.
+    #[wasm_bindgen]
+    pub fn is_rendered(&self, n: usize) -> bool {
+        self.state
+            .borrow()
+            .chunks
+            .get(n)
+            .is_some_and(|chunk| chunk.audio.is_some())
+    }
+
+    /// Renders every chunk without audio, one after another.
+    #[wasm_bindgen]
+    pub async fn render(&self) -> Result<(), JsValue> {
+        let total = self.state.borrow().chunks.len();
+        for n in 0..total {
+            if !self.is_rendered(n) {
+                self.render_chunk(n).await?;
+            }
+            let callback = self.state.borrow().on_progress.clone();
+            if let Some(callback) = callback {
+                let _ = callback.call2(&JsValue::NULL, &(n + 1).into(), &total.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces chunk `n`'s text and renders it again.
+    #[wasm_bindgen]
+    pub async fn rerender(&self, n: usize, text: &str) -> Result<(), JsValue> {
+        {
+            let mut state = self.state.borrow_mut();
+            let chunk = state
+                .chunks
+                .get_mut(n)
+                .ok_or_else(|| JsValue::from_str(&format!("no chunk {n}")))?;
+            chunk.text = text.trim().to_string();
+            chunk.audio = None;
+        }
+        self.render_chunk(n).await?;
+        console_log!("Chunk {} rendered again", n);
+        Ok(())
+    }
+
+    /// Chunk `n`'s audio as a WAV `Blob`.
+    #[wasm_bindgen]
+    pub fn chunk_audio(&self, n: usize) -> Result<Blob, JsValue> {
+        let state = self.state.borrow();
+        let (samples, sample_rate) = state
+            .chunks
+            .get(n)
+            .and_then(|chunk| chunk.audio.as_ref())
+            .ok_or_else(|| JsValue::from_str(&format!("chunk {n} is not rendered")))?;
+        wav_blob(&encode_wav(samples, *sample_rate))
+    }
+
+    /// All chunks joined into one WAV `Blob`, with the server's gap between
+    /// them. Fails while any chunk is not rendered.
+    #[wasm_bindgen]
+    pub fn assembled(&self) -> Result<Blob, JsValue> {
+        let state = self.state.borrow();
+        let mut joined = Vec::new();
+        let mut rate = None;
+        for (n, chunk) in state.chunks.iter().enumerate() {
+            let (samples, sample_rate) = chunk
+                .audio
+                .as_ref()
+                .ok_or_else(|| JsValue::from_str(&format!("chunk {n} is not rendered")))?;
+            if n > 0 {
+                let gap = (state.gap_secs * *sample_rate as f64) as usize;
+                joined.extend(std::iter::repeat_n(0.0, gap));
+            }
+            joined.extend_from_slice(samples);
+            rate = Some(*sample_rate);
+        }
+        let sample_rate = rate.ok_or("there are no chunks")?;
+        wav_blob(&encode_wav(&joined, sample_rate))
+    }
+}
+
+impl ChunkEditor {
+    async fn render_chunk(&self, n: usize) -> Result<(), JsValue> {
+        let (text, description, params) = {
+            let state = self.state.borrow();
+            let chunk = state
+                .chunks
+                .get(n)
+                .ok_or_else(|| JsValue::from_str(&format!("no chunk {n}")))?;
+            (chunk.text.clone(), state.description.clone(), state.params.clone())
+        };
+        let wav = fetch_speech(&text, &description, &params).await?;
+        let audio = decode_wav(&js_sys::Uint8Array::new(&wav).to_vec())
+            .ok_or("the server's audio is not a 16-bit WAV")?;
+        // The chunk may have been edited again while this one rendered.
+        let mut state = self.state.borrow_mut();
+        if let Some(chunk) = state.chunks.get_mut(n).filter(|chunk| chunk.text == text) {
+            chunk.audio = Some(audio);
+        }
+        Ok(())
+    }
+}
+
+/// Samples and sample rate of a 16-bit mono PCM WAV, as the server writes.
+fn decode_wav(bytes: &[u8]) -> Option<(Vec<f32>, u32)> {
+    if bytes.get(..4)? != b"RIFF" || bytes.get(8..12)? != b"WAVE" {
+        return None;
+    }
+    let mut sample_rate = None;
+    let mut at = 12;
+    while let Some(header) = bytes.get(at..at + 8) {
+        let len = u32::from_le_bytes(header[4..8].try_into().ok()?) as usize;
+        let body = &bytes[at + 8..];
+        match &header[..4] {
+            b"fmt " => {
+                let bits = u16::from_le_bytes(body.get(14..16)?.try_into().ok()?);
+                if bits != 16 {
+                    return None;
+                }
+                sample_rate = Some(u32::from_le_bytes(body.get(4..8)?.try_into().ok()?));
+            }
+            b"data" => {
+                let data = &body[..len.min(body.len())];
+                let samples = data
+                    .chunks_exact(2)
+                    .map(|s| i16::from_le_bytes([s[0], s[1]]) as f32 / 32768.0)
+                    .collect();
+                return Some((samples, sample_rate?));
+            }
+            _ => {}
+        }
+        at += 8 + len + len % 2;
+    }
+    None
+}
+
+fn wav_blob(wav: &[u8]) -> Result<Blob, JsValue> {
+    let parts = js_sys::Array::new();
+    parts.push(&js_sys::Uint8Array::from(wav));
+    let options = BlobPropertyBag::new();
+    options.set_type("audio/wav");
+    Blob::new_with_u8_array_sequence_and_options(&parts, &options)
+}
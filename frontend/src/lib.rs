@@ -8,19 +8,56 @@ use std::cell::RefCell;
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(js_namespace = console)]
-    fn log(s: &str);
+    pub(crate) fn log(s: &str);
 }
 
 macro_rules! console_log {
-    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+    ($($t:tt)*) => ($crate::log(&format_args!($($t)*).to_string()))
 }
 
+mod a11y;
+mod audiobook;
+mod capabilities;
+mod clipboard;
+mod conversation;
+mod describe;
+mod drop;
+mod editor;
+mod player;
+mod project;
+mod ptt;
+mod reader;
+mod relay;
+mod rtc;
+mod speak;
+
+pub use a11y::{Announcer, KeyboardControls};
+pub use audiobook::Audiobook;
+pub use capabilities::{capabilities, negotiate, Capabilities};
+pub use conversation::ConversationRecorder;
+pub use describe::draft_description;
+pub use drop::FileDrop;
+pub use editor::ChunkEditor;
+pub use player::AudioQueue;
+pub use project::Project;
+pub use ptt::PushToTalk;
+pub use reader::ReadAloud;
+pub use relay::SpeechRelay;
+pub use rtc::RtcPlayer;
+pub use speak::{speak, speaker, stop_speaking};
+
 #[wasm_bindgen]
 pub struct AudioRecorder {
     media_recorder: Option<MediaRecorder>,
     audio_data: Rc<RefCell<Vec<u8>>>,
 }
 
+impl Default for AudioRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[wasm_bindgen]
 impl AudioRecorder {
     #[wasm_bindgen(constructor)]
@@ -37,9 +74,9 @@ impl AudioRecorder {
         let navigator = window.navigator();
         let media_devices = navigator.media_devices()?;
 
-        let mut constraints = MediaStreamConstraints::new();
-        constraints.audio(&JsValue::from(true));
-        constraints.video(&JsValue::from(false));
+        let constraints = MediaStreamConstraints::new();
+        constraints.set_audio(&JsValue::from(true));
+        constraints.set_video(&JsValue::from(false));
 
         let promise = media_devices.get_user_media_with_constraints(&constraints)?;
         let stream = JsFuture::from(promise).await?;
@@ -98,9 +135,9 @@ impl AudioRecorder {
         form_data.append_with_str("text", text)?;
         form_data.append_with_str("description", description)?;
 
-        let mut opts = RequestInit::new();
-        opts.method("POST");
-        opts.body(Some(&form_data));
+        let opts = RequestInit::new();
+        opts.set_method("POST");
+        opts.set_body(&form_data);
 
         let request = Request::new_with_str_and_init("/api/tts", &opts)?;
         
@@ -123,8 +160,8 @@ impl AudioRecorder {
             let blob_parts = js_sys::Array::new();
             blob_parts.push(&uint8_array);
             
-            let mut blob_options = web_sys::BlobPropertyBag::new();
-            blob_options.type_("audio/wav");
+            let blob_options = web_sys::BlobPropertyBag::new();
+            blob_options.set_type("audio/wav");
             let blob = Blob::new_with_u8_array_sequence_and_options(&blob_parts, &blob_options)?;
             
             let url = Url::create_object_url_with_blob(&blob)?;
@@ -0,0 +1,364 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use wasm_bindgen::JsCast;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::{JsFuture, spawn_local};
+use web_sys::*;
+
+use crate::capabilities::capabilities;
+
+/// Queue of texts that are synthesized one after another and scheduled on a
+/// single `AudioContext` timeline, so consecutive clips play without gaps.
+#[wasm_bindgen]
+#[derive(Clone)]
+pub struct AudioQueue {
+    inner: Rc<RefCell<QueueState>>,
+}
+
+struct QueueState {
+    context: AudioContext,
+    description: String,
+    params: Vec<(String, String)>,
+    pending: VecDeque<Queued>,
+    // Scheduled sources with their end time on the context clock.
+    sources: Vec<(AudioBufferSourceNode, f64)>,
+    next_start: f64,
+    running: bool,
+    // Bumped by `clear` so that a request already in flight is discarded.
+    epoch: u32,
+    on_error: Option<js_sys::Function>,
+    on_start: Option<js_sys::Function>,
+    playback_rate: f64,
+    // Texts queued since the last `clear`, numbering them for `on_start`.
+    queued: u32,
+    // Decoded clips by request, most recently used last.
+    cache: VecDeque<(String, AudioBuffer)>,
+    cache_size: usize,
+}
+
+struct Queued {
+    index: u32,
+    text: String,
+    /// A voice preset spoken instead of the queue's description.
+    voice: Option<String>,
+}
+
+#[wasm_bindgen]
+impl AudioQueue {
+    #[wasm_bindgen(constructor)]
+    pub fn new(description: &str) -> Result<AudioQueue, JsValue> {
+        Ok(AudioQueue {
+            inner: Rc::new(RefCell::new(QueueState {
+                context: AudioContext::new()?,
+                description: description.to_string(),
+                params: Vec::new(),
+                pending: VecDeque::new(),
+                sources: Vec::new(),
+                next_start: 0.0,
+                running: false,
+                epoch: 0,
+                on_error: None,
+                on_start: None,
+                playback_rate: 1.0,
+                queued: 0,
+                cache: VecDeque::new(),
+                cache_size: 0,
+            })),
+        })
+    }
+
+    #[wasm_bindgen]
+    pub fn set_description(&self, description: &str) {
+        self.inner.borrow_mut().description = description.to_string();
+    }
+
+    /// Sets an extra form field (e.g. `seed`, `temperature`) sent with every request.
+    #[wasm_bindgen]
+    pub fn set_param(&self, name: &str, value: &str) {
+        let mut state = self.inner.borrow_mut();
+        state.params.retain(|(n, _)| n != name);
+        state.params.push((name.to_string(), value.to_string()));
+    }
+
+    /// Called with the error message and the text whenever a request
+    /// fails; without it failures are only logged.
+    #[wasm_bindgen]
+    pub fn on_error(&self, callback: js_sys::Function) {
+        self.inner.borrow_mut().on_error = Some(callback);
+    }
+
+    /// Called with a text's number (counting from 0 at the last `clear`)
+    /// when its audio starts playing.
+    #[wasm_bindgen]
+    pub fn on_start(&self, callback: js_sys::Function) {
+        self.inner.borrow_mut().on_start = Some(callback);
+    }
+
+    /// Speed of clips scheduled from now on, between 0.5 and 2. Like a tape,
+    /// the pitch changes with it.
+    #[wasm_bindgen(setter)]
+    pub fn set_playback_rate(&self, rate: f64) {
+        self.inner.borrow_mut().playback_rate = rate.clamp(0.5, 2.0);
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn playback_rate(&self) -> f64 {
+        self.inner.borrow().playback_rate
+    }
+
+    /// Keeps up to `clips` decoded clips, so repeated texts play without a
+    /// new request. Off (0) by default.
+    #[wasm_bindgen]
+    pub fn set_cache_size(&self, clips: usize) {
+        let mut state = self.inner.borrow_mut();
+        state.cache_size = clips;
+        let excess = state.cache.len().saturating_sub(clips);
+        state.cache.drain(..excess);
+    }
+
+    #[wasm_bindgen]
+    pub fn enqueue(&self, text: &str) {
+        self.enqueue_voice(text, None);
+    }
+
+    /// Queues `text` spoken by the voice preset `voice` instead of the
+    /// queue's description.
+    #[wasm_bindgen]
+    pub fn enqueue_voice(&self, text: &str, voice: Option<String>) {
+        let text = text.trim();
+        if text.is_empty() {
+            return;
+        }
+        let mut state = self.inner.borrow_mut();
+        let index = state.next_index();
+        state.pending.push_back(Queued {
+            index,
+            text: text.to_string(),
+            voice: voice.filter(|v| !v.is_empty()),
+        });
+        drop(state);
+        self.pump();
+    }
+
+    /// Number of texts still waiting to be synthesized.
+    #[wasm_bindgen]
+    pub fn pending(&self) -> usize {
+        self.inner.borrow().pending.len()
+    }
+
+    /// Whether audio is still scheduled to play on the context clock.
+    #[wasm_bindgen]
+    pub fn is_playing(&self) -> bool {
+        let state = self.inner.borrow();
+        state.next_start > state.context.current_time()
+    }
+
+    /// Drops pending texts and stops everything that is already scheduled.
+    #[wasm_bindgen]
+    pub fn clear(&self) {
+        let mut state = self.inner.borrow_mut();
+        state.pending.clear();
+        state.epoch = state.epoch.wrapping_add(1);
+        state.queued = 0;
+        for (source, _) in state.sources.drain(..) {
+            let _ = AudioScheduledSourceNode::stop(&source);
+        }
+        state.next_start = 0.0;
+    }
+}
+
+impl AudioQueue {
+    /// Nothing pending, in flight or playing.
+    pub(crate) fn is_idle(&self) -> bool {
+        let state = self.inner.borrow();
+        !state.running && state.next_start <= state.context.current_time()
+    }
+
+    pub(crate) fn enqueue_all<I: IntoIterator<Item = String>>(&self, texts: I) -> usize {
+        let mut count = 0;
+        {
+            let mut state = self.inner.borrow_mut();
+            for text in texts {
+                if !text.trim().is_empty() {
+                    let index = state.next_index();
+                    state.pending.push_back(Queued { index, text, voice: None });
+                    count += 1;
+                }
+            }
+        }
+        self.pump();
+        count
+    }
+
+    fn pump(&self) {
+        {
+            let mut state = self.inner.borrow_mut();
+            if state.running {
+                return;
+            }
+            state.running = true;
+            // Browsers keep a context suspended until a user gesture resumes it.
+            let _ = state.context.resume();
+        }
+
+        let queue = self.clone();
+        spawn_local(async move {
+            loop {
+                let next = {
+                    let mut state = queue.inner.borrow_mut();
+                    state.pending.pop_front().map(|queued| {
+                        let mut params = state.params.clone();
+                        // A preset brings its own description; one the server
+                        // doesn't have falls back to the queue's.
+                        let voice = queued.voice.filter(|voice| {
+                            let known = capabilities().is_none_or(|caps| caps.has_voice(voice));
+                            if !known {
+                                console_log!("The server has no voice {voice:?}, using the description");
+                            }
+                            known
+                        });
+                        let description = match voice {
+                            Some(voice) => {
+                                params.push(("voice".to_string(), voice));
+                                String::new()
+                            }
+                            None => state.description.clone(),
+                        };
+                        (queued.index, queued.text, description, params, state.epoch)
+                    })
+                };
+                let Some((index, text, description, params, epoch)) = next else {
+                    break;
+                };
+                if let Err(err) = queue.play_next(index, &text, &description, &params, epoch).await {
+                    console_log!("Queued TTS request failed: {:?}", err);
+                    let callback = queue.inner.borrow().on_error.clone();
+                    if let Some(callback) = callback {
+                        let message = err.as_string().unwrap_or_else(|| format!("{err:?}"));
+                        let _ = callback.call2(&JsValue::NULL, &message.into(), &text.as_str().into());
+                    }
+                }
+            }
+            queue.inner.borrow_mut().running = false;
+        });
+    }
+
+    async fn play_next(
+        &self,
+        index: u32,
+        text: &str,
+        description: &str,
+        params: &[(String, String)],
+        epoch: u32,
+    ) -> Result<(), JsValue> {
+        if let Some(caps) = capabilities()
+            && text.len() > caps.max_text_bytes() as usize
+        {
+            return Err(JsValue::from_str(&format!(
+                "text is longer than the server accepts ({} bytes)",
+                caps.max_text_bytes()
+            )));
+        }
+        let key = format!("{description}\u{0}{params:?}\u{0}{text}");
+        let cached = {
+            let mut state = self.inner.borrow_mut();
+            let hit = state.cache.iter().position(|(k, _)| *k == key);
+            hit.and_then(|n| state.cache.remove(n))
+        };
+        let buffer = match cached {
+            Some((_, buffer)) => buffer,
+            None => {
+                let array_buffer = fetch_speech(text, description, params).await?;
+                let context = self.inner.borrow().context.clone();
+                let decoded = JsFuture::from(context.decode_audio_data(&array_buffer)?).await?;
+                decoded.dyn_into()?
+            }
+        };
+
+        let mut state = self.inner.borrow_mut();
+        if state.cache_size > 0 {
+            if state.cache.len() >= state.cache_size {
+                state.cache.pop_front();
+            }
+            state.cache.push_back((key, buffer.clone()));
+        }
+        if state.epoch != epoch {
+            return Ok(());
+        }
+        let now = state.context.current_time();
+        state.sources.retain(|(_, end)| *end > now);
+
+        let source = state.context.create_buffer_source()?;
+        source.set_buffer(Some(&buffer));
+        source.playback_rate().set_value(state.playback_rate as f32);
+        source.connect_with_audio_node(&state.context.destination())?;
+        let start = state.next_start.max(now);
+        source.start_with_when(start)?;
+        state.next_start = start + buffer.duration() / state.playback_rate;
+        let end = state.next_start;
+        state.sources.push((source, end));
+        if state.on_start.is_some() {
+            self.notify_start(index, epoch, ((start - now) * 1000.0) as i32)?;
+        }
+        Ok(())
+    }
+
+    /// Calls `on_start` with `index` after `delay_ms`, unless the queue was
+    /// cleared in between.
+    fn notify_start(&self, index: u32, epoch: u32, delay_ms: i32) -> Result<(), JsValue> {
+        let queue = self.clone();
+        let notify = Closure::once_into_js(move || {
+            let state = queue.inner.borrow();
+            let callback = state.on_start.clone().filter(|_| state.epoch == epoch);
+            drop(state);
+            if let Some(callback) = callback {
+                let _ = callback.call1(&JsValue::NULL, &index.into());
+            }
+        });
+        web_sys::window()
+            .ok_or("no window")?
+            .set_timeout_with_callback_and_timeout_and_arguments_0(notify.unchecked_ref(), delay_ms)?;
+        Ok(())
+    }
+}
+
+impl QueueState {
+    fn next_index(&mut self) -> u32 {
+        self.queued += 1;
+        self.queued - 1
+    }
+}
+
+/// Posts `text` to `/api/tts` and returns the WAV body.
+pub(crate) async fn fetch_speech(
+    text: &str,
+    description: &str,
+    params: &[(String, String)],
+) -> Result<js_sys::ArrayBuffer, JsValue> {
+    let window = web_sys::window().ok_or("no window")?;
+
+    let form_data = FormData::new()?;
+    form_data.append_with_str("text", text)?;
+    form_data.append_with_str("description", description)?;
+    for (name, value) in params {
+        form_data.append_with_str(name, value)?;
+    }
+
+    let opts = RequestInit::new();
+    opts.set_method("POST");
+    opts.set_body(&form_data);
+    let request = Request::new_with_str_and_init("/api/tts", &opts)?;
+
+    let response = JsFuture::from(window.fetch_with_request(&request)).await?;
+    let response: Response = response.dyn_into()?;
+    if !response.ok() {
+        return Err(JsValue::from_str(&format!(
+            "TTS request failed with status: {}",
+            response.status()
+        )));
+    }
+    let array_buffer = JsFuture::from(response.array_buffer()?).await?;
+    array_buffer.dyn_into()
+}
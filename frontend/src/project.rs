@@ -0,0 +1,520 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use wasm_bindgen::JsCast;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::*;
+
+const DB_NAME: &str = "ttser";
+const DB_VERSION: u32 = 1;
+const STORE: &str = "projects";
+
+/// Marks exported projects, so `from_json` can tell them from other JSON.
+const EXPORT_FORMAT: &str = "ttser-project";
+const EXPORT_VERSION: u32 = 1;
+
+/// A multi-clip piece, such as a dialogue, kept in IndexedDB across
+/// sessions.
+///
+/// A project is an ordered list of text segments, each with its own voice
+/// preset (or a description; the project's `description` otherwise) and,
+/// once rendered, the history id of its clip. Editing a segment's text or
+/// voice drops its clip. `render()` generates the segments that have no
+/// clip, reporting `on_progress(done, total)`; `assemble()` has the server
+/// join the clips in order, `gap_secs` apart, into one WAV `Blob`. `save()`
+/// stores the project, `Project.load(id)`, `Project.list()` and
+/// `Project.remove(id)` reach the stored ones, and `to_json()` /
+/// `Project.from_json(json)` move a project between browsers.
+#[wasm_bindgen]
+pub struct Project {
+    state: Rc<RefCell<ProjectData>>,
+    on_progress: Rc<RefCell<Option<js_sys::Function>>>,
+}
+
+#[derive(Clone)]
+struct ProjectData {
+    id: String,
+    name: String,
+    description: String,
+    gap_secs: f64,
+    /// Milliseconds since the epoch.
+    created_at: f64,
+    updated_at: f64,
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone)]
+struct Segment {
+    id: String,
+    text: String,
+    voice: Option<String>,
+    description: Option<String>,
+    clip_id: Option<String>,
+}
+
+#[wasm_bindgen]
+impl Project {
+    #[wasm_bindgen(constructor)]
+    pub fn new(name: &str) -> Project {
+        let now = js_sys::Date::now();
+        Project::from_data(ProjectData {
+            id: new_id(),
+            name: name.to_string(),
+            description: String::new(),
+            gap_secs: 0.3,
+            created_at: now,
+            updated_at: now,
+            segments: Vec::new(),
+        })
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn id(&self) -> String {
+        self.state.borrow().id.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn name(&self) -> String {
+        self.state.borrow().name.clone()
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_name(&self, name: &str) {
+        self.edit(|data| data.name = name.to_string());
+    }
+
+    /// Used by segments that have neither a voice nor a description.
+    #[wasm_bindgen(getter)]
+    pub fn description(&self) -> String {
+        self.state.borrow().description.clone()
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_description(&self, description: &str) {
+        self.edit(|data| data.description = description.to_string());
+    }
+
+    /// Silence between segments in the assembled piece.
+    #[wasm_bindgen(getter)]
+    pub fn gap_secs(&self) -> f64 {
+        self.state.borrow().gap_secs
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_gap_secs(&self, gap_secs: f64) {
+        self.edit(|data| data.gap_secs = gap_secs.max(0.0));
+    }
+
+    #[wasm_bindgen]
+    pub fn on_progress(&self, callback: js_sys::Function) {
+        *self.on_progress.borrow_mut() = Some(callback);
+    }
+
+    /// The segments in order: `[{ id, text, voice, description, clip_id }]`.
+    #[wasm_bindgen]
+    pub fn segments(&self) -> Result<js_sys::Array, JsValue> {
+        self.state.borrow().segments.iter().map(Segment::to_js).collect()
+    }
+
+    /// Appends a segment spoken by preset `voice` (or in the project's
+    /// description when unset). Returns its id.
+    #[wasm_bindgen]
+    pub fn add_segment(&self, text: &str, voice: Option<String>) -> String {
+        let id = new_id();
+        let segment = Segment {
+            id: id.clone(),
+            text: text.trim().to_string(),
+            voice: voice.filter(|v| !v.is_empty()),
+            description: None,
+            clip_id: None,
+        };
+        self.edit(|data| data.segments.push(segment));
+        id
+    }
+
+    #[wasm_bindgen]
+    pub fn set_text(&self, id: &str, text: &str) -> Result<(), JsValue> {
+        self.edit_segment(id, |segment| {
+            segment.text = text.trim().to_string();
+            segment.clip_id = None;
+        })
+    }
+
+    /// Gives the segment a preset, a description of its own, or (with
+    /// neither) the project's description.
+    #[wasm_bindgen]
+    pub fn set_voice(&self, id: &str, voice: Option<String>, description: Option<String>) -> Result<(), JsValue> {
+        self.edit_segment(id, |segment| {
+            segment.voice = voice.filter(|v| !v.is_empty());
+            segment.description = description.filter(|d| !d.is_empty());
+            segment.clip_id = None;
+        })
+    }
+
+    /// Records a clip rendered elsewhere (e.g. picked from the history) as
+    /// the segment's audio, or drops it with `None`.
+    #[wasm_bindgen]
+    pub fn set_clip(&self, id: &str, clip_id: Option<String>) -> Result<(), JsValue> {
+        self.edit_segment(id, |segment| segment.clip_id = clip_id)
+    }
+
+    #[wasm_bindgen]
+    pub fn remove_segment(&self, id: &str) -> Result<(), JsValue> {
+        let at = self.index_of(id)?;
+        self.edit(|data| {
+            data.segments.remove(at);
+        });
+        Ok(())
+    }
+
+    /// Moves a segment to position `index` (clamped to the end).
+    #[wasm_bindgen]
+    pub fn move_segment(&self, id: &str, index: usize) -> Result<(), JsValue> {
+        let at = self.index_of(id)?;
+        self.edit(|data| {
+            let segment = data.segments.remove(at);
+            let index = index.min(data.segments.len());
+            data.segments.insert(index, segment);
+        });
+        Ok(())
+    }
+
+    /// Renders every segment without a clip through `/api/tts`, one after
+    /// another.
+    #[wasm_bindgen]
+    pub async fn render(&self) -> Result<(), JsValue> {
+        let total = self.state.borrow().segments.len();
+        for n in 0..total {
+            let pending = {
+                let data = self.state.borrow();
+                data.segments
+                    .get(n)
+                    .filter(|segment| segment.clip_id.is_none())
+                    .map(|segment| (segment.clone(), data.description.clone()))
+            };
+            if let Some((segment, project_description)) = pending {
+                let clip_id = render_segment(&segment, &project_description).await?;
+                // The segment may have been edited while it rendered.
+                let mut data = self.state.borrow_mut();
+                if let Some(current) = data
+                    .segments
+                    .iter_mut()
+                    .find(|s| s.id == segment.id && s.text == segment.text && s.voice == segment.voice)
+                {
+                    current.clip_id = Some(clip_id);
+                }
+                data.updated_at = js_sys::Date::now();
+            }
+            let callback = self.on_progress.borrow().clone();
+            if let Some(callback) = callback {
+                let _ = callback.call2(&JsValue::NULL, &(n + 1).into(), &total.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// The segments' clips joined in order as a WAV `Blob`. Fails while
+    /// any segment is not rendered.
+    #[wasm_bindgen]
+    pub async fn assemble(&self) -> Result<Blob, JsValue> {
+        let body = {
+            let data = self.state.borrow();
+            let clips = js_sys::Array::new();
+            for (n, segment) in data.segments.iter().enumerate() {
+                let clip_id = segment
+                    .clip_id
+                    .as_ref()
+                    .ok_or_else(|| JsValue::from_str(&format!("segment {} is not rendered", n + 1)))?;
+                let clip = js_sys::Object::new();
+                js_sys::Reflect::set(&clip, &"id".into(), &clip_id.into())?;
+                js_sys::Reflect::set(&clip, &"gap_secs".into(), &data.gap_secs.into())?;
+                clips.push(&clip);
+            }
+            if clips.length() == 0 {
+                return Err("the project has no segments".into());
+            }
+            let body = js_sys::Object::new();
+            js_sys::Reflect::set(&body, &"clips".into(), &clips)?;
+            js_sys::Reflect::set(&body, &"format".into(), &"wav".into())?;
+            body
+        };
+
+        let window = web_sys::window().ok_or("no window")?;
+        let headers = js_sys::Object::new();
+        js_sys::Reflect::set(&headers, &"Content-Type".into(), &"application/json".into())?;
+        let opts = RequestInit::new();
+        opts.set_method("POST");
+        opts.set_headers(&headers);
+        opts.set_body(&js_sys::JSON::stringify(&body)?.into());
+        let request = Request::new_with_str_and_init("/api/audio/concat", &opts)?;
+        let response: Response = JsFuture::from(window.fetch_with_request(&request))
+            .await?
+            .dyn_into()?;
+        if !response.ok() {
+            return Err(JsValue::from_str(&format!(
+                "Concat request failed with status: {}",
+                response.status()
+            )));
+        }
+        JsFuture::from(response.blob()?).await?.dyn_into()
+    }
+
+    /// The project as JSON, for `from_json` in another browser.
+    #[wasm_bindgen]
+    pub fn to_json(&self) -> Result<String, JsValue> {
+        let value = self.state.borrow().to_js()?;
+        js_sys::Reflect::set(&value, &"format".into(), &EXPORT_FORMAT.into())?;
+        js_sys::Reflect::set(&value, &"version".into(), &EXPORT_VERSION.into())?;
+        js_sys::JSON::stringify_with_replacer_and_space(&value, &JsValue::NULL, &2.into())?
+            .as_string()
+            .ok_or_else(|| "the project could not be serialized".into())
+    }
+
+    /// A project exported with `to_json`. It keeps its id, so saving it
+    /// replaces a stored copy of the same project.
+    #[wasm_bindgen]
+    pub fn from_json(json: &str) -> Result<Project, JsValue> {
+        let value = js_sys::JSON::parse(json)?;
+        if get_string(&value, "format").as_deref() != Some(EXPORT_FORMAT) {
+            return Err("not an exported project".into());
+        }
+        let version = js_sys::Reflect::get(&value, &"version".into())?.as_f64().unwrap_or(0.0);
+        if version > EXPORT_VERSION as f64 {
+            return Err(JsValue::from_str(&format!(
+                "the project was exported by a newer version (format {version})"
+            )));
+        }
+        Ok(Project::from_data(ProjectData::from_js(&value)?))
+    }
+
+    /// Stores the project in IndexedDB, replacing any earlier save.
+    #[wasm_bindgen]
+    pub async fn save(&self) -> Result<(), JsValue> {
+        let value = self.state.borrow().to_js()?;
+        let store = open_store(IdbTransactionMode::Readwrite).await?;
+        wait(&store.put(&value)?).await?;
+        console_log!("Saved project {}", self.id());
+        Ok(())
+    }
+
+    /// The stored project with id `id`.
+    #[wasm_bindgen]
+    pub async fn load(id: String) -> Result<Project, JsValue> {
+        let store = open_store(IdbTransactionMode::Readonly).await?;
+        let value = wait(&store.get(&id.as_str().into())?).await?;
+        if value.is_undefined() {
+            return Err(JsValue::from_str(&format!("no project {id}")));
+        }
+        Ok(Project::from_data(ProjectData::from_js(&value)?))
+    }
+
+    /// Every stored project, most recently changed first: `[{ id, name,
+    /// segments, updated_at }]` with `segments` the number of segments.
+    #[wasm_bindgen]
+    pub async fn list() -> Result<js_sys::Array, JsValue> {
+        let store = open_store(IdbTransactionMode::Readonly).await?;
+        let all: js_sys::Array = wait(&store.get_all()?).await?.dyn_into()?;
+        let mut projects = all
+            .iter()
+            .map(|value| ProjectData::from_js(&value))
+            .collect::<Result<Vec<_>, _>>()?;
+        projects.sort_by(|a, b| b.updated_at.total_cmp(&a.updated_at));
+        projects
+            .iter()
+            .map(|project| {
+                let summary = js_sys::Object::new();
+                js_sys::Reflect::set(&summary, &"id".into(), &project.id.as_str().into())?;
+                js_sys::Reflect::set(&summary, &"name".into(), &project.name.as_str().into())?;
+                js_sys::Reflect::set(&summary, &"segments".into(), &project.segments.len().into())?;
+                js_sys::Reflect::set(&summary, &"updated_at".into(), &project.updated_at.into())?;
+                Ok::<JsValue, JsValue>(summary.into())
+            })
+            .collect()
+    }
+
+    /// Deletes the stored project with id `id`. Its clips stay in the
+    /// server's history.
+    #[wasm_bindgen]
+    pub async fn remove(id: String) -> Result<(), JsValue> {
+        let store = open_store(IdbTransactionMode::Readwrite).await?;
+        wait(&store.delete(&id.as_str().into())?).await?;
+        Ok(())
+    }
+}
+
+impl Project {
+    fn from_data(data: ProjectData) -> Project {
+        Project {
+            state: Rc::new(RefCell::new(data)),
+            on_progress: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    fn edit(&self, change: impl FnOnce(&mut ProjectData)) {
+        let mut data = self.state.borrow_mut();
+        change(&mut data);
+        data.updated_at = js_sys::Date::now();
+    }
+
+    fn edit_segment(&self, id: &str, change: impl FnOnce(&mut Segment)) -> Result<(), JsValue> {
+        let at = self.index_of(id)?;
+        self.edit(|data| change(&mut data.segments[at]));
+        Ok(())
+    }
+
+    fn index_of(&self, id: &str) -> Result<usize, JsValue> {
+        self.state
+            .borrow()
+            .segments
+            .iter()
+            .position(|segment| segment.id == id)
+            .ok_or_else(|| JsValue::from_str(&format!("no segment {id}")))
+    }
+}
+
+impl ProjectData {
+    fn to_js(&self) -> Result<js_sys::Object, JsValue> {
+        let value = js_sys::Object::new();
+        js_sys::Reflect::set(&value, &"id".into(), &self.id.as_str().into())?;
+        js_sys::Reflect::set(&value, &"name".into(), &self.name.as_str().into())?;
+        js_sys::Reflect::set(&value, &"description".into(), &self.description.as_str().into())?;
+        js_sys::Reflect::set(&value, &"gap_secs".into(), &self.gap_secs.into())?;
+        js_sys::Reflect::set(&value, &"created_at".into(), &self.created_at.into())?;
+        js_sys::Reflect::set(&value, &"updated_at".into(), &self.updated_at.into())?;
+        let segments = self.segments.iter().map(Segment::to_js).collect::<Result<js_sys::Array, _>>()?;
+        js_sys::Reflect::set(&value, &"segments".into(), &segments)?;
+        Ok(value)
+    }
+
+    fn from_js(value: &JsValue) -> Result<ProjectData, JsValue> {
+        let number = |key: &str| js_sys::Reflect::get(value, &key.into()).ok().and_then(|v| v.as_f64());
+        let segments: js_sys::Array = js_sys::Reflect::get(value, &"segments".into())?
+            .dyn_into()
+            .map_err(|_| JsValue::from_str("a project needs a segments list"))?;
+        Ok(ProjectData {
+            id: get_string(value, "id").ok_or("a project needs an id")?,
+            name: get_string(value, "name").unwrap_or_default(),
+            description: get_string(value, "description").unwrap_or_default(),
+            gap_secs: number("gap_secs").unwrap_or(0.0).max(0.0),
+            created_at: number("created_at").unwrap_or(0.0),
+            updated_at: number("updated_at").unwrap_or(0.0),
+            segments: segments
+                .iter()
+                .map(|segment| Segment::from_js(&segment))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl Segment {
+    fn to_js(&self) -> Result<JsValue, JsValue> {
+        let optional = |value: &Option<String>| value.as_deref().map_or(JsValue::NULL, JsValue::from_str);
+        let value = js_sys::Object::new();
+        js_sys::Reflect::set(&value, &"id".into(), &self.id.as_str().into())?;
+        js_sys::Reflect::set(&value, &"text".into(), &self.text.as_str().into())?;
+        js_sys::Reflect::set(&value, &"voice".into(), &optional(&self.voice))?;
+        js_sys::Reflect::set(&value, &"description".into(), &optional(&self.description))?;
+        js_sys::Reflect::set(&value, &"clip_id".into(), &optional(&self.clip_id))?;
+        Ok(value.into())
+    }
+
+    fn from_js(value: &JsValue) -> Result<Segment, JsValue> {
+        Ok(Segment {
+            id: get_string(value, "id").ok_or("a segment needs an id")?,
+            text: get_string(value, "text").unwrap_or_default(),
+            voice: get_string(value, "voice"),
+            description: get_string(value, "description"),
+            clip_id: get_string(value, "clip_id"),
+        })
+    }
+}
+
+/// Generates `segment` and resolves to its clip's history id.
+async fn render_segment(segment: &Segment, project_description: &str) -> Result<String, JsValue> {
+    let window = web_sys::window().ok_or("no window")?;
+    let form_data = FormData::new()?;
+    form_data.append_with_str("text", &segment.text)?;
+    let description = segment.description.as_deref().unwrap_or(match segment.voice {
+        Some(_) => "",
+        None => project_description,
+    });
+    form_data.append_with_str("description", description)?;
+    if let Some(voice) = &segment.voice {
+        form_data.append_with_str("voice", voice)?;
+    }
+
+    let opts = RequestInit::new();
+    opts.set_method("POST");
+    opts.set_body(&form_data);
+    let request = Request::new_with_str_and_init("/api/tts", &opts)?;
+    let response: Response = JsFuture::from(window.fetch_with_request(&request))
+        .await?
+        .dyn_into()?;
+    if !response.ok() {
+        return Err(JsValue::from_str(&format!(
+            "TTS request failed with status: {}",
+            response.status()
+        )));
+    }
+    response
+        .headers()
+        .get("x-clip-id")?
+        .ok_or_else(|| "the server sent no clip id".into())
+}
+
+fn get_string(value: &JsValue, key: &str) -> Option<String> {
+    js_sys::Reflect::get(value, &key.into()).ok()?.as_string()
+}
+
+fn new_id() -> String {
+    let random = (js_sys::Math::random() * u32::MAX as f64) as u32;
+    format!("{:x}-{random:08x}", js_sys::Date::now() as u64)
+}
+
+/// The projects store, in a transaction of `mode`.
+async fn open_store(mode: IdbTransactionMode) -> Result<IdbObjectStore, JsValue> {
+    let window = web_sys::window().ok_or("no window")?;
+    let factory = window.indexed_db()?.ok_or("IndexedDB is not available")?;
+    let request = factory.open_with_u32(DB_NAME, DB_VERSION)?;
+    let upgrade = Closure::<dyn FnMut(IdbVersionChangeEvent)>::new(move |event: IdbVersionChangeEvent| {
+        let Some(db) = event
+            .target()
+            .and_then(|target| target.dyn_into::<IdbOpenDbRequest>().ok())
+            .and_then(|request| request.result().ok())
+            .and_then(|db| db.dyn_into::<IdbDatabase>().ok())
+        else {
+            return;
+        };
+        if !db.object_store_names().contains(STORE) {
+            let params = IdbObjectStoreParameters::new();
+            params.set_key_path(&"id".into());
+            let _ = db.create_object_store_with_optional_parameters(STORE, &params);
+        }
+    });
+    request.set_onupgradeneeded(Some(upgrade.as_ref().unchecked_ref()));
+    let db = wait(&request).await;
+    request.set_onupgradeneeded(None);
+    let db: IdbDatabase = db?.dyn_into()?;
+    db.transaction_with_str_and_mode(STORE, mode)?.object_store(STORE)
+}
+
+/// Resolves to the request's result once it succeeds.
+async fn wait(request: &IdbRequest) -> Result<JsValue, JsValue> {
+    let mut settle = None;
+    let promise = js_sys::Promise::new(&mut |resolve, reject| settle = Some((resolve, reject)));
+    let (resolve, reject) = settle.ok_or("the promise did not start")?;
+    let target = request.clone();
+    let onsuccess = Closure::<dyn FnMut(Event)>::new(move |_: Event| {
+        let _ = resolve.call1(&JsValue::NULL, &target.result().unwrap_or(JsValue::UNDEFINED));
+    });
+    let onerror = Closure::<dyn FnMut(Event)>::new(move |_: Event| {
+        let _ = reject.call1(&JsValue::NULL, &"IndexedDB request failed".into());
+    });
+    request.set_onsuccess(Some(onsuccess.as_ref().unchecked_ref()));
+    request.set_onerror(Some(onerror.as_ref().unchecked_ref()));
+    let result = JsFuture::from(promise).await;
+    request.set_onsuccess(None);
+    request.set_onerror(None);
+    result
+}
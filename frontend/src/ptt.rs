@@ -0,0 +1,256 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use wasm_bindgen::JsCast;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::{JsFuture, spawn_local};
+use web_sys::*;
+
+/// Hold-to-record binding on a global key.
+///
+/// While the key (a `KeyboardEvent.code`, e.g. `"Space"`) is held the
+/// microphone records; on release the recording is delivered to the
+/// `on_recorded` callback as a `Blob`. The `on_state` callback receives
+/// `"arming"` (waiting for microphone access), `"recording"` and `"idle"`.
+#[wasm_bindgen]
+pub struct PushToTalk {
+    state: Rc<RefCell<PttState>>,
+    listeners: Option<Listeners>,
+}
+
+struct PttState {
+    key: String,
+    held: bool,
+    recorder: Option<MediaRecorder>,
+    stream: Option<MediaStream>,
+    chunks: js_sys::Array,
+    on_state: Option<js_sys::Function>,
+    on_recorded: Option<js_sys::Function>,
+}
+
+struct Listeners {
+    keydown: Closure<dyn FnMut(KeyboardEvent)>,
+    keyup: Closure<dyn FnMut(KeyboardEvent)>,
+}
+
+#[wasm_bindgen]
+impl PushToTalk {
+    #[wasm_bindgen(constructor)]
+    pub fn new(key: &str) -> PushToTalk {
+        PushToTalk {
+            state: Rc::new(RefCell::new(PttState {
+                key: key.to_string(),
+                held: false,
+                recorder: None,
+                stream: None,
+                chunks: js_sys::Array::new(),
+                on_state: None,
+                on_recorded: None,
+            })),
+            listeners: None,
+        }
+    }
+
+    #[wasm_bindgen]
+    pub fn set_key(&self, key: &str) {
+        self.state.borrow_mut().key = key.to_string();
+    }
+
+    #[wasm_bindgen]
+    pub fn on_state(&self, callback: js_sys::Function) {
+        self.state.borrow_mut().on_state = Some(callback);
+    }
+
+    #[wasm_bindgen]
+    pub fn on_recorded(&self, callback: js_sys::Function) {
+        self.state.borrow_mut().on_recorded = Some(callback);
+    }
+
+    /// Starts listening for the key on `window`.
+    #[wasm_bindgen]
+    pub fn bind(&mut self) -> Result<(), JsValue> {
+        if self.listeners.is_some() {
+            return Ok(());
+        }
+        let window = web_sys::window().ok_or("no window")?;
+
+        let state = self.state.clone();
+        let keydown = Closure::wrap(Box::new(move |event: KeyboardEvent| {
+            if event.code() != state.borrow().key || typing_target(&event) {
+                return;
+            }
+            event.prevent_default();
+            if event.repeat() || state.borrow().held {
+                return;
+            }
+            state.borrow_mut().held = true;
+            start(state.clone());
+        }) as Box<dyn FnMut(KeyboardEvent)>);
+
+        let state = self.state.clone();
+        let keyup = Closure::wrap(Box::new(move |event: KeyboardEvent| {
+            if event.code() != state.borrow().key || !state.borrow().held {
+                return;
+            }
+            event.prevent_default();
+            state.borrow_mut().held = false;
+            stop(&state);
+        }) as Box<dyn FnMut(KeyboardEvent)>);
+
+        window.add_event_listener_with_callback("keydown", keydown.as_ref().unchecked_ref())?;
+        window.add_event_listener_with_callback("keyup", keyup.as_ref().unchecked_ref())?;
+        self.listeners = Some(Listeners { keydown, keyup });
+        Ok(())
+    }
+
+    /// Removes the key listeners and stops any recording in progress.
+    #[wasm_bindgen]
+    pub fn unbind(&mut self) {
+        if let (Some(listeners), Some(window)) = (self.listeners.take(), web_sys::window()) {
+            let _ = window.remove_event_listener_with_callback(
+                "keydown",
+                listeners.keydown.as_ref().unchecked_ref(),
+            );
+            let _ = window.remove_event_listener_with_callback(
+                "keyup",
+                listeners.keyup.as_ref().unchecked_ref(),
+            );
+        }
+        self.state.borrow_mut().held = false;
+        stop(&self.state);
+    }
+
+    #[wasm_bindgen]
+    pub fn is_recording(&self) -> bool {
+        self.state.borrow().recorder.is_some()
+    }
+}
+
+impl Drop for PushToTalk {
+    fn drop(&mut self) {
+        self.unbind();
+    }
+}
+
+/// Keys typed into form fields must keep working, so those are not captured.
+pub(crate) fn typing_target(event: &KeyboardEvent) -> bool {
+    let Some(element) = event
+        .target()
+        .and_then(|t| t.dyn_into::<HtmlElement>().ok())
+    else {
+        return false;
+    };
+    matches!(element.tag_name().as_str(), "INPUT" | "TEXTAREA" | "SELECT")
+        || element.is_content_editable()
+}
+
+fn notify(state: &Rc<RefCell<PttState>>, value: &str) {
+    let callback = state.borrow().on_state.clone();
+    if let Some(callback) = callback {
+        let _ = callback.call1(&JsValue::NULL, &JsValue::from_str(value));
+    }
+}
+
+fn start(state: Rc<RefCell<PttState>>) {
+    notify(&state, "arming");
+    spawn_local(async move {
+        match open_recorder(&state).await {
+            Ok((recorder, stream)) => {
+                // The key may have been released while the permission prompt was up.
+                if !state.borrow().held {
+                    stop_tracks(&stream);
+                    notify(&state, "idle");
+                    return;
+                }
+                if let Err(err) = recorder.start() {
+                    console_log!("Push-to-talk failed to start: {:?}", err);
+                    stop_tracks(&stream);
+                    notify(&state, "idle");
+                    return;
+                }
+                let mut s = state.borrow_mut();
+                s.recorder = Some(recorder);
+                s.stream = Some(stream);
+                drop(s);
+                notify(&state, "recording");
+            }
+            Err(err) => {
+                console_log!("Push-to-talk could not open the microphone: {:?}", err);
+                notify(&state, "idle");
+            }
+        }
+    });
+}
+
+fn stop(state: &Rc<RefCell<PttState>>) {
+    let recorder = state.borrow_mut().recorder.take();
+    if let Some(recorder) = recorder {
+        // Delivery happens in the recorder's `onstop` handler.
+        let _ = recorder.stop();
+    }
+}
+
+async fn open_recorder(
+    state: &Rc<RefCell<PttState>>,
+) -> Result<(MediaRecorder, MediaStream), JsValue> {
+    let media_devices = web_sys::window()
+        .ok_or("no window")?
+        .navigator()
+        .media_devices()?;
+    let constraints = MediaStreamConstraints::new();
+    constraints.set_audio(&JsValue::from(true));
+    constraints.set_video(&JsValue::from(false));
+    let stream =
+        JsFuture::from(media_devices.get_user_media_with_constraints(&constraints)?).await?;
+    let stream: MediaStream = stream.dyn_into()?;
+    let recorder = MediaRecorder::new_with_media_stream(&stream)?;
+
+    state.borrow_mut().chunks = js_sys::Array::new();
+
+    let chunks_state = state.clone();
+    let ondataavailable = Closure::wrap(Box::new(move |event: BlobEvent| {
+        if let Some(blob) = event.data() {
+            chunks_state.borrow().chunks.push(&blob);
+        }
+    }) as Box<dyn FnMut(BlobEvent)>);
+    recorder.set_ondataavailable(Some(ondataavailable.as_ref().unchecked_ref()));
+    ondataavailable.forget();
+
+    let stop_state = state.clone();
+    let mime_type = recorder.mime_type();
+    let onstop = Closure::wrap(Box::new(move |_: Event| {
+        let (chunks, stream, callback) = {
+            let mut s = stop_state.borrow_mut();
+            (s.chunks.clone(), s.stream.take(), s.on_recorded.clone())
+        };
+        if let Some(stream) = stream {
+            stop_tracks(&stream);
+        }
+        notify(&stop_state, "idle");
+        let options = BlobPropertyBag::new();
+        options.set_type(&mime_type);
+        match (
+            Blob::new_with_blob_sequence_and_options(&chunks, &options),
+            callback,
+        ) {
+            (Ok(blob), Some(callback)) => {
+                let _ = callback.call1(&JsValue::NULL, &blob);
+            }
+            (Err(err), _) => console_log!("Push-to-talk could not assemble recording: {:?}", err),
+            _ => {}
+        }
+    }) as Box<dyn FnMut(Event)>);
+    recorder.set_onstop(Some(onstop.as_ref().unchecked_ref()));
+    onstop.forget();
+
+    Ok((recorder, stream))
+}
+
+/// Releases the microphone so the browser's recording indicator goes away.
+fn stop_tracks(stream: &MediaStream) {
+    for track in stream.get_tracks().iter() {
+        if let Ok(track) = track.dyn_into::<MediaStreamTrack>() {
+            track.stop();
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use wasm_bindgen::JsCast;
+use wasm_bindgen::prelude::*;
+use web_sys::*;
+
+use crate::player::AudioQueue;
+
+/// Longest sentence sent as a single request; longer runs are split at a
+/// comma or space so one request never covers a whole paragraph.
+const MAX_SENTENCE_CHARS: usize = 300;
+
+/// `localStorage` key the playback rate is kept under.
+const RATE_KEY: &str = "ttser.reader.rate";
+
+/// Blocks `read_element` reads one by one, so the page can scroll to each.
+const BLOCKS: &str = "p, li, h1, h2, h3, h4, h5, h6, blockquote, pre, td, dd";
+
+/// Reads text from the page aloud, one sentence per TTS request.
+///
+/// In reader mode the page follows along: while `auto_scroll` is on, each
+/// block of text is scrolled into view as its first sentence plays, and
+/// `next()`, `previous()` and `skip(n)` move by sentence. `playback_rate`
+/// is kept in `localStorage` for the next visit.
+#[wasm_bindgen]
+pub struct ReadAloud {
+    queue: AudioQueue,
+    reading: Rc<RefCell<Reading>>,
+}
+
+/// What is being read, sentence by sentence.
+struct Reading {
+    sentences: Vec<(String, Option<Element>)>,
+    /// The sentence the queue's first text is.
+    offset: usize,
+    /// The sentence playing, once one has started.
+    current: Option<usize>,
+    auto_scroll: bool,
+    on_sentence: Option<js_sys::Function>,
+}
+
+#[wasm_bindgen]
+impl ReadAloud {
+    #[wasm_bindgen(constructor)]
+    pub fn new(description: &str) -> Result<ReadAloud, JsValue> {
+        let queue = AudioQueue::new(description)?;
+        if let Some(rate) = stored_rate() {
+            queue.set_playback_rate(rate);
+        }
+        let reading = Rc::new(RefCell::new(Reading {
+            sentences: Vec::new(),
+            offset: 0,
+            current: None,
+            auto_scroll: true,
+            on_sentence: None,
+        }));
+        let started = reading.clone();
+        let on_start = Closure::<dyn FnMut(u32)>::new(move |index: u32| sentence_started(&started, index));
+        queue.on_start(on_start.into_js_value().unchecked_into());
+        Ok(ReadAloud { queue, reading })
+    }
+
+    /// The underlying queue, e.g. to set the voice description or params.
+    #[wasm_bindgen(getter)]
+    pub fn queue(&self) -> AudioQueue {
+        self.queue.clone()
+    }
+
+    /// Reads the current text selection. Returns the number of sentences queued.
+    #[wasm_bindgen]
+    pub fn read_selection(&self) -> Result<usize, JsValue> {
+        let window = web_sys::window().ok_or("no window")?;
+        let (text, element) = match window.get_selection()? {
+            Some(selection) => (
+                String::from(selection.to_string()),
+                selection
+                    .anchor_node()
+                    .and_then(|node| node.dyn_ref::<Element>().cloned().or_else(|| node.parent_element())),
+            ),
+            None => (String::new(), None),
+        };
+        Ok(self.read(sentences_in(&text, element.as_ref())))
+    }
+
+    /// Reads the rendered text of the element with the given id.
+    #[wasm_bindgen]
+    pub fn read_element(&self, id: &str) -> Result<usize, JsValue> {
+        let document = web_sys::window()
+            .and_then(|w| w.document())
+            .ok_or("no document")?;
+        let element = document
+            .get_element_by_id(id)
+            .ok_or_else(|| JsValue::from_str(&format!("no element with id '{id}'")))?;
+        // Innermost blocks only, so nested ones (a `p` in an `li`) aren't read twice.
+        let blocks = element.query_selector_all(BLOCKS)?;
+        let mut sentences = Vec::new();
+        for n in 0..blocks.length() {
+            let Some(block) = blocks.item(n).and_then(|node| node.dyn_into::<Element>().ok()) else {
+                continue;
+            };
+            if block.query_selector(BLOCKS)?.is_none() {
+                sentences.extend(sentences_in(&rendered_text(&block), Some(&block)));
+            }
+        }
+        if sentences.is_empty() {
+            sentences = sentences_in(&rendered_text(&element), Some(&element));
+        }
+        Ok(self.read(sentences))
+    }
+
+    #[wasm_bindgen]
+    pub fn read_text(&self, text: &str) -> usize {
+        self.read(sentences_in(text, None))
+    }
+
+    #[wasm_bindgen]
+    pub fn stop(&self) {
+        self.queue.clear();
+        let mut reading = self.reading.borrow_mut();
+        reading.sentences.clear();
+        reading.current = None;
+    }
+
+    /// Called with the sentence number and text as each sentence starts.
+    #[wasm_bindgen]
+    pub fn on_sentence(&self, callback: js_sys::Function) {
+        self.reading.borrow_mut().on_sentence = Some(callback);
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn auto_scroll(&self) -> bool {
+        self.reading.borrow().auto_scroll
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_auto_scroll(&self, on: bool) {
+        self.reading.borrow_mut().auto_scroll = on;
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn playback_rate(&self) -> f64 {
+        self.queue.playback_rate()
+    }
+
+    /// Sets the reading speed (0.5 to 2) and remembers it. What is playing
+    /// restarts from the current sentence at the new speed.
+    #[wasm_bindgen(setter)]
+    pub fn set_playback_rate(&self, rate: f64) {
+        self.queue.set_playback_rate(rate);
+        if let Some(storage) = web_sys::window().and_then(|w| w.local_storage().ok().flatten()) {
+            let _ = storage.set_item(RATE_KEY, &self.queue.playback_rate().to_string());
+        }
+        if self.queue.is_playing() {
+            self.skip(0);
+        }
+    }
+
+    /// The sentence playing, counting from 0.
+    #[wasm_bindgen(getter)]
+    pub fn current(&self) -> Option<usize> {
+        self.reading.borrow().current
+    }
+
+    #[wasm_bindgen]
+    pub fn next(&self) -> bool {
+        self.skip(1)
+    }
+
+    #[wasm_bindgen]
+    pub fn previous(&self) -> bool {
+        self.skip(-1)
+    }
+
+    /// Restarts reading `by` sentences after (or, if negative, before) the
+    /// one playing. Returns `false` when there is nothing to read there.
+    #[wasm_bindgen]
+    pub fn skip(&self, by: i32) -> bool {
+        let from = {
+            let reading = self.reading.borrow();
+            let current = reading.current.unwrap_or(reading.offset) as i64;
+            let from = (current + by as i64).max(0) as usize;
+            if from >= reading.sentences.len() {
+                return false;
+            }
+            from
+        };
+        self.queue.clear();
+        self.queue_from(from);
+        true
+    }
+}
+
+impl ReadAloud {
+    /// Queues `sentences` after what is still being read.
+    fn read(&self, sentences: Vec<(String, Option<Element>)>) -> usize {
+        if self.queue.is_idle() {
+            self.queue.clear();
+            let mut reading = self.reading.borrow_mut();
+            reading.sentences.clear();
+            reading.offset = 0;
+            reading.current = None;
+        }
+        let texts: Vec<String> = sentences.iter().map(|(text, _)| text.clone()).collect();
+        self.reading.borrow_mut().sentences.extend(sentences);
+        let count = self.queue.enqueue_all(texts);
+        console_log!("Read-aloud queued {} sentences", count);
+        count
+    }
+
+    fn queue_from(&self, from: usize) {
+        let texts: Vec<String> = {
+            let mut reading = self.reading.borrow_mut();
+            reading.offset = from;
+            reading.current = None;
+            reading.sentences[from..].iter().map(|(text, _)| text.clone()).collect()
+        };
+        self.queue.enqueue_all(texts);
+    }
+}
+
+fn sentence_started(reading: &Rc<RefCell<Reading>>, index: u32) {
+    let (n, text, element, previous, callback) = {
+        let mut reading = reading.borrow_mut();
+        let n = reading.offset + index as usize;
+        let Some((text, element)) = reading.sentences.get(n).cloned() else {
+            return;
+        };
+        let previous = reading.current.and_then(|p| reading.sentences.get(p)).and_then(|(_, e)| e.clone());
+        reading.current = Some(n);
+        let element = element.filter(|_| reading.auto_scroll);
+        (n, text, element, previous, reading.on_sentence.clone())
+    };
+    // Scroll once per block, not on every sentence in it.
+    if let Some(element) = element.filter(|e| previous.as_ref() != Some(e)) {
+        let options = ScrollIntoViewOptions::new();
+        options.set_behavior(ScrollBehavior::Smooth);
+        options.set_block(ScrollLogicalPosition::Center);
+        element.scroll_into_view_with_scroll_into_view_options(&options);
+    }
+    if let Some(callback) = callback {
+        let _ = callback.call2(&JsValue::NULL, &n.into(), &text.into());
+    }
+}
+
+fn sentences_in(text: &str, element: Option<&Element>) -> Vec<(String, Option<Element>)> {
+    split_sentences(text)
+        .into_iter()
+        .map(|sentence| (sentence, element.cloned()))
+        .collect()
+}
+
+/// `innerText` skips hidden nodes and respects layout line breaks.
+fn rendered_text(element: &Element) -> String {
+    match element.dyn_ref::<HtmlElement>() {
+        Some(html) => html.inner_text(),
+        None => element.text_content().unwrap_or_default(),
+    }
+}
+
+fn stored_rate() -> Option<f64> {
+    let storage = web_sys::window()?.local_storage().ok()??;
+    storage.get_item(RATE_KEY).ok()??.parse().ok()
+}
+
+/// Splits text into sentences on terminal punctuation and blank lines,
+/// collapsing whitespace and breaking overlong sentences.
+pub(crate) fn split_sentences(text: &str) -> Vec<String> {
+    let mut sentences = Vec::new();
+    let mut current = String::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            let mut newlines = usize::from(c == '\n');
+            while let Some(&next) = chars.peek() {
+                if !next.is_whitespace() {
+                    break;
+                }
+                newlines += usize::from(next == '\n');
+                chars.next();
+            }
+            if newlines >= 2 {
+                push_sentence(&mut sentences, &mut current);
+            } else if !current.is_empty() {
+                current.push(' ');
+            }
+            continue;
+        }
+
+        current.push(c);
+        if matches!(c, '.' | '!' | '?' | '…') {
+            // Keep closing quotes and brackets with the sentence they end.
+            while let Some(&next) = chars.peek() {
+                if !matches!(next, '"' | '\'' | ')' | ']' | '”' | '’' | '.' | '!' | '?') {
+                    break;
+                }
+                current.push(next);
+                chars.next();
+            }
+            if chars.peek().is_none_or(|next| next.is_whitespace()) {
+                push_sentence(&mut sentences, &mut current);
+            }
+        } else if current.len() >= MAX_SENTENCE_CHARS {
+            let cut = current
+                .rfind(", ")
+                .map(|i| i + 1)
+                .or_else(|| current.rfind(' '))
+                .unwrap_or(current.len());
+            let rest = current.split_off(cut);
+            push_sentence(&mut sentences, &mut current);
+            current = rest.trim_start().to_string();
+        }
+    }
+    push_sentence(&mut sentences, &mut current);
+    sentences
+}
+
+fn push_sentence(sentences: &mut Vec<String>, current: &mut String) {
+    let sentence = current.trim();
+    if sentence.chars().any(char::is_alphanumeric) {
+        sentences.push(sentence.to_string());
+    }
+    current.clear();
+}
@@ -0,0 +1,427 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use wasm_bindgen::JsCast;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::{JsFuture, spawn_local};
+use web_sys::*;
+
+/// Speaks text as an LLM writes it, over the server's `/api/tts/relay`
+/// WebSocket.
+///
+/// `connect()` opens the socket; `push(token)` adds text, which is held
+/// until it completes a sentence so a token stream doesn't become one
+/// message per token; `end()` speaks the rest. `pipe(tokens)` does all of
+/// that for an async iterable of strings, `pipe_sse(response)` for a
+/// `fetch` response streaming server-sent events, as LLM APIs answer. Each
+/// sentence plays as soon as its audio arrives, back to back with the one
+/// before, so speech starts with the first sentence rather than the reply.
+#[wasm_bindgen]
+pub struct SpeechRelay {
+    state: Rc<RefCell<RelayState>>,
+}
+
+struct RelayState {
+    description: String,
+    params: Vec<(String, String)>,
+    on_sentence: Option<js_sys::Function>,
+    on_error: Option<js_sys::Function>,
+    link: Option<Link>,
+    /// Text pushed since the last complete sentence.
+    buffer: String,
+    context: Option<AudioContext>,
+    next_start: f64,
+    sources: Vec<(AudioBufferSourceNode, f64)>,
+    /// The `sentence` event waiting for its audio.
+    announced: Option<(u32, String)>,
+    /// Audio waiting to be decoded, in the order it arrived.
+    undecoded: VecDeque<(u32, String, js_sys::ArrayBuffer)>,
+    decoding: bool,
+}
+
+/// An open socket and what keeps its handlers alive.
+struct Link {
+    socket: WebSocket,
+    /// Resolves once the server has spoken everything, or the socket closed.
+    finished: js_sys::Promise,
+    /// Resolves `finished`; taken by whichever comes first.
+    resolver: Rc<RefCell<Option<js_sys::Function>>>,
+    _onmessage: Closure<dyn FnMut(MessageEvent)>,
+    _onclose: Closure<dyn FnMut(Event)>,
+}
+
+#[wasm_bindgen]
+impl SpeechRelay {
+    #[wasm_bindgen(constructor)]
+    pub fn new(description: &str) -> SpeechRelay {
+        SpeechRelay {
+            state: Rc::new(RefCell::new(RelayState {
+                description: description.to_string(),
+                params: Vec::new(),
+                on_sentence: None,
+                on_error: None,
+                link: None,
+                buffer: String::new(),
+                context: None,
+                next_start: 0.0,
+                sources: Vec::new(),
+                announced: None,
+                undecoded: VecDeque::new(),
+                decoding: false,
+            })),
+        }
+    }
+
+    #[wasm_bindgen]
+    pub fn set_description(&self, description: &str) {
+        self.state.borrow_mut().description = description.to_string();
+    }
+
+    /// Sets an extra `/api/tts` field (e.g. `voice`, `seed`) every sentence
+    /// is rendered with, from the next `connect`.
+    #[wasm_bindgen]
+    pub fn set_param(&self, name: &str, value: &str) {
+        let mut state = self.state.borrow_mut();
+        state.params.retain(|(n, _)| n != name);
+        state.params.push((name.to_string(), value.to_string()));
+    }
+
+    /// Called with a sentence's number and text when its audio starts.
+    #[wasm_bindgen]
+    pub fn on_sentence(&self, callback: js_sys::Function) {
+        self.state.borrow_mut().on_sentence = Some(callback);
+    }
+
+    /// Called with the error code and message when a sentence isn't
+    /// spoken; the relay goes on with the next one.
+    #[wasm_bindgen]
+    pub fn on_error(&self, callback: js_sys::Function) {
+        self.state.borrow_mut().on_error = Some(callback);
+    }
+
+    /// Opens the socket, closing any previous one. Call it from a user
+    /// gesture so the browser allows playback.
+    #[wasm_bindgen]
+    pub async fn connect(&self) -> Result<(), JsValue> {
+        self.stop();
+        let url = relay_url(&self.state.borrow())?;
+        let socket = WebSocket::new(&url)?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+        {
+            let mut state = self.state.borrow_mut();
+            if state.context.is_none() {
+                state.context = Some(AudioContext::new()?);
+            }
+            if let Some(context) = &state.context {
+                // Browsers keep a context suspended until a user gesture resumes it.
+                let _ = context.resume();
+            }
+        }
+
+        let resolver: Rc<RefCell<Option<js_sys::Function>>> = Rc::default();
+        let finished = js_sys::Promise::new(&mut |resolve, _| {
+            *resolver.borrow_mut() = Some(resolve);
+        });
+        let (state, done) = (self.state.clone(), resolver.clone());
+        let onmessage = Closure::wrap(Box::new(move |event: MessageEvent| {
+            let data = event.data();
+            match data.as_string() {
+                Some(json) => on_event(&state, &json, &done),
+                None => match data.dyn_into::<js_sys::ArrayBuffer>() {
+                    Ok(audio) => on_audio(&state, audio),
+                    Err(_) => console_log!("Relay sent a message that is neither text nor audio"),
+                },
+            }
+        }) as Box<dyn FnMut(MessageEvent)>);
+        socket.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+        let done = resolver.clone();
+        let onclose = Closure::wrap(Box::new(move |_: Event| {
+            if let Some(resolve) = done.borrow_mut().take() {
+                let _ = resolve.call0(&JsValue::NULL);
+            }
+        }) as Box<dyn FnMut(Event)>);
+        socket.set_onclose(Some(onclose.as_ref().unchecked_ref()));
+
+        opened(&socket).await?;
+        self.state.borrow_mut().link = Some(Link {
+            socket,
+            finished,
+            resolver,
+            _onmessage: onmessage,
+            _onclose: onclose,
+        });
+        Ok(())
+    }
+
+    /// Adds text, sending it once it completes a sentence.
+    #[wasm_bindgen]
+    pub fn push(&self, text: &str) -> Result<(), JsValue> {
+        let complete = {
+            let mut state = self.state.borrow_mut();
+            state.buffer.push_str(text);
+            match last_sentence_end(&state.buffer) {
+                Some(end) => state.buffer.drain(..end).collect::<String>(),
+                None => return Ok(()),
+            }
+        };
+        self.send(&complete, false, false)
+    }
+
+    /// Speaks what is held even without a sentence end, e.g. when the
+    /// writer pauses.
+    #[wasm_bindgen]
+    pub fn flush(&self) -> Result<(), JsValue> {
+        let rest = std::mem::take(&mut self.state.borrow_mut().buffer);
+        self.send(&rest, true, false)
+    }
+
+    /// Speaks what is held and has the server close the socket once
+    /// everything is spoken.
+    #[wasm_bindgen]
+    pub fn end(&self) -> Result<(), JsValue> {
+        let rest = std::mem::take(&mut self.state.borrow_mut().buffer);
+        self.send(&rest, false, true)
+    }
+
+    /// Resolves once everything sent before `end()` has been spoken by the
+    /// server (it may still be playing), or the socket closed.
+    #[wasm_bindgen]
+    pub async fn finished(&self) -> Result<(), JsValue> {
+        let finished = self.state.borrow().link.as_ref().map(|l| l.finished.clone());
+        if let Some(finished) = finished {
+            JsFuture::from(finished).await?;
+        }
+        Ok(())
+    }
+
+    /// Pushes every string of the async iterable `tokens`, then `end()`s and
+    /// waits until the server is done.
+    #[wasm_bindgen]
+    pub async fn pipe(&self, tokens: JsValue) -> Result<(), JsValue> {
+        let make = js_sys::Reflect::get(&tokens, &js_sys::Symbol::async_iterator())?;
+        let make: js_sys::Function = make.dyn_into().map_err(|_| "not an async iterable")?;
+        let iterator = make.call0(&tokens)?;
+        let next: js_sys::Function = js_sys::Reflect::get(&iterator, &"next".into())?.dyn_into()?;
+        loop {
+            let step = JsFuture::from(js_sys::Promise::resolve(&next.call0(&iterator)?)).await?;
+            if js_sys::Reflect::get(&step, &"done".into())?.is_truthy() {
+                break;
+            }
+            match js_sys::Reflect::get(&step, &"value".into())?.as_string() {
+                Some(token) => self.push(&token)?,
+                None => console_log!("Relay skipped a token that is not a string"),
+            }
+        }
+        self.end()?;
+        self.finished().await
+    }
+
+    /// Pushes the text of each `data:` line of a `fetch` response streaming
+    /// server-sent events, up to `[DONE]`, then `end()`s and waits until the
+    /// server is done. `extract`, when given, maps each event's data to its
+    /// text (e.g. parsing an LLM API's JSON chunk); it may return undefined
+    /// for events without any.
+    #[wasm_bindgen]
+    pub async fn pipe_sse(&self, response: Response, extract: Option<js_sys::Function>) -> Result<(), JsValue> {
+        let body = response.body().ok_or("the response has no body")?;
+        let reader: ReadableStreamDefaultReader = body.get_reader().dyn_into()?;
+        let mut pending: Vec<u8> = Vec::new();
+        'read: loop {
+            let chunk = JsFuture::from(reader.read()).await?;
+            if js_sys::Reflect::get(&chunk, &"done".into())?.is_truthy() {
+                break;
+            }
+            let value: js_sys::Uint8Array = js_sys::Reflect::get(&chunk, &"value".into())?.dyn_into()?;
+            pending.extend(value.to_vec());
+            // Only whole lines, so multi-byte characters aren't split.
+            while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = pending.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                let Some(data) = line.trim_end_matches(['\r', '\n']).strip_prefix("data:") else {
+                    continue;
+                };
+                let data = data.strip_prefix(' ').unwrap_or(data);
+                if data == "[DONE]" {
+                    let _ = reader.cancel();
+                    break 'read;
+                }
+                let text = match &extract {
+                    Some(extract) => extract.call1(&JsValue::NULL, &data.into())?.as_string(),
+                    None => Some(data.to_string()),
+                };
+                if let Some(text) = text {
+                    self.push(&text)?;
+                }
+            }
+        }
+        self.end()?;
+        self.finished().await
+    }
+
+    /// Closes the socket and silences what is playing. Whatever waits for
+    /// the socket to finish stops waiting.
+    #[wasm_bindgen]
+    pub fn stop(&self) {
+        let mut state = self.state.borrow_mut();
+        if let Some(link) = state.link.take() {
+            // The handlers are dropped with the link, before the close event.
+            link.socket.set_onmessage(None);
+            link.socket.set_onclose(None);
+            let _ = link.socket.close();
+            if let Some(resolve) = link.resolver.borrow_mut().take() {
+                let _ = resolve.call0(&JsValue::NULL);
+            }
+        }
+        state.buffer.clear();
+        state.announced = None;
+        state.undecoded.clear();
+        for (source, _) in state.sources.drain(..) {
+            let _ = AudioScheduledSourceNode::stop(&source);
+        }
+        state.next_start = 0.0;
+    }
+}
+
+impl SpeechRelay {
+    fn send(&self, text: &str, flush: bool, end: bool) -> Result<(), JsValue> {
+        let state = self.state.borrow();
+        let link = state.link.as_ref().ok_or("not connected")?;
+        let message = js_sys::Object::new();
+        js_sys::Reflect::set(&message, &"text".into(), &text.into())?;
+        js_sys::Reflect::set(&message, &"flush".into(), &flush.into())?;
+        js_sys::Reflect::set(&message, &"end".into(), &end.into())?;
+        link.socket.send_with_str(&String::from(js_sys::JSON::stringify(&message)?))
+    }
+}
+
+/// The socket's address: `/api/tts/relay` on this page's host, with the
+/// description and params as the query string.
+fn relay_url(state: &RelayState) -> Result<String, JsValue> {
+    let location = web_sys::window().ok_or("no window")?.location();
+    let scheme = if location.protocol()? == "https:" { "wss" } else { "ws" };
+    let mut query = Vec::new();
+    if !state.description.is_empty() {
+        query.push(("description".to_string(), state.description.clone()));
+    }
+    query.extend(state.params.iter().cloned());
+    let query: Vec<String> = query
+        .iter()
+        .map(|(name, value)| format!("{}={}", js_sys::encode_uri_component(name), js_sys::encode_uri_component(value)))
+        .collect();
+    Ok(format!("{scheme}://{}/api/tts/relay?{}", location.host()?, query.join("&")))
+}
+
+/// Byte offset just past the last sentence end in `text`: sentence
+/// punctuation followed by whitespace, a full-width end, or a line break.
+fn last_sentence_end(text: &str) -> Option<usize> {
+    let mut end = None;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let after = i + c.len_utf8();
+        let spaced = chars.peek().is_some_and(|&(_, next)| next.is_whitespace());
+        if c == '\n' || matches!(c, '。' | '！' | '？') || (matches!(c, '.' | '!' | '?' | ';' | '…') && spaced) {
+            end = Some(after);
+        }
+    }
+    end
+}
+
+async fn opened(socket: &WebSocket) -> Result<(), JsValue> {
+    let opening = js_sys::Promise::new(&mut |resolve, reject| {
+        socket.set_onopen(Some(&resolve));
+        socket.set_onerror(Some(&reject));
+    });
+    let result = JsFuture::from(opening).await;
+    socket.set_onopen(None);
+    socket.set_onerror(None);
+    result.map(|_| ()).map_err(|_| JsValue::from_str("the relay socket could not be opened"))
+}
+
+fn on_event(state: &Rc<RefCell<RelayState>>, json: &str, done: &Rc<RefCell<Option<js_sys::Function>>>) {
+    let Ok(event) = js_sys::JSON::parse(json) else {
+        return;
+    };
+    let field = |name: &str| js_sys::Reflect::get(&event, &name.into()).unwrap_or(JsValue::UNDEFINED);
+    match field("type").as_string().as_deref() {
+        Some("sentence") => {
+            let index = field("index").as_f64().unwrap_or(0.0) as u32;
+            state.borrow_mut().announced = Some((index, field("text").as_string().unwrap_or_default()));
+        }
+        Some("error") => {
+            let (code, message) = (field("code"), field("message"));
+            console_log!("Relay sentence failed: {:?}", message);
+            let callback = state.borrow().on_error.clone();
+            if let Some(callback) = callback {
+                let _ = callback.call2(&JsValue::NULL, &code, &message);
+            }
+        }
+        Some("done") => {
+            if let Some(resolve) = done.borrow_mut().take() {
+                let _ = resolve.call0(&JsValue::NULL);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn on_audio(state: &Rc<RefCell<RelayState>>, audio: js_sys::ArrayBuffer) {
+    {
+        let mut relay = state.borrow_mut();
+        let (index, text) = relay.announced.take().unwrap_or_default();
+        relay.undecoded.push_back((index, text, audio));
+        if relay.decoding {
+            return;
+        }
+        relay.decoding = true;
+    }
+    // One at a time, so sentences are scheduled in the order they came.
+    let state = state.clone();
+    spawn_local(async move {
+        loop {
+            let next = state.borrow_mut().undecoded.pop_front();
+            let Some((index, text, audio)) = next else {
+                break;
+            };
+            if let Err(err) = schedule(&state, index, &text, &audio).await {
+                console_log!("Relay audio could not be played: {:?}", err);
+            }
+        }
+        state.borrow_mut().decoding = false;
+    });
+}
+
+async fn schedule(
+    state: &Rc<RefCell<RelayState>>,
+    index: u32,
+    text: &str,
+    audio: &js_sys::ArrayBuffer,
+) -> Result<(), JsValue> {
+    let context = state.borrow().context.clone().ok_or("no audio context")?;
+    let buffer: AudioBuffer = JsFuture::from(context.decode_audio_data(audio)?).await?.dyn_into()?;
+    let mut relay = state.borrow_mut();
+    if relay.link.is_none() {
+        return Ok(());
+    }
+    let now = context.current_time();
+    relay.sources.retain(|(_, end)| *end > now);
+    let source = context.create_buffer_source()?;
+    source.set_buffer(Some(&buffer));
+    source.connect_with_audio_node(&context.destination())?;
+    let start = relay.next_start.max(now);
+    source.start_with_when(start)?;
+    relay.next_start = start + buffer.duration();
+    let end = relay.next_start;
+    relay.sources.push((source, end));
+
+    if let Some(callback) = relay.on_sentence.clone() {
+        let text = text.to_string();
+        let notify = Closure::once_into_js(move || {
+            let _ = callback.call2(&JsValue::NULL, &index.into(), &text.into());
+        });
+        web_sys::window()
+            .ok_or("no window")?
+            .set_timeout_with_callback_and_timeout_and_arguments_0(notify.unchecked_ref(), ((start - now) * 1000.0) as i32)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,278 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use wasm_bindgen::JsCast;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::*;
+
+/// Live playback of speech the server sends over WebRTC.
+///
+/// `connect(audio)` negotiates a session with `/api/webrtc/offer` and plays
+/// its track on the given `<audio>` element; `speak(text)` then has the
+/// server say `text` into it as it is generated. The `on_state` callback
+/// receives the peer connection state (`"connecting"`, `"connected"`,
+/// `"disconnected"`, `"failed"`, `"closed"`).
+#[wasm_bindgen]
+pub struct RtcPlayer {
+    state: Rc<RefCell<PlayerState>>,
+}
+
+struct PlayerState {
+    description: String,
+    params: Vec<(String, String)>,
+    ice_servers: Vec<String>,
+    on_state: Option<js_sys::Function>,
+    link: Option<Link>,
+}
+
+/// A negotiated session and what keeps its handlers alive.
+struct Link {
+    session: String,
+    peer: RtcPeerConnection,
+    audio: HtmlAudioElement,
+    _ontrack: Closure<dyn FnMut(RtcTrackEvent)>,
+    _onstatechange: Closure<dyn FnMut(Event)>,
+}
+
+#[wasm_bindgen]
+impl RtcPlayer {
+    #[wasm_bindgen(constructor)]
+    pub fn new(description: &str) -> RtcPlayer {
+        RtcPlayer {
+            state: Rc::new(RefCell::new(PlayerState {
+                description: description.to_string(),
+                params: Vec::new(),
+                ice_servers: Vec::new(),
+                on_state: None,
+                link: None,
+            })),
+        }
+    }
+
+    #[wasm_bindgen]
+    pub fn set_description(&self, description: &str) {
+        self.state.borrow_mut().description = description.to_string();
+    }
+
+    /// Sets an extra form field (e.g. `voice`, `seed`) sent with every `speak`.
+    #[wasm_bindgen]
+    pub fn set_param(&self, name: &str, value: &str) {
+        let mut state = self.state.borrow_mut();
+        state.params.retain(|(n, _)| n != name);
+        state.params.push((name.to_string(), value.to_string()));
+    }
+
+    /// Adds a STUN/TURN server URL, used from the next `connect`.
+    #[wasm_bindgen]
+    pub fn add_ice_server(&self, url: &str) {
+        self.state.borrow_mut().ice_servers.push(url.to_string());
+    }
+
+    #[wasm_bindgen]
+    pub fn on_state(&self, callback: js_sys::Function) {
+        self.state.borrow_mut().on_state = Some(callback);
+    }
+
+    /// The server's id for the current session, once connected.
+    #[wasm_bindgen]
+    pub fn session(&self) -> Option<String> {
+        self.state.borrow().link.as_ref().map(|l| l.session.clone())
+    }
+
+    /// Opens a session, hanging up any previous one, and plays it on
+    /// `audio`. Call it from a user gesture so the browser allows playback.
+    #[wasm_bindgen]
+    pub async fn connect(&self, audio: HtmlAudioElement) -> Result<(), JsValue> {
+        self.hang_up().await;
+
+        let config = RtcConfiguration::new();
+        let servers = js_sys::Array::new();
+        for url in &self.state.borrow().ice_servers {
+            let server = RtcIceServer::new();
+            server.set_urls(&JsValue::from_str(url));
+            servers.push(&server);
+        }
+        config.set_ice_servers(&servers);
+        let peer = RtcPeerConnection::new_with_configuration(&config)?;
+
+        let init = RtcRtpTransceiverInit::new();
+        init.set_direction(RtcRtpTransceiverDirection::Recvonly);
+        peer.add_transceiver_with_str_and_init("audio", &init);
+
+        let player = audio.clone();
+        let ontrack = Closure::wrap(Box::new(move |event: RtcTrackEvent| {
+            let stream = match event.streams().get(0).dyn_into::<MediaStream>() {
+                Ok(stream) => stream,
+                Err(_) => match MediaStream::new() {
+                    Ok(stream) => {
+                        stream.add_track(&event.track());
+                        stream
+                    }
+                    Err(err) => {
+                        console_log!("WebRTC track could not be attached: {:?}", err);
+                        return;
+                    }
+                },
+            };
+            player.set_src_object(Some(&stream));
+            let _ = player.play();
+        }) as Box<dyn FnMut(RtcTrackEvent)>);
+        peer.set_ontrack(Some(ontrack.as_ref().unchecked_ref()));
+
+        let state = self.state.clone();
+        let watched = peer.clone();
+        let onstatechange = Closure::wrap(Box::new(move |_: Event| {
+            notify(&state, watched.connection_state().into());
+        }) as Box<dyn FnMut(Event)>);
+        peer.set_onconnectionstatechange(Some(onstatechange.as_ref().unchecked_ref()));
+
+        let session = match negotiate(&peer).await {
+            Ok(session) => session,
+            Err(err) => {
+                peer.close();
+                notify(&self.state, RtcPeerConnectionState::Failed.into());
+                return Err(err);
+            }
+        };
+        self.state.borrow_mut().link = Some(Link {
+            session,
+            peer,
+            audio,
+            _ontrack: ontrack,
+            _onstatechange: onstatechange,
+        });
+        Ok(())
+    }
+
+    /// Has the server speak `text` into the session; resolves to the clip's
+    /// history id as soon as the request is accepted.
+    #[wasm_bindgen]
+    pub async fn speak(&self, text: &str) -> Result<String, JsValue> {
+        let (session, description, params) = {
+            let state = self.state.borrow();
+            let session = state
+                .link
+                .as_ref()
+                .map(|l| l.session.clone())
+                .ok_or("not connected")?;
+            (session, state.description.clone(), state.params.clone())
+        };
+
+        let form_data = FormData::new()?;
+        form_data.append_with_str("text", text)?;
+        form_data.append_with_str("description", &description)?;
+        for (name, value) in &params {
+            form_data.append_with_str(name, value)?;
+        }
+        form_data.append_with_str("webrtc_session", &session)?;
+
+        let opts = RequestInit::new();
+        opts.set_method("POST");
+        opts.set_body(&form_data);
+        let response = fetch("/api/tts", &opts).await?;
+        let reply = JsFuture::from(response.json()?).await?;
+        js_sys::Reflect::get(&reply, &"clip_id".into())?
+            .as_string()
+            .ok_or_else(|| "TTS reply has no clip_id".into())
+    }
+
+    /// Closes the session, if any, on both ends.
+    #[wasm_bindgen]
+    pub async fn hang_up(&self) {
+        let link = self.state.borrow_mut().link.take();
+        let Some(link) = link else {
+            return;
+        };
+        link.peer.set_onconnectionstatechange(None);
+        link.peer.close();
+        link.audio.set_src_object(None);
+        notify(&self.state, RtcPeerConnectionState::Closed.into());
+
+        let opts = RequestInit::new();
+        opts.set_method("DELETE");
+        let url = format!("/api/webrtc/{}", link.session);
+        // The server drops the session by itself once the peer is gone.
+        if let Err(err) = fetch(&url, &opts).await {
+            console_log!("WebRTC hang-up request failed: {:?}", err);
+        }
+    }
+}
+
+fn notify(state: &Rc<RefCell<PlayerState>>, value: JsValue) {
+    let callback = state.borrow().on_state.clone();
+    if let Some(callback) = callback {
+        let _ = callback.call1(&JsValue::NULL, &value);
+    }
+}
+
+/// Offers a receive-only audio session to the server and applies its
+/// answer. Returns the session id.
+async fn negotiate(peer: &RtcPeerConnection) -> Result<String, JsValue> {
+    let offer: RtcSessionDescriptionInit = JsFuture::from(peer.create_offer()).await?.unchecked_into();
+    JsFuture::from(peer.set_local_description(&offer)).await?;
+    // The server takes no trickled candidates, so the offer carries them all.
+    gathering_complete(peer).await?;
+    let local = peer.local_description().ok_or("no local description")?;
+
+    let body = js_sys::Object::new();
+    js_sys::Reflect::set(&body, &"type".into(), &"offer".into())?;
+    js_sys::Reflect::set(&body, &"sdp".into(), &local.sdp().into())?;
+    let headers = js_sys::Object::new();
+    js_sys::Reflect::set(&headers, &"Content-Type".into(), &"application/json".into())?;
+    let opts = RequestInit::new();
+    opts.set_method("POST");
+    opts.set_headers(&headers);
+    opts.set_body(&js_sys::JSON::stringify(&body)?.into());
+    let response = fetch("/api/webrtc/offer", &opts).await?;
+    let reply = JsFuture::from(response.json()?).await?;
+
+    let session = js_sys::Reflect::get(&reply, &"session".into())?
+        .as_string()
+        .ok_or("WebRTC reply has no session")?;
+    let answer = js_sys::Reflect::get(&reply, &"answer".into())?;
+    let sdp = js_sys::Reflect::get(&answer, &"sdp".into())?
+        .as_string()
+        .ok_or("WebRTC reply has no answer")?;
+    let answer = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
+    answer.set_sdp(&sdp);
+    JsFuture::from(peer.set_remote_description(&answer)).await?;
+    Ok(session)
+}
+
+async fn gathering_complete(peer: &RtcPeerConnection) -> Result<(), JsValue> {
+    if peer.ice_gathering_state() == RtcIceGatheringState::Complete {
+        return Ok(());
+    }
+    let resolver: Rc<RefCell<Option<js_sys::Function>>> = Rc::default();
+    let (watched, waiting) = (peer.clone(), resolver.clone());
+    let onchange = Closure::wrap(Box::new(move |_: Event| {
+        if watched.ice_gathering_state() == RtcIceGatheringState::Complete
+            && let Some(resolve) = waiting.borrow_mut().take()
+        {
+            let _ = resolve.call0(&JsValue::NULL);
+        }
+    }) as Box<dyn FnMut(Event)>);
+    let done = js_sys::Promise::new(&mut |resolve, _| {
+        *resolver.borrow_mut() = Some(resolve);
+    });
+    peer.set_onicegatheringstatechange(Some(onchange.as_ref().unchecked_ref()));
+    let result = JsFuture::from(done).await;
+    peer.set_onicegatheringstatechange(None);
+    result.map(|_| ())
+}
+
+async fn fetch(url: &str, opts: &RequestInit) -> Result<Response, JsValue> {
+    let window = web_sys::window().ok_or("no window")?;
+    let request = Request::new_with_str_and_init(url, opts)?;
+    let response: Response = JsFuture::from(window.fetch_with_request(&request))
+        .await?
+        .dyn_into()?;
+    if !response.ok() {
+        return Err(JsValue::from_str(&format!(
+            "{url} failed with status: {}",
+            response.status()
+        )));
+    }
+    Ok(response)
+}
@@ -0,0 +1,64 @@
+use std::cell::RefCell;
+
+use wasm_bindgen::prelude::*;
+
+use crate::player::AudioQueue;
+use crate::reader::split_sentences;
+
+/// Used by `speak` calls without a voice until `speaker().set_description`
+/// changes it.
+const DEFAULT_DESCRIPTION: &str =
+    "A female speaker delivers her words in a clear, moderately paced voice, with very clear audio.";
+
+/// Clips the shared queue keeps, so repeated prompts (e.g. "Done!") play at
+/// once.
+const CACHE_CLIPS: usize = 32;
+
+thread_local! {
+    static SPEAKER: RefCell<Option<AudioQueue>> = const { RefCell::new(None) };
+}
+
+/// Says `text`, in the voice preset `voice` or the default description,
+/// after whatever is already being said.
+///
+/// One shared `AudioQueue` (see `speaker()`) does the work: the text is
+/// split into sentences, recently spoken sentences come from its cache, and
+/// failed requests go to `on_error` (`speaker().on_error(cb)`) or the
+/// console. The first call should come from a user gesture so the browser
+/// lets the audio play. Returns the number of sentences queued.
+#[wasm_bindgen]
+pub fn speak(text: &str, voice: Option<String>) -> Result<usize, JsValue> {
+    let queue = speaker()?;
+    let sentences = split_sentences(text);
+    let count = sentences.len();
+    for sentence in sentences {
+        queue.enqueue_voice(&sentence, voice.clone());
+    }
+    Ok(count)
+}
+
+/// Stops what `speak` is saying and drops what it has queued.
+#[wasm_bindgen]
+pub fn stop_speaking() {
+    SPEAKER.with(|speaker| {
+        if let Some(queue) = speaker.borrow().as_ref() {
+            queue.clear();
+        }
+    });
+}
+
+/// The queue behind `speak`, e.g. to change its description, set params
+/// or add an `on_error` callback. Created on first use.
+#[wasm_bindgen]
+pub fn speaker() -> Result<AudioQueue, JsValue> {
+    SPEAKER.with(|speaker| {
+        let mut speaker = speaker.borrow_mut();
+        if let Some(queue) = speaker.as_ref() {
+            return Ok(queue.clone());
+        }
+        let queue = AudioQueue::new(DEFAULT_DESCRIPTION)?;
+        queue.set_cache_size(CACHE_CLIPS);
+        *speaker = Some(queue.clone());
+        Ok(queue)
+    })
+}